@@ -0,0 +1,31 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Stamps the binary with the commit it was built from and when, for GET /version to report. Builds outside a git
+// checkout report the sha as 'unknown', and SOURCE_DATE_EPOCH pins the build time for reproducible builds
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let built_at = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).expect("Clock is before 1970").as_secs());
+
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=BUILT_AT_EPOCH_SECONDS={}", built_at);
+
+    // A new commit or changed sources restamp the binary, anything else leaves the stamp as it was
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
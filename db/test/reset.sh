@@ -17,5 +17,9 @@ delete_entries() {
 # Delete entries from different tables and measure time
 delete_entries "ships"
 delete_entries "empires"
+delete_entries "idempotency_keys"
 delete_entries "locations"
 delete_entries "users"
+
+delete_entries "audit_log"
+delete_entries "location_audit"
\ No newline at end of file
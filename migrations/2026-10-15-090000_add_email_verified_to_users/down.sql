@@ -0,0 +1,2 @@
+-- Drop the email_verified column from the users table
+ALTER TABLE users DROP COLUMN email_verified;
\ No newline at end of file
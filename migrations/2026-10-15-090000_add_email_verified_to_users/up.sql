@@ -0,0 +1,2 @@
+-- Track whether a user has verified their email address
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Drop the timestamp columns from the locations table
+ALTER TABLE locations
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at;
\ No newline at end of file
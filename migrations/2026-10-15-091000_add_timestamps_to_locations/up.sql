@@ -0,0 +1,4 @@
+-- Track when locations are created and last updated
+ALTER TABLE locations
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
\ No newline at end of file
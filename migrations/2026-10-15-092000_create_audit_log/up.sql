@@ -0,0 +1,8 @@
+-- Create the audit_log table recording security sensitive actions and who performed them
+CREATE TABLE audit_log (
+                          id SERIAL PRIMARY KEY,
+                          actor VARCHAR(100) NOT NULL,
+                          action VARCHAR(50) NOT NULL,
+                          target VARCHAR(100) NOT NULL,
+                          created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
\ No newline at end of file
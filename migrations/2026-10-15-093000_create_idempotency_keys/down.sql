@@ -0,0 +1,2 @@
+-- Drop the idempotency_keys table
+DROP TABLE idempotency_keys;
\ No newline at end of file
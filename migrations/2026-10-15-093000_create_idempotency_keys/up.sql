@@ -0,0 +1,7 @@
+-- Create the idempotency_keys table remembering which location a retried POST /locations already created
+CREATE TABLE idempotency_keys (
+                          idempotency_key VARCHAR(255) PRIMARY KEY,
+                          request_body TEXT NOT NULL,
+                          location_id INTEGER NOT NULL REFERENCES locations(id) ON DELETE CASCADE,
+                          created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,2 @@
+-- Drop the case-insensitive unique index on email
+DROP INDEX users_email_lower_key;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Emails differing only by casing belong to the same person
+CREATE UNIQUE INDEX users_email_lower_key ON users (lower(email));
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Drop the deleted_at column from the users table
+ALTER TABLE users DROP COLUMN deleted_at;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Deleted users are kept, so their audit history stays attributable and they can be restored
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
\ No newline at end of file
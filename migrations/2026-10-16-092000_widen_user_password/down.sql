@@ -0,0 +1,2 @@
+-- Narrow the password column back to 100 characters
+ALTER TABLE users ALTER COLUMN password TYPE VARCHAR(100);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- argon2 hashes carry their parameters and salt, and may outgrow 100 characters
+ALTER TABLE users ALTER COLUMN password TYPE VARCHAR(255);
\ No newline at end of file
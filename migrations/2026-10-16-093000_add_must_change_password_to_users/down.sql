@@ -0,0 +1,2 @@
+-- Drop the must_change_password column from the users table
+ALTER TABLE users DROP COLUMN must_change_password;
\ No newline at end of file
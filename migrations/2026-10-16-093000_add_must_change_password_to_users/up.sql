@@ -0,0 +1,2 @@
+-- Set when an admin resets the password, so the user picks a new one on their next login
+ALTER TABLE users ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT FALSE;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Drop the location_audit table
+DROP TABLE location_audit;
\ No newline at end of file
@@ -0,0 +1,13 @@
+-- Create the location_audit table recording every change to a location, who made it and what it looked like before and after.
+-- location_id deliberately has no foreign key, so the history of a deleted location is kept
+CREATE TABLE location_audit (
+                                id SERIAL PRIMARY KEY,
+                                location_id INTEGER NOT NULL,
+                                action VARCHAR(10) NOT NULL,
+                                actor VARCHAR(100) NOT NULL,
+                                before JSONB,
+                                after JSONB,
+                                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX location_audit_location_id_idx ON location_audit (location_id, id);
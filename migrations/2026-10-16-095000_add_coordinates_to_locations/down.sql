@@ -0,0 +1,5 @@
+-- Remove the coordinate columns from the locations table
+ALTER TABLE locations
+    DROP COLUMN x,
+    DROP COLUMN y,
+    DROP COLUMN z;
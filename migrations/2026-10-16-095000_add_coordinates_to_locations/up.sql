@@ -0,0 +1,5 @@
+-- Add coordinates to the locations table. They are nullable as existing locations have none, and those are left out of nearby searches
+ALTER TABLE locations
+    ADD COLUMN x DOUBLE PRECISION,
+    ADD COLUMN y DOUBLE PRECISION,
+    ADD COLUMN z DOUBLE PRECISION;
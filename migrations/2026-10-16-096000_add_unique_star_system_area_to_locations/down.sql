@@ -0,0 +1,2 @@
+-- Allow duplicate locations again, renamed duplicates keep their new area
+ALTER TABLE locations DROP CONSTRAINT locations_star_system_area_key;
@@ -0,0 +1,7 @@
+-- Existing duplicates are kept, as other tables refer to them, but every copy after the first gets its id appended to the area
+UPDATE locations
+SET area = LEFT(area, 100 - LENGTH(' (#' || id || ')')) || ' (#' || id || ')'
+WHERE id NOT IN (SELECT MIN(id) FROM locations GROUP BY star_system, area);
+
+-- No two locations may share both star system and area
+ALTER TABLE locations ADD CONSTRAINT locations_star_system_area_key UNIQUE (star_system, area);
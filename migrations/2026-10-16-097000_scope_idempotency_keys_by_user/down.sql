@@ -0,0 +1,5 @@
+-- Make idempotency keys global again. Keys can't be told apart without their user, so they are dropped
+DELETE FROM idempotency_keys;
+ALTER TABLE idempotency_keys DROP CONSTRAINT idempotency_keys_pkey;
+ALTER TABLE idempotency_keys DROP COLUMN user_id;
+ALTER TABLE idempotency_keys ADD PRIMARY KEY (idempotency_key);
\ No newline at end of file
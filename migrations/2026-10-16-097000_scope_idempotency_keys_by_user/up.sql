@@ -0,0 +1,6 @@
+-- Scope idempotency keys by the user who sent them, so one user's key never replays another user's response.
+-- Stored keys can't be attributed to anyone and expire within a day anyway, so they are dropped
+DELETE FROM idempotency_keys;
+ALTER TABLE idempotency_keys DROP CONSTRAINT idempotency_keys_pkey;
+ALTER TABLE idempotency_keys ADD COLUMN user_id INTEGER NOT NULL;
+ALTER TABLE idempotency_keys ADD PRIMARY KEY (user_id, idempotency_key);
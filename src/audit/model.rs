@@ -0,0 +1,34 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde_derive::{Serialize, Deserialize};
+use crate::{common::util::load_flag_environment_variable, schema::audit_log};
+
+#[derive(Serialize, Debug, Clone, Queryable)]
+#[diesel(table_name = audit_log)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable, Deserialize, Serialize)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditEntry {
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+}
+
+// Whether reads of sensitive resources are recorded in the audit log, which costs a write per read
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReadAudit(pub bool);
+
+impl ReadAudit {
+
+    // Reads READ_AUDIT, which is off unless explicitly enabled
+    pub fn from_env() -> ReadAudit {
+        ReadAudit(load_flag_environment_variable("READ_AUDIT", false))
+    }
+}
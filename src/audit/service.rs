@@ -0,0 +1,31 @@
+pub mod service {
+    use diesel::{
+        prelude::*,
+        PgConnection,
+        r2d2::{ConnectionManager, PooledConnection},
+    };
+    use crate::{
+        audit::model::{AuditEntry, NewAuditEntry},
+        schema
+    };
+
+    type PooledPg = PooledConnection<ConnectionManager<PgConnection>>;
+
+    pub struct AuditLogTable {
+        connection: PooledPg,
+    }
+
+    impl AuditLogTable {
+        pub fn new(connection: PooledPg) -> AuditLogTable {
+            AuditLogTable { connection }
+        }
+
+        pub fn record(&mut self, new_entry: NewAuditEntry) -> Result<AuditEntry, diesel::result::Error> {
+            use schema::audit_log;
+
+            diesel::insert_into(audit_log::table)
+                .values(&new_entry)
+                .get_result(&mut self.connection)
+        }
+    }
+}
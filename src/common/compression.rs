@@ -0,0 +1,30 @@
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
+use crate::common::util::load_optional_environment_variable;
+
+// Below this size the compression overhead outweighs the bytes saved
+const DEFAULT_COMPRESSION_MIN_BYTES: u16 = 1024;
+
+// Reads COMPRESSION_MIN_BYTES - the smallest response body that is compressed
+pub fn compression_min_bytes() -> u16 {
+    match load_optional_environment_variable("COMPRESSION_MIN_BYTES") {
+        Some(bytes) => bytes.parse::<u16>()
+            .expect("COMPRESSION_MIN_BYTES must be a whole number of bytes no larger than 65535"),
+        None => DEFAULT_COMPRESSION_MIN_BYTES,
+    }
+}
+
+// Compresses responses with gzip or brotli, whichever the client's Accept-Encoding prefers. Streamed responses
+// have no known size and are always compressed, while images and event streams never are
+pub fn compression_layer(min_bytes: u16) -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(
+        SizeAbove::new(min_bytes)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+
+            // An encoder holds back what it was given until it has enough to compress, which would delay events
+            .and(NotForContentType::const_new("text/event-stream"))
+    )
+}
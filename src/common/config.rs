@@ -0,0 +1,193 @@
+use std::{fmt, time::Duration};
+use crate::common::{
+    cors::{parse_allowed_origins, CorsOrigins, DEFAULT_CORS_MAX_AGE_SECONDS},
+    db::PoolConfig,
+    login_attempts::{DEFAULT_FAILURE_WINDOW_SECONDS, DEFAULT_MAX_FAILURES},
+    net::{parse_trusted_proxies, TrustedProxies},
+    security::token_ttl,
+    util::{load_optional_environment_variable, parse_environment_variable, parse_flag},
+};
+use crate::users::model::InvisibleCharPolicy;
+
+// Settings read once at startup and handed to the handlers through the router state, so none of them is read from
+// the environment mid-request. The required ones are checked together, so a deployment missing several of them
+// learns about all of them at once instead of one restart at a time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub database_url: String,
+    pub pool: PoolConfig,
+
+    // How long a token issued at login stays valid
+    pub token_ttl: Duration,
+    pub require_verified_login: bool,
+
+    // Whether zero-width characters are stripped from emails or refuse them
+    pub invisible_email_chars: InvisibleCharPolicy,
+
+    // Browsers on other origins are not let in at all without CORS_ALLOWED_ORIGINS
+    pub cors_allowed_origins: Option<CorsOrigins>,
+    pub cors_max_age: Duration,
+
+    // Whether location changes are announced with NOTIFY and listened for
+    pub notify_changes: bool,
+
+    // The load balancers whose X-Forwarded-For and X-Real-IP headers name the client
+    pub trusted_proxies: TrustedProxies,
+
+    // Failed logins allowed for an email within the window before it is locked out
+    pub login_max_failures: u32,
+    pub login_failure_window: Duration,
+
+    // Whether 500 responses carry the underlying error, only ever in debug builds
+    pub expose_error_details: bool,
+}
+
+// Every required environment variable that was unset or blank, in the order they are checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingVariables(pub Vec<&'static str>);
+
+impl fmt::Display for MissingVariables {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Missing required environment variables: {}", self.0.join(", "))
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        Config::from_lookup(load_optional_environment_variable).unwrap_or_else(|missing| panic!("{}", missing))
+    }
+
+    // Which variables are required depends on others - the JWT algorithm picks the secrets, and bootstrapping an admin
+    // needs their credentials. The JWT secrets themselves are only checked for presence, JwtConfig loads them
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Config, MissingVariables> {
+        let mut required = vec!["DEV_DB"];
+
+        match lookup("JWT_ALG").as_deref().map(str::trim) {
+            Some("RS256") => required.extend(["JWT_PRIVATE_KEY_PATH", "JWT_PUBLIC_KEY_PATH"]),
+            _ => required.push("ENCRYPTION_KEY"),
+        }
+
+        if lookup("BOOTSTRAP_ADMIN").is_some_and(|value| matches!(value.to_lowercase().as_str(), "true" | "1")) {
+            required.extend(["ADMIN_EMAIL", "ADMIN_PASSWORD"]);
+        }
+
+        let value = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+        let missing: Vec<&'static str> = required.into_iter().filter(|name| value(name).is_none()).collect();
+
+        if !missing.is_empty() {
+            return Err(MissingVariables(missing));
+        }
+
+        Ok(Config {
+            database_url: value("DEV_DB").unwrap_or_default(),
+            pool: PoolConfig::from_lookup(&lookup),
+            token_ttl: token_ttl(lookup("JWT_TTL_SECONDS").as_deref()),
+            require_verified_login: parse_flag("REQUIRE_VERIFIED_LOGIN", lookup("REQUIRE_VERIFIED_LOGIN").as_deref(), false),
+            invisible_email_chars: InvisibleCharPolicy::stripping(
+                parse_flag("STRIP_INVISIBLE_EMAIL_CHARS", lookup("STRIP_INVISIBLE_EMAIL_CHARS").as_deref(), false)
+            ),
+            cors_allowed_origins: lookup("CORS_ALLOWED_ORIGINS").map(|origins| parse_allowed_origins(&origins)),
+            cors_max_age: Duration::from_secs(lookup("CORS_MAX_AGE")
+                .map_or(DEFAULT_CORS_MAX_AGE_SECONDS, |seconds| parse_environment_variable("CORS_MAX_AGE", &seconds))),
+            notify_changes: parse_flag("ENABLE_NOTIFY", lookup("ENABLE_NOTIFY").as_deref(), false),
+            trusted_proxies: lookup("TRUSTED_PROXIES").map(|proxies| parse_trusted_proxies(&proxies)).unwrap_or_default(),
+            login_max_failures: lookup("LOGIN_MAX_FAILURES")
+                .map_or(DEFAULT_MAX_FAILURES, |max_failures| parse_environment_variable("LOGIN_MAX_FAILURES", &max_failures)),
+            login_failure_window: Duration::from_secs(lookup("LOGIN_FAILURE_WINDOW_SECONDS")
+                .map_or(DEFAULT_FAILURE_WINDOW_SECONDS, |seconds| parse_environment_variable("LOGIN_FAILURE_WINDOW_SECONDS", &seconds))),
+            expose_error_details: cfg!(debug_assertions)
+                && parse_flag("EXPOSE_ERROR_DETAILS", lookup("EXPOSE_ERROR_DETAILS").as_deref(), false),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+    use axum::http::HeaderValue;
+    use crate::common::{
+        config::{Config, MissingVariables},
+        cors::CorsOrigins,
+        db::PoolConfig,
+        net::{parse_trusted_proxies, TrustedProxies},
+    };
+    use crate::users::model::InvisibleCharPolicy;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<Config, MissingVariables> {
+        let vars: HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Config::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn every_missing_variable_is_reported_together() {
+        let missing = from_vars(&[("BOOTSTRAP_ADMIN", "true"), ("ADMIN_EMAIL", " ")]).unwrap_err();
+
+        assert_eq!(missing, MissingVariables(vec!["DEV_DB", "ENCRYPTION_KEY", "ADMIN_EMAIL", "ADMIN_PASSWORD"]));
+        assert_eq!(missing.to_string(), "Missing required environment variables: DEV_DB, ENCRYPTION_KEY, ADMIN_EMAIL, ADMIN_PASSWORD");
+    }
+
+    #[test]
+    fn rs256_requires_key_paths_instead_of_the_secret() {
+        assert_eq!(
+            from_vars(&[("DEV_DB", "postgres://localhost/dev_db"), ("JWT_ALG", "RS256")]),
+            Err(MissingVariables(vec!["JWT_PRIVATE_KEY_PATH", "JWT_PUBLIC_KEY_PATH"]))
+        );
+    }
+
+    #[test]
+    fn unset_optional_settings_fall_back_to_defaults() {
+        let config = from_vars(&[("DEV_DB", "postgres://localhost/dev_db"), ("ENCRYPTION_KEY", "hemmelig")]).unwrap();
+
+        assert_eq!(config, Config {
+            database_url: "postgres://localhost/dev_db".to_string(),
+            pool: PoolConfig::from_lookup(&|_: &str| None),
+            token_ttl: Duration::from_secs(3600),
+            require_verified_login: false,
+            invisible_email_chars: InvisibleCharPolicy::Reject,
+            cors_allowed_origins: None,
+            cors_max_age: Duration::from_secs(600),
+            notify_changes: false,
+            trusted_proxies: TrustedProxies::default(),
+            login_max_failures: 10,
+            login_failure_window: Duration::from_secs(900),
+            expose_error_details: false,
+        });
+    }
+
+    #[test]
+    fn settings_are_typed_from_a_controlled_environment() {
+        let config = from_vars(&[
+            ("DEV_DB", "postgres://localhost/dev_db"),
+            ("ENCRYPTION_KEY", "hemmelig"),
+            ("DB_POOL_MAX_SIZE", "4"),
+            ("JWT_TTL_SECONDS", "900"),
+            ("REQUIRE_VERIFIED_LOGIN", "true"),
+            ("STRIP_INVISIBLE_EMAIL_CHARS", "true"),
+            ("CORS_ALLOWED_ORIGINS", "https://app.example.com"),
+            ("CORS_MAX_AGE", "120"),
+            ("ENABLE_NOTIFY", "1"),
+            ("TRUSTED_PROXIES", "10.0.0.0/8"),
+            ("LOGIN_MAX_FAILURES", "3"),
+            ("LOGIN_FAILURE_WINDOW_SECONDS", "60"),
+            ("EXPOSE_ERROR_DETAILS", "true"),
+        ]).unwrap();
+
+        assert_eq!(config.pool.max_size, 4);
+        assert_eq!(config.token_ttl, Duration::from_secs(900));
+        assert!(config.require_verified_login);
+        assert_eq!(config.invisible_email_chars, InvisibleCharPolicy::Strip);
+        assert_eq!(config.cors_allowed_origins, Some(CorsOrigins::List(vec![HeaderValue::from_static("https://app.example.com")])));
+        assert_eq!(config.cors_max_age, Duration::from_secs(120));
+        assert!(config.notify_changes);
+        assert_eq!(config.trusted_proxies, parse_trusted_proxies("10.0.0.0/8"));
+        assert_eq!(config.login_max_failures, 3);
+        assert_eq!(config.login_failure_window, Duration::from_secs(60));
+        assert_eq!(config.expose_error_details, cfg!(debug_assertions));
+    }
+
+    #[test]
+    #[should_panic(expected = "LOGIN_MAX_FAILURES")]
+    fn malformed_login_max_failures_is_refused_at_startup() {
+        let _ = from_vars(&[("DEV_DB", "postgres://localhost/dev_db"), ("ENCRYPTION_KEY", "hemmelig"), ("LOGIN_MAX_FAILURES", "ten")]);
+    }
+}
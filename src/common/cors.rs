@@ -0,0 +1,91 @@
+use std::time::Duration;
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use crate::common::login_attempts::{RATE_LIMIT_LIMIT_HEADER, RATE_LIMIT_REMAINING_HEADER, RATE_LIMIT_RESET_HEADER};
+
+// Long enough to spare most preflights, short enough that a change of policy reaches browsers the same day
+pub const DEFAULT_CORS_MAX_AGE_SECONDS: u64 = 600;
+
+// The origins browsers may call the API from, as CORS_ALLOWED_ORIGINS lists them
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+// CORS_ALLOWED_ORIGINS is a comma separated list of origins, or '*' for any
+pub fn parse_allowed_origins(origins: &str) -> CorsOrigins {
+    if origins.trim() == "*" {
+        return CorsOrigins::Any;
+    }
+
+    CorsOrigins::List(origins.split(',').map(str::trim).filter(|origin| !origin.is_empty()).map(|origin| {
+        origin.parse::<HeaderValue>()
+            .unwrap_or_else(|_| panic!("CORS_ALLOWED_ORIGINS must be a comma separated list of origins, got '{}'", origin))
+    }).collect())
+}
+
+// Answers preflights for the methods and headers the API uses, and lets scripts read the headers it answers with
+pub fn cors_layer(allowed_origins: &CorsOrigins, max_age: Duration) -> CorsLayer {
+    let allow_origin = match allowed_origins {
+        CorsOrigins::Any => AllowOrigin::any(),
+        CorsOrigins::List(origins) => AllowOrigin::list(origins.iter().cloned()),
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::IF_MATCH, HeaderName::from_static("idempotency-key")])
+        .expose_headers([
+            header::ETAG,
+            header::LOCATION,
+            header::LINK,
+            HeaderName::from_static("x-request-id"),
+            HeaderName::from_static(RATE_LIMIT_LIMIT_HEADER),
+            HeaderName::from_static(RATE_LIMIT_REMAINING_HEADER),
+            HeaderName::from_static(RATE_LIMIT_RESET_HEADER),
+        ])
+        .max_age(max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use axum::{body::Body, http::{HeaderValue, Request, StatusCode}, routing::get, Router};
+    use tower::ServiceExt;
+    use crate::common::cors::{cors_layer, parse_allowed_origins, CorsOrigins};
+
+    async fn preflight(max_age: Duration) -> axum::response::Response {
+        let service = Router::new()
+            .route("/locations", get(|| async { "[]" }))
+            .layer(cors_layer(&parse_allowed_origins("https://app.example.com, https://admin.example.com"), max_age));
+
+        let request = Request::builder()
+            .uri("/locations")
+            .method("OPTIONS")
+            .header("Origin", "https://app.example.com")
+            .header("Access-Control-Request-Method", "GET")
+            .body(Body::empty())
+            .unwrap();
+
+        service.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn preflight_is_answered_with_the_configured_max_age() {
+        let response = preflight(Duration::from_secs(120)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["access-control-max-age"], "120");
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://app.example.com");
+    }
+
+    #[test]
+    fn origins_are_a_trimmed_list_or_any() {
+        assert_eq!(parse_allowed_origins(" * "), CorsOrigins::Any);
+        assert_eq!(parse_allowed_origins("https://app.example.com, ,https://admin.example.com"), CorsOrigins::List(vec![
+            HeaderValue::from_static("https://app.example.com"),
+            HeaderValue::from_static("https://admin.example.com"),
+        ]));
+    }
+}
@@ -1,20 +1,311 @@
-use diesel::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
+use std::time::Duration;
+use diesel::{
+    result::{DatabaseErrorKind, Error::DatabaseError},
+    sql_query, PgConnection, RunQueryDsl,
+};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection};
+use crate::common::util::{load_optional_environment_variable, parse_environment_variable};
+
+// The app has always run on a single connection, the timeouts match r2d2's own
+const DEFAULT_MAX_SIZE: u32 = 1;
+const DEFAULT_CONNECTION_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 600;
+
+// A read that lost its connection is tried twice more, 50ms and then 100ms later
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 50;
 
 #[derive(Clone)]
 pub struct ConnectionPool {
     pub pool: Pool<ConnectionManager<PgConnection>>,
+    pub retry: RetryPolicy,
+}
+
+// How often and how soon an operation that failed on a transient error is tried again. The backoff doubles with
+// every retry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl ConnectionPool {
+
+    // Runs the operation on a connection of the pool, and again on a fresh one if it failed because the connection
+    // was lost. Only for operations that are safe to repeat, as a write may have been committed before its
+    // connection went away
+    pub async fn with_retry<T>(
+        &self,
+        mut operation: impl FnMut(PooledConnection<ConnectionManager<PgConnection>>) -> Result<T, diesel::result::Error>,
+    ) -> Result<T, diesel::result::Error> {
+        retry_transient(self.retry, || operation(self.pool.get().expect("Failed to acquire connection from pool"))).await
+    }
+}
+
+// Errors the same operation may well not run into again, like a connection the database closed on a restart.
+// NotFound, constraint violations and the like are the answer to the operation and are never retried
+pub fn is_transient(err: &diesel::result::Error) -> bool {
+    matches!(err, DatabaseError(DatabaseErrorKind::ClosedConnection | DatabaseErrorKind::UnableToSendCommand, _))
+}
+
+pub async fn retry_transient<T>(
+    policy: RetryPolicy,
+    mut operation: impl FnMut() -> Result<T, diesel::result::Error>,
+) -> Result<T, diesel::result::Error> {
+    let mut backoff = policy.backoff;
+
+    for retry in 0.. {
+        match operation() {
+            Err(err) if retry < policy.retries && is_transient(&err) => {
+                tracing::warn!("Retrying database operation in {:?} after transient error: {}", backoff, err);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    unreachable!("Every attempt either returns or is retried")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    pub connection_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub statement_timeout: Option<Duration>,
+    pub retry: RetryPolicy,
 }
 
+impl PoolConfig {
+
+    // Reads DB_POOL_MAX_SIZE, DB_POOL_MIN_IDLE, DB_POOL_CONNECTION_TIMEOUT_SECONDS, DB_POOL_IDLE_TIMEOUT_SECONDS,
+    // DB_STATEMENT_TIMEOUT_MS, DB_RETRIES and DB_RETRY_BACKOFF_MS
+    pub fn from_env() -> PoolConfig {
+        PoolConfig::from_lookup(&load_optional_environment_variable)
+    }
+
+    // The same variables, read through the lookup rather than from the process environment
+    pub fn from_lookup(lookup: &impl Fn(&str) -> Option<String>) -> PoolConfig {
+        let parsed = |name: &str| lookup(name).map(|value| parse_environment_variable::<u64>(name, &value));
+
+        PoolConfig {
+            max_size: lookup("DB_POOL_MAX_SIZE").map_or(DEFAULT_MAX_SIZE, |value| parse_environment_variable("DB_POOL_MAX_SIZE", &value)),
+            min_idle: lookup("DB_POOL_MIN_IDLE").map(|value| parse_environment_variable("DB_POOL_MIN_IDLE", &value)),
+            connection_timeout: Duration::from_secs(
+                parsed("DB_POOL_CONNECTION_TIMEOUT_SECONDS").unwrap_or(DEFAULT_CONNECTION_TIMEOUT_SECONDS)
+            ),
+
+            // An idle timeout of 0 keeps idle connections open indefinitely
+            idle_timeout: match parsed("DB_POOL_IDLE_TIMEOUT_SECONDS").unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS) {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+
+            // Statements are allowed to run indefinitely unless a timeout is configured, 0 disables it too
+            statement_timeout: match parsed("DB_STATEMENT_TIMEOUT_MS").unwrap_or(0) {
+                0 => None,
+                milliseconds => Some(Duration::from_millis(milliseconds)),
+            },
+
+            // DB_RETRIES=0 turns retrying off
+            retry: RetryPolicy {
+                retries: lookup("DB_RETRIES").map_or(DEFAULT_RETRIES, |value| parse_environment_variable("DB_RETRIES", &value)),
+                backoff: Duration::from_millis(parsed("DB_RETRY_BACKOFF_MS").unwrap_or(DEFAULT_RETRY_BACKOFF_MS)),
+            },
+        }
+    }
+}
+
+// Sets a Postgres statement_timeout on every connection, so the database aborts overlong statements itself
+// and the connection is freed even if the request that issued the statement has long since given up
+#[derive(Debug, Clone, Copy)]
+pub struct StatementTimeout(pub Duration);
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        sql_query(format!("SET statement_timeout = {}", self.0.as_millis()))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+// Sizes the pool explicitly and takes every other setting from the environment
+#[allow(dead_code)]
 pub fn create_shared_connection_pool(database_url: String, max_size: u32) -> ConnectionPool {
+    create_shared_connection_pool_with_config(database_url, PoolConfig {
+        max_size,
+        ..PoolConfig::from_env()
+    })
+}
+
+pub fn create_shared_connection_pool_with_config(database_url: String, config: PoolConfig) -> ConnectionPool {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
 
-    let pool = Pool::builder()
-        .max_size(max_size)
+    // r2d2 refuses to build a pool asked to keep more idle connections than it may hold
+    let min_idle = config.min_idle.map(|min_idle| min_idle.min(config.max_size));
+
+    // Connections are checked with 'SELECT 1' as they are borrowed, so ones left dead by a database restart are
+    // replaced rather than handed to a request that would fail with 500. r2d2 does this by default, it is set here
+    // so it doesn't get lost
+    let mut builder = Pool::builder()
+        .test_on_check_out(true)
+        .max_size(config.max_size)
+        .min_idle(min_idle)
+        .connection_timeout(config.connection_timeout)
+        .idle_timeout(config.idle_timeout);
+
+    if let Some(statement_timeout) = config.statement_timeout {
+        builder = builder.connection_customizer(Box::new(StatementTimeout(statement_timeout)));
+    }
+
+    let pool = builder
         .build(manager)
         .unwrap();
 
     ConnectionPool {
         pool,
+        retry: config.retry,
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use axum::http::StatusCode;
+    use diesel::{
+        dsl::sql,
+        result::{DatabaseErrorKind, Error::DatabaseError},
+        select, sql_query, sql_types::Integer, Connection, PgConnection, RunQueryDsl,
+    };
+    use crate::common::{
+        db::{create_shared_connection_pool_with_config, retry_transient, PoolConfig, RetryPolicy},
+        error::database_error,
+        util::load_environment_variable
+    };
+
+    #[test]
+    fn pool_is_built_with_custom_config() {
+        let database_url = load_environment_variable("TEST_DB");
+        let config = PoolConfig {
+            max_size: 3,
+            min_idle: Some(1),
+            connection_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(120)),
+            statement_timeout: None,
+            retry: RetryPolicy { retries: 0, backoff: Duration::ZERO },
+        };
+
+        let connection_pool = create_shared_connection_pool_with_config(database_url, config);
+
+        assert_eq!(connection_pool.pool.max_size(), 3);
+        assert_eq!(connection_pool.pool.min_idle(), Some(1));
+        assert_eq!(connection_pool.pool.connection_timeout(), Duration::from_secs(5));
+        assert_eq!(connection_pool.pool.idle_timeout(), Some(Duration::from_secs(120)));
+
+        // Assert that the pool actually hands out connections
+        assert!(connection_pool.pool.get().is_ok());
+    }
+
+    #[test]
+    fn pool_keeps_a_single_connection_by_default() {
+        assert_eq!(PoolConfig::from_lookup(&|_: &str| None).max_size, 1);
+    }
+
+    #[test]
+    fn statement_exceeding_timeout_is_aborted_and_maps_to_504() {
+        let database_url = load_environment_variable("TEST_DB");
+        let config = PoolConfig {
+            max_size: 1,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(5),
+            idle_timeout: None,
+            statement_timeout: Some(Duration::from_millis(100)),
+            retry: RetryPolicy { retries: 0, backoff: Duration::ZERO },
+        };
+
+        let connection_pool = create_shared_connection_pool_with_config(database_url, config);
+        let mut connection = connection_pool.pool.get().expect("Failed to get connection");
+
+        // The database cancels the sleep long before it would finish on its own
+        let err = sql_query("SELECT pg_sleep(2)")
+            .execute(&mut connection)
+            .expect_err("Expected the statement to be aborted");
+
+        let (status, _) = database_error("Failed to read location", &err);
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+
+        // Assert that the connection is still usable afterwards
+        assert!(sql_query("SELECT 1").execute(&mut connection).is_ok());
+    }
+
+    #[test]
+    fn severed_connection_is_replaced_on_checkout() {
+        let database_url = load_environment_variable("TEST_DB");
+        let config = PoolConfig {
+            max_size: 1,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(5),
+            idle_timeout: None,
+            statement_timeout: None,
+            retry: RetryPolicy { retries: 0, backoff: Duration::ZERO },
+        };
+
+        let connection_pool = create_shared_connection_pool_with_config(database_url.clone(), config);
+        let backend_pid = |connection: &mut PgConnection| select(sql::<Integer>("pg_backend_pid()")).get_result::<i32>(connection);
+
+        let severed_pid = backend_pid(&mut connection_pool.pool.get().expect("Failed to get connection"))
+            .expect("Failed to read backend pid");
+
+        // Kill the pooled connection's backend from the outside, as a database restart would, waiting until it is gone
+        let mut admin_connection = PgConnection::establish(&database_url).expect("Failed to connect");
+        sql_query(format!("SELECT pg_terminate_backend({}, 5000)", severed_pid))
+            .execute(&mut admin_connection)
+            .expect("Failed to terminate backend");
+
+        // Assert that the next checkout hands out a fresh, working connection instead of the dead one
+        let mut connection = connection_pool.pool.get().expect("Failed to get connection after the backend was terminated");
+        let pid = backend_pid(&mut connection).expect("Expected the replacement connection to work");
+        assert_ne!(pid, severed_pid);
+    }
+
+    #[tokio::test]
+    async fn operation_failing_once_on_a_lost_connection_is_retried() {
+        let policy = RetryPolicy { retries: 2, backoff: Duration::from_millis(1) };
+        let mut attempts = 0;
+
+        let result = retry_transient(policy, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(DatabaseError(DatabaseErrorKind::ClosedConnection, Box::new("server closed the connection".to_string()))),
+                _ => Ok(42),
+            }
+        }).await;
+
+        assert_eq!(result, Ok(42));
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn logical_errors_are_not_retried_and_transient_ones_only_so_often() {
+        let policy = RetryPolicy { retries: 2, backoff: Duration::from_millis(1) };
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_transient(policy, || {
+            attempts += 1;
+            Err(diesel::result::Error::NotFound)
+        }).await;
+        assert_eq!(result, Err(diesel::result::Error::NotFound));
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_transient(policy, || {
+            attempts += 1;
+            Err(DatabaseError(DatabaseErrorKind::UnableToSendCommand, Box::new("connection lost".to_string())))
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+}
@@ -1,10 +1,19 @@
 use std::fmt;
+use axum::{body::Body, extract::State, http::{Request, StatusCode}, middleware::Next, response::{IntoResponse, Response}, Json};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+use crate::{
+    common::validation::ValidationErrors,
+    users::model::UserRole
+};
 
 #[derive(Debug, PartialEq)]
 pub enum ErrorType {
     NotFound,
     Internal,
     UniqueViolation,
+    InvalidInput,
 }
 
 #[derive(Debug)]
@@ -42,4 +51,297 @@ impl fmt::Display for CustomError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.message)
     }
-}
\ No newline at end of file
+}
+
+tokio::task_local! {
+    static EXPOSE_ERROR_DETAILS: bool;
+}
+
+// Hands Config's expose_error_details to the errors built while handling the request, which are built far from the
+// router state. Errors built outside a request never expose details
+pub async fn expose_error_details_within(
+    State(expose_details): State<bool>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    EXPOSE_ERROR_DETAILS.scope(expose_details, next.run(request)).await
+}
+
+fn expose_error_details() -> bool {
+    EXPOSE_ERROR_DETAILS.try_with(|expose_details| *expose_details).unwrap_or(false)
+}
+
+pub fn internal_error<E: fmt::Debug>(message: &str, err: &E) -> (StatusCode, Json<Value>) {
+    internal_error_with_details(message, err, expose_error_details())
+}
+
+pub fn internal_error_with_details<E: fmt::Debug>(message: &str, err: &E, expose_details: bool) -> (StatusCode, Json<Value>) {
+    let body = if expose_details && cfg!(debug_assertions) {
+        json!({"error": message, "detail": format!("{:?}", err)})
+    } else {
+        json!({"error": message})
+    };
+
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(body))
+}
+
+// Postgres reports statements cancelled by statement_timeout with this message, which diesel maps to an unknown error kind
+pub fn is_statement_timeout(err: &diesel::result::Error) -> bool {
+    match err {
+        diesel::result::Error::DatabaseError(_, info) => info.message().contains("canceling statement due to statement timeout"),
+        _ => false,
+    }
+}
+
+// Like internal_error, but a statement aborted by the database's statement_timeout is reported as 504
+pub fn database_error(message: &str, err: &diesel::result::Error) -> (StatusCode, Json<Value>) {
+    if is_statement_timeout(err) {
+        return (StatusCode::GATEWAY_TIMEOUT, Json(json!({"error": format!("{}: the database took too long to respond", message)})));
+    }
+
+    internal_error(message, err)
+}
+
+// Maps a failed query to the response it deserves. 'resource' names what the query was about, e.g. "location",
+// and 'message' is what errors that aren't the client's fault are reported as
+pub fn map_diesel_error(resource: &str, message: &str, err: &diesel::result::Error) -> (StatusCode, Json<Value>) {
+    use diesel::result::{DatabaseErrorKind, Error};
+
+    match err {
+        Error::NotFound => ApiError::not_found(resource).into(),
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            ApiError::conflict(&format!("{} already exists", capitalize(resource))).into()
+        }
+        Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+            ApiError::conflict(&format!("{} is referenced by, or refers to, another resource", capitalize(resource))).into()
+        }
+        _ => database_error(message, err),
+    }
+}
+
+fn capitalize(value: &str) -> String {
+    let mut chars = value.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// The envelope of every error response, also what the OpenAPI spec documents errors as
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    #[schema(example = "Location not found")]
+    pub error: String,
+}
+
+// Sent instead of ErrorResponse when the input fails validation, listing each problem found by field
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ValidationErrorResponse {
+    #[schema(example = "Invalid location")]
+    pub error: String,
+    #[schema(example = json!({"area": ["Field 'area' must not be empty"]}))]
+    pub errors: ValidationErrors,
+}
+
+// An error response - a status along with the JSON envelope {"error": ...} every handler answers with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub body: Value,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: &str) -> ApiError {
+        ApiError { status, body: json!(ErrorResponse { error: message.to_string() }) }
+    }
+
+    // Names the missing resource, e.g. not_found("location") reads "Location not found"
+    pub fn not_found(resource: &str) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, &format!("{} not found", capitalize(resource)))
+    }
+
+    pub fn bad_request(message: &str) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    // Lists every problem with the input, so clients can fix them all in one go
+    pub fn unprocessable(message: &str, errors: ValidationErrors) -> ApiError {
+        ApiError {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            body: json!(ValidationErrorResponse { error: message.to_string(), errors }),
+        }
+    }
+
+    pub fn conflict(message: &str) -> ApiError {
+        ApiError::new(StatusCode::CONFLICT, message)
+    }
+
+    // Insufficient roles have always been answered with 401 by this API, and clients rely on it
+    pub fn forbidden(required: UserRole, actual: UserRole) -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, &format!("Current role of {} does not have access to {}", actual, required))
+    }
+
+    pub fn internal<E: fmt::Debug>(message: &str, err: &E) -> ApiError {
+        internal_error(message, err).into()
+    }
+
+    pub fn database(message: &str, err: &diesel::result::Error) -> ApiError {
+        database_error(message, err).into()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+// Lets handlers returning ApiError use '?' on helpers that still return plain tuples, and vice versa
+impl From<(StatusCode, Json<Value>)> for ApiError {
+    fn from((status, Json(body)): (StatusCode, Json<Value>)) -> ApiError {
+        ApiError { status, body }
+    }
+}
+
+// For the odd query where naming the resource adds nothing, handlers otherwise go through map_diesel_error
+impl From<diesel::result::Error> for ApiError {
+    fn from(err: diesel::result::Error) -> ApiError {
+        map_diesel_error("resource", "Database error", &err).into()
+    }
+}
+
+impl From<ApiError> for (StatusCode, Json<Value>) {
+    fn from(err: ApiError) -> (StatusCode, Json<Value>) {
+        (err.status, Json(err.body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use axum::{http::StatusCode, response::IntoResponse};
+    use serde_json::json;
+    use crate::{
+        common::error::{internal_error_with_details, map_diesel_error, ApiError},
+        users::model::UserRole
+    };
+
+    #[test]
+    fn api_error_constructors_yield_status_and_envelope() {
+        let cases = [
+            (ApiError::not_found("location"), StatusCode::NOT_FOUND, json!({"error": "Location not found"})),
+            (ApiError::bad_request("Query param 'limit' is invalid"), StatusCode::BAD_REQUEST, json!({"error": "Query param 'limit' is invalid"})),
+            (
+                ApiError::unprocessable("Invalid location", HashMap::from([("area".to_string(), vec!["Field 'area' must not be empty".to_string()])])),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({"error": "Invalid location", "errors": {"area": ["Field 'area' must not be empty"]}})
+            ),
+            (ApiError::conflict("Already exists"), StatusCode::CONFLICT, json!({"error": "Already exists"})),
+            (
+                ApiError::forbidden(UserRole::ADMIN, UserRole::READER),
+                StatusCode::UNAUTHORIZED,
+                json!({"error": "Current role of READER does not have access to ADMIN"})
+            ),
+        ];
+
+        for (err, status, body) in cases {
+            assert_eq!(err.status, status);
+            assert_eq!(err.body, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn api_error_responds_with_its_status_and_json_body() {
+        let response = ApiError::not_found("empire").into_response();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), json!({"error": "Empire not found"}));
+    }
+
+    #[test]
+    fn api_error_for_other_database_errors_is_500() {
+        let err = ApiError::database("Failed to read location", &diesel::result::Error::NotFound);
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn diesel_errors_map_to_the_status_they_deserve() {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        let database_error = |kind| Error::DatabaseError(kind, Box::new("violates a constraint".to_string()));
+
+        let cases = [
+            (Error::NotFound, StatusCode::NOT_FOUND, "Location not found"),
+            (database_error(DatabaseErrorKind::UniqueViolation), StatusCode::CONFLICT, "Location already exists"),
+            (database_error(DatabaseErrorKind::ForeignKeyViolation), StatusCode::CONFLICT, "Location is referenced by, or refers to, another resource"),
+            (Error::RollbackTransaction, StatusCode::INTERNAL_SERVER_ERROR, "Failed to update location"),
+        ];
+
+        for (err, status, message) in cases {
+            let (actual_status, body) = map_diesel_error("location", "Failed to update location", &err);
+            assert_eq!(actual_status, status);
+            assert_eq!(body.0, json!({"error": message}));
+        }
+    }
+
+    #[test]
+    fn unique_violation_converts_to_409() {
+        let err: ApiError = diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            Box::new("duplicate key value violates unique constraint".to_string()),
+        ).into();
+
+        assert_eq!(err.status, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn internal_error_includes_detail_only_when_enabled() {
+        let err = diesel::result::Error::NotFound;
+
+        let (status, body) = internal_error_with_details("Failed to read location", &err, true);
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body.0, json!({"error": "Failed to read location", "detail": "NotFound"}));
+
+        let (status, body) = internal_error_with_details("Failed to read location", &err, false);
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body.0, json!({"error": "Failed to read location"}));
+    }
+
+    #[tokio::test]
+    #[cfg(debug_assertions)]
+    async fn internal_error_exposes_detail_only_within_a_request_that_allows_it() {
+        use axum::{body::Body, http::Request, middleware, routing::get, Router};
+        use tower::ServiceExt;
+        use crate::common::error::{expose_error_details_within, internal_error};
+
+        let failing_route = |expose_details: bool| Router::new()
+            .route("/", get(|| async { internal_error("Failed to read location", &diesel::result::Error::NotFound) }))
+            .layer(middleware::from_fn_with_state(expose_details, expose_error_details_within));
+
+        for (expose_details, expected) in [
+            (true, json!({"error": "Failed to read location", "detail": "NotFound"})),
+            (false, json!({"error": "Failed to read location"})),
+        ] {
+            let response = failing_route(expose_details).oneshot(Request::builder().uri("/").body(Body::empty()).unwrap()).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), expected);
+        }
+
+        // Assert that an error built outside of any request keeps its details to itself
+        let (_, body) = internal_error("Failed to read location", &diesel::result::Error::NotFound);
+        assert_eq!(body.0, json!({"error": "Failed to read location"}));
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn internal_error_never_includes_detail_in_release_builds() {
+        let err = diesel::result::Error::NotFound;
+
+        let (_, body) = internal_error_with_details("Failed to read location", &err, true);
+        assert_eq!(body.0, json!({"error": "Failed to read location"}));
+    }
+}
@@ -0,0 +1,306 @@
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::{FromRef, FromRequest, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    BoxError,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use crate::{
+    common::{
+        db::ConnectionPool,
+        error::ApiError,
+        security::{decode_claims, enforce_role_policy},
+    },
+    users::model::{User, UserRole},
+};
+
+// Decodes the bearer token and checks its user holds the role, answering like the handlers always have -
+// 401 for a missing or invalid token and for a role that falls short, 403 while a password change is pending
+pub async fn authorize(headers: &HeaderMap, shared_state: &ConnectionPool, required_role: UserRole) -> Result<User, ApiError> {
+    let claims = decode_claims(headers)?;
+
+    match enforce_role_policy(shared_state, &claims, required_role).await? {
+        Some(user) => Ok(user),
+        None => Err(ApiError::new(StatusCode::UNAUTHORIZED, "User in claims not found in DB")),
+    }
+}
+
+// Declares an extractor yielding the authenticated user, who holds at least the given role. Taking it as a
+// handler argument replaces decoding the claims and enforcing the role policy by hand
+macro_rules! role_extractor {
+    ($name:ident, $role:expr) => {
+        pub struct $name(pub User);
+
+        #[async_trait]
+        impl<S> FromRequestParts<S> for $name
+        where
+            ConnectionPool: FromRef<S>,
+            S: Send + Sync,
+        {
+            type Rejection = ApiError;
+
+            async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+                authorize(&parts.headers, &ConnectionPool::from_ref(state), $role).await.map($name)
+            }
+        }
+    };
+}
+
+role_extractor!(AuthedWriter, UserRole::WRITER);
+
+// Drop-in replacement for axum's Json extractor whose rejections are JSON like every other error we send,
+// rather than axum's plain text, and name the fields that failed to deserialize
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_json_content_type(request.headers()) {
+            return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected request with `Content-Type: application/json`"));
+        }
+
+        let bytes = Bytes::from_request(request, state).await
+            .map_err(|rejection| ApiError::new(rejection.status(), &rejection.body_text()))?;
+
+        parse_json_body(&bytes).map(JsonBody)
+    }
+}
+
+// A text/csv body as a string, refused with JSON errors like JsonBody's when the content type is wrong or the
+// body isn't UTF-8. Parsing the rows is left to the handler, which knows their columns
+pub struct CsvBody(pub String);
+
+#[async_trait]
+impl<S, B> FromRequest<S, B> for CsvBody
+where
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_media_type(request.headers(), "text/csv") {
+            return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected request with `Content-Type: text/csv`"));
+        }
+
+        let bytes = Bytes::from_request(request, state).await
+            .map_err(|rejection| ApiError::new(rejection.status(), &rejection.body_text()))?;
+
+        String::from_utf8(bytes.to_vec())
+            .map(CsvBody)
+            .map_err(|_| ApiError::bad_request("CSV body must be UTF-8"))
+    }
+}
+
+fn has_media_type(headers: &HeaderMap, expected: &str) -> bool {
+    headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(expected))
+        .unwrap_or(false)
+}
+
+// Accepts application/json along with its structured syntax suffixes, such as application/problem+json
+fn has_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media_type == "application/json" || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
+
+fn parse_json_body<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ApiError> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(bytes);
+
+    let err = match serde_path_to_error::deserialize(deserializer) {
+        Ok(value) => return Ok(value),
+        Err(err) => err,
+    };
+
+    // Well-formed JSON of the wrong shape is the client's data at fault rather than its syntax
+    if err.inner().is_data() {
+        let mut errors = Map::new();
+        let (field, message) = field_error(&err.path().to_string(), &without_position(&err.inner().to_string()));
+        errors.insert(field, Value::String(message));
+
+        return Err(ApiError {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            body: json!({"error": "invalid body", "errors": errors}),
+        });
+    }
+
+    Err(ApiError {
+        status: StatusCode::BAD_REQUEST,
+        body: json!({"error": "invalid JSON", "detail": err.inner().to_string()}),
+    })
+}
+
+// serde reports a missing field against the object that lacks it, so the field's own name is taken from the message.
+// An unknown field's path already ends in it, and the fields that were expected are kept from its message
+fn field_error(path: &str, message: &str) -> (String, String) {
+    let missing_field = message.strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next());
+
+    if let Some(expected) = message.strip_prefix("unknown field `").and_then(|rest| rest.split_once('`')).map(|(_, expected)| expected) {
+        return (path.to_string(), format!("unknown field{}", expected));
+    }
+
+    match missing_field {
+        Some(field) if path == "." => (field.to_string(), "missing field".to_string()),
+        Some(field) => (format!("{}.{}", path, field), "missing field".to_string()),
+        None => (path.to_string(), message.to_string()),
+    }
+}
+
+// The field's path already says where the problem is, better than a line and column would
+fn without_position(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(position) => message[..position].to_string(),
+        None => message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::{get, post},
+        Router
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use crate::{
+        common::{
+            db::{create_shared_connection_pool, ConnectionPool},
+            extract::{AuthedWriter, JsonBody},
+            security::{generate_token, hash_password},
+            util::load_environment_variable,
+        },
+        users::{model::UpsertUser, service::service::UsersTable},
+    };
+
+    fn service() -> Router {
+        Router::new().route("/", post(|JsonBody(body): JsonBody<Value>| async move { axum::Json(body) }))
+    }
+
+    async fn post_body(content_type: &str, body: &'static str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .header("content-type", content_type)
+            .body(Body::from(body))
+            .unwrap();
+
+        // Send the request through the service
+        let response = service()
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn malformed_json_returns_400_with_json_error() {
+        let (status, body) = post_body("application/json", "{").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, json!({"error": "invalid JSON", "detail": "EOF while parsing an object at line 1 column 1"}));
+    }
+
+    #[tokio::test]
+    async fn missing_content_type_returns_415_with_json_error() {
+        let (status, body) = post_body("text/plain", "{}").await;
+
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body, json!({"error": "Expected request with `Content-Type: application/json`"}));
+    }
+
+    #[tokio::test]
+    async fn well_formed_json_is_extracted() {
+        let (status, body) = post_body("application/json; charset=utf-8", r#"{"area": "Core"}"#).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"area": "Core"}));
+    }
+
+    fn authed_service(connection_pool: ConnectionPool) -> Router {
+        Router::new()
+            .route("/", get(|AuthedWriter(user): AuthedWriter| async move { user.email }))
+            .with_state(connection_pool)
+    }
+
+    // Creates a user with the role and returns a bearer token for them
+    fn token_for(connection_pool: &ConnectionPool, email: &str, role: &str, must_change_password: bool) -> String {
+        let mut new_user = UpsertUser {
+            email: email.to_string(),
+            password: "Extractor123".to_string(),
+            fullname: "Uttrekker Rolle".to_string(),
+            role: role.to_string()
+        };
+        hash_password(&mut new_user).expect("Hash password failed");
+
+        let connection = connection_pool.pool.get().expect("Failed to get connection");
+        let mut user_db = UsersTable::new(connection);
+        let user = user_db.create(new_user).expect("Create user failed");
+        if must_change_password {
+            user_db.update_password(user.id, &user.password, true).expect("Flag user failed");
+        }
+
+        generate_token(&user).expect("Generate token failed")
+    }
+
+    async fn get_authed(connection_pool: ConnectionPool, token: Option<String>) -> StatusCode {
+        let mut request = Request::builder().uri("/").method("GET");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token)); // Add the bearer token
+        }
+
+        authed_service(connection_pool).oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn authed_writer_lets_writers_and_above_through() {
+        let connection_pool = create_shared_connection_pool(load_environment_variable("TEST_DB"), 1);
+
+        for (email, role) in [("skriver@uttrekk.no", "WRITER"), ("redaktør@uttrekk.no", "EDITOR")] {
+            let token = token_for(&connection_pool, email, role, false);
+            assert_eq!(get_authed(connection_pool.clone(), Some(token)).await, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn authed_writer_returns_401_without_token_or_with_a_lower_role() {
+        let connection_pool = create_shared_connection_pool(load_environment_variable("TEST_DB"), 1);
+
+        assert_eq!(get_authed(connection_pool.clone(), None).await, StatusCode::UNAUTHORIZED);
+
+        let token = token_for(&connection_pool, "leser@uttrekk.no", "READER", false);
+        assert_eq!(get_authed(connection_pool, Some(token)).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn authed_writer_returns_403_while_a_password_change_is_pending() {
+        let connection_pool = create_shared_connection_pool(load_environment_variable("TEST_DB"), 1);
+
+        let token = token_for(&connection_pool, "nullstilt@uttrekk.no", "WRITER", true);
+        assert_eq!(get_authed(connection_pool, Some(token)).await, StatusCode::FORBIDDEN);
+    }
+}
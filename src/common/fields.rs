@@ -0,0 +1,58 @@
+use serde_json::{Map, Value};
+use crate::common::error::ApiError;
+
+// Splits a 'fields' query param given as 'field,field,...' and checks each against the resource's allowlist. A field
+// asked for twice is kept once, and only fields from the allowlist ever come back
+pub fn parse_fields(fields: &str, allowed_fields: &[&'static str]) -> Result<Vec<&'static str>, ApiError> {
+    let mut selected = Vec::new();
+
+    for field in fields.split(',').map(str::trim) {
+        let field = allowed_fields.iter().find(|allowed| **allowed == field).ok_or_else(|| ApiError::bad_request(
+            &format!("Unknown field '{}', expected one of {}", field, allowed_fields.join(", "))
+        ))?;
+
+        if !selected.contains(field) {
+            selected.push(*field);
+        }
+    }
+
+    Ok(selected)
+}
+
+// Keeps only the selected keys of a JSON object. A selected key the object doesn't have is left out rather than
+// set to null, the same as the object itself leaves it out
+pub fn select_fields(value: Value, fields: &[&str]) -> Value {
+    match value {
+        Value::Object(mut object) => Value::Object(fields.iter()
+            .filter_map(|field| object.remove(*field).map(|value| (field.to_string(), value)))
+            .collect::<Map<String, Value>>()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use crate::common::fields::{parse_fields, select_fields};
+
+    const FIELDS: [&str; 3] = ["id", "area", "x"];
+
+    #[test]
+    fn fields_are_parsed_against_the_allowlist() {
+        assert_eq!(parse_fields("id,area", &FIELDS).unwrap(), vec!["id", "area"]);
+        assert_eq!(parse_fields(" area , id,area", &FIELDS).unwrap(), vec!["area", "id"]);
+
+        for fields in ["password", "id,password", "id,", ""] {
+            let err = parse_fields(fields, &FIELDS).expect_err("Expected the fields to be refused");
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "{}", fields);
+        }
+    }
+
+    #[test]
+    fn only_selected_keys_are_kept() {
+        let value = json!({"id": 1, "star_system": "Stanton", "area": "Crusader"});
+
+        assert_eq!(select_fields(value, &["id", "area", "x"]), json!({"id": 1, "area": "Crusader"}));
+    }
+}
@@ -0,0 +1,174 @@
+use axum::{
+    extract::{DefaultBodyLimit, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use tower_http::limit::RequestBodyLimitLayer;
+use crate::common::util::load_optional_environment_variable;
+
+const DEFAULT_MAX_HEADER_BYTES: usize = 8 * 1024;
+const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+// Batches carry many items per request, so they are allowed a larger body than single-item writes
+const DEFAULT_MAX_BATCH_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+// Reads MAX_HEADER_BYTES - the combined size of all header names and values a request may carry
+pub fn max_header_bytes() -> usize {
+    match load_optional_environment_variable("MAX_HEADER_BYTES") {
+        Some(bytes) => bytes.parse::<usize>()
+            .expect("MAX_HEADER_BYTES must be a whole number of bytes"),
+        None => DEFAULT_MAX_HEADER_BYTES,
+    }
+}
+
+// Reads MAX_BODY_BYTES - the largest body accepted by endpoints that create or update a single resource
+pub fn max_body_bytes() -> usize {
+    load_byte_limit("MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES)
+}
+
+// Reads MAX_BATCH_BODY_BYTES - the largest body accepted by endpoints that take many resources at once
+pub fn max_batch_body_bytes() -> usize {
+    load_byte_limit("MAX_BATCH_BODY_BYTES", DEFAULT_MAX_BATCH_BODY_BYTES)
+}
+
+fn load_byte_limit(variable_name: &str, default: usize) -> usize {
+    match load_optional_environment_variable(variable_name) {
+        Some(bytes) => bytes.parse::<usize>()
+            .unwrap_or_else(|_| panic!("{} must be a whole number of bytes", variable_name)),
+        None => default,
+    }
+}
+
+// Rejects bodies larger than max_bytes with 413 before they are buffered. Axum's own 2MB default is disabled
+// so the configured limit is the only one that applies, also when it is set higher than that
+pub fn body_limit(max_bytes: usize) -> (DefaultBodyLimit, RequestBodyLimitLayer) {
+    (DefaultBodyLimit::disable(), RequestBodyLimitLayer::new(max_bytes))
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+pub async fn reject_oversized_headers<B>(
+    State(max_bytes): State<usize>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let header_bytes: usize = request.headers().iter()
+        .map(|(name, value)| name.as_str().len() + value.as_bytes().len())
+        .sum();
+
+    if header_bytes > max_bytes {
+        eprintln!("Rejected request with {} bytes of headers (limit is {})", header_bytes, max_bytes);
+        return (
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Json(json!({"error": format!("Request headers must not exceed {} bytes", max_bytes)})),
+        ).into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        middleware,
+        routing::{get, post},
+        Json,
+        Router
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+    use crate::common::limits::{body_limit, reject_oversized_headers};
+
+    fn service(max_bytes: usize) -> Router {
+        Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(max_bytes, reject_oversized_headers))
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_return_431() {
+        let request = Request::builder()
+            .uri("/")
+            .method("GET")
+            .header("Authorization", format!("Bearer {}", "x".repeat(2048)))
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = service(1024)
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 431
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn headers_within_limit_are_passed_through() {
+        let request = Request::builder()
+            .uri("/")
+            .method("GET")
+            .header("Authorization", "Bearer short")
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = service(1024)
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 200
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn body_limited_service(max_bytes: usize) -> Router {
+        Router::new()
+            .route("/", post(|Json(body): Json<Value>| async move { Json(body) }).layer(body_limit(max_bytes)))
+    }
+
+    #[tokio::test]
+    async fn body_over_limit_returns_413() {
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .header("content-type", "application/json")
+            .body(Body::from(format!("{{\"area\": \"{}\"}}", "x".repeat(2048))))
+            .unwrap();
+
+        // Send the request through the service
+        let response = body_limited_service(1024)
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 413
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn body_above_axum_default_is_accepted_when_limit_allows_it() {
+        // Axum refuses bodies over 2MB on its own unless its default limit is lifted
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .header("content-type", "application/json")
+            .body(Body::from(format!("{{\"area\": \"{}\"}}", "x".repeat(3 * 1024 * 1024))))
+            .unwrap();
+
+        // Send the request through the service
+        let response = body_limited_service(4 * 1024 * 1024)
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 200
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
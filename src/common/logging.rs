@@ -0,0 +1,485 @@
+use axum::{
+    body::{Body, boxed, Bytes, HttpBody},
+    extract::State,
+    http::{header::CONTENT_TYPE, HeaderMap, Request, StatusCode, Uri},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde_json::{json, Value};
+use tracing::{field::Empty, Level, Span, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use crate::{common::util::load_optional_environment_variable, users::model::User};
+
+// How log lines are written - readable text for people, or one JSON object per line for the log aggregator
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl LogFormat {
+    // Reads LOG_FORMAT, either 'pretty' or 'json'
+    pub fn from_env() -> LogFormat {
+        match load_optional_environment_variable("LOG_FORMAT") {
+            Some(format) => LogFormat::parse(&format)
+                .unwrap_or_else(|| panic!("LOG_FORMAT must be either pretty or json, got '{}'", format)),
+            None => LogFormat::default(),
+        }
+    }
+
+    fn parse(format: &str) -> Option<LogFormat> {
+        match format.trim().to_lowercase().as_str() {
+            "pretty" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// JSON lines carry the timestamp, level, target and message along with the fields of every span the event happened in
+pub fn log_subscriber<W>(format: LogFormat, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(Level::INFO)
+        .with_writer(writer);
+
+    match format {
+        LogFormat::Pretty => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().with_current_span(true).with_span_list(true).finish()),
+    }
+}
+
+// Installs the subscriber for LOG_FORMAT, writing to stderr like the rest of our output
+pub fn init_logging() {
+    tracing::subscriber::set_global_default(log_subscriber(LogFormat::from_env(), std::io::stderr))
+        .expect("Failed to install the log subscriber");
+}
+
+// The span every request is handled in, with the fields tower-http's default span has. The client's address and the
+// acting user's fields are declared empty, as a span only takes the fields it was created with, and are filled in
+// by resolve_client_ip and record_user
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %redacted_uri(request.uri()),
+        version = ?request.version(),
+        client_ip = Empty,
+        user_id = Empty,
+        email = Empty,
+        role = Empty,
+    )
+}
+
+// The URI with the values of sensitive query params blanked out, as a token may come in the query where headers
+// can't be set
+fn redacted_uri(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+
+    let query: Vec<String> = query.split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if REDACTED_FIELDS.iter().any(|field| field.eq_ignore_ascii_case(name)) => format!("{}={}", name, REDACTED),
+            _ => param.to_string(),
+        })
+        .collect();
+
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+// Tags the current request's span with the user it was authenticated as, so every line logged for the rest of
+// the request carries them
+pub fn record_user(user: &User) {
+    let span = Span::current();
+    span.record("user_id", user.id);
+    span.record("email", user.email.as_str());
+    span.record("role", user.role.as_str());
+}
+
+// Fields whose values must never end up in logs, matched case-insensitively at any depth
+pub const REDACTED_FIELDS: &[&str] = &["password", "token", "authorization"];
+
+const REDACTED: &str = "[REDACTED]";
+
+// Reads BODY_LOG_SAMPLE_RATE as a fraction between 0 and 1 - body logging is disabled unless it is set
+pub fn body_log_sample_rate() -> f64 {
+    match load_optional_environment_variable("BODY_LOG_SAMPLE_RATE") {
+        Some(rate) => rate.parse::<f64>()
+            .expect("BODY_LOG_SAMPLE_RATE must be a number between 0 and 1")
+            .clamp(0.0, 1.0),
+        None => 0.0,
+    }
+}
+
+pub fn should_sample(sample_rate: f64) -> bool {
+    sample_rate > 0.0 && rand::random::<f64>() < sample_rate
+}
+
+pub fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if REDACTED_FIELDS.iter().any(|redacted| redacted.eq_ignore_ascii_case(key)) {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(field);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+pub fn redact_body(body: &[u8]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+
+    // Only JSON is logged verbatim since it is the only format we know how to redact
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes of non-JSON body omitted>", body.len()),
+    }
+}
+
+// Bodies are only buffered for logging up to this size, larger ones are left alone
+const MAX_LOGGED_BODY_BYTES: u64 = 64 * 1024;
+
+// Streamed, or too large to ever be worth logging - reading them through would hold the response back until the
+// stream ends, which for events it never does
+const UNLOGGED_CONTENT_TYPES: &[&str] = &["text/event-stream", "application/x-ndjson", "text/csv"];
+
+// Whether the body can be read in full for logging, rather than passed through untouched. Bodies of unknown length
+// are streams too
+fn is_loggable<B: HttpBody>(headers: &HeaderMap, body: &B) -> bool {
+    let streamed = headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| UNLOGGED_CONTENT_TYPES.iter().any(|unlogged| content_type.starts_with(unlogged)))
+        .unwrap_or(false);
+
+    !streamed && body.size_hint().upper().map(|length| length <= MAX_LOGGED_BODY_BYTES).unwrap_or(false)
+}
+
+// Stands in for a body that was passed through without being read
+fn unlogged_body<B: HttpBody>(headers: &HeaderMap, body: &B) -> String {
+    let content_type = headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("unknown");
+
+    match body.size_hint().exact() {
+        Some(length) => format!("<{} bytes of {} body not logged>", length, content_type),
+        None => format!("<streamed {} body not logged>", content_type),
+    }
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+pub async fn log_sampled_bodies(
+    State(sample_rate): State<f64>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+
+    // Decide up front so unsampled requests are never buffered
+    if !should_sample(sample_rate) {
+        return Ok(next.run(request).await);
+    }
+
+    let (parts, body) = request.into_parts();
+    let method = parts.method.clone();
+    let uri = redacted_uri(&parts.uri);
+
+    let request = if is_loggable(&parts.headers, &body) {
+        let request_bytes: Bytes = hyper::body::to_bytes(body).await
+            .map_err(|_| (StatusCode::BAD_REQUEST, Json(json!({"error": "Failed to read request body"}))))?;
+
+        tracing::info!("{} {} request body: {}", method, uri, redact_body(&request_bytes));
+        Request::from_parts(parts, Body::from(request_bytes))
+    } else {
+        tracing::info!("{} {} request body: {}", method, uri, unlogged_body(&parts.headers, &body));
+        Request::from_parts(parts, body)
+    };
+
+    let response = next.run(request).await;
+
+    let (parts, body) = response.into_parts();
+    if !is_loggable(&parts.headers, &body) {
+        tracing::info!("{} {} response body ({}): {}", method, uri, parts.status, unlogged_body(&parts.headers, &body));
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let response_bytes: Bytes = hyper::body::to_bytes(body).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to read response body"}))))?;
+
+    tracing::info!("{} {} response body ({}): {}", method, uri, parts.status, redact_body(&response_bytes));
+
+    Ok(Response::from_parts(parts, boxed(Body::from(response_bytes))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, io, sync::{Arc, Mutex}, time::Duration};
+    use futures_util::{stream, StreamExt};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use tracing_subscriber::fmt::MakeWriter;
+    use axum::{
+        body::{Body, HttpBody, StreamBody},
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+        middleware,
+        response::Response,
+        routing::{get, post},
+        Json, Router,
+    };
+    use crate::{
+        common::logging::{log_sampled_bodies, log_subscriber, record_user, request_span, LogFormat, MAX_LOGGED_BODY_BYTES},
+        users::model::User,
+    };
+
+    // Collects everything the subscriber writes, so tests can look at the log lines
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'writer> MakeWriter<'writer> for CapturedLogs {
+        type Writer = CapturedLogs;
+
+        fn make_writer(&'writer self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn log_request_event(format: LogFormat) -> String {
+        let logs = CapturedLogs::default();
+
+        tracing::subscriber::with_default(log_subscriber(format, logs.clone()), || {
+            let span = tracing::info_span!("request", method = "GET", uri = "/locations");
+            let _entered = span.enter();
+            tracing::info!("finished processing request");
+        });
+
+        let bytes = logs.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn log_format_is_parsed_case_insensitively() {
+        assert_eq!(LogFormat::parse("pretty"), Some(LogFormat::Pretty));
+        assert_eq!(LogFormat::parse("JSON"), Some(LogFormat::Json));
+        assert_eq!(LogFormat::parse("logfmt"), None);
+    }
+
+    #[test]
+    fn pretty_format_writes_readable_lines() {
+        let logged = log_request_event(LogFormat::Pretty);
+
+        assert!(logged.contains("INFO"));
+        assert!(logged.contains("finished processing request"));
+        assert!(serde_json::from_str::<Value>(logged.trim()).is_err());
+    }
+
+    #[test]
+    fn json_format_writes_structured_lines_with_span_fields() {
+        let logged = log_request_event(LogFormat::Json);
+        let line: Value = serde_json::from_str(logged.trim()).expect("Expected a single JSON line");
+
+        assert!(line["timestamp"].is_string());
+        assert_eq!(line["level"], json!("INFO"));
+        assert_eq!(line["target"], json!(module_path!()));
+        assert_eq!(line["fields"]["message"], json!("finished processing request"));
+        assert_eq!(line["span"]["uri"], json!("/locations"));
+        assert_eq!(line["spans"][0]["method"], json!("GET"));
+    }
+
+    // Logs a line in a request's span as JSON, with the user recorded on the span first if there is one
+    fn log_in_request_span(user: Option<User>) -> Value {
+        let logs = CapturedLogs::default();
+        let request = Request::get("/locations").body(Body::empty()).unwrap();
+
+        tracing::subscriber::with_default(log_subscriber(LogFormat::Json, logs.clone()), || {
+            let span = request_span(&request);
+            let _entered = span.enter();
+
+            if let Some(user) = &user {
+                record_user(user);
+            }
+            tracing::info!("finished processing request");
+        });
+
+        let bytes = logs.0.lock().unwrap().clone();
+        serde_json::from_slice(&bytes).expect("Expected a single JSON line")
+    }
+
+    #[test]
+    fn lines_logged_after_auth_carry_the_user() {
+        let line = log_in_request_span(Some(User {
+            id: 42,
+            email: "sporbar@logging.no".to_string(),
+            password: "$argon2id$hash".to_string(),
+            fullname: "Sporbar Bruker".to_string(),
+            role: "EDITOR".to_string(),
+            email_verified: true,
+            must_change_password: false,
+        }));
+
+        assert_eq!(line["span"]["uri"], json!("/locations"));
+        assert_eq!(line["span"]["user_id"], json!(42));
+        assert_eq!(line["span"]["email"], json!("sporbar@logging.no"));
+        assert_eq!(line["span"]["role"], json!("EDITOR"));
+        assert!(!line.to_string().contains("argon2"));
+    }
+
+    #[test]
+    fn tokens_in_the_query_are_not_logged() {
+        let request = Request::get("/locations/stream?token=eyJhbGciOi&Token=eyJzdWIiOi&x=1").body(Body::empty()).unwrap();
+        let logs = CapturedLogs::default();
+
+        tracing::subscriber::with_default(log_subscriber(LogFormat::Json, logs.clone()), || {
+            let span = request_span(&request);
+            let _entered = span.enter();
+            tracing::info!("finished processing request");
+        });
+
+        let line: Value = serde_json::from_slice(&logs.0.lock().unwrap()).unwrap();
+        assert_eq!(line["span"]["uri"], json!("/locations/stream?token=[REDACTED]&Token=[REDACTED]&x=1"));
+    }
+
+    #[test]
+    fn lines_logged_without_auth_have_no_user_fields() {
+        let line = log_in_request_span(None);
+
+        assert_eq!(line["span"]["method"], json!("GET"));
+        for field in ["user_id", "email", "role"] {
+            assert!(line["span"].get(field).is_none(), "{}", field);
+        }
+    }
+
+    // Sends the request through a router sampling bodies at the given rate, returning the response and what was logged
+    async fn send_sampled(sample_rate: f64, router: Router, request: Request<Body>) -> (Response, String) {
+        let logs = CapturedLogs::default();
+        let _default = tracing::subscriber::set_default(log_subscriber(LogFormat::Pretty, logs.clone()));
+
+        let response = router
+            .layer(middleware::from_fn_with_state(sample_rate, log_sampled_bodies))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        let bytes = logs.0.lock().unwrap().clone();
+        (response, String::from_utf8(bytes).unwrap())
+    }
+
+    fn echo_router() -> Router {
+        Router::new().route("/users/login", post(|Json(body): Json<Value>| async move { Json(body) }))
+    }
+
+    fn login_request() -> Request<Body> {
+        let body = json!({
+            "email": "sampled@logging.no",
+            "password": "HunterHunter2",
+            "nested": { "token": "eyJhbGciOi" }
+        });
+
+        Request::post("/users/login")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn full_sampling_logs_bodies_with_sensitive_fields_redacted() {
+        let (response, logged) = send_sampled(1.0, echo_router(), login_request()).await;
+
+        // Assert that the echoed body still reaches the client in full
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let echoed: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(echoed["password"], json!("HunterHunter2"));
+
+        assert!(logged.contains("request body"));
+        assert!(logged.contains("response body (200 OK)"));
+        assert!(logged.contains("sampled@logging.no"));
+        assert!(logged.contains("[REDACTED]"));
+        assert!(!logged.contains("HunterHunter2"));
+        assert!(!logged.contains("eyJhbGciOi"));
+    }
+
+    #[tokio::test]
+    async fn zero_sampling_logs_nothing() {
+        let (response, logged) = send_sampled(0.0, echo_router(), login_request()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(logged.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sampled_bodies_are_logged_without_tokens_in_the_query() {
+        let router = Router::new().route("/locations/stream", get(|| async { Json(json!({"ok": true})) }));
+        let request = Request::get("/locations/stream?token=eyJhbGciOi").body(Body::empty()).unwrap();
+
+        let (_, logged) = send_sampled(1.0, router, request).await;
+
+        assert!(logged.contains("/locations/stream?token=[REDACTED]"));
+        assert!(!logged.contains("eyJhbGciOi"));
+    }
+
+    #[tokio::test]
+    async fn sampled_event_streams_are_passed_through_unread() {
+
+        // An event stream sends its first event and then stays open, like /locations/events does
+        let router = Router::new().route("/locations/events", get(|| async {
+            let events = stream::once(async { Ok::<_, Infallible>("data: {}\n\n") }).chain(stream::pending());
+            ([(CONTENT_TYPE, "text/event-stream")], StreamBody::new(events))
+        }));
+        let request = Request::get("/locations/events").body(Body::empty()).unwrap();
+
+        let (response, logged) = tokio::time::timeout(Duration::from_secs(5), send_sampled(1.0, router, request)).await
+            .expect("Expected the stream to be handed over without waiting for it to end");
+
+        let mut body = response.into_body();
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.data()).await
+            .expect("Expected the first event right away")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(&frame[..], b"data: {}\n\n");
+        assert!(logged.contains("<streamed text/event-stream body not logged>"));
+    }
+
+    #[tokio::test]
+    async fn sampled_bodies_over_the_cap_are_not_read() {
+        let oversized = "x".repeat(MAX_LOGGED_BODY_BYTES as usize + 1);
+        let router = Router::new().route("/users/login", post(|body: String| async move { body.len().to_string() }));
+        let request = Request::post("/users/login")
+            .header("content-type", "application/json")
+            .body(Body::from(oversized))
+            .unwrap();
+
+        let (response, logged) = send_sampled(1.0, router, request).await;
+
+        // Assert that the handler still gets the whole body
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, (MAX_LOGGED_BODY_BYTES + 1).to_string());
+
+        assert!(logged.contains(&format!("<{} bytes of application/json body not logged>", MAX_LOGGED_BODY_BYTES + 1)));
+        assert!(!logged.contains("xxxx"));
+    }
+}
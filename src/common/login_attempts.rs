@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use axum::http::{HeaderMap, HeaderValue};
+
+pub const DEFAULT_MAX_FAILURES: u32 = 10;
+pub const DEFAULT_FAILURE_WINDOW_SECONDS: u64 = 900;
+
+pub const RATE_LIMIT_LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub const RATE_LIMIT_REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub const RATE_LIMIT_RESET_HEADER: &str = "x-ratelimit-reset";
+
+// Counts failed credential checks per email. Once an email reaches the maximum, further attempts are refused
+// until the window that started with its first failure has passed, whether the password is right or not
+#[derive(Debug, Clone)]
+pub struct LoginAttempts {
+    max_failures: u32,
+    window: Duration,
+    failures: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+}
+
+impl LoginAttempts {
+    pub fn new(max_failures: u32, window: Duration) -> LoginAttempts {
+        LoginAttempts { max_failures, window, failures: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn is_locked(&self, email: &str) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        self.forget_expired(&mut failures);
+
+        failures.get(email).map(|(_, count)| *count >= self.max_failures).unwrap_or(false)
+    }
+
+    pub fn record_failure(&self, email: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        self.forget_expired(&mut failures);
+
+        failures.entry(email.to_string()).or_insert((Instant::now(), 0)).1 += 1;
+    }
+
+    pub fn reset(&self, email: &str) {
+        self.failures.lock().unwrap().remove(email);
+    }
+
+    pub fn attempts_left(&self, email: &str) -> AttemptsLeft {
+        let mut failures = self.failures.lock().unwrap();
+        self.forget_expired(&mut failures);
+
+        match failures.get(email) {
+            Some((first_failure, count)) => AttemptsLeft {
+                limit: self.max_failures,
+                remaining: self.max_failures.saturating_sub(*count),
+                reset: self.window.saturating_sub(first_failure.elapsed()),
+            },
+            None => AttemptsLeft { limit: self.max_failures, remaining: self.max_failures, reset: Duration::ZERO },
+        }
+    }
+
+    // Expired windows are dropped as we go, so the map only ever holds emails that failed recently
+    fn forget_expired(&self, failures: &mut HashMap<String, (Instant, u32)>) {
+        let window = self.window;
+        failures.retain(|_, (first_failure, _)| first_failure.elapsed() < window);
+    }
+}
+
+// How many more failures an email has before it is locked out, and how long until its window ends and it has them
+// all back. An email without recent failures has nothing to wait for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttemptsLeft {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,
+}
+
+impl AttemptsLeft {
+
+    // Sent on every answer to a credentials check, not just the 429s, so clients can back off before being locked
+    // out. The reset is in whole seconds, rounded up so a client waiting it out is never early
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        let reset_seconds = self.reset.as_secs() + u64::from(self.reset.subsec_nanos() > 0);
+
+        headers.insert(RATE_LIMIT_LIMIT_HEADER, HeaderValue::from(self.limit));
+        headers.insert(RATE_LIMIT_REMAINING_HEADER, HeaderValue::from(self.remaining));
+        headers.insert(RATE_LIMIT_RESET_HEADER, HeaderValue::from(reset_seconds));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use axum::http::HeaderMap;
+    use crate::common::login_attempts::{AttemptsLeft, LoginAttempts};
+
+    #[test]
+    fn email_is_locked_after_max_failures() {
+        let attempts = LoginAttempts::new(2, Duration::from_secs(60));
+
+        attempts.record_failure("guesser@brute.no");
+        assert!(!attempts.is_locked("guesser@brute.no"));
+
+        attempts.record_failure("guesser@brute.no");
+        assert!(attempts.is_locked("guesser@brute.no"));
+
+        // Other emails are unaffected
+        assert!(!attempts.is_locked("someone.else@brute.no"));
+    }
+
+    #[test]
+    fn lock_is_lifted_once_the_window_has_passed() {
+        let attempts = LoginAttempts::new(1, Duration::from_millis(10));
+
+        attempts.record_failure("patient@brute.no");
+        assert!(attempts.is_locked("patient@brute.no"));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!attempts.is_locked("patient@brute.no"));
+    }
+
+    #[test]
+    fn reset_clears_failures() {
+        let attempts = LoginAttempts::new(1, Duration::from_secs(60));
+
+        attempts.record_failure("forgetful@brute.no");
+        attempts.reset("forgetful@brute.no");
+
+        assert!(!attempts.is_locked("forgetful@brute.no"));
+    }
+
+    #[test]
+    fn failures_count_down_the_attempts_left() {
+        let attempts = LoginAttempts::new(3, Duration::from_secs(60));
+        assert_eq!(attempts.attempts_left("counted@brute.no"), AttemptsLeft { limit: 3, remaining: 3, reset: Duration::ZERO });
+
+        attempts.record_failure("counted@brute.no");
+        attempts.record_failure("counted@brute.no");
+
+        let attempts_left = attempts.attempts_left("counted@brute.no");
+        assert_eq!((attempts_left.limit, attempts_left.remaining), (3, 1));
+        assert!(attempts_left.reset > Duration::from_secs(59) && attempts_left.reset <= Duration::from_secs(60));
+
+        // Failing past the limit leaves nothing rather than wrapping around
+        attempts.record_failure("counted@brute.no");
+        attempts.record_failure("counted@brute.no");
+        assert_eq!(attempts.attempts_left("counted@brute.no").remaining, 0);
+    }
+
+    #[test]
+    fn reset_header_is_rounded_up_to_whole_seconds() {
+        let mut headers = HeaderMap::new();
+        AttemptsLeft { limit: 10, remaining: 7, reset: Duration::from_millis(59_001) }.insert_headers(&mut headers);
+
+        assert_eq!(headers["x-ratelimit-limit"], "10");
+        assert_eq!(headers["x-ratelimit-remaining"], "7");
+        assert_eq!(headers["x-ratelimit-reset"], "60");
+    }
+}
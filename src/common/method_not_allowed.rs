@@ -0,0 +1,80 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use serde_json::json;
+
+// The methods axum registered for the path, as it lists them in the 'Allow' header of its 405s
+fn allowed_methods(headers: &HeaderMap) -> Vec<String> {
+    headers.get_all(header::ALLOW).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|methods| methods.split(','))
+        .map(|method| method.trim().to_string())
+        .filter(|method| !method.is_empty())
+        .collect()
+}
+
+// Axum only adds the 'Allow' header to a 405 once the response has passed the route's own layers, which any
+// layer of the router is among. Wrapping the whole router instead lets describe_method_not_allowed read it
+pub fn describe_methods_not_allowed(routes: Router) -> Router {
+    Router::new()
+        .fallback_service(routes)
+        .layer(middleware::from_fn(describe_method_not_allowed))
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+// Axum answers a method a path has no handler for with an empty 405. This gives it a JSON body listing the
+// allowed methods like every other error we send, keeping the 'Allow' header axum derived from the routes
+pub async fn describe_method_not_allowed(request: Request<Body>, next: Next<Body>) -> Response {
+    let response = next.run(request).await;
+
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+
+    let allowed = allowed_methods(response.headers());
+    let mut described = (StatusCode::METHOD_NOT_ALLOWED, Json(json!({
+        "error": "method not allowed",
+        "allowed": allowed
+    }))).into_response();
+
+    if let Some(allow) = response.headers().get(header::ALLOW) {
+        described.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+
+    described
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{Request, StatusCode}, routing::get, Router};
+    use serde_json::json;
+    use tower::ServiceExt;
+    use crate::common::method_not_allowed::describe_methods_not_allowed;
+
+    #[tokio::test]
+    async fn unsupported_method_on_an_existing_path_is_answered_with_json_and_allow_header() {
+        let service = describe_methods_not_allowed(Router::new()
+            .route("/locations/:location_id", get(|| async { "{}" }).put(|| async { "{}" }).delete(|| async { "" })));
+
+        let request = Request::builder()
+            .uri("/locations/1")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = service.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()["allow"], "GET,HEAD,PUT,DELETE");
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response_json, json!({"error": "method not allowed", "allowed": ["GET", "HEAD", "PUT", "DELETE"]}));
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+use axum::{
+    extract::MatchedPath,
+    http::Request,
+    middleware::Next,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+const REQUESTS_TOTAL: &str = "http_requests_total";
+const REQUESTS_DURATION_SECONDS: &str = "http_requests_duration_seconds";
+
+// Bucket boundaries (in seconds) used for the handler latency histogram
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+static PROMETHEUS_HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+// The recorder is process global, so it is installed once and shared by every router that exposes it
+pub fn prometheus_handle() -> &'static PrometheusHandle {
+    PROMETHEUS_HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full(REQUESTS_DURATION_SECONDS.to_string()), LATENCY_BUCKETS)
+            .expect("Failed to set latency buckets")
+            .install_recorder()
+            .expect("Failed to install Prometheus recorder")
+    })
+}
+
+// - - - - - - - - - - - [ROUTES] - - - - - - - - - - -
+
+pub fn metrics_route() -> Router {
+    // Install the recorder up front so requests served before the first scrape are counted
+    prometheus_handle();
+
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+}
+
+// - - - - - - - - - - - [HANDLERS] - - - - - - - - - - -
+
+pub async fn metrics_handler() -> impl IntoResponse {
+    prometheus_handle().render()
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+pub async fn track_metrics<B>(request: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let start = Instant::now();
+
+    // Prefer the route template (e.g. "/locations/:location_id") to keep label cardinality bounded
+    let path = match request.extensions().get::<MatchedPath>() {
+        Some(matched_path) => matched_path.as_str().to_owned(),
+        None => request.uri().path().to_owned(),
+    };
+    let method = request.method().clone();
+
+    let response = next.run(request).await;
+
+    let latency = start.elapsed().as_secs_f64();
+    let labels = [
+        ("method", method.to_string()),
+        ("path", path),
+        ("status", response.status().as_u16().to_string()),
+    ];
+
+    metrics::increment_counter!(REQUESTS_TOTAL, &labels);
+    metrics::histogram!(REQUESTS_DURATION_SECONDS, latency, &labels);
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode}
+    };
+    use tower::ServiceExt;
+    use crate::{
+        common::{
+            db::create_shared_connection_pool,
+            metrics::metrics_route,
+            state::AppState,
+            util::load_environment_variable
+        },
+        create_app
+    };
+
+    #[tokio::test]
+    async fn get_metrics_returns_request_counters_and_latencies() {
+        let database_url = load_environment_variable("TEST_DB");
+        let connection_pool = create_shared_connection_pool(database_url, 1);
+        let service = create_app(AppState::test(connection_pool)).merge(metrics_route());
+
+        // Issue a few requests so there is something to report
+        for _ in 0..3 {
+            let request = Request::builder()
+                .uri(format!("/users/{}", -666)) // Use a non-existent ID
+                .method("GET")
+                .body(Body::empty())
+                .unwrap();
+
+            let response = service.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = service
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 200
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Extract body from response
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+
+        // Assert that both the counter and the histogram are exported with the expected labels
+        assert!(metrics.contains("http_requests_total"));
+        assert!(metrics.contains("http_requests_duration_seconds_bucket"));
+        assert!(metrics.contains("path=\"/users/:user_id\""));
+        assert!(metrics.contains("status=\"404\""));
+    }
+}
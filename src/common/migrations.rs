@@ -0,0 +1,72 @@
+use std::error::Error;
+use diesel::PgConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use crate::common::{db::ConnectionPool, util::load_flag_environment_variable};
+
+// Compiled into the binary, so a deployed server carries the migrations its queries were written against
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+// Applies the migrations the database hasn't seen yet unless started with RUN_MIGRATIONS=false. A migration that
+// fails stops the server, as serving against a stale schema would only fail request by request instead
+pub fn run_migrations_from_env(shared_connection_pool: &ConnectionPool) {
+    if !load_flag_environment_variable("RUN_MIGRATIONS", true) {
+        tracing::info!("RUN_MIGRATIONS is off, skipped running migrations");
+        return;
+    }
+
+    let mut connection = shared_connection_pool.pool.get()
+        .expect("Failed to acquire connection from pool");
+
+    match run_pending_migrations(&mut connection) {
+        Ok(versions) if versions.is_empty() => tracing::info!("Database schema is up to date"),
+        Ok(versions) => {
+            for version in versions {
+                tracing::info!("Ran migration {}", version);
+            }
+        }
+        Err(err) => panic!("Failed to run migrations, refusing to serve against a stale schema: {}", err),
+    }
+}
+
+// Returns the versions of the migrations that ran, oldest first. Each runs in a transaction of its own, so one
+// that fails leaves those before it applied and itself not at all
+pub fn run_pending_migrations(connection: &mut PgConnection) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    connection.run_pending_migrations(MIGRATIONS)
+        .map(|versions| versions.iter().map(|version| version.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use diesel::{connection::SimpleConnection, sql_query, Connection, PgConnection, RunQueryDsl};
+    use uuid::Uuid;
+    use crate::common::{migrations::run_pending_migrations, util::load_environment_variable};
+
+    #[test]
+    fn pending_migrations_are_applied_to_a_fresh_database_once() {
+        let mut connection = PgConnection::establish(&load_environment_variable("TEST_DB"))
+            .expect("Failed to connect to TEST_DB");
+
+        // An empty schema of its own stands in for a fresh database, the migrations table included
+        let schema = format!("test_{}", Uuid::new_v4().simple());
+        connection.batch_execute(&format!("CREATE SCHEMA {0}; SET search_path TO {0}", schema))
+            .expect("Failed to create test schema");
+
+        let first_run = run_pending_migrations(&mut connection);
+        let second_run = run_pending_migrations(&mut connection);
+        let locations_table = sql_query("SELECT id, star_system, area FROM locations LIMIT 1").execute(&mut connection);
+
+        connection.batch_execute(&format!("SET search_path TO DEFAULT; DROP SCHEMA {} CASCADE", schema))
+            .expect("Failed to drop test schema");
+
+        let mut expected: Vec<String> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+            .expect("Failed to read migrations directory")
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().split('_').next().unwrap().replace('-', ""))
+            .collect();
+        expected.sort();
+
+        assert_eq!(first_run.expect("Migrations failed"), expected);
+        assert_eq!(second_run.expect("Migrations failed"), Vec::<String>::new());
+        assert!(locations_table.is_ok());
+    }
+}
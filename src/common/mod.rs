@@ -1,4 +1,29 @@
+pub mod config;
 pub mod db;
+pub mod migrations;
 pub mod security;
 pub mod util;
 pub mod error;
+pub mod extract;
+pub mod metrics;
+pub mod logging;
+pub mod shutdown;
+pub mod limits;
+pub mod compression;
+pub mod cors;
+pub mod net;
+pub mod request_id;
+pub mod method_not_allowed;
+pub mod not_found;
+pub mod timeout;
+pub mod openapi;
+pub mod version;
+pub mod pagination;
+pub mod fields;
+pub mod sort;
+pub mod state;
+pub mod login_attempts;
+pub mod validation;
+
+#[cfg(test)]
+pub mod test_db;
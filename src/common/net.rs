@@ -0,0 +1,254 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Span;
+
+pub const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+pub const REAL_IP_HEADER: &str = "x-real-ip";
+
+// A block of addresses written as 'address/prefix', or a single address without a prefix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    // An IPv4 address is never in an IPv6 block or the other way round, mapped addresses included
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix_len),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => prefix_matches(u128::from(network), u128::from(ip), 128, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let host_bits = u32::from(bits - prefix_len);
+    network.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(cidr: &str) -> Result<Cidr, String> {
+        let (address, prefix_len) = match cidr.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (cidr.trim(), None),
+        };
+
+        let network = address.parse::<IpAddr>().map_err(|_| format!("'{}' is not an IP address", address))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().ok()
+                .filter(|prefix_len| *prefix_len <= bits)
+                .ok_or_else(|| format!("'{}' is not a prefix length between 0 and {}", prefix_len, bits))?,
+            None => bits,
+        };
+
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+// The peers allowed to tell us who the client is. With none configured the forwarding headers are never believed,
+// as anyone can send them
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+// TRUSTED_PROXIES is a comma separated list of CIDR blocks or single addresses, e.g. '10.0.0.0/8, 192.168.1.7'
+pub fn parse_trusted_proxies(proxies: &str) -> TrustedProxies {
+    TrustedProxies(proxies.split(',').map(str::trim).filter(|cidr| !cidr.is_empty()).map(|cidr| {
+        cidr.parse::<Cidr>()
+            .unwrap_or_else(|err| panic!("TRUSTED_PROXIES must be a comma separated list of CIDR blocks, {}", err))
+    }).collect())
+}
+
+// The address the request came from as far as we can tell, available to handlers through the request extensions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+// Only a trusted peer's headers are read. X-Forwarded-For is walked from the right, as every proxy appends the
+// address it was reached from, and the first address that isn't one of our own proxies is the client - anything
+// further left was written by the client and can't be believed. When every hop is one of ours, the leftmost one is
+// the closest we get. X-Real-IP is used when there is no X-Forwarded-For
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &TrustedProxies) -> IpAddr {
+    if !trusted_proxies.contains(peer) {
+        return peer;
+    }
+
+    let forwarded_for: Vec<&str> = headers.get_all(FORWARDED_FOR_HEADER).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    if !forwarded_for.is_empty() {
+        let mut nearest = peer;
+
+        // An entry that isn't an address ends the walk at the last hop we could still vouch for
+        for address in forwarded_for.iter().rev() {
+            match address.parse::<IpAddr>() {
+                Ok(address) if trusted_proxies.contains(address) => nearest = address,
+                Ok(address) => return address,
+                Err(_) => break,
+            }
+        }
+
+        return nearest;
+    }
+
+    headers.get(REAL_IP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+        .unwrap_or(peer)
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+// Resolves the client's address and tags the request's span with it. Requests that didn't come through a socket,
+// like those in tests, have no peer and are left without one
+pub async fn resolve_client_ip(
+    State(trusted_proxies): State<TrustedProxies>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| address.ip());
+
+    if let Some(peer) = peer {
+        let ip = client_ip(peer, request.headers(), &trusted_proxies);
+        Span::current().record("client_ip", ip.to_string().as_str());
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{HeaderMap, HeaderValue, Request},
+        middleware,
+        routing::get,
+        Extension,
+        Router,
+    };
+    use tower::ServiceExt;
+    use crate::common::net::{client_ip, parse_trusted_proxies, resolve_client_ip, Cidr, ClientIp};
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn forwarded(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.append(*name, HeaderValue::from_static(value));
+        }
+        header_map
+    }
+
+    #[test]
+    fn cidr_blocks_contain_their_addresses() {
+        let block = "10.1.0.0/16".parse::<Cidr>().unwrap();
+        assert!(block.contains(ip("10.1.255.3")));
+        assert!(!block.contains(ip("10.2.0.1")));
+        assert!(!block.contains(ip("::ffff:10.1.0.1")));
+
+        let single = "2001:db8::1".parse::<Cidr>().unwrap();
+        assert!(single.contains(ip("2001:db8::1")));
+        assert!(!single.contains(ip("2001:db8::2")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("203.0.113.9")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(ip("fd12:3456::1")));
+
+        for cidr in ["10.0.0.0/33", "10.0.0/8", "proxy", "::/129", "10.0.0.0/"] {
+            assert!(cidr.parse::<Cidr>().is_err(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "TRUSTED_PROXIES")]
+    fn malformed_trusted_proxies_are_refused() {
+        parse_trusted_proxies("10.0.0.0/8, loadbalancer");
+    }
+
+    #[test]
+    fn trusted_peer_resolves_the_forwarded_client() {
+        let trusted = parse_trusted_proxies("10.0.0.0/8, 192.168.1.7");
+        let headers = forwarded(&[("x-forwarded-for", "203.0.113.9, 10.0.0.4")]);
+
+        assert_eq!(client_ip(ip("192.168.1.7"), &headers, &trusted), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn untrusted_peer_resolves_to_itself() {
+        let trusted = parse_trusted_proxies("10.0.0.0/8");
+        let headers = forwarded(&[("x-forwarded-for", "203.0.113.9"), ("x-real-ip", "203.0.113.9")]);
+
+        assert_eq!(client_ip(ip("198.51.100.20"), &headers, &trusted), ip("198.51.100.20"));
+        assert_eq!(client_ip(ip("10.0.0.4"), &headers, &parse_trusted_proxies("")), ip("10.0.0.4"));
+    }
+
+    #[test]
+    fn addresses_the_client_wrote_itself_are_not_believed() {
+        let trusted = parse_trusted_proxies("10.0.0.0/8");
+
+        // The client sent 'X-Forwarded-For: 1.2.3.4' and our proxy appended the address it was reached from
+        let spoofed = forwarded(&[("x-forwarded-for", "1.2.3.4, 203.0.113.9")]);
+        assert_eq!(client_ip(ip("10.0.0.4"), &spoofed, &trusted), ip("203.0.113.9"));
+
+        // Repeated headers are read as one list, and garbage stops the walk at the last of our proxies before it
+        let repeated = forwarded(&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-for", "203.0.113.9, 10.0.0.5")]);
+        assert_eq!(client_ip(ip("10.0.0.4"), &repeated, &trusted), ip("203.0.113.9"));
+        let garbage = forwarded(&[("x-forwarded-for", "1.2.3.4, not-an-ip, 10.0.0.5")]);
+        assert_eq!(client_ip(ip("10.0.0.4"), &garbage, &trusted), ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn real_ip_is_used_without_forwarded_for() {
+        let trusted = parse_trusted_proxies("10.0.0.0/8");
+
+        assert_eq!(client_ip(ip("10.0.0.4"), &forwarded(&[("x-real-ip", "203.0.113.9")]), &trusted), ip("203.0.113.9"));
+        assert_eq!(client_ip(ip("10.0.0.4"), &forwarded(&[("x-real-ip", "nobody")]), &trusted), ip("10.0.0.4"));
+    }
+
+    #[tokio::test]
+    async fn trusted_and_untrusted_peers_resolve_different_client_ips() {
+        let service = Router::new()
+            .route("/ip", get(|Extension(ClientIp(ip)): Extension<ClientIp>| async move { ip.to_string() }))
+            .layer(middleware::from_fn_with_state(parse_trusted_proxies("10.0.0.0/8"), resolve_client_ip));
+
+        for (peer, expected) in [("10.0.0.4:4000", "203.0.113.9"), ("198.51.100.20:4000", "198.51.100.20")] {
+            let mut request = Request::builder()
+                .uri("/ip")
+                .header("X-Forwarded-For", "203.0.113.9")
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+
+            let response = service.clone().oneshot(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            assert_eq!(body, expected, "{}", peer);
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use axum::http::{StatusCode, Uri};
+use serde_json::json;
+use crate::common::error::ApiError;
+
+// Answers requests for paths no route matches in JSON like every other error. The path is echoed back, so a client
+// configured with the wrong base URL or prefix can tell from the response what it actually asked for
+pub async fn route_not_found(uri: Uri) -> ApiError {
+    ApiError { status: StatusCode::NOT_FOUND, body: json!({"error": "not found", "path": uri.path()}) }
+}
@@ -0,0 +1,148 @@
+use axum::Router;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+use crate::{
+    common::error::{ErrorResponse, ValidationErrorResponse},
+    locations::{
+        model::{AreaStats, BulkDeleteLocations, ImportLineError, ImportSummary, Location, LocationAuditEntry, PatchLocation, StarSystemCount, UpsertLocation},
+        router::router as locations,
+    },
+    users::{
+        model::{ChangePassword, ChangeRole, LoginUser, PublicUser, UpdateUser, UpsertUser, User},
+        router::router as users,
+    },
+};
+
+// The spec is derived from the handler annotations and model structs, so it can't drift from the code
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        locations::create_location_handler,
+        locations::bulk_delete_locations_handler,
+        locations::delete_locations_by_filter_handler,
+        locations::import_locations_handler,
+        locations::validate_import_handler,
+        locations::list_locations_handler,
+        locations::export_locations_handler,
+        locations::area_stats_handler,
+        locations::star_system_counts_handler,
+        locations::nearby_locations_handler,
+        locations::stream_location_changes_handler,
+        locations::location_events_handler,
+        locations::read_location_handler,
+        locations::update_location_handler,
+        locations::patch_location_handler,
+        locations::delete_location_handler,
+        locations::location_history_handler,
+        users::create_user_handler,
+        users::list_users_handler,
+        users::get_user_handler,
+        users::update_user_handler,
+        users::delete_user_handler,
+        users::restore_user_handler,
+        users::reset_password_handler,
+        users::change_role_handler,
+        users::login_user_handler,
+        users::check_credentials_handler,
+        users::me_handler,
+        users::change_password_handler,
+        users::impersonate_user_handler,
+    ),
+    components(schemas(
+        Location, UpsertLocation, PatchLocation, BulkDeleteLocations, AreaStats, StarSystemCount, LocationAuditEntry, ImportSummary, ImportLineError,
+        User, PublicUser, UpsertUser, UpdateUser, LoginUser, ChangePassword, ChangeRole,
+        ErrorResponse, ValidationErrorResponse,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "locations", description = "Locations of the star map"),
+        (name = "users", description = "Users, login and impersonation"),
+    )
+)]
+pub struct ApiDoc;
+
+// Registers the 'bearer_auth' scheme that protected paths refer to in their 'security' section
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+    }
+}
+
+// - - - - - - - - - - - [ROUTES] - - - - - - - - - - -
+
+// Serves the spec at /openapi.json and a Swagger UI reading it at /docs, both without authentication
+pub fn docs_route() -> Router {
+    Router::new()
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode}
+    };
+    use tower::ServiceExt;
+    use crate::common::openapi::docs_route;
+
+    #[tokio::test]
+    async fn get_openapi_json_documents_paths_bodies_auth_and_errors() {
+        let request = Request::builder()
+            .uri("/openapi.json")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = docs_route()
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 200
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Extract body from response
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        // Assert that path params, request bodies, auth requirements and error shapes are all described
+        let read_location = &spec["paths"]["/locations/{location_id}"]["get"];
+        assert_eq!(read_location["parameters"][0]["name"], "location_id");
+        assert_eq!(read_location["parameters"][0]["in"], "path");
+        assert!(read_location["security"][0].get("bearer_auth").is_some());
+        assert_eq!(read_location["responses"]["404"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ErrorResponse");
+
+        assert_eq!(spec["paths"]["/locations"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/UpsertLocation");
+        assert_eq!(spec["paths"]["/users/login"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/LoginUser");
+        assert_eq!(spec["components"]["securitySchemes"]["bearer_auth"]["scheme"], "bearer");
+        assert!(spec["components"]["schemas"]["ValidationErrorResponse"]["properties"].get("errors").is_some());
+    }
+
+    #[tokio::test]
+    async fn get_docs_serves_swagger_ui() {
+        let request = Request::builder()
+            .uri("/docs/")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = docs_route()
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 200
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::OnceLock;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Uri},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde_derive::Deserialize;
+use utoipa::IntoParams;
+use crate::common::{error::ApiError, util::load_env_parsed};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+const DEFAULT_MAX_PAGE_SIZE: i64 = 200;
+
+static MAX_PAGE_SIZE: OnceLock<i64> = OnceLock::new();
+
+// Reads MAX_PAGE_SIZE - the most items a single page of any listing holds, however many the client asks for
+pub fn max_page_size() -> i64 {
+    *MAX_PAGE_SIZE.get_or_init(|| {
+        let max_page_size = load_env_parsed("MAX_PAGE_SIZE", DEFAULT_MAX_PAGE_SIZE);
+        assert!(max_page_size > 0, "MAX_PAGE_SIZE must be a positive number, got {}", max_page_size);
+        max_page_size
+    })
+}
+
+// The query params every offset paged listing takes, parsed by the Pagination extractor
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PaginationQuery {
+    /// Items per page, 50 unless given and at most MAX_PAGE_SIZE
+    pub limit: Option<i64>,
+    /// Items to skip, 0 unless given
+    pub offset: Option<i64>,
+}
+
+// A validated window into a listing - neither value is negative and the end of the window fits in an i64.
+// Handlers take it as an argument, which answers bad 'limit' and 'offset' params with 400 before they run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pagination {
+    limit: i64,
+    offset: i64,
+}
+
+impl Pagination {
+    pub fn new(limit: Option<i64>, offset: Option<i64>) -> Result<Pagination, ApiError> {
+        Pagination::with_max_page_size(limit, offset, max_page_size())
+    }
+
+    // Clamped to the max page size, which listings answer with rather than the limit asked for
+    pub fn limit(&self) -> i64 {
+        self.limit
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    // Limits above the max are clamped rather than refused, and listings answer with the limit actually used
+    fn with_max_page_size(limit: Option<i64>, offset: Option<i64>, max_page_size: i64) -> Result<Pagination, ApiError> {
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        let offset = offset.unwrap_or(0);
+
+        if limit < 0 || offset < 0 {
+            return Err(ApiError::bad_request("Query params 'limit' and 'offset' must not be negative"));
+        }
+
+        let limit = limit.min(max_page_size);
+
+        // Rejected up front so nothing downstream ever has to reason about a window that wraps around
+        if offset.checked_add(limit).is_none() {
+            return Err(ApiError::bad_request("Query params 'limit' and 'offset' are too large"));
+        }
+
+        Ok(Pagination { limit, offset })
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Pagination {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<PaginationQuery>::from_request_parts(parts, state).await
+            .map_err(|_| ApiError::bad_request("Query params 'limit' and 'offset' must be whole numbers"))?;
+
+        Pagination::new(query.limit, query.offset)
+    }
+}
+
+// Cursors are opaque to clients, who only ever pass back what we gave them. Inside, one is the id of the last item seen
+pub fn encode_cursor(last_seen_id: i32) -> String {
+    URL_SAFE_NO_PAD.encode(last_seen_id.to_string())
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<i32, ApiError> {
+    URL_SAFE_NO_PAD.decode(cursor.trim())
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|id| id.parse::<i32>().ok())
+        .ok_or_else(|| ApiError::bad_request("Query param 'cursor' is not a cursor returned by this API"))
+}
+
+// RFC 5988 'Link' header pointing at the first, previous, next and last page of a listing, for clients that page through headers
+pub fn pagination_links(uri: &Uri, pagination: Pagination, total: i64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    if let Some(value) = link_header_value(uri, pagination, total).and_then(|links| HeaderValue::from_str(&links).ok()) {
+        headers.insert(header::LINK, value);
+    }
+
+    headers
+}
+
+fn link_header_value(uri: &Uri, Pagination { limit, offset }: Pagination, total: i64) -> Option<String> {
+
+    // A page size of 0 never gets anywhere, so there is nothing to link to
+    if limit == 0 {
+        return None;
+    }
+
+    let last = if total > 0 { (total - 1) / limit * limit } else { 0 };
+
+    let mut links = vec![("first", 0)];
+    if offset > 0 {
+        links.push(("prev", offset.saturating_sub(limit).max(0)));
+    }
+    if offset + limit < total {
+        links.push(("next", offset + limit));
+    }
+    links.push(("last", last));
+
+    Some(links.into_iter()
+        .map(|(rel, offset)| format!("<{}>; rel=\"{}\"", page_url(uri, limit, offset), rel))
+        .collect::<Vec<_>>()
+        .join(", "))
+}
+
+// The request's own URL with 'limit' and 'offset' replaced, keeping every other query param as it was sent
+fn page_url(uri: &Uri, limit: i64, offset: i64) -> String {
+    let mut params: Vec<String> = uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && name != "limit" && name != "offset"
+        })
+        .map(str::to_string)
+        .collect();
+
+    params.push(format!("limit={}", limit));
+    params.push(format!("offset={}", offset));
+
+    format!("{}?{}", uri.path(), params.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{Request, StatusCode, Uri}, routing::get, Json, Router};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use crate::common::pagination::{decode_cursor, encode_cursor, link_header_value, max_page_size, Pagination, DEFAULT_PAGE_SIZE};
+
+    // Extracts the pagination of a request for the query, answering with the window it got
+    async fn extract(query: &str) -> (StatusCode, Value) {
+        let service = Router::new().route("/", get(|pagination: Pagination| async move {
+            Json(json!({"limit": pagination.limit(), "offset": pagination.offset()}))
+        }));
+
+        let request = Request::builder()
+            .uri(format!("/{}", query))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = service.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn extractor_falls_back_to_defaults() {
+        assert_eq!(extract("").await, (StatusCode::OK, json!({"limit": DEFAULT_PAGE_SIZE, "offset": 0})));
+        assert_eq!(extract("?q=ring").await, (StatusCode::OK, json!({"limit": DEFAULT_PAGE_SIZE, "offset": 0})));
+    }
+
+    #[tokio::test]
+    async fn extractor_clamps_limit_to_max_page_size() {
+        assert_eq!(extract("?limit=10&offset=30").await, (StatusCode::OK, json!({"limit": 10, "offset": 30})));
+        assert_eq!(extract("?limit=1000000").await, (StatusCode::OK, json!({"limit": max_page_size(), "offset": 0})));
+    }
+
+    #[tokio::test]
+    async fn extractor_returns_400_on_negative_or_non_numeric_values() {
+        for query in ["?limit=-1", "?offset=-5", "?limit=ten", "?offset=1.5"] {
+            let (status, body) = extract(query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+            assert!(body["error"].as_str().unwrap().starts_with("Query params 'limit' and 'offset'"), "{}", query);
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips_and_garbage_returns_400() {
+        assert_eq!(decode_cursor(&encode_cursor(4711)).unwrap(), 4711);
+        assert_eq!(decode_cursor("not a cursor").unwrap_err().status, StatusCode::BAD_REQUEST);
+        assert_eq!(decode_cursor(&encode_cursor(4711).replace('N', "x")).map_err(|err| err.status), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn limit_above_max_page_size_is_clamped() {
+        assert_eq!(Pagination::with_max_page_size(Some(10000), Some(5), 200).unwrap(), Pagination { limit: 200, offset: 5 });
+        assert_eq!(Pagination::with_max_page_size(Some(i64::MAX), Some(1), 200).unwrap(), Pagination { limit: 200, offset: 1 });
+        assert_eq!(Pagination::with_max_page_size(Some(20), None, 200).unwrap(), Pagination { limit: 20, offset: 0 });
+    }
+
+    #[test]
+    fn missing_values_fall_back_to_defaults() {
+        assert_eq!(Pagination::new(None, None).unwrap(), Pagination { limit: DEFAULT_PAGE_SIZE, offset: 0 });
+    }
+
+    #[test]
+    fn window_ending_at_i64_max_is_accepted() {
+        assert!(Pagination::new(Some(1), Some(i64::MAX - 1)).is_ok());
+    }
+
+    #[test]
+    fn window_past_i64_max_returns_400() {
+        for (limit, offset) in [(1, i64::MAX), (i64::MAX, i64::MAX)] {
+            let err = Pagination::new(Some(limit), Some(offset)).expect_err("Expected an overflowing window to be refused");
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn negative_values_return_400() {
+        let err = Pagination::new(Some(-1), Some(0)).expect_err("Expected a negative limit to be refused");
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    }
+
+    fn links(uri: &str, limit: i64, offset: i64, total: i64) -> String {
+        link_header_value(&uri.parse::<Uri>().unwrap(), Pagination { limit, offset }, total).unwrap()
+    }
+
+    #[test]
+    fn first_page_links_to_next_and_last_but_not_prev() {
+        assert_eq!(links("/locations?limit=10", 10, 0, 25), concat!(
+            "</locations?limit=10&offset=0>; rel=\"first\", ",
+            "</locations?limit=10&offset=10>; rel=\"next\", ",
+            "</locations?limit=10&offset=20>; rel=\"last\""
+        ));
+    }
+
+    #[test]
+    fn middle_page_links_both_ways_and_keeps_other_params() {
+        assert_eq!(links("/locations?q=ring&offset=10&limit=10&sort=created_at", 10, 10, 25), concat!(
+            "</locations?q=ring&sort=created_at&limit=10&offset=0>; rel=\"first\", ",
+            "</locations?q=ring&sort=created_at&limit=10&offset=0>; rel=\"prev\", ",
+            "</locations?q=ring&sort=created_at&limit=10&offset=20>; rel=\"next\", ",
+            "</locations?q=ring&sort=created_at&limit=10&offset=20>; rel=\"last\""
+        ));
+    }
+
+    #[test]
+    fn last_page_links_to_prev_but_not_next() {
+        assert_eq!(links("/users", 10, 20, 25), concat!(
+            "</users?limit=10&offset=0>; rel=\"first\", ",
+            "</users?limit=10&offset=10>; rel=\"prev\", ",
+            "</users?limit=10&offset=20>; rel=\"last\""
+        ));
+    }
+
+    #[test]
+    fn last_is_exact_when_total_is_a_multiple_of_limit_and_offset_is_unaligned() {
+        assert!(links("/locations", 10, 5, 30).contains("</locations?limit=10&offset=15>; rel=\"next\""));
+        assert!(links("/locations", 10, 5, 30).contains("</locations?limit=10&offset=20>; rel=\"last\""));
+        assert!(links("/locations", 10, 5, 30).contains("</locations?limit=10&offset=0>; rel=\"prev\""));
+    }
+
+    #[test]
+    fn empty_listing_links_only_first_and_last() {
+        assert_eq!(links("/locations", 10, 0, 0), concat!(
+            "</locations?limit=10&offset=0>; rel=\"first\", ",
+            "</locations?limit=10&offset=0>; rel=\"last\""
+        ));
+    }
+
+    #[test]
+    fn zero_limit_has_no_links() {
+        assert!(link_header_value(&"/locations".parse::<Uri>().unwrap(), Pagination { limit: 0, offset: 0 }, 5).is_none());
+    }
+}
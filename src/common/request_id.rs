@@ -0,0 +1,146 @@
+use axum::{
+    body::{Body, boxed, Bytes},
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer ids are replaced rather than echoed, so a client can't blow up our logs and responses
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// The id a request is known by, available to handlers through the request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+// Keeps the caller's id when it is usable, so one id can be followed across services, and makes one up otherwise
+fn request_id_from<B>(request: &Request<B>) -> RequestId {
+    let provided = request.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH);
+
+    match provided {
+        Some(id) => RequestId(id.to_string()),
+        None => RequestId(Uuid::new_v4().to_string()),
+    }
+}
+
+fn is_json(response: &Response) -> bool {
+    response.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"))
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+// Tags every request with an id that is echoed in the 'X-Request-Id' response header and, for JSON error
+// responses, in the body as 'request_id'. Doing it here covers every error, however the handler built it
+pub async fn assign_request_id(mut request: Request<Body>, next: Next<Body>) -> Response {
+    let request_id = request_id_from(&request);
+    request.extensions_mut().insert(request_id.clone());
+
+    let mut response = next.run(request).await;
+
+    // The id is either valid header text we were sent or a generated uuid, so this only fails on a bug
+    let header_value = HeaderValue::from_str(&request_id.0).expect("Request id is not a valid header value");
+    response.headers_mut().insert(REQUEST_ID_HEADER, header_value);
+
+    if !(response.status().is_client_error() || response.status().is_server_error()) || !is_json(&response) {
+        return response;
+    }
+
+    // Error bodies are small, so buffering them is cheap
+    let (mut parts, body) = response.into_parts();
+    let bytes: Bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Error reading error response body: {:?}", err);
+            return Response::from_parts(parts, boxed(Body::empty()));
+        }
+    };
+
+    let bytes = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut body)) => {
+            body.insert("request_id".to_string(), Value::String(request_id.0));
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Bytes::from(Value::Object(body).to_string())
+        }
+        _ => bytes,
+    };
+
+    Response::from_parts(parts, boxed(Body::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        middleware,
+        routing::get,
+        Json,
+        Router
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use crate::common::request_id::{assign_request_id, REQUEST_ID_HEADER};
+
+    fn service() -> Router {
+        Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route("/missing", get(|| async { (StatusCode::NOT_FOUND, Json(json!({"error": "Missing not found"}))) }))
+            .layer(middleware::from_fn(assign_request_id))
+    }
+
+    #[tokio::test]
+    async fn provided_request_id_is_echoed_in_header_and_error_body() {
+        let request = Request::builder()
+            .uri("/missing")
+            .method("GET")
+            .header(REQUEST_ID_HEADER, "support-ticket-4711")
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = service()
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(REQUEST_ID_HEADER).unwrap(), "support-ticket-4711");
+
+        // Extract body from response
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response_json: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(response_json, json!({"error": "Missing not found", "request_id": "support-ticket-4711"}));
+    }
+
+    #[tokio::test]
+    async fn request_id_is_generated_when_absent() {
+        let request = Request::builder()
+            .uri("/ok")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = service()
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let request_id = response.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(request_id).is_ok());
+
+        // Successful bodies are left alone
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+}
@@ -1,20 +1,31 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
 use std::time::{Duration, SystemTime};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm as Argon2Algorithm, Argon2, Params as Argon2Params, Version as Argon2Version,
+};
 use axum::{http, Json};
-use bcrypt::hash;
+use bcrypt::{hash, verify, HashParts};
 use http::{HeaderMap, StatusCode};
-use jsonwebtoken::{Algorithm, decode, DecodingKey, TokenData, Validation, errors::ErrorKind as JwtErrorKind, encode, Header, EncodingKey};
+use rand::{distributions::Alphanumeric, Rng};
+use jsonwebtoken::{Algorithm, decode, decode_header, DecodingKey, TokenData, Validation, errors::ErrorKind as JwtErrorKind, encode, Header, EncodingKey};
 use serde_json::{json, Value};
 use crate::{
-    common::{db::ConnectionPool, util::load_environment_variable},
+    common::{db::ConnectionPool, error::ApiError, logging::record_user, util::{load_environment_variable, load_flag_environment_variable, load_optional_environment_variable}},
     users::{
-        model::{Claims, User, UpsertUser, UserRole, string_to_user_role},
+        model::{Claims, User, UpsertUser, UserRole, password_strength_errors, string_to_user_role},
         service::service::UsersTable as UsersDB,
     },
 };
 
+const PASSWORD_HASH_COST: u32 = 12;
+
 pub fn hash_password(body: &mut UpsertUser) -> Result<(), (StatusCode, Json<Value>)> {
-    if let Ok(hashed_password) = hash(&body.password, 12) {
+    if let Ok(hashed_password) = hash(&body.password, PASSWORD_HASH_COST) {
         body.password = hashed_password;
         Ok(())
     } else {
@@ -22,10 +33,333 @@ pub fn hash_password(body: &mut UpsertUser) -> Result<(), (StatusCode, Json<Valu
     }
 }
 
+// Bounds on the argon2 cost settings. Anything outside them is either too weak to be worth hashing with or slow
+// enough to make every login a denial of service
+const ARGON2_MEMORY_KIB_RANGE: RangeInclusive<u32> = 8..=1_048_576;
+const ARGON2_ITERATIONS_RANGE: RangeInclusive<u32> = 1..=16;
+const ARGON2_PARALLELISM_RANGE: RangeInclusive<u32> = 1..=16;
+
+static ARGON2_PARAMS: OnceLock<Argon2Params> = OnceLock::new();
+
+// Read once from ARGON2_MEMORY_KIB, ARGON2_ITERATIONS and ARGON2_PARALLELISM, see argon2_params_from
+pub fn argon2_params() -> &'static Argon2Params {
+    ARGON2_PARAMS.get_or_init(|| argon2_params_from(
+        load_optional_environment_variable("ARGON2_MEMORY_KIB").as_deref(),
+        load_optional_environment_variable("ARGON2_ITERATIONS").as_deref(),
+        load_optional_environment_variable("ARGON2_PARALLELISM").as_deref(),
+    ))
+}
+
+// Settings that are malformed or out of range fall back to the argon2 defaults with a warning rather than stopping
+// the server, as the defaults are always safe to hash with
+fn argon2_params_from(memory_kib: Option<&str>, iterations: Option<&str>, parallelism: Option<&str>) -> Argon2Params {
+    let memory_kib = argon2_setting("ARGON2_MEMORY_KIB", memory_kib, Argon2Params::DEFAULT_M_COST, ARGON2_MEMORY_KIB_RANGE);
+    let iterations = argon2_setting("ARGON2_ITERATIONS", iterations, Argon2Params::DEFAULT_T_COST, ARGON2_ITERATIONS_RANGE);
+    let parallelism = argon2_setting("ARGON2_PARALLELISM", parallelism, Argon2Params::DEFAULT_P_COST, ARGON2_PARALLELISM_RANGE);
+
+    // argon2 needs at least 8 KiB of memory per lane, which the ranges alone don't rule out
+    Argon2Params::new(memory_kib, iterations, parallelism, None).unwrap_or_else(|err| {
+        tracing::warn!("Invalid argon2 parameters ({}), falling back to the defaults", err);
+        Argon2Params::default()
+    })
+}
+
+fn argon2_setting(variable_name: &str, value: Option<&str>, default: u32, range: RangeInclusive<u32>) -> u32 {
+    let Some(value) = value else {
+        return default;
+    };
+
+    match value.trim().parse::<u32>() {
+        Ok(parsed) if range.contains(&parsed) => parsed,
+        _ => {
+            tracing::warn!(
+                "{} must be a whole number between {} and {}, got '{}'. Using the default of {}",
+                variable_name, range.start(), range.end(), value, default
+            );
+            default
+        }
+    }
+}
+
+// Changed passwords are hashed with argon2, while users who haven't changed theirs since keep their bcrypt hash
+pub fn hash_password_argon2(password: &str) -> Result<String, (StatusCode, Json<Value>)> {
+    hash_password_argon2_with_params(password, argon2_params())
+}
+
+// The parameters are stored in the hash itself, so hashes made before the cost settings changed still verify
+pub fn hash_password_argon2_with_params(password: &str, params: &Argon2Params) -> Result<String, (StatusCode, Json<Value>)> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    let argon2 = Argon2::new(Argon2Algorithm::Argon2id, Argon2Version::V0x13, params.clone());
+
+    match argon2.hash_password(password.as_bytes(), &salt) {
+        Ok(password_hash) => Ok(password_hash.to_string()),
+        Err(err) => {
+            eprintln!("Error hashing password: {:?}", err);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to hash password"}))))
+        }
+    }
+}
+
+const TEMPORARY_PASSWORD_LENGTH: usize = 16;
+
+// Handed out once by an admin reset. Drawn again in the rare case it lacks a letter or a digit
+pub fn generate_temporary_password() -> String {
+    loop {
+        let password: String = rand::rngs::OsRng
+            .sample_iter(&Alphanumeric)
+            .take(TEMPORARY_PASSWORD_LENGTH)
+            .map(char::from)
+            .collect();
+
+        if password_strength_errors(&password).is_empty() {
+            return password;
+        }
+    }
+}
+
+// Tells the two hash formats apart by their prefix, both are compared in constant time by their crates
+fn verify_hash(password: &str, password_hash: &str) -> bool {
+    if password_hash.starts_with("$argon2") {
+        PasswordHash::new(password_hash)
+            .map(|parsed_hash| Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok())
+            .unwrap_or(false)
+    } else {
+        verify(password, password_hash).unwrap_or(false)
+    }
+}
+
+// Whether a hash was made with other costs than we hash with now - argon2 hashes whose memory, iterations or
+// parallelism differ from the configured ones, or bcrypt hashes of another cost. Hashes that can't be read are left
+// alone, as they don't verify anyway
+pub fn needs_rehash(password_hash: &str) -> bool {
+    needs_rehash_with(password_hash, argon2_params(), PASSWORD_HASH_COST)
+}
+
+fn needs_rehash_with(password_hash: &str, params: &Argon2Params, bcrypt_cost: u32) -> bool {
+    if password_hash.starts_with("$argon2") {
+        let Ok(parsed_hash) = PasswordHash::new(password_hash) else {
+            return false;
+        };
+
+        parsed_hash.algorithm != Argon2Algorithm::Argon2id.ident()
+            || parsed_hash.version != Some(Argon2Version::V0x13.into())
+            || Argon2Params::try_from(&parsed_hash).is_ok_and(|hashed_with| {
+                (hashed_with.m_cost(), hashed_with.t_cost(), hashed_with.p_cost()) != (params.m_cost(), params.t_cost(), params.p_cost())
+            })
+    } else {
+        password_hash.parse::<HashParts>().is_ok_and(|parts| parts.get_cost() != bcrypt_cost)
+    }
+}
+
+// Hashes the password again with the current costs, keeping the algorithm the old hash was made with
+pub fn rehash_password(password: &str, password_hash: &str) -> Result<String, (StatusCode, Json<Value>)> {
+    if password_hash.starts_with("$argon2") {
+        return hash_password_argon2(password);
+    }
+
+    hash(password, PASSWORD_HASH_COST)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to hash password"}))))
+}
+
+static UNKNOWN_USER_HASH: OnceLock<String> = OnceLock::new();
+
+// Checks the password against the user's hash. Without a user it is checked against a hash nobody has, so a missing
+// account takes as long to refuse as a wrong password and response times don't reveal which emails are registered
+pub fn verify_password(password: &str, user: Option<&User>) -> bool {
+    match user {
+        Some(user) => verify_hash(password, &user.password),
+        None => {
+            let unknown_user_hash = UNKNOWN_USER_HASH.get_or_init(|| {
+                hash("NoAccountHasThisPassword", PASSWORD_HASH_COST).expect("Failed to hash password")
+            });
+
+            verify_hash(password, unknown_user_hash);
+            false
+        }
+    }
+}
+
+// Regular tokens expire in 1 hour unless JWT_TTL_SECONDS says otherwise, while impersonation tokens only last 15 minutes
+const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(3600);
+pub const IMPERSONATION_TOKEN_TTL: Duration = Duration::from_secs(900);
+
+const DEFAULT_JWT_ISSUER: &str = "axum_api_with_auth";
+const DEFAULT_JWT_AUDIENCE: &str = "axum_api_with_auth";
+const DEFAULT_JWT_KEY_ID: &str = "default";
+
+// Signing and verification keys for the configured JWT algorithm, along with the expected issuer and audience.
+// New tokens are signed with the active key and name it in their 'kid' header. Keys that were rotated out stay
+// in decoding_keys under their own ids, so the tokens they signed keep working until they expire
+#[derive(Clone)]
+pub struct JwtConfig {
+    pub algorithm: Algorithm,
+    pub issuer: String,
+    pub audience: String,
+    pub ttl: Duration,
+    pub key_id: String,
+    encoding_key: EncodingKey,
+    decoding_keys: HashMap<String, DecodingKey>,
+}
+
+impl JwtConfig {
+    pub fn hs256(secret: &[u8]) -> JwtConfig {
+        JwtConfig::new(Algorithm::HS256, EncodingKey::from_secret(secret), DecodingKey::from_secret(secret))
+    }
+
+    pub fn rs256(private_key_pem: &[u8], public_key_pem: &[u8]) -> Result<JwtConfig, jsonwebtoken::errors::Error> {
+        Ok(JwtConfig::new(Algorithm::RS256, EncodingKey::from_rsa_pem(private_key_pem)?, DecodingKey::from_rsa_pem(public_key_pem)?))
+    }
+
+    fn new(algorithm: Algorithm, encoding_key: EncodingKey, decoding_key: DecodingKey) -> JwtConfig {
+        JwtConfig {
+            algorithm,
+            issuer: DEFAULT_JWT_ISSUER.to_string(),
+            audience: DEFAULT_JWT_AUDIENCE.to_string(),
+            ttl: DEFAULT_TOKEN_TTL,
+            key_id: DEFAULT_JWT_KEY_ID.to_string(),
+            encoding_key,
+            decoding_keys: HashMap::from([(DEFAULT_JWT_KEY_ID.to_string(), decoding_key)]),
+        }
+    }
+
+    pub fn rs256_from_files(private_key_path: &str, public_key_path: &str) -> JwtConfig {
+        let private_key = fs::read(private_key_path)
+            .unwrap_or_else(|err| panic!("Failed to read JWT private key at {}: {}", private_key_path, err));
+        let public_key = fs::read(public_key_path)
+            .unwrap_or_else(|err| panic!("Failed to read JWT public key at {}: {}", public_key_path, err));
+
+        JwtConfig::rs256(&private_key, &public_key).expect("JWT keys must be PEM encoded RSA keys")
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> JwtConfig {
+        self.issuer = issuer.to_string();
+        self
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> JwtConfig {
+        self.audience = audience.to_string();
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> JwtConfig {
+        self.ttl = ttl;
+        self
+    }
+
+    // Renames the active key, which is verified under the new id from then on
+    pub fn with_key_id(mut self, key_id: &str) -> JwtConfig {
+        let decoding_key = self.decoding_keys.remove(&self.key_id).expect("The active JWT key has no verification key");
+        self.decoding_keys.insert(key_id.to_string(), decoding_key);
+        self.key_id = key_id.to_string();
+        self
+    }
+
+    // A key no longer signing anything whose tokens are still accepted. It can't take the active key's id
+    pub fn with_previous_key(mut self, key_id: &str, decoding_key: DecodingKey) -> JwtConfig {
+        assert!(!self.decoding_keys.contains_key(key_id), "JWT key id '{}' is used more than once", key_id);
+        self.decoding_keys.insert(key_id.to_string(), decoding_key);
+        self
+    }
+
+    // The key a token names in its 'kid' header. Tokens issued before keys had ids name none and are checked
+    // against the active key
+    fn decoding_key_for(&self, key_id: Option<&str>) -> Option<&DecodingKey> {
+        self.decoding_keys.get(key_id.unwrap_or(&self.key_id))
+    }
+
+    // JWT_PREVIOUS_KEYS lists the rotated out keys as comma separated 'kid=key' pairs, where the key is the old
+    // secret for HS256 or the path to the old public key for RS256
+    fn with_previous_keys_from(self, previous_keys: &str) -> JwtConfig {
+        previous_keys.split(',').map(str::trim).filter(|pair| !pair.is_empty()).fold(self, |config, pair| {
+            let (key_id, key) = pair.split_once('=')
+                .map(|(key_id, key)| (key_id.trim(), key.trim()))
+                .filter(|(key_id, key)| !key_id.is_empty() && !key.is_empty())
+                .unwrap_or_else(|| panic!("JWT_PREVIOUS_KEYS must be a comma separated list of 'kid=key' pairs, got '{}'", pair));
+
+            let decoding_key = match config.algorithm {
+                Algorithm::RS256 => {
+                    let public_key = fs::read(key)
+                        .unwrap_or_else(|err| panic!("Failed to read JWT public key '{}' at {}: {}", key_id, key, err));
+                    DecodingKey::from_rsa_pem(&public_key).expect("JWT keys must be PEM encoded RSA keys")
+                }
+                _ => DecodingKey::from_secret(key.as_bytes()),
+            };
+
+            config.with_previous_key(key_id, decoding_key)
+        })
+    }
+
+    // JWT_ALG selects the algorithm - HS256 (default) signs with ENCRYPTION_KEY, while RS256 signs with the
+    // private key at JWT_PRIVATE_KEY_PATH and verifies with the public key at JWT_PUBLIC_KEY_PATH
+    pub fn from_env() -> JwtConfig {
+        let algorithm = load_optional_environment_variable("JWT_ALG").unwrap_or_else(|| "HS256".to_string());
+
+        let config = match algorithm.to_uppercase().as_str() {
+            "HS256" => JwtConfig::hs256(load_environment_variable("ENCRYPTION_KEY").as_ref()),
+            "RS256" => JwtConfig::rs256_from_files(
+                &load_environment_variable("JWT_PRIVATE_KEY_PATH"),
+                &load_environment_variable("JWT_PUBLIC_KEY_PATH"),
+            ),
+            other => panic!("Unsupported JWT_ALG '{}', expected HS256 or RS256", other),
+        };
+
+        // JWT_ISSUER and JWT_AUDIENCE identify who issues our tokens and which service they are meant for
+        config
+            .with_issuer(&load_optional_environment_variable("JWT_ISSUER").unwrap_or_else(|| DEFAULT_JWT_ISSUER.to_string()))
+            .with_audience(&load_optional_environment_variable("JWT_AUDIENCE").unwrap_or_else(|| DEFAULT_JWT_AUDIENCE.to_string()))
+            .with_ttl(token_ttl(load_optional_environment_variable("JWT_TTL_SECONDS").as_deref()))
+            .with_key_id(&load_optional_environment_variable("JWT_KEY_ID").unwrap_or_else(|| DEFAULT_JWT_KEY_ID.to_string()))
+            .with_previous_keys_from(&load_optional_environment_variable("JWT_PREVIOUS_KEYS").unwrap_or_default())
+    }
+}
+
+// JWT_TTL_SECONDS - how long a token issued at login stays valid
+pub fn token_ttl(seconds: Option<&str>) -> Duration {
+    match seconds {
+        Some(seconds) => parse_token_ttl(seconds)
+            .unwrap_or_else(|| panic!("JWT_TTL_SECONDS must be a positive whole number of seconds, got '{}'", seconds)),
+        None => DEFAULT_TOKEN_TTL,
+    }
+}
+
+// A token that expires the moment it is issued is never what anyone meant, so 0 is refused along with garbage
+fn parse_token_ttl(seconds: &str) -> Option<Duration> {
+    seconds.trim().parse::<u64>().ok()
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+static JWT_CONFIG: OnceLock<JwtConfig> = OnceLock::new();
+
+// Configuration is loaded once on first use rather than reading env and key files on every request
+pub fn jwt_config() -> &'static JwtConfig {
+    JWT_CONFIG.get_or_init(JwtConfig::from_env)
+}
+
+// Logins issue their tokens with the TTL from Config, this one is for tests and scripts
+#[allow(dead_code)]
 pub fn generate_token(user: &User) -> Result<String, jsonwebtoken::errors::Error> {
+    generate_token_with_config(user, jwt_config())
+}
+
+pub fn generate_token_with_config(user: &User, config: &JwtConfig) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_token(user, config, config.ttl, None)
+}
+
+// Signed like any other token, but valid for the given time rather than the configured TTL
+pub fn generate_token_with_ttl(user: &User, ttl: Duration) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_token(user, jwt_config(), ttl, None)
+}
+
+// Impersonation tokens are short-lived and carry the email of the admin who is acting on the user's behalf
+pub fn generate_impersonation_token(user: &User, impersonated_by: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_token(user, jwt_config(), IMPERSONATION_TOKEN_TTL, Some(impersonated_by.to_string()))
+}
+
+fn encode_token(user: &User, config: &JwtConfig, ttl: Duration, impersonated_by: Option<String>) -> Result<String, jsonwebtoken::errors::Error> {
     let role = string_to_user_role(user.clone().role);
     let expiration = SystemTime::now()
-        .checked_add(Duration::from_secs(3600)) // Set the token to expire in 1 hour
+        .checked_add(ttl)
         .expect("Failed to calculate token expiration")
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("SystemTime before UNIX EPOCH")
@@ -35,13 +369,67 @@ pub fn generate_token(user: &User) -> Result<String, jsonwebtoken::errors::Error
         sub: user.email.clone(),
         role: role.clone(),
         exp: expiration,
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
+        impersonated_by,
+        must_change_password: user.must_change_password,
     };
 
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(load_environment_variable("ENCRYPTION_KEY").as_ref()))
+    let header = Header { kid: Some(config.key_id.clone()), ..Header::new(config.algorithm) };
+
+    encode(&header, &claims, &config.encoding_key)
+}
+
+static AUTH_DISABLED: OnceLock<bool> = OnceLock::new();
+
+// Reads DISABLE_AUTH, which lets every request through as an ADMIN so local development needs no tokens.
+// Only debug builds look at it - release builds always enforce auth, whatever the environment says
+#[cfg(debug_assertions)]
+pub fn auth_disabled() -> bool {
+    *AUTH_DISABLED.get_or_init(|| load_flag_environment_variable("DISABLE_AUTH", false))
+}
+
+#[cfg(not(debug_assertions))]
+pub fn auth_disabled() -> bool {
+    *AUTH_DISABLED.get_or_init(|| {
+        if load_flag_environment_variable("DISABLE_AUTH", false) {
+            eprintln!("DISABLE_AUTH is ignored in release builds, auth is enforced");
+        }
+        false
+    })
+}
+
+// Called at startup so a server running without auth can't go unnoticed
+pub fn warn_if_auth_disabled() {
+    if auth_disabled() {
+        eprintln!("**************************************************************");
+        eprintln!("* WARNING: DISABLE_AUTH is set, every request is served as    *");
+        eprintln!("* an ADMIN without checking tokens. Never use this outside of *");
+        eprintln!("* local development.                                          *");
+        eprintln!("**************************************************************");
+    }
+}
+
+// The user every request acts as while auth is disabled. It exists nowhere in the database
+fn auth_disabled_user() -> User {
+    User {
+        id: 0,
+        email: "auth.disabled@localhost".to_string(),
+        password: String::new(),
+        fullname: "Auth Disabled".to_string(),
+        role: UserRole::ADMIN.to_string(),
+        email_verified: true,
+        must_change_password: false
+    }
 }
 
 pub fn decode_claims(headers: &HeaderMap) -> Result<Option<TokenData<Claims>>, (StatusCode, Json<Value>)> {
 
+    // With auth disabled there may be no token at all, and enforce_role_policy doesn't need one
+    if auth_disabled() {
+        return Ok(None);
+    }
+
     // Retrieve Authorization header from the map of request headers
     let token_header = headers.get("Authorization");
 
@@ -49,28 +437,73 @@ pub fn decode_claims(headers: &HeaderMap) -> Result<Option<TokenData<Claims>>, (
     let token = match token_header {
         None => {
             return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "Missing header"})),
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "missing authorization header"})),
             ));
         }
-        Some(header) => header.to_str().unwrap(),
+        Some(header) => match header.to_str() {
+            Ok(token) => token,
+            Err(_) => {
+                eprintln!("Authorization header contains non-visible ASCII characters");
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    Json(json!({"error": "malformed authorization header"})),
+                ));
+            }
+        },
     };
 
     // Return error if the the token does not start with "Bearer"
     if !token.starts_with("Bearer ") {
         eprintln!("Token is missing 'Bearer ' prefix");
         return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "Token is missing 'Bearer ' prefix"})),
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "malformed authorization header"})),
+        ));
+    }
+
+    // A bare "Bearer " would otherwise surface as a confusing JWT parse error
+    let token = token[7..].trim();
+    if token.is_empty() {
+        eprintln!("Bearer token is empty");
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "auth_malformed", "message": "Bearer token is empty"})),
         ));
     }
 
+    decode_token(token, jwt_config()).map(Some)
+}
+
+// For requests that can't carry an Authorization header, like a browser opening a WebSocket, which pass the token
+// as a query param instead
+pub fn decode_query_token(token: Option<&str>) -> Result<Option<TokenData<Claims>>, (StatusCode, Json<Value>)> {
+    if auth_disabled() {
+        return Ok(None);
+    }
+
+    match token.map(str::trim).filter(|token| !token.is_empty()) {
+        Some(token) => decode_token(token, jwt_config()).map(Some),
+        None => Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "missing token"})))),
+    }
+}
+
+pub fn decode_token(token: &str, config: &JwtConfig) -> Result<TokenData<Claims>, (StatusCode, Json<Value>)> {
+
     // Attempt to decode token and match the results
-    match decode::<Claims>(
-        &token[7..],
-        &DecodingKey::from_secret(load_environment_variable("ENCRYPTION_KEY").as_ref()),
-        &Validation::new(Algorithm::HS256),
-    ) {
+    // Besides the signature and expiry, tokens must be issued by us and meant for this service
+    let mut validation = Validation::new(config.algorithm);
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.audience]);
+
+    // A token signed with a key we don't know can't be verified at all
+    let key_id = decode_header(token).ok().and_then(|header| header.kid);
+    let Some(decoding_key) = config.decoding_key_for(key_id.as_deref()) else {
+        eprintln!("JWT signed with unknown key id: {:?}", key_id);
+        return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "invalid token"}))));
+    };
+
+    match decode::<Claims>(token, decoding_key, &validation) {
         Err(err) => {
             match err.kind() {
                 // Handle the specific ExpiredSignature error
@@ -86,12 +519,12 @@ pub fn decode_claims(headers: &HeaderMap) -> Result<Option<TokenData<Claims>>, (
                     eprintln!("Error decoding JWT: {:?}", err);
                     Err((
                         StatusCode::UNAUTHORIZED,
-                        Json(json!({"error": "Invalid JWT"})),
+                        Json(json!({"error": "invalid token"})),
                     ))
                 }
             }
         }
-        Ok(decoded_claims) => Ok(Some(decoded_claims)),
+        Ok(decoded_claims) => Ok(decoded_claims),
     }
 }
 
@@ -100,13 +533,39 @@ pub async fn enforce_role_policy(
     claims: &Option<TokenData<Claims>>,
     required_role: UserRole,
 ) -> Result<Option<User>, (StatusCode, Json<Value>)> {
+    enforce_role_policy_unless_disabled(shared_state, claims, required_role, auth_disabled()).await
+}
+
+async fn enforce_role_policy_unless_disabled(
+    shared_state: &ConnectionPool,
+    claims: &Option<TokenData<Claims>>,
+    required_role: UserRole,
+    auth_disabled: bool,
+) -> Result<Option<User>, (StatusCode, Json<Value>)> {
+    if auth_disabled {
+        let user = auth_disabled_user();
+        record_user(&user);
+        return Ok(Some(user));
+    }
+
     let connection = shared_state.pool.get().expect("Failed to acquire connection from pool");
     let mut users = UsersDB::new(connection);
 
     match users.get_by_email(claims.clone().unwrap().claims.sub) {
+
+        // Tokens outlive the accounts they were issued for, which lose access once soft-deleted
+        Ok(None) => {
+            eprintln!("User in claims not found in DB");
+            Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "User in claims not found in DB"}))))
+        }
         Ok(user) => {
             let user_role = string_to_user_role(user.clone().unwrap().role);
 
+            // Known from here on, so even a refusal below is logged with who it refused
+            if let Some(user) = &user {
+                record_user(user);
+            }
+
             // Accessing this map under UserRole key will return a list of associated subset roles
             let role_hierarchy: HashMap<UserRole, Vec<UserRole>> = {
                 let mut hierarchy = HashMap::new();
@@ -117,13 +576,25 @@ pub async fn enforce_role_policy(
                 hierarchy
             };
 
+            // Users whose password was reset may read, but nothing more until they have picked a password of their own
+            if required_role != UserRole::READER && user.as_ref().map(|user| user.must_change_password).unwrap_or(false) {
+                eprintln!("Refused request requiring role '{}' until the password is changed", required_role);
+                return Err((StatusCode::FORBIDDEN, Json(json!({"error": "Password change required"}))));
+            }
+
             // Check if the list of UserRoles associated with HashMap retrieval under key '&user_role' contains the required role '&required_role'
             if role_hierarchy.get(&user_role).map(|roles| roles.contains(&required_role)).unwrap_or(false) {
                 eprintln!("Access granted: User role '{}' is a superset of or equal to required role '{}'", user_role, required_role);
+
+                // The effective role is honored, but the person actually behind the request is logged
+                if let Some(impersonated_by) = claims.as_ref().and_then(|claims| claims.claims.impersonated_by.as_ref()) {
+                    eprintln!("Request made by {} while impersonating {}", impersonated_by, user.as_ref().map(|user| user.email.as_str()).unwrap_or("unknown user"));
+                }
+
                 Ok(user)
             } else {
                 eprintln!("User role: {} does not match required role: {}", user_role, required_role);
-                Err((StatusCode::UNAUTHORIZED, Json(json!({"error": format!("Current role of {} does not have access to {}", user_role, required_role)}))))
+                Err(ApiError::forbidden(required_role, user_role).into())
             }
         }
         Err(err) => {
@@ -131,4 +602,347 @@ pub async fn enforce_role_policy(
             Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "User in claims not found in DB"}))))
         }
     }
-}
\ No newline at end of file
+}
+
+// Operations an impersonation token may never perform, whatever role the impersonated user has
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensitiveOperation {
+    ChangeRole,
+    ChangePassword,
+    Impersonate,
+}
+
+impl fmt::Display for SensitiveOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensitiveOperation::ChangeRole => write!(f, "change roles"),
+            SensitiveOperation::ChangePassword => write!(f, "change passwords"),
+            SensitiveOperation::Impersonate => write!(f, "impersonate"),
+        }
+    }
+}
+
+pub fn enforce_not_impersonating(
+    claims: &Option<TokenData<Claims>>,
+    operation: SensitiveOperation,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    match claims.as_ref().and_then(|claims| claims.claims.impersonated_by.as_ref()) {
+        Some(impersonated_by) => {
+            eprintln!("Refused attempt by {} to {} while impersonating {}", impersonated_by, operation, claims.as_ref().unwrap().claims.sub);
+            Err((StatusCode::FORBIDDEN, Json(json!({"error": format!("Impersonation tokens cannot be used to {}", operation)}))))
+        }
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue, StatusCode};
+    use serde_json::json;
+    use crate::{
+        common::{
+            security::{
+                argon2_params_from, decode_claims, decode_token, enforce_role_policy_unless_disabled, generate_token, generate_token_with_config,
+                hash_password, hash_password_argon2_with_params, jwt_config, needs_rehash_with, parse_token_ttl, verify_hash, JwtConfig
+            },
+            test_db::with_test_db
+        },
+        users::{model::{UpsertUser, User, UserRole}, service::service::UsersTable}
+    };
+
+    fn token_subject() -> User {
+        User {
+            id: 1,
+            email: "roundtrip@jwt.io".to_string(),
+            password: "NotUsedForTokens".to_string(),
+            fullname: "Round Tripper".to_string(),
+            role: "EDITOR".to_string(),
+            email_verified: true,
+            must_change_password: false
+        }
+    }
+
+    fn rs256_config() -> JwtConfig {
+        JwtConfig::rs256_from_files("keys/test/jwt_rs256_private.pem", "keys/test/jwt_rs256_public.pem")
+    }
+
+    #[test]
+    fn hs256_token_round_trips() {
+        let config = JwtConfig::hs256(b"SecretOnlyUsedInTests");
+
+        let token = generate_token_with_config(&token_subject(), &config).expect("Generate token failed");
+        let decoded = decode_token(&token, &config).expect("Decode token failed");
+
+        assert_eq!(decoded.header.alg, jsonwebtoken::Algorithm::HS256);
+        assert_eq!(decoded.claims.sub, "roundtrip@jwt.io");
+        assert_eq!(decoded.claims.role, UserRole::EDITOR);
+    }
+
+    #[test]
+    fn token_ttl_must_be_a_positive_number_of_seconds() {
+        assert_eq!(parse_token_ttl("7200"), Some(std::time::Duration::from_secs(7200)));
+        assert_eq!(parse_token_ttl("0"), None);
+        assert_eq!(parse_token_ttl("-60"), None);
+        assert_eq!(parse_token_ttl("an hour"), None);
+    }
+
+    #[test]
+    fn rs256_token_round_trips() {
+        let config = rs256_config();
+
+        let token = generate_token_with_config(&token_subject(), &config).expect("Generate token failed");
+        let decoded = decode_token(&token, &config).expect("Decode token failed");
+
+        assert_eq!(decoded.header.alg, jsonwebtoken::Algorithm::RS256);
+        assert_eq!(decoded.claims.sub, "roundtrip@jwt.io");
+        assert_eq!(decoded.claims.role, UserRole::EDITOR);
+    }
+
+    #[test]
+    fn hs256_token_is_rejected_by_rs256_config() {
+        let token = generate_token_with_config(&token_subject(), &JwtConfig::hs256(b"SecretOnlyUsedInTests"))
+            .expect("Generate token failed");
+
+        // The algorithm is pinned by the verifier, so a token signed with another algorithm must be refused
+        let result = decode_token(&token, &rs256_config());
+
+        assert_eq!(result.err().map(|(status, _)| status), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn token_with_matching_audience_and_issuer_is_accepted() {
+        let config = JwtConfig::hs256(b"SecretOnlyUsedInTests")
+            .with_issuer("gateway")
+            .with_audience("locations-service");
+
+        let token = generate_token_with_config(&token_subject(), &config).expect("Generate token failed");
+        let decoded = decode_token(&token, &config).expect("Decode token failed");
+
+        assert_eq!(decoded.claims.iss, "gateway");
+        assert_eq!(decoded.claims.aud, "locations-service");
+    }
+
+    #[test]
+    fn token_for_another_audience_returns_401() {
+        let issued_for_other_service = JwtConfig::hs256(b"SecretOnlyUsedInTests")
+            .with_audience("billing-service");
+        let this_service = JwtConfig::hs256(b"SecretOnlyUsedInTests")
+            .with_audience("locations-service");
+
+        let token = generate_token_with_config(&token_subject(), &issued_for_other_service).expect("Generate token failed");
+        let result = decode_token(&token, &this_service);
+
+        assert_eq!(result.err().map(|(status, _)| status), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn token_from_another_issuer_returns_401() {
+        let other_issuer = JwtConfig::hs256(b"SecretOnlyUsedInTests")
+            .with_issuer("someone-else");
+        let this_service = JwtConfig::hs256(b"SecretOnlyUsedInTests");
+
+        let token = generate_token_with_config(&token_subject(), &other_issuer).expect("Generate token failed");
+        let result = decode_token(&token, &this_service);
+
+        assert_eq!(result.err().map(|(status, _)| status), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn token_names_the_active_key_id() {
+        let config = JwtConfig::hs256(b"SecretOnlyUsedInTests").with_key_id("2024-b");
+
+        let token = generate_token_with_config(&token_subject(), &config).expect("Generate token failed");
+        let decoded = decode_token(&token, &config).expect("Decode token failed");
+
+        assert_eq!(decoded.header.kid.as_deref(), Some("2024-b"));
+    }
+
+    #[test]
+    fn token_signed_with_a_previous_key_is_still_accepted() {
+        let before_rotation = JwtConfig::hs256(b"OldSecretOnlyUsedInTests").with_key_id("2024-a");
+        let after_rotation = JwtConfig::hs256(b"NewSecretOnlyUsedInTests")
+            .with_key_id("2024-b")
+            .with_previous_keys_from("2024-a=OldSecretOnlyUsedInTests");
+
+        let old_token = generate_token_with_config(&token_subject(), &before_rotation).expect("Generate token failed");
+        let decoded = decode_token(&old_token, &after_rotation).expect("Decode token failed");
+        assert_eq!(decoded.claims.sub, "roundtrip@jwt.io");
+
+        let new_token = generate_token_with_config(&token_subject(), &after_rotation).expect("Generate token failed");
+        assert!(decode_token(&new_token, &after_rotation).is_ok());
+        assert!(decode_token(&new_token, &before_rotation).is_err());
+    }
+
+    #[test]
+    fn rs256_token_signed_with_a_previous_key_is_still_accepted() {
+        let before_rotation = rs256_config().with_key_id("old");
+        let after_rotation = rs256_config()
+            .with_key_id("new")
+            .with_previous_keys_from("old=keys/test/jwt_rs256_public.pem");
+
+        let old_token = generate_token_with_config(&token_subject(), &before_rotation).expect("Generate token failed");
+
+        assert!(decode_token(&old_token, &after_rotation).is_ok());
+    }
+
+    #[test]
+    fn token_with_an_unknown_key_id_returns_401() {
+        let retired = JwtConfig::hs256(b"SecretOnlyUsedInTests").with_key_id("2023");
+        let this_service = JwtConfig::hs256(b"SecretOnlyUsedInTests").with_key_id("2024-b");
+
+        let token = generate_token_with_config(&token_subject(), &retired).expect("Generate token failed");
+        let (status, body) = decode_token(&token, &this_service).expect_err("Expected an unknown key id to be refused");
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.0, json!({"error": "invalid token"}));
+    }
+
+    #[test]
+    fn token_claiming_a_previous_key_id_must_be_signed_with_that_key() {
+        let forged = JwtConfig::hs256(b"SomebodyElsesSecret").with_key_id("2024-a");
+        let this_service = JwtConfig::hs256(b"NewSecretOnlyUsedInTests")
+            .with_key_id("2024-b")
+            .with_previous_keys_from("2024-a=OldSecretOnlyUsedInTests");
+
+        let token = generate_token_with_config(&token_subject(), &forged).expect("Generate token failed");
+
+        assert_eq!(decode_token(&token, &this_service).err().map(|(status, _)| status), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    #[should_panic(expected = "JWT_PREVIOUS_KEYS")]
+    fn previous_keys_without_an_id_are_refused() {
+        JwtConfig::hs256(b"SecretOnlyUsedInTests").with_previous_keys_from("OldSecretOnlyUsedInTests");
+    }
+
+    #[test]
+    fn empty_bearer_token_returns_401_auth_malformed() {
+        for header in ["Bearer ", "Bearer    "] {
+            let mut headers = HeaderMap::new();
+            headers.insert("Authorization", HeaderValue::from_static(header));
+
+            let (status, body) = decode_claims(&headers).expect_err("Expected an empty bearer token to be refused");
+
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body.0, json!({"error": "auth_malformed", "message": "Bearer token is empty"}));
+        }
+    }
+
+    #[test]
+    fn missing_authorization_header_returns_401() {
+        let (status, body) = decode_claims(&HeaderMap::new()).expect_err("Expected a missing header to be refused");
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.0, json!({"error": "missing authorization header"}));
+    }
+
+    #[test]
+    fn authorization_header_without_bearer_prefix_returns_401() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_static("Basic dXNlcjpwYXNz"));
+
+        let (status, body) = decode_claims(&headers).expect_err("Expected a non-bearer header to be refused");
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.0, json!({"error": "malformed authorization header"}));
+    }
+
+    #[test]
+    fn unparseable_token_returns_401() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_static("Bearer definitely.not.ajwt"));
+
+        let (status, body) = decode_claims(&headers).expect_err("Expected a garbage token to be refused");
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.0, json!({"error": "invalid token"}));
+    }
+
+    #[test]
+    fn token_with_wrong_signature_returns_401() {
+        let signed_elsewhere = JwtConfig::hs256(b"SomebodyElsesSecret");
+        let token = generate_token_with_config(&token_subject(), &signed_elsewhere).expect("Generate token failed");
+
+        let (status, body) = decode_token(&token, &JwtConfig::hs256(b"SecretOnlyUsedInTests"))
+            .expect_err("Expected a token with a foreign signature to be refused");
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.0, json!({"error": "invalid token"}));
+    }
+
+    #[tokio::test]
+    async fn disabled_auth_short_circuits_role_policy_only_when_set() {
+        with_test_db(|connection_pool| async move {
+            let mut new_user = UpsertUser {
+                email: "leser@utvikling.no".to_string(),
+                password: "IkkeAdmin123".to_string(),
+                fullname: "Lokal Leser".to_string(),
+                role: UserRole::READER.to_string()
+            };
+            hash_password(&mut new_user).expect("Hash password failed");
+
+            let reader = {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                UsersTable::new(connection).create(new_user).expect("Create user failed")
+            };
+            let token = generate_token(&reader).expect("Generate token failed");
+            let claims = Some(decode_token(&token, jwt_config()).expect("Decode token failed"));
+
+            // Assert that a reader is refused an admin operation while auth is enforced
+            let (status, _) = enforce_role_policy_unless_disabled(&connection_pool, &claims, UserRole::ADMIN, false).await
+                .expect_err("Expected a reader to be refused");
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            // Assert that with auth disabled even a request without a token acts as an admin
+            let user = enforce_role_policy_unless_disabled(&connection_pool, &None, UserRole::ADMIN, true).await
+                .expect("Expected disabled auth to let the request through")
+                .expect("Expected the synthetic user");
+            assert_eq!(user.role, UserRole::ADMIN.to_string());
+        }).await;
+    }
+
+    #[test]
+    fn argon2_hashes_with_custom_params_and_still_verify() {
+        let params = argon2_params_from(Some("64"), Some("1"), Some("2"));
+        assert_eq!((params.m_cost(), params.t_cost(), params.p_cost()), (64, 1, 2));
+
+        let password_hash = hash_password_argon2_with_params("Custom123", &params).expect("Hash password failed");
+        assert!(password_hash.contains("m=64,t=1,p=2"));
+
+        assert!(verify_hash("Custom123", &password_hash));
+        assert!(!verify_hash("Custom124", &password_hash));
+    }
+
+    #[test]
+    fn hashes_made_with_other_costs_need_rehashing() {
+        let params = argon2_params_from(Some("64"), Some("1"), Some("2"));
+
+        let current_hash = hash_password_argon2_with_params("Custom123", &params).expect("Hash password failed");
+        assert!(!needs_rehash_with(&current_hash, &params, 4));
+
+        for (memory_kib, iterations, parallelism) in [("128", "1", "2"), ("64", "2", "2"), ("64", "1", "1")] {
+            let old_params = argon2_params_from(Some(memory_kib), Some(iterations), Some(parallelism));
+            let old_hash = hash_password_argon2_with_params("Custom123", &old_params).expect("Hash password failed");
+            assert!(needs_rehash_with(&old_hash, &params, 4), "{}", old_hash);
+        }
+
+        let bcrypt_hash = bcrypt::hash("Custom123", 4).expect("Hash password failed");
+        assert!(!needs_rehash_with(&bcrypt_hash, &params, 4));
+        assert!(needs_rehash_with(&bcrypt_hash, &params, 5));
+
+        assert!(!needs_rehash_with("$argon2id$v=19$m=many", &params, 4));
+        assert!(!needs_rehash_with("not a hash", &params, 4));
+    }
+
+    #[test]
+    fn malformed_or_out_of_range_argon2_settings_fall_back_to_defaults() {
+        let defaults = argon2::Params::default();
+
+        let params = argon2_params_from(Some("plenty"), Some("0"), Some("64"));
+        assert_eq!((params.m_cost(), params.t_cost(), params.p_cost()), (defaults.m_cost(), defaults.t_cost(), defaults.p_cost()));
+
+        // Each setting is in range on its own, but 8 KiB can't be shared between 4 lanes
+        let params = argon2_params_from(Some("8"), None, Some("4"));
+        assert_eq!(params.m_cost(), defaults.m_cost());
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use axum::{
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use tokio::signal;
+use crate::common::util::load_optional_environment_variable;
+
+const DEFAULT_GRACE_PERIOD_SECONDS: u64 = 30;
+
+// Reads SHUTDOWN_GRACE_PERIOD_SECONDS - the time in-flight requests are given to complete after a shutdown signal
+pub fn shutdown_grace_period() -> Duration {
+    let seconds = match load_optional_environment_variable("SHUTDOWN_GRACE_PERIOD_SECONDS") {
+        Some(seconds) => seconds.parse::<u64>()
+            .expect("SHUTDOWN_GRACE_PERIOD_SECONDS must be a whole number of seconds"),
+        None => DEFAULT_GRACE_PERIOD_SECONDS,
+    };
+
+    Duration::from_secs(seconds)
+}
+
+// Resolves once the process receives either SIGINT (Ctrl+C) or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT, shutting down"),
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down"),
+    }
+}
+
+// Counts requests currently being handled so shutdown can report how many were drained
+#[derive(Clone, Default)]
+pub struct InFlightRequests {
+    count: Arc<AtomicUsize>,
+}
+
+impl InFlightRequests {
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+// Decrements the counter on drop so requests that panic or get cancelled are still accounted for
+struct InFlightGuard(InFlightRequests);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+pub async fn track_in_flight<B>(
+    State(in_flight): State<InFlightRequests>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    in_flight.count.fetch_add(1, Ordering::SeqCst);
+    let _guard = InFlightGuard(in_flight);
+
+    next.run(request).await
+}
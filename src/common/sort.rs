@@ -0,0 +1,53 @@
+use crate::common::error::ApiError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+// Splits a 'sort' query param given as 'field' or 'field:direction' and checks the field against the resource's
+// allowlist. Only fields from the allowlist ever come back, so nothing the client sent reaches the query itself
+pub fn parse_sort(sort: &str, allowed_fields: &[&'static str]) -> Result<(&'static str, SortDirection), ApiError> {
+    let (field, direction) = match sort.split_once(':') {
+        Some((field, direction)) => (field, Some(direction)),
+        None => (sort, None),
+    };
+
+    let field = allowed_fields.iter().find(|allowed| **allowed == field).ok_or_else(|| ApiError::bad_request(
+        &format!("Unknown sort field '{}', expected one of {}", field, allowed_fields.join(", "))
+    ))?;
+
+    let direction = match direction {
+        None | Some("asc") => SortDirection::Asc,
+        Some("desc") => SortDirection::Desc,
+        Some(direction) => return Err(ApiError::bad_request(
+            &format!("Unknown sort direction '{}', expected 'asc' or 'desc'", direction)
+        )),
+    };
+
+    Ok((field, direction))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use crate::common::sort::{parse_sort, SortDirection};
+
+    const FIELDS: [&str; 2] = ["area", "created_at"];
+
+    #[test]
+    fn direction_defaults_to_ascending() {
+        assert_eq!(parse_sort("area", &FIELDS).unwrap(), ("area", SortDirection::Asc));
+        assert_eq!(parse_sort("area:asc", &FIELDS).unwrap(), ("area", SortDirection::Asc));
+        assert_eq!(parse_sort("created_at:desc", &FIELDS).unwrap(), ("created_at", SortDirection::Desc));
+    }
+
+    #[test]
+    fn unknown_field_or_direction_returns_400() {
+        for sort in ["password", "area; DROP TABLE locations", "area:sideways", "area:DESC", ":asc", ""] {
+            let err = parse_sort(sort, &FIELDS).expect_err("Expected the sort to be refused");
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "{}", sort);
+        }
+    }
+}
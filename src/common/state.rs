@@ -0,0 +1,35 @@
+use std::sync::Arc;
+use axum::extract::FromRef;
+use crate::{
+    common::{config::Config, db::ConnectionPool},
+    locations::changes::LocationChanges,
+};
+
+// Router state every route is built with. Handlers take it whole as State<AppState>, while the role extractors
+// only need the pool and get it through FromRef
+#[derive(Clone)]
+pub struct AppState {
+    pub connection_pool: ConnectionPool,
+    pub config: Arc<Config>,
+
+    // Stays quiet unless ENABLE_NOTIFY starts the listener feeding it
+    pub location_changes: LocationChanges,
+}
+
+impl AppState {
+    pub fn new(connection_pool: ConnectionPool, config: Config) -> AppState {
+        AppState { connection_pool, config: Arc::new(config), location_changes: LocationChanges::new() }
+    }
+
+    // State for a test's pool, with the settings read from the environment the tests run in
+    #[cfg(test)]
+    pub fn test(connection_pool: ConnectionPool) -> AppState {
+        AppState::new(connection_pool, Config::from_env())
+    }
+}
+
+impl FromRef<AppState> for ConnectionPool {
+    fn from_ref(state: &AppState) -> ConnectionPool {
+        state.connection_pool.clone()
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fs, future::Future, panic::{self, AssertUnwindSafe}, path::Path};
+use diesel::{connection::SimpleConnection, Connection, PgConnection};
+use futures_util::FutureExt;
+use uuid::Uuid;
+use crate::common::{
+    db::{create_shared_connection_pool, ConnectionPool},
+    util::load_environment_variable,
+};
+
+// Connections per isolated schema, enough for a test holding a connection while a handler takes another
+const TEST_POOL_SIZE: u32 = 4;
+
+// Runs the test against a schema of its own in TEST_DB, migrated from scratch and dropped afterwards, even when
+// the test fails. Tests using it see none of each other's rows, so they can run concurrently
+pub async fn with_test_db<F, Fut, T>(test: F) -> T
+where
+    F: FnOnce(ConnectionPool) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let database_url = load_environment_variable("TEST_DB");
+    let schema = format!("test_{}", Uuid::new_v4().simple());
+
+    let mut admin_connection = PgConnection::establish(&database_url)
+        .unwrap_or_else(|err| panic!("Failed to connect to TEST_DB: {}", err));
+    create_migrated_schema(&mut admin_connection, &schema);
+
+    let connection_pool = create_shared_connection_pool(schema_database_url(&database_url, &schema), TEST_POOL_SIZE);
+    let outcome = AssertUnwindSafe(test(connection_pool)).catch_unwind().await;
+
+    admin_connection.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema))
+        .unwrap_or_else(|err| panic!("Failed to drop test schema {}: {}", schema, err));
+
+    match outcome {
+        Ok(output) => output,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+// Applies the migrations' up.sql files in order, the same way diesel would, but inside the given schema
+fn create_migrated_schema(connection: &mut PgConnection, schema: &str) {
+    let migrations_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut migrations: Vec<_> = fs::read_dir(&migrations_dir)
+        .expect("Failed to read migrations directory")
+        .map(|entry| entry.expect("Failed to read migration").path())
+        .collect();
+    migrations.sort();
+
+    connection.batch_execute(&format!("CREATE SCHEMA {0}; SET search_path TO {0}", schema))
+        .unwrap_or_else(|err| panic!("Failed to create test schema {}: {}", schema, err));
+
+    for migration in migrations {
+        let up = fs::read_to_string(migration.join("up.sql"))
+            .unwrap_or_else(|err| panic!("Failed to read {}: {}", migration.display(), err));
+        connection.batch_execute(&up)
+            .unwrap_or_else(|err| panic!("Migration {} failed: {}", migration.display(), err));
+    }
+
+    connection.batch_execute("SET search_path TO DEFAULT").expect("Failed to reset search path");
+}
+
+// Every connection of the pool starts out with the schema as its search path, so unqualified table names resolve to it
+fn schema_database_url(database_url: &str, schema: &str) -> String {
+    let separator = if database_url.contains('?') { '&' } else { '?' };
+    format!("{}{}options=-csearch_path%3D{}", database_url, separator, schema)
+}
@@ -0,0 +1,166 @@
+use std::time::Duration;
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use crate::common::util::load_optional_environment_variable;
+
+const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 15;
+
+// Reads REQUEST_TIMEOUT_SECONDS - the time a request may take before it is answered with 504
+pub fn request_timeout() -> Duration {
+    let seconds = match load_optional_environment_variable("REQUEST_TIMEOUT_SECONDS") {
+        Some(seconds) => seconds.parse::<u64>()
+            .expect("REQUEST_TIMEOUT_SECONDS must be a whole number of seconds"),
+        None => DEFAULT_REQUEST_TIMEOUT_SECONDS,
+    };
+
+    Duration::from_secs(seconds)
+}
+
+// The request timeout can't cut a query short, so without a statement_timeout below it a slow query holds on to the
+// request, its worker thread and its connection for as long as it runs
+pub fn warn_if_statement_timeout_outlasts(request_timeout: Duration, statement_timeout: Option<Duration>) {
+    if statement_timeout.is_none_or(|statement_timeout| statement_timeout >= request_timeout) {
+        tracing::warn!(
+            "DB_STATEMENT_TIMEOUT_MS is unset or not below the request timeout of {}s, slow queries won't be answered with 504 in time",
+            request_timeout.as_secs()
+        );
+    }
+}
+
+// - - - - - - - - - - - [MIDDLEWARE] - - - - - - - - - - -
+
+// tower_http's TimeoutLayer answers with 408, which blames the client for what is a slow server, hence our own.
+// The timeout only fires while the handler is waiting at an await, where the handler is dropped along with any
+// pooled connection it holds. Handlers run their diesel queries synchronously though, blocking the worker until
+// the query returns, so a slow query is never interrupted here. Those are bounded by DB_STATEMENT_TIMEOUT_MS
+// instead - Postgres cancels the statement, which frees the connection and is answered with 504 by database_error
+pub async fn enforce_request_timeout<B>(
+    State(timeout): State<Duration>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            eprintln!("{} {} timed out after {}ms", method, path, timeout.as_millis());
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                Json(json!({"error": "Request timed out"})),
+            ).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use axum::{
+        body::Body,
+        extract::State,
+        http::{Request, StatusCode},
+        middleware,
+        routing::get,
+        Json, Router
+    };
+    use diesel::{sql_query, RunQueryDsl};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use crate::common::{
+        db::{create_shared_connection_pool, create_shared_connection_pool_with_config, ConnectionPool, PoolConfig},
+        error::database_error,
+        timeout::enforce_request_timeout,
+        util::load_environment_variable
+    };
+
+    // Holds a connection from the pool for far longer than the request is allowed to take, waiting at an await
+    async fn slow_handler(State(shared_state): State<ConnectionPool>) -> &'static str {
+        let _connection = shared_state.pool.get().expect("Failed to acquire connection from pool");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        "too late"
+    }
+
+    // Runs a slow query the way every handler does, synchronously and without ever yielding to the timeout
+    async fn slow_query_handler(State(shared_state): State<ConnectionPool>) -> Result<&'static str, (StatusCode, Json<Value>)> {
+        let mut connection = shared_state.pool.get().expect("Failed to acquire connection from pool");
+
+        match sql_query("SELECT pg_sleep(5)").execute(&mut connection) {
+            Ok(_) => Ok("too late"),
+            Err(err) => Err(database_error("Failed to read location", &err)),
+        }
+    }
+
+    fn service(connection_pool: ConnectionPool, timeout: Duration) -> Router {
+        Router::new()
+            .route("/slow", get(slow_handler))
+            .route("/slow-query", get(slow_query_handler))
+            .with_state(connection_pool)
+            .layer(middleware::from_fn_with_state(timeout, enforce_request_timeout))
+    }
+
+    #[tokio::test]
+    async fn awaiting_handler_returns_504_and_releases_its_connection() {
+        let database_url = load_environment_variable("TEST_DB");
+        let connection_pool = create_shared_connection_pool(database_url, 1);
+
+        let request = Request::builder()
+            .uri("/slow")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+
+        // Send the request through the service
+        let response = service(connection_pool.clone(), Duration::from_millis(100))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the response status is 504
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        // Assert that the only connection in the pool was handed back when the handler was abandoned
+        assert!(connection_pool.pool.get_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn blocking_query_returns_504_once_the_statement_timeout_cancels_it() {
+        let database_url = load_environment_variable("TEST_DB");
+        let connection_pool = create_shared_connection_pool_with_config(database_url, PoolConfig {
+            max_size: 1,
+            statement_timeout: Some(Duration::from_millis(200)),
+            ..PoolConfig::from_env()
+        });
+
+        let request = Request::builder()
+            .uri("/slow-query")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+
+        // The request timeout is far shorter than the query, but the blocked handler never gives it a chance to fire
+        let started = Instant::now();
+        let response = service(connection_pool.clone(), Duration::from_millis(50))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        // Assert that the database cut the query short, long before pg_sleep would have returned
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response_json, json!({"error": "Failed to read location: the database took too long to respond"}));
+
+        // Assert that the connection was handed back
+        assert!(connection_pool.pool.get_timeout(Duration::from_secs(1)).is_ok());
+    }
+}
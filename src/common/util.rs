@@ -1,4 +1,4 @@
-use std::env;
+use std::{any::type_name, env, net::{IpAddr, SocketAddr}, str::FromStr};
 use dotenvy::dotenv;
 
 pub fn load_environment_variable(variable_name: &str) -> String {
@@ -7,3 +7,116 @@ pub fn load_environment_variable(variable_name: &str) -> String {
         .expect(&format!("{} must be set", variable_name))
 }
 
+pub fn load_optional_environment_variable(variable_name: &str) -> Option<String> {
+    dotenv().ok();
+    env::var(variable_name).ok()
+}
+
+pub fn load_flag_environment_variable(variable_name: &str, default: bool) -> bool {
+    parse_flag(variable_name, load_optional_environment_variable(variable_name).as_deref(), default)
+}
+
+// The value of a flag, which must be either true or false when set, or the default when it isn't
+pub fn parse_flag(variable_name: &str, value: Option<&str>, default: bool) -> bool {
+    match value {
+        Some(value) => match value.to_lowercase().as_str() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => panic!("{} must be either true or false", variable_name),
+        },
+        None => default,
+    }
+}
+
+// Parses the value as a T, panicking with the variable name and the type it should have been when it can't be
+pub fn parse_environment_variable<T: FromStr>(variable_name: &str, value: &str) -> T {
+    value.trim().parse::<T>()
+        .unwrap_or_else(|_| panic!("{} must be a valid {}, got '{}'", variable_name, type_name::<T>(), value))
+}
+
+pub fn load_env_parsed<T: FromStr>(variable_name: &str, default: T) -> T {
+    load_env_optional(variable_name).unwrap_or(default)
+}
+
+// Every typed setting has a sensible default today, but settings without one should go through here
+#[allow(dead_code)]
+pub fn load_env_required<T: FromStr>(variable_name: &str) -> T {
+    parse_environment_variable(variable_name, &load_environment_variable(variable_name))
+}
+
+// For settings where being unset means something other than any single default value
+pub fn load_env_optional<T: FromStr>(variable_name: &str) -> Option<T> {
+    load_optional_environment_variable(variable_name).map(|value| parse_environment_variable(variable_name, &value))
+}
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: &str = "3000";
+
+// Reads HOST and PORT - the address the API listens on
+pub fn bind_address() -> SocketAddr {
+    let host = load_optional_environment_variable("HOST").unwrap_or_else(|| DEFAULT_HOST.to_string());
+    let port = load_optional_environment_variable("PORT").unwrap_or_else(|| DEFAULT_PORT.to_string());
+
+    parse_bind_address(&host, &port).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn parse_bind_address(host: &str, port: &str) -> Result<SocketAddr, String> {
+    let host = host.trim().parse::<IpAddr>()
+        .map_err(|_| format!("HOST must be an IP address such as 127.0.0.1 or 0.0.0.0, got '{}'", host))?;
+    let port = port.trim().parse::<u16>()
+        .map_err(|_| format!("PORT must be a whole number between 0 and 65535, got '{}'", port))?;
+
+    Ok(SocketAddr::new(host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, panic};
+    use crate::common::util::{load_env_optional, load_env_parsed, load_env_required, parse_bind_address};
+
+    #[test]
+    fn bind_address_is_built_from_host_and_port() {
+        assert_eq!(parse_bind_address("0.0.0.0", "8080").unwrap().to_string(), "0.0.0.0:8080");
+        assert_eq!(parse_bind_address("::1", "3000").unwrap().to_string(), "[::1]:3000");
+    }
+
+    #[test]
+    fn bind_address_with_invalid_port_is_refused() {
+        assert_eq!(parse_bind_address("127.0.0.1", "70000").unwrap_err(), "PORT must be a whole number between 0 and 65535, got '70000'");
+        assert!(parse_bind_address("127.0.0.1", "http").is_err());
+    }
+
+    #[test]
+    fn missing_variable_falls_back_to_default() {
+        env::remove_var("UTIL_TEST_MISSING_NUMBER");
+
+        assert_eq!(load_env_parsed::<u32>("UTIL_TEST_MISSING_NUMBER", 42), 42);
+        assert_eq!(load_env_optional::<u32>("UTIL_TEST_MISSING_NUMBER"), None);
+    }
+
+    #[test]
+    fn present_variable_is_parsed() {
+        env::set_var("UTIL_TEST_PRESENT_NUMBER", " 7 ");
+
+        assert_eq!(load_env_parsed::<u32>("UTIL_TEST_PRESENT_NUMBER", 42), 7);
+        assert_eq!(load_env_required::<u16>("UTIL_TEST_PRESENT_NUMBER"), 7);
+    }
+
+    #[test]
+    fn malformed_variable_panics_naming_the_variable_and_type() {
+        env::set_var("UTIL_TEST_MALFORMED_NUMBER", "lots");
+
+        let panic = panic::catch_unwind(|| load_env_parsed::<u32>("UTIL_TEST_MALFORMED_NUMBER", 42))
+            .expect_err("Expected a malformed value to panic");
+        let message = panic.downcast_ref::<String>().expect("Expected a formatted panic message");
+
+        assert_eq!(message, "UTIL_TEST_MALFORMED_NUMBER must be a valid u32, got 'lots'");
+    }
+
+    #[test]
+    fn missing_required_variable_panics() {
+        env::remove_var("UTIL_TEST_REQUIRED_NUMBER");
+
+        assert!(panic::catch_unwind(|| load_env_required::<u32>("UTIL_TEST_REQUIRED_NUMBER")).is_err());
+    }
+}
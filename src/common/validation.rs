@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+use crate::common::error::ApiError;
+
+// Every problem found with the input, keyed by the field it concerns
+pub type ValidationErrors = HashMap<String, Vec<String>>;
+
+// Implemented by request bodies, so every handler reports invalid input the same way
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+
+    // Answers with 422 and the errors by field when the input is invalid
+    fn validate_or_422(&self, message: &str) -> Result<(), ApiError> {
+        self.validate().map_err(|errors| ApiError::unprocessable(message, errors))
+    }
+}
+
+// Records a problem with the field, next to any found before it
+pub fn add_error(errors: &mut ValidationErrors, field: &str, message: String) {
+    errors.entry(field.to_string()).or_default().push(message);
+}
+
+pub fn into_result(errors: ValidationErrors) -> Result<(), ValidationErrors> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// The messages in field order, for responses listing them without their fields
+pub fn error_messages(errors: ValidationErrors) -> Vec<String> {
+    let mut fields: Vec<_> = errors.into_iter().collect();
+    fields.sort();
+    fields.into_iter().flat_map(|(_, messages)| messages).collect()
+}
@@ -0,0 +1,67 @@
+use axum::{response::IntoResponse, routing::get, Json, Router};
+use chrono::{DateTime, SecondsFormat};
+use serde_derive::Serialize;
+
+// What build is deployed, as stamped in by build.rs
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub built_at: String,
+}
+
+impl VersionInfo {
+    pub fn of_this_build() -> VersionInfo {
+        let built_at = env!("BUILT_AT_EPOCH_SECONDS").parse::<i64>().ok()
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+            .map(|built_at| built_at.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_else(|| "unknown".to_string());
+
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("GIT_SHA"),
+            built_at,
+        }
+    }
+}
+
+// - - - - - - - - - - - [ROUTES] - - - - - - - - - - -
+
+// Served without authentication, like the docs, as it tells nothing about the data
+pub fn version_route() -> Router {
+    Router::new()
+        .route("/version", get(version_handler))
+}
+
+// - - - - - - - - - - - [HANDLERS] - - - - - - - - - - -
+
+pub async fn version_handler() -> impl IntoResponse {
+    Json(VersionInfo::of_this_build())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{Request, StatusCode}};
+    use chrono::DateTime;
+    use tower::ServiceExt;
+    use crate::common::version::version_route;
+
+    #[tokio::test]
+    async fn get_version_returns_the_crate_version_without_a_token() {
+        let request = Request::builder()
+            .uri("/version")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = version_route().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(response_json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!response_json["git_sha"].as_str().unwrap().is_empty());
+        assert!(DateTime::parse_from_rfc3339(response_json["built_at"].as_str().unwrap()).is_ok());
+    }
+}
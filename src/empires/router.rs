@@ -5,32 +5,37 @@ pub mod router {
     };
     use http::HeaderMap;
     use crate::{
-        common::db::ConnectionPool,
+        common::state::AppState,
+        common::extract::JsonBody,
+        common::limits::{body_limit, max_body_bytes},
         empires::{
             service::service::EmpiresTable as empiresTable,
             model::UpsertEmpire
         },
         users::model::UserRole,
-        common::security::{enforce_role_policy, decode_claims}
+        common::security::{enforce_role_policy, decode_claims},
+        common::error::database_error
     };
 
     // - - - - - - - - - - - [ROUTES] - - - - - - - - - - -
 
-    pub fn empires_route(shared_connection_pool: ConnectionPool) -> Router {
+    pub fn empires_route(state: AppState) -> Router {
+        let max_body_bytes = max_body_bytes();
+
         Router::new()
-            .route("/empires", axum::routing::post(create_empire_handler))
+            .route("/empires", axum::routing::post(create_empire_handler).layer(body_limit(max_body_bytes)))
             .route("/empires/:empire_id", axum::routing::get(read_empire_handler))
-            .route("/empires/:empire_id", axum::routing::put(update_empire_handler))
+            .route("/empires/:empire_id", axum::routing::put(update_empire_handler).layer(body_limit(max_body_bytes)))
             .route("/empires/:empire_id", axum::routing::delete(delete_empire_handler))
-            .with_state(shared_connection_pool)
+            .with_state(state)
     }
 
     // - - - - - - - - - - - [HANDLERS] - - - - - - - - - - -
 
     pub async fn create_empire_handler(
         headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
-        Json(upsert_empire): Json<UpsertEmpire>,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        JsonBody(upsert_empire): JsonBody<UpsertEmpire>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
 
         // Decode claims from bearer token header
@@ -51,7 +56,7 @@ pub mod router {
                     Ok(new_empire) => Ok((StatusCode::CREATED, Json(new_empire))),
                     Err(err) => {
                         eprintln!("Error creating empire: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to create empire"}))))
+                        Err(database_error("Failed to create empire", &err))
                     }
                 }
             }
@@ -62,7 +67,7 @@ pub mod router {
 
     pub async fn read_empire_handler(
         headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
         path: extract::Path<(i32, )>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
         let (empire_id, ) = path.0;
@@ -91,7 +96,7 @@ pub mod router {
                     },
                     Err(err) => {
                         eprintln!("Error reading empire: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to read empire"}))))
+                        Err(database_error("Failed to read empire", &err))
                     }
                 }
             }
@@ -101,9 +106,9 @@ pub mod router {
 
     pub async fn update_empire_handler(
         headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
         path: extract::Path<(i32, )>,
-        Json(upsert_empire): Json<UpsertEmpire>,
+        JsonBody(upsert_empire): JsonBody<UpsertEmpire>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
         let (empire_id, ) = path.0;
 
@@ -128,7 +133,7 @@ pub mod router {
                     },
                     Err(err) => {
                         eprintln!("Error updating empire: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to update empire"}))))
+                        Err(database_error("Failed to update empire", &err))
                     }
                 }
             }
@@ -138,7 +143,7 @@ pub mod router {
 
     pub async fn delete_empire_handler(
         headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
         path: extract::Path<(i32, )>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
         let (empire_id, ) = path.0;
@@ -161,7 +166,7 @@ pub mod router {
                     Ok(_) => Ok((StatusCode::NO_CONTENT, ())),
                     Err(err) => {
                         eprintln!("Error deleting empire: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to delete empire"}))))
+                        Err(database_error("Failed to delete empire", &err))
                     }
                 }
             }
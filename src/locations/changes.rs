@@ -0,0 +1,205 @@
+use std::{
+    error::Error,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+use diesel::{sql_query, sql_types::Text, Connection, PgConnection, QueryResult, RunQueryDsl};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+pub const LOCATION_CHANGES_CHANNEL: &str = "location_changes";
+
+// Diesel can only check for notifications that already arrived, it can't wait for the next one
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Subscribers that fall further behind than this miss the oldest changes rather than holding up the others
+const CHANNEL_CAPACITY: usize = 1024;
+
+// Off unless ENABLE_NOTIFY turns it on at startup, as every NOTIFY briefly serializes the commits sending one
+static NOTIFY_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable_change_notifications() {
+    NOTIFY_ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn change_notifications_enabled() -> bool {
+    NOTIFY_ENABLED.load(Ordering::Relaxed)
+}
+
+// A location that was created, updated or deleted, as sent on the location_changes channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LocationChange {
+    pub id: i32,
+    pub action: String,
+}
+
+impl LocationChange {
+
+    // As pushed to clients of GET /locations/stream, with the action in the past tense
+    pub fn to_message(&self) -> Value {
+        let action = match self.action.as_str() {
+            "create" => "created",
+            "update" => "updated",
+            "delete" => "deleted",
+            other => other,
+        };
+
+        json!({"action": action, "id": self.id})
+    }
+}
+
+// Postgres only delivers the notification once the caller's transaction commits, so changes that are rolled back
+// are never announced
+pub fn notify_change(connection: &mut PgConnection, change: &LocationChange) -> QueryResult<()> {
+    let payload = serde_json::to_string(change).expect("Failed to serialize location change");
+
+    sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(LOCATION_CHANGES_CHANNEL)
+        .bind::<Text, _>(payload)
+        .execute(connection)
+        .map(|_| ())
+}
+
+// The in-process end of the channel, which any part of the server can subscribe to
+#[derive(Clone)]
+pub struct LocationChanges(broadcast::Sender<LocationChange>);
+
+impl LocationChanges {
+    pub fn new() -> LocationChanges {
+        LocationChanges(broadcast::channel(CHANNEL_CAPACITY).0)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LocationChange> {
+        self.0.subscribe()
+    }
+
+    // Nobody being subscribed is fine, the change simply has no one to tell
+    pub fn publish(&self, change: LocationChange) {
+        let _ = self.0.send(change);
+    }
+
+    #[cfg(test)]
+    pub fn subscriber_count(&self) -> usize {
+        self.0.receiver_count()
+    }
+}
+
+impl Default for LocationChanges {
+    fn default() -> LocationChanges {
+        LocationChanges::new()
+    }
+}
+
+// A connection of its own listening on location_changes, outside the pool so no request ever gets it
+pub struct ChangeListener {
+    connection: PgConnection,
+}
+
+impl ChangeListener {
+    pub fn connect(database_url: &str) -> Result<ChangeListener, Box<dyn Error + Send + Sync>> {
+        let mut connection = PgConnection::establish(database_url)?;
+        sql_query(format!("LISTEN {}", LOCATION_CHANGES_CHANNEL)).execute(&mut connection)?;
+
+        Ok(ChangeListener { connection })
+    }
+
+    // Hands the notifications that arrived since the last call to the subscribers, and returns how many there were.
+    // An error means the connection is gone
+    pub fn forward(&mut self, changes: &LocationChanges) -> QueryResult<usize> {
+        let mut forwarded = 0;
+
+        for notification in self.connection.notifications_iter() {
+            match serde_json::from_str::<LocationChange>(&notification?.payload) {
+                Ok(change) => {
+                    changes.publish(change);
+                    forwarded += 1;
+                }
+                Err(err) => tracing::warn!("Ignored malformed location change: {}", err),
+            }
+        }
+
+        Ok(forwarded)
+    }
+}
+
+// Runs on a thread of its own rather than the runtime's blocking pool, which shutdown would wait on forever. A lost
+// connection is reopened, and changes committed while it was gone are missed
+pub fn listen_for_location_changes(database_url: String, changes: LocationChanges) {
+    thread::Builder::new()
+        .name("location-changes".to_string())
+        .spawn(move || loop {
+            match ChangeListener::connect(&database_url) {
+                Ok(mut listener) => {
+                    tracing::info!("Listening for location changes");
+
+                    while listener.forward(&changes).is_ok() {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+
+                    tracing::warn!("Lost the connection listening for location changes, reconnecting");
+                }
+                Err(err) => tracing::warn!("Failed to listen for location changes: {}", err),
+            }
+
+            thread::sleep(RECONNECT_DELAY);
+        })
+        .expect("Failed to start the location changes listener");
+}
+
+// Logs every change that comes through, so they can be followed in the logs next to the requests making them
+pub async fn log_location_changes(mut subscriber: broadcast::Receiver<LocationChange>) {
+    loop {
+        match subscriber.recv().await {
+            Ok(change) => tracing::info!("Location {} changed: {}", change.id, change.action),
+            Err(RecvError::Lagged(missed)) => tracing::warn!("Missed {} location changes", missed),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+    use crate::{
+        common::{test_db::with_test_db, util::load_environment_variable},
+        locations::{
+            changes::{ChangeListener, LocationChange, LocationChanges},
+            model::UpsertLocation,
+            service::service::LocationsTable,
+        },
+    };
+
+    #[tokio::test]
+    async fn create_emits_a_change_with_the_new_id() {
+        with_test_db(|connection_pool| async move {
+            let changes = LocationChanges::new();
+            let mut subscriber = changes.subscribe();
+            let mut listener = ChangeListener::connect(&load_environment_variable("TEST_DB")).expect("Failed to listen");
+
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let created_location = LocationsTable::new(connection).notifying_changes(true).create(UpsertLocation {
+                star_system: "Varsling".to_string(),
+                area: "Kanalen".to_string(),
+            }).expect("Create location failed");
+
+            // Other tests may be announcing changes of their own on the same database
+            let expected = LocationChange { id: created_location.id, action: "create".to_string() };
+            for _ in 0..50 {
+                listener.forward(&changes).expect("Failed to forward changes");
+
+                while let Ok(change) = subscriber.try_recv() {
+                    if change == expected {
+                        return;
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(20));
+            }
+
+            panic!("Expected a change for location {}", created_location.id);
+        }).await;
+    }
+}
@@ -1,3 +1,4 @@
 pub mod router;
 pub mod service;
-pub mod model;
\ No newline at end of file
+pub mod model;
+pub mod changes;
@@ -1,18 +1,441 @@
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use serde_derive::{Serialize, Deserialize};
-use crate::schema::locations;
+use utoipa::{IntoParams, ToSchema};
+use crate::{
+    common::{
+        error::ApiError,
+        sort::{parse_sort, SortDirection},
+        validation::{add_error, error_messages, into_result, Validate, ValidationErrors},
+    },
+    schema::locations,
+    users::model::UserRole,
+};
 
-#[derive(Serialize, Debug, Clone, Queryable)]
+#[derive(Serialize, Debug, Clone, Queryable, ToSchema)]
 #[diesel(table_name = locations)]
 pub struct Location {
     pub id: i32,
     pub star_system: String,
     pub area: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+
+    // Left out rather than null for locations that have not been given coordinates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub z: Option<f64>,
+}
+
+// One change to a location, with the location as it was before and after it - 'before' is empty for a create and 'after' for a delete
+#[derive(Serialize, Debug, Clone, Queryable, ToSchema)]
+pub struct LocationAuditEntry {
+    pub id: i32,
+    pub location_id: i32,
+    pub action: String,
+    pub actor: String,
+    #[schema(value_type = Option<Object>)]
+    pub before: Option<serde_json::Value>,
+    #[schema(value_type = Option<Object>)]
+    pub after: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Insertable, Deserialize, Serialize)]
+// What an Idempotency-Key is remembered with - the body its first request was sent with and the location it created
+#[derive(Debug, Clone, Queryable)]
+pub struct IdempotencyKey {
+    pub request_body: String,
+    pub location_id: i32,
+}
+
+// Fields it doesn't have are refused rather than ignored, so a misspelt one doesn't go unnoticed
+#[derive(Debug, Clone, Insertable, Deserialize, Serialize, ToSchema)]
 #[diesel(table_name = locations)]
+#[serde(deny_unknown_fields)]
 pub struct UpsertLocation {
     pub star_system: String,
     pub area: String,
-}
\ No newline at end of file
+}
+
+// Only the fields that are present are updated, absent fields keep their current value
+#[derive(Debug, Clone, Default, AsChangeset, Deserialize, Serialize, ToSchema)]
+#[diesel(table_name = locations, treat_none_as_null = false)]
+pub struct PatchLocation {
+    pub star_system: Option<String>,
+    pub area: Option<String>,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct BulkDeleteLocations {
+    pub ids: Vec<i32>,
+}
+
+// 'star_system' is required, it is optional here so a missing filter is refused rather than deleting everything
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteLocationsQuery {
+    pub star_system: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListLocationsQuery {
+
+    // Parsed by the Pagination extractor as well, it is only read here to refuse it alongside a cursor
+    pub offset: Option<i64>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+    pub q: Option<String>,
+    pub star_system: Option<String>,
+    pub fields: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReadLocationQuery {
+    pub fields: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportLocationsQuery {
+    pub strict: Option<bool>,
+}
+
+// A line of an import that was not imported, numbered from 1 like an editor would
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ImportLineError {
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+    pub errors: Vec<ImportLineError>,
+}
+
+// 'action' is one of HISTORY_ACTIONS, anything else is refused rather than matching nothing
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LocationHistoryQuery {
+    pub action: Option<String>,
+}
+
+// The kinds of change recorded in a location's history, patches are recorded as updates
+pub const HISTORY_ACTIONS: [&str; 3] = ["create", "update", "delete"];
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportLocationsQuery {
+    pub since: Option<String>,
+    pub format: Option<String>,
+    pub q: Option<String>,
+}
+
+// 'return=representation' answers a delete with the deleted location instead of an empty 204, so clients can offer an undo
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteLocationQuery {
+    #[serde(rename = "return")]
+    pub return_preference: Option<String>,
+}
+
+// Every param is required, they are optional here so a missing one is answered like any other invalid value
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct NearbyLocationsQuery {
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+    pub radius: Option<f64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamLocationsQuery {
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AreaStatsQuery {
+    pub star_system: Option<String>,
+}
+
+// The number of distinct areas within a single star system
+#[derive(Serialize, Debug, Clone, PartialEq, Queryable, ToSchema)]
+pub struct AreaStats {
+    pub star_system: String,
+    pub distinct_areas: i64,
+}
+
+// 'limit' keeps only the star systems with the most locations, all of them are returned without it
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StarSystemCountsQuery {
+    pub limit: Option<i64>,
+}
+
+// The number of locations within a single star system
+#[derive(Serialize, Debug, Clone, PartialEq, Queryable, ToSchema)]
+pub struct StarSystemCount {
+    pub star_system: String,
+    pub count: i64,
+}
+
+// Constraints applied to the list endpoint - every constraint that is present must match
+#[derive(Debug, Clone, Default)]
+pub struct LocationFilter {
+    pub q: Option<String>,
+    pub star_system: Option<String>,
+}
+
+// The fields the read and list endpoints may be narrowed down to with 'fields'
+pub const LOCATION_FIELDS: [&str; 8] = ["id", "star_system", "area", "x", "y", "z", "created_at", "updated_at"];
+
+// The columns the list endpoint may be sorted by
+pub const LOCATION_SORT_FIELDS: [&str; 5] = ["id", "star_system", "area", "created_at", "updated_at"];
+
+// Orderings accepted by the list endpoint's 'sort' query param, given as 'field:direction'
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LocationSort {
+    #[default]
+    IdAsc,
+    IdDesc,
+    StarSystemAsc,
+    StarSystemDesc,
+    AreaAsc,
+    AreaDesc,
+    CreatedAtAsc,
+    CreatedAtDesc,
+    UpdatedAtAsc,
+    UpdatedAtDesc,
+}
+
+impl LocationSort {
+    pub fn from_query(sort: &str) -> Result<LocationSort, ApiError> {
+        let (field, direction) = parse_sort(sort, &LOCATION_SORT_FIELDS)?;
+
+        Ok(match (field, direction) {
+            ("id", SortDirection::Asc) => LocationSort::IdAsc,
+            ("id", SortDirection::Desc) => LocationSort::IdDesc,
+            ("star_system", SortDirection::Asc) => LocationSort::StarSystemAsc,
+            ("star_system", SortDirection::Desc) => LocationSort::StarSystemDesc,
+            ("area", SortDirection::Asc) => LocationSort::AreaAsc,
+            ("area", SortDirection::Desc) => LocationSort::AreaDesc,
+            ("created_at", SortDirection::Asc) => LocationSort::CreatedAtAsc,
+            ("created_at", SortDirection::Desc) => LocationSort::CreatedAtDesc,
+            ("updated_at", SortDirection::Asc) => LocationSort::UpdatedAtAsc,
+            ("updated_at", SortDirection::Desc) => LocationSort::UpdatedAtDesc,
+            (field, _) => unreachable!("Sort field '{}' is allowed but has no ordering", field),
+        })
+    }
+}
+
+// Matches the VARCHAR(100) columns of the locations table
+const MAX_FIELD_LENGTH: usize = 100;
+
+fn field_validation_error(field: &str, value: &str) -> Option<String> {
+    if value.trim().is_empty() {
+        Some(format!("Field '{}' must not be empty", field))
+    } else if value.chars().count() > MAX_FIELD_LENGTH {
+        Some(format!("Field '{}' must be at most {} characters", field, MAX_FIELD_LENGTH))
+    } else {
+        None
+    }
+}
+
+// The header row an import may start with, the same columns without the id that exports start with
+pub const IMPORT_HEADER: &str = "star_system,area";
+
+// Splits one CSV line into its fields, unquoting quoted ones (RFC 4180). Quoted fields can't span lines, as
+// imports are read line by line
+fn parse_csv_record(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        let mut field = String::new();
+
+        if chars.peek() == Some(&'"') {
+            chars.next();
+
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("Unterminated quoted field".to_string()),
+                }
+            }
+
+            if !matches!(chars.peek(), None | Some(',')) {
+                return Err("Unexpected character after quoted field".to_string());
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                field.push(c);
+            }
+        }
+
+        fields.push(field);
+
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+// Reads a 'star_system,area' line of an import, held to the same rules as a location created through the API
+pub fn parse_import_line(line: &str) -> Result<UpsertLocation, String> {
+    let fields = parse_csv_record(line.strip_suffix('\r').unwrap_or(line))?;
+
+    let [star_system, area]: [String; 2] = fields.try_into()
+        .map_err(|fields: Vec<String>| format!("Expected 2 columns, got {}", fields.len()))?;
+
+    let upsert_location = UpsertLocation { star_system, area };
+    upsert_location.validate().map_err(|errors| error_messages(errors).join("; "))?;
+
+    Ok(upsert_location)
+}
+
+impl Location {
+
+    // Changes whenever the location does, as every update bumps updated_at
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.updated_at.timestamp_micros())
+    }
+
+    // The location as the role may see it. When it was created and last changed is audit information,
+    // which READERs are left without
+    pub fn to_response(&self, role: &UserRole) -> serde_json::Value {
+        let mut response = serde_json::to_value(self).expect("Location serializes to JSON");
+
+        if matches!(role, UserRole::READER | UserRole::INVALID) {
+            if let Some(fields) = response.as_object_mut() {
+                fields.remove("created_at");
+                fields.remove("updated_at");
+            }
+        }
+
+        response
+    }
+}
+
+impl Validate for UpsertLocation {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+
+        for (field, value) in [("star_system", &self.star_system), ("area", &self.area)] {
+            if let Some(message) = field_validation_error(field, value) {
+                add_error(&mut errors, field, message);
+            }
+        }
+
+        into_result(errors)
+    }
+}
+
+impl PatchLocation {
+    pub fn is_empty(&self) -> bool {
+        self.star_system.is_none() && self.area.is_none() && self.x.is_none() && self.y.is_none() && self.z.is_none()
+    }
+
+}
+
+// Provided fields are held to the same rules as a full update
+impl Validate for PatchLocation {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+
+        for (field, value) in [("star_system", &self.star_system), ("area", &self.area)] {
+            if let Some(message) = value.as_ref().and_then(|value| field_validation_error(field, value)) {
+                add_error(&mut errors, field, message);
+            }
+        }
+
+        into_result(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::{
+        common::validation::Validate,
+        locations::model::{parse_import_line, Location, PatchLocation, UpsertLocation},
+        users::model::UserRole,
+    };
+
+    #[test]
+    fn upsert_location_lists_every_invalid_field() {
+        let valid = UpsertLocation { star_system: "Stanton".to_string(), area: "Crusader".to_string() };
+        assert_eq!(valid.validate(), Ok(()));
+
+        let invalid = UpsertLocation { star_system: " ".to_string(), area: "a".repeat(101) };
+        assert_eq!(invalid.validate(), Err(HashMap::from([
+            ("star_system".to_string(), vec!["Field 'star_system' must not be empty".to_string()]),
+            ("area".to_string(), vec!["Field 'area' must be at most 100 characters".to_string()]),
+        ])));
+    }
+
+    #[test]
+    fn patch_location_validates_only_the_fields_provided() {
+        let only_area = PatchLocation { area: Some("Hurston".to_string()), ..Default::default() };
+        assert_eq!(only_area.validate(), Ok(()));
+
+        let empty_star_system = PatchLocation { star_system: Some(String::new()), ..Default::default() };
+        assert_eq!(empty_star_system.validate(), Err(HashMap::from([
+            ("star_system".to_string(), vec!["Field 'star_system' must not be empty".to_string()]),
+        ])));
+    }
+
+    #[test]
+    fn import_line_is_split_into_star_system_and_area() {
+        let location = parse_import_line("Stanton,\"Port Olisar, \"\"Old\"\"\"\r").unwrap();
+
+        assert_eq!(location.star_system, "Stanton");
+        assert_eq!(location.area, "Port Olisar, \"Old\"");
+    }
+
+    #[test]
+    fn malformed_import_lines_are_refused_with_a_reason() {
+        assert_eq!(parse_import_line("Stanton").unwrap_err(), "Expected 2 columns, got 1");
+        assert_eq!(parse_import_line("Stanton,Crusader,Extra").unwrap_err(), "Expected 2 columns, got 3");
+        assert_eq!(parse_import_line("Stanton,\"Crusader").unwrap_err(), "Unterminated quoted field");
+        assert_eq!(parse_import_line("Stanton,").unwrap_err(), "Field 'area' must not be empty");
+    }
+
+    #[test]
+    fn to_response_leaves_audit_fields_out_for_readers_only() {
+        let location = Location {
+            id: 7,
+            star_system: "Stanton".to_string(),
+            area: "Crusader".to_string(),
+            created_at: "2024-01-01T00:00:00Z".parse().unwrap(),
+            updated_at: "2024-02-01T00:00:00Z".parse().unwrap(),
+            x: None,
+            y: None,
+            z: None,
+        };
+
+        let reader_fields: Vec<String> = location.to_response(&UserRole::READER).as_object().unwrap().keys().cloned().collect();
+        assert_eq!(reader_fields, vec!["area", "id", "star_system"]);
+
+        for role in [UserRole::WRITER, UserRole::EDITOR, UserRole::ADMIN] {
+            assert_eq!(location.to_response(&role), serde_json::to_value(&location).unwrap());
+        }
+    }
+}
@@ -1,548 +1,3391 @@
 pub mod router {
+    use std::{collections::{HashMap, HashSet}, convert::Infallible, sync::Arc, time::Duration};
     use serde_json::{json, Value};
     use axum::{
-        Router, http::StatusCode, Json, response::IntoResponse, extract::State, extract,
+        Router, http::StatusCode, Json, response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}}, extract::State, extract, body::StreamBody, Extension,
+        extract::ws::{Message, WebSocket, WebSocketUpgrade},
     };
-    use http::HeaderMap;
+    use chrono::{DateTime, Utc};
+    use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+    use futures_util::{stream, StreamExt};
+    use tokio::sync::broadcast::{self, error::RecvError};
+    use http::{header, HeaderMap, HeaderValue, Uri};
     use crate::{
+        audit::{
+            model::{NewAuditEntry, ReadAudit},
+            service::service::AuditLogTable,
+        },
+        locations::changes::LocationChange,
         common::db::ConnectionPool,
+        common::state::AppState,
+        common::extract::{AuthedWriter, CsvBody, JsonBody},
+        common::fields::{parse_fields, select_fields},
+        common::limits::{body_limit, max_batch_body_bytes, max_body_bytes},
+        common::pagination::{decode_cursor, encode_cursor, pagination_links, Pagination, PaginationQuery},
+        common::validation::Validate,
         locations::{
-            service::service::LocationsTable as locationsDB,
-            model::UpsertLocation
+            service::service::{LocationsTable as locationsDB, Upserted},
+            model::{
+                parse_import_line, AreaStatsQuery, BulkDeleteLocations, DeleteLocationQuery, DeleteLocationsQuery, ExportLocationsQuery, ImportLineError,
+                ImportLocationsQuery, ImportSummary, ListLocationsQuery, Location, LocationFilter, LocationHistoryQuery, LocationSort,
+                NearbyLocationsQuery, PatchLocation, ReadLocationQuery, StarSystemCountsQuery, StreamLocationsQuery, UpsertLocation, HISTORY_ACTIONS, IMPORT_HEADER,
+                LOCATION_FIELDS
+            }
         },
-        users::model::UserRole,
-        common::security::{enforce_role_policy, decode_claims}
+        users::model::{string_to_user_role, User, UserRole},
+        common::security::{enforce_role_policy, decode_claims, decode_query_token},
+        common::error::{map_diesel_error, ApiError}
     };
 
+    const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+    // Keeps two locations from sharing both star system and area
+    const UNIQUE_LOCATION_CONSTRAINT: &str = "locations_star_system_area_key";
+    const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+    // Exports are read and streamed in pages of this many rows, so memory use doesn't grow with the catalog
+    const EXPORT_PAGE_SIZE: i64 = 500;
+
+    // Proxies tend to close connections that have been quiet for a minute or so
+    const EVENTS_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+    // Keeps a single bulk delete from locking a large part of the table
+    const MAX_BULK_DELETE_IDS: usize = 500;
+
+    // Imports are inserted this many rows per transaction, so a large file doesn't hold one long transaction
+    const IMPORT_BATCH_SIZE: usize = 500;
+
     // - - - - - - - - - - - [ROUTES] - - - - - - - - - - -
 
-    pub fn locations_route(shared_connection_pool: ConnectionPool) -> Router {
+    pub fn locations_route(state: AppState) -> Router {
+        locations_route_with_read_audit(state, ReadAudit::from_env())
+    }
+
+    pub fn locations_route_with_read_audit(state: AppState, read_audit: ReadAudit) -> Router {
+        let max_body_bytes = max_body_bytes();
+
         Router::new()
-            .route("/locations", axum::routing::post(create_location_handler))
+            .route("/locations", axum::routing::post(create_location_handler).layer(body_limit(max_body_bytes)))
+            .route("/locations", axum::routing::get(list_locations_handler))
+            .route("/locations", axum::routing::delete(delete_locations_by_filter_handler))
+            .route("/locations/export", axum::routing::get(export_locations_handler))
+            .route("/locations/area-stats", axum::routing::get(area_stats_handler))
+            .route("/locations/stats/by-star-system", axum::routing::get(star_system_counts_handler))
+            .route("/locations/nearby", axum::routing::get(nearby_locations_handler))
+            .route("/locations/stream", axum::routing::get(stream_location_changes_handler))
+            .route("/locations/events", axum::routing::get(location_events_handler))
+            .route("/locations/bulk-delete", axum::routing::post(bulk_delete_locations_handler).layer(body_limit(max_batch_body_bytes())))
+            .route("/locations/import", axum::routing::post(import_locations_handler).layer(body_limit(max_batch_body_bytes())))
+            .route("/locations/import/validate", axum::routing::post(validate_import_handler).layer(body_limit(max_batch_body_bytes())))
             .route("/locations/:location_id", axum::routing::get(read_location_handler))
-            .route("/locations/:location_id", axum::routing::put(update_location_handler))
+            .route("/locations/:location_id", axum::routing::put(update_location_handler).layer(body_limit(max_body_bytes)))
+            .route("/locations/:location_id", axum::routing::patch(patch_location_handler).layer(body_limit(max_body_bytes)))
             .route("/locations/:location_id", axum::routing::delete(delete_location_handler))
-            .with_state(shared_connection_pool)
+            .route("/locations/:location_id/history", axum::routing::get(location_history_handler))
+            .layer(Extension(read_audit))
+            .with_state(state)
     }
 
     // - - - - - - - - - - - [HANDLERS] - - - - - - - - - - -
 
-    pub async fn create_location_handler(
-        headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
-        Json(upsert_location): Json<UpsertLocation>,
-    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+    // Writes that would duplicate another location's star system and area are the client's conflict to resolve
+    fn location_write_error(message: &str, err: &diesel::result::Error) -> ApiError {
+        match err {
+            DatabaseError(DatabaseErrorKind::UniqueViolation, info) if info.constraint_name() == Some(UNIQUE_LOCATION_CONSTRAINT) => {
+                ApiError::conflict("location already exists")
+            }
+            _ => map_diesel_error("location", message, err).into(),
+        }
+    }
 
-        // Decode claims from bearer token header
-        let claims = match decode_claims(&headers) {
-            Ok(claims) => claims,
-            Err((status_code, json_value)) => return Err((status_code, json_value)),
-        };
+    // 201 pointing at the new location, so clients can follow it without reading the body
+    fn created(location: Location) -> (StatusCode, [(header::HeaderName, String); 1], Json<Location>) {
+        (StatusCode::CREATED, [(header::LOCATION, format!("/locations/{}", location.id))], Json(location))
+    }
 
-        // Ensure that the user derived from claims exists and has the role 'WRITER' or higher
-        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::WRITER).await;
+    // How locations read by the user are shaped - for their role, and down to the 'fields' they asked for if any
+    struct LocationView {
+        role: UserRole,
+        fields: Option<Vec<&'static str>>,
+    }
 
-        match authorization {
-            Ok(_authorized_user) => {
-                let connection = shared_state.pool.get()
-                    .expect("Failed to acquire connection from pool");
+    impl LocationView {
+        fn new(user: &Option<User>, fields: Option<&str>) -> Result<LocationView, ApiError> {
+            Ok(LocationView {
+                role: user.as_ref().map(|user| string_to_user_role(user.role.clone())).unwrap_or(UserRole::INVALID),
+                fields: fields.map(|fields| parse_fields(fields, &LOCATION_FIELDS)).transpose()?,
+            })
+        }
 
-                match locationsDB::new(connection).create(upsert_location) {
-                    Ok(new_location) => Ok((StatusCode::CREATED, Json(new_location))),
-                    Err(err) => {
-                        eprintln!("Error creating location: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to create location"}))))
-                    }
-                }
+        fn one(&self, location: &Location) -> Value {
+            match &self.fields {
+                Some(fields) => select_fields(location.to_response(&self.role), fields),
+                None => location.to_response(&self.role),
             }
-            Err(err) => Err(err)
+        }
+
+        fn many(&self, locations: &[Location]) -> Vec<Value> {
+            locations.iter().map(|location| self.one(location)).collect()
         }
     }
 
-    pub async fn read_location_handler(
+    #[utoipa::path(
+        post,
+        path = "/locations",
+        tag = "locations",
+        request_body = UpsertLocation,
+        params(("Idempotency-Key" = Option<String>, Header, description = "Retrying with the same key returns the originally created location")),
+        responses(
+            (status = 201, description = "Location created", body = Location, headers(("Location" = String, description = "URL of the created location"))),
+            (status = 400, description = "Malformed Idempotency-Key", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below WRITER", body = ErrorResponse),
+            (status = 409, description = "Idempotency-Key reused with a different body, or a location with the same star system and area exists", body = ErrorResponse),
+            (status = 413, description = "Body too large"),
+            (status = 422, description = "Invalid location", body = ValidationErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn create_location_handler(
         headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
-        path: extract::Path<(i32, )>,
-    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-        let (location_id, ) = path.0;
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        AuthedWriter(authorized_user): AuthedWriter,
+        JsonBody(upsert_location): JsonBody<UpsertLocation>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        upsert_location.validate_or_422("Invalid location")?;
+        let idempotency_key = idempotency_key(&headers)?;
 
-        // Decode claims from bearer token header
-        let claims = match decode_claims(&headers) {
-            Ok(claims) => claims,
-            Err((status_code, json_value)) => return Err((status_code, json_value)),
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+        let mut locations = locationsDB::new(connection).acting_as(&authorized_user.email);
+
+        let Some(idempotency_key) = idempotency_key else {
+            return match locations.create(upsert_location) {
+                Ok(new_location) => Ok(created(new_location)),
+                Err(err) => {
+                    eprintln!("Error creating location: {:?}", err);
+                    Err(location_write_error("Failed to create location", &err))
+                }
+            };
         };
 
-        // Ensure that the user derived from claims exists and has the role 'READER' or higher
-        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::READER).await;
+        // Bodies are compared in their parsed form, so formatting differences don't count as a different request
+        let request_body = serde_json::to_string(&upsert_location)
+            .expect("Failed to serialize location");
 
-        match authorization {
-            Ok(_authorized_user) => {
-                let connection = shared_state.pool.get()
-                    .expect("Failed to acquire connection from pool");
+        if let Some(location) = replay_idempotent_create(&mut locations, authorized_user.id, &idempotency_key, &request_body)? {
+            return Ok(created(location));
+        }
 
-                match locationsDB::new(connection).get(location_id) {
-                    Ok(location) => {
-                        if let Some(location) = location {
-                            Ok((StatusCode::OK, Json(location)))
-                        } else {
-                            Err((StatusCode::NOT_FOUND, Json(json!({"error": "Location not found"}))))
-                        }
-                    },
-                    Err(err) => {
-                        eprintln!("Error reading location: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to read location"}))))
-                    }
+        match locations.create_with_idempotency_key(upsert_location, authorized_user.id, &idempotency_key, &request_body) {
+            Ok(new_location) => Ok(created(new_location)),
+
+            // A concurrent request with the same key got there first, so answer with its result
+            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, info)) if info.constraint_name() != Some(UNIQUE_LOCATION_CONSTRAINT) => {
+                match replay_idempotent_create(&mut locations, authorized_user.id, &idempotency_key, &request_body)? {
+                    Some(location) => Ok(created(location)),
+                    None => Err(ApiError::conflict("A request with this Idempotency-Key is already being processed")),
                 }
             }
-            Err(err) => Err(err)
+            Err(err) => {
+                eprintln!("Error creating location: {:?}", err);
+                Err(location_write_error("Failed to create location", &err))
+            }
         }
     }
 
-    pub async fn update_location_handler(
+    #[utoipa::path(
+        post,
+        path = "/locations/bulk-delete",
+        tag = "locations",
+        request_body = BulkDeleteLocations,
+        responses(
+            (status = 200, description = "The number of locations deleted as 'deleted', ids that don't exist are skipped", body = Object),
+            (status = 401, description = "Missing or invalid token, or a role below EDITOR", body = ErrorResponse),
+            (status = 413, description = "More ids than allowed in one request", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn bulk_delete_locations_handler(
         headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
-        path: extract::Path<(i32, )>,
-        Json(upsert_location): Json<UpsertLocation>,
-    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-        let (location_id, ) = path.0;
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        JsonBody(bulk_delete): JsonBody<BulkDeleteLocations>,
+    ) -> Result<impl IntoResponse, ApiError> {
 
         // Decode claims from bearer token header
-        let claims = match decode_claims(&headers) {
-            Ok(claims) => claims,
-            Err((status_code, json_value)) => return Err((status_code, json_value)),
-        };
+        let claims = decode_claims(&headers)?;
 
         // Ensure that the user derived from claims exists and has the role 'EDITOR' or higher
         let authorization = enforce_role_policy(&shared_state, &claims, UserRole::EDITOR).await;
 
         match authorization {
-            Ok(_authorized_user) => {
+            Ok(authorized_user) => {
+                if bulk_delete.ids.len() > MAX_BULK_DELETE_IDS {
+                    return Err(ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        &format!("At most {} ids can be deleted at once", MAX_BULK_DELETE_IDS)
+                    ));
+                }
+
                 let connection = shared_state.pool.get()
                     .expect("Failed to acquire connection from pool");
 
-                match locationsDB::new(connection).update(location_id, upsert_location) {
-                    Ok(updated_location) => Ok((StatusCode::OK, Json(updated_location))),
-                    Err(diesel::result::Error::NotFound) => {
-                        Err((StatusCode::NOT_FOUND, Json(json!({"error": "Location not found"}))))
-                    },
+                match locationsDB::new(connection).acting_as(&actor_email(authorized_user)).delete_many(&bulk_delete.ids) {
+                    Ok(deleted) => Ok((StatusCode::OK, Json(json!({"deleted": deleted})))),
                     Err(err) => {
-                        eprintln!("Error updating location: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to update location"}))))
+                        eprintln!("Error deleting locations: {:?}", err);
+                        Err(map_diesel_error("location", "Failed to delete locations", &err).into())
                     }
                 }
             }
-            Err(err) => Err(err)
+            Err(err) => Err(err.into())
         }
     }
 
-    pub async fn delete_location_handler(
+    #[utoipa::path(
+        delete,
+        path = "/locations",
+        tag = "locations",
+        params(
+            DeleteLocationsQuery
+        ),
+        responses(
+            (status = 200, description = "The number of locations in the star system that were deleted as 'deleted'", body = Object),
+            (status = 400, description = "No 'star_system' to filter by", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn delete_locations_by_filter_handler(
         headers: HeaderMap,
-        State(shared_state): State<ConnectionPool>,
-        path: extract::Path<(i32, )>,
-    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-        let (location_id, ) = path.0;
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        extract::Query(query): extract::Query<DeleteLocationsQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
 
         // Decode claims from bearer token header
-        let claims = match decode_claims(&headers) {
-            Ok(claims) => claims,
-            Err((status_code, json_value)) => return Err((status_code, json_value)),
-        };
+        let claims = decode_claims(&headers)?;
 
         // Ensure that the user derived from claims exists and has the role 'ADMIN'
-        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await;
+        let authorized_user = enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await?;
 
-        match authorization {
-            Ok(_authorized_user) => {
-                let connection = shared_state.pool.get()
-                    .expect("Failed to acquire connection from pool");
+        // A forgotten or blank filter would otherwise wipe the whole catalog
+        let star_system = query.star_system.filter(|star_system| !star_system.trim().is_empty())
+            .ok_or_else(|| ApiError::bad_request("A 'star_system' filter is required to delete locations"))?;
 
-                match locationsDB::new(connection).delete(location_id) {
-                    Ok(_) => Ok((StatusCode::NO_CONTENT, ())),
-                    Err(err) => {
-                        eprintln!("Error deleting location: {:?}", err);
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to delete location"}))))
-                    }
-                }
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        match locationsDB::new(connection).acting_as(&actor_email(authorized_user)).delete_by_star_system(&star_system) {
+            Ok(deleted) => Ok((StatusCode::OK, Json(json!({"deleted": deleted})))),
+            Err(err) => {
+                eprintln!("Error deleting locations: {:?}", err);
+                Err(map_diesel_error("location", "Failed to delete locations", &err).into())
             }
-            Err(err) => Err(err)
         }
     }
 
-    #[cfg(test)]
-    mod tests {
-        use axum::{
-            body::Body,
-            http::{Request, StatusCode}
-        };
-        use serde_json::json;
-        use tower::ServiceExt;
-        use crate::{
-            common::{
-                db::create_shared_connection_pool,
-                util::load_environment_variable,
-                security::hash_password
-            },
-            locations::{
-                model::UpsertLocation,
-                service::service::LocationsTable
-            },
-            users::{
-                model::UpsertUser,
-                service::service::UsersTable
-            },
-            locations_route
-        };
-        use crate::common::db::ConnectionPool;
-        use crate::common::security::generate_token;
-        use crate::users::model::UserRole;
+    #[utoipa::path(
+        post,
+        path = "/locations/import",
+        tag = "locations",
+        params(ImportLocationsQuery),
+        request_body(content = String, content_type = "text/csv", description = "Lines of 'star_system,area', optionally after a header row naming them"),
+        responses(
+            (status = 200, description = "How many lines were imported and why the others were skipped", body = ImportSummary),
+            (status = 401, description = "Missing or invalid token, or a role below WRITER", body = ErrorResponse),
+            (status = 409, description = "With 'strict=true', a line duplicates a location that exists", body = ErrorResponse),
+            (status = 413, description = "Body too large"),
+            (status = 415, description = "Body is not text/csv", body = ErrorResponse),
+            (status = 422, description = "With 'strict=true', the lines that are invalid. Nothing is imported", body = Object),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn import_locations_handler(
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        AuthedWriter(authorized_user): AuthedWriter,
+        extract::Query(query): extract::Query<ImportLocationsQuery>,
+        CsvBody(body): CsvBody,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let strict = query.strict.unwrap_or(false);
+        let (rows, mut errors) = parse_import_body(&body);
 
-        // Helper method utilized to create user with a specific role and return the associated bearer token in one line of code
-        pub fn create_user_and_generate_token(connection_pool: ConnectionPool, email: &str, user_role: UserRole) -> Result<String, jsonwebtoken::errors::Error> {
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+        let mut locations = locationsDB::new(connection).acting_as(&authorized_user.email);
 
-            // Only email and role are mutable as password and fullname has no constraints
-            let mut new_user = UpsertUser {
-                email: email.to_string(),
-                role: user_role.to_string(),
-                password: "StålGardinerFunkerFjell53".to_string(),
-                fullname: "Josef Stålhard".to_string()
-            };
+        // A strict import is all or nothing, in a single transaction
+        if strict {
+            if !errors.is_empty() {
+                return Err(ApiError {
+                    status: StatusCode::UNPROCESSABLE_ENTITY,
+                    body: json!({"error": "Import has invalid lines", "errors": errors}),
+                });
+            }
 
-            // Hash the password
-            hash_password(&mut new_user).expect("Hash failed");
+            let upsert_locations: Vec<UpsertLocation> = rows.into_iter().map(|(_, upsert_location)| upsert_location).collect();
 
-            // Perform the user creation
-            let create_user_result = {
-                let connection = connection_pool.pool.get().expect("Failed to get connection");
-                UsersTable::new(connection).create(new_user.clone())
+            return match locations.create_many(&upsert_locations, false) {
+                Ok(new_locations) => Ok((StatusCode::OK, Json(ImportSummary { imported: new_locations.len(), skipped: 0, errors }))),
+                Err(err) => {
+                    eprintln!("Error importing locations: {:?}", err);
+                    Err(location_write_error("Failed to import locations", &err))
+                }
             };
-
-            // Generate the bearer token
-            generate_token(&create_user_result.unwrap())
         }
 
-        #[tokio::test]
-        async fn post_locations_returns_201_for_authorized_user_with_write_access() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let service = locations_route(connection_pool.clone());
+        // Batches committed before a failing one stay imported, like the lines before a malformed one
+        let mut imported = 0;
+        for batch in rows.chunks(IMPORT_BATCH_SIZE) {
+            let upsert_locations: Vec<UpsertLocation> = batch.iter().map(|(_, upsert_location)| upsert_location.clone()).collect();
 
-            // Create user with role WRITER and generate associated bearer token
-            let bearer_token = create_user_and_generate_token(connection_pool, "stål.hard.russer@ugreit.ru", UserRole::WRITER);
-
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+            let new_locations = locations.create_many(&upsert_locations, true).map_err(|err| {
+                eprintln!("Error importing locations: {:?}", err);
+                ApiError::from(map_diesel_error("location", "Failed to import locations", &err))
+            })?;
 
-            // Create a request with the above data as payload
-            let request = Request::builder()
-                .uri("/locations")
-                .method("POST")
-                .header("content-type", "application/json")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::from(serde_json::to_string(&request_body).unwrap()))
-                .unwrap();
+            let created: HashSet<(&str, &str)> = new_locations.iter()
+                .map(|location| (location.star_system.as_str(), location.area.as_str()))
+                .collect();
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+            for (line_number, upsert_location) in batch {
+                if !created.contains(&(upsert_location.star_system.as_str(), upsert_location.area.as_str())) {
+                    errors.push(ImportLineError { line: *line_number, reason: "location already exists".to_string() });
+                }
+            }
 
-            // Assert that the response status is 201
-            assert_eq!(response.status(), StatusCode::CREATED);
+            imported += new_locations.len();
         }
 
-        #[tokio::test]
-        async fn post_locations_returns_401_for_unauthorized_user_without_write_access() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let service = locations_route(connection_pool.clone());
+        errors.sort_by_key(|error| error.line);
 
-            // Create user with role READER and generate associated bearer token
-            let bearer_token = create_user_and_generate_token(connection_pool, "myk.og.ekkel.russer@put.in", UserRole::READER);
+        Ok((StatusCode::OK, Json(ImportSummary { imported, skipped: errors.len(), errors })))
+    }
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+    #[utoipa::path(
+        post,
+        path = "/locations/import/validate",
+        tag = "locations",
+        request_body(content = String, content_type = "text/csv", description = "The file that would be sent to /locations/import"),
+        responses(
+            (status = 200, description = "Per line results, by line number, of what importing the file would do. Nothing is imported", body = Object),
+            (status = 401, description = "Missing or invalid token, or a role below WRITER", body = ErrorResponse),
+            (status = 413, description = "Body too large"),
+            (status = 415, description = "Body is not text/csv", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn validate_import_handler(
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        AuthedWriter(_authorized_user): AuthedWriter,
+        CsvBody(body): CsvBody,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let (rows, mut errors) = parse_import_body(&body);
 
-            // Create a request with the above data as payload
-            let request = Request::builder()
-                .uri("/locations")
-                .method("POST")
-                .header("content-type", "application/json")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::from(serde_json::to_string(&request_body).unwrap()))
-                .unwrap();
+        let upsert_locations: Vec<UpsertLocation> = rows.iter().map(|(_, upsert_location)| upsert_location.clone()).collect();
+        let existing = shared_state.with_retry(|connection| locationsDB::new(connection).existing(&upsert_locations)).await
+            .map_err(|err| {
+                eprintln!("Error validating import: {:?}", err);
+                ApiError::from(map_diesel_error("location", "Failed to validate import", &err))
+            })?;
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+        // The same lines the import itself would skip as already existing
+        let existing: HashSet<(&str, &str)> = existing.iter()
+            .map(|location| (location.star_system.as_str(), location.area.as_str()))
+            .collect();
 
-            // Assert that the response status is 401
-            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let mut results: Vec<(usize, Option<String>)> = errors.drain(..).map(|error| (error.line, Some(error.reason))).collect();
+        for (line_number, upsert_location) in &rows {
+            let reason = existing.contains(&(upsert_location.star_system.as_str(), upsert_location.area.as_str()))
+                .then(|| "location already exists".to_string());
+            results.push((*line_number, reason));
         }
+        results.sort_by_key(|(line_number, _)| *line_number);
 
-        #[tokio::test]
-        async fn put_locations_returns_200_for_authorized_user_with_edit_access() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
-            let service = locations_route(connection_pool.clone());
+        let valid = results.iter().filter(|(_, reason)| reason.is_none()).count();
+        let results: Vec<Value> = results.into_iter().map(|(line_number, reason)| match reason {
+            None => json!({"line": line_number, "valid": true}),
+            Some(reason) => json!({"line": line_number, "valid": false, "reason": reason}),
+        }).collect();
 
-            // Create user with role WRITER and generate associated bearer token
-            let bearer_token = create_user_and_generate_token(connection_pool, "dagfinnkuk@blåfjelletsvenner.no", UserRole::EDITOR);
+        Ok((StatusCode::OK, Json(json!({
+            "valid": valid,
+            "invalid": results.len() - valid,
+            "results": results
+        }))))
+    }
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+    // Splits an import into the lines that parse, numbered from 1, and the reasons the others don't. Blank lines and a
+    // header row on the first line are neither
+    fn parse_import_body(body: &str) -> (Vec<(usize, UpsertLocation)>, Vec<ImportLineError>) {
+        let mut rows: Vec<(usize, UpsertLocation)> = Vec::new();
+        let mut errors: Vec<ImportLineError> = Vec::new();
+        let mut first_lines: HashMap<(String, String), usize> = HashMap::new();
 
-            // Create a new location with the above data
-            let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+        for (index, line) in body.lines().enumerate() {
+            let line_number = index + 1;
 
-            // Assert equality
-            assert_eq!(request_body.star_system, created_location.star_system);
-            assert_eq!(request_body.area, created_location.area);
+            if line.trim().is_empty() || (line_number == 1 && line.trim().eq_ignore_ascii_case(IMPORT_HEADER)) {
+                continue;
+            }
 
-            let updated_request_body = UpsertLocation {
-                star_system: "Kador".to_string(),
-                area: "The Crimson Expanse".to_string(),
-            };
+            match parse_import_line(line) {
+                Ok(upsert_location) => {
+                    let key = (upsert_location.star_system.clone(), upsert_location.area.clone());
 
-            // Create a request with the above data as payload
-            let request = Request::builder()
-                .uri(format!("/locations/{}", created_location.id))
-                .method("PUT")
-                .header("content-type", "application/json")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::from(serde_json::to_string(&updated_request_body).unwrap()))
-                .unwrap();
+                    // Caught here, as the database would silently keep only one of them
+                    match first_lines.get(&key) {
+                        Some(first_line) => errors.push(ImportLineError { line: line_number, reason: format!("Duplicate of line {}", first_line) }),
+                        None => {
+                            first_lines.insert(key, line_number);
+                            rows.push((line_number, upsert_location));
+                        }
+                    }
+                }
+                Err(reason) => errors.push(ImportLineError { line: line_number, reason }),
+            }
+        }
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+        (rows, errors)
+    }
 
-            // Assert that the response status is 200
-            assert_eq!(response.status(), StatusCode::OK);
+    #[utoipa::path(
+        get,
+        path = "/locations",
+        tag = "locations",
+        params(ListLocationsQuery, ("limit" = Option<i64>, Query, description = "Items per page, 50 unless given and at most MAX_PAGE_SIZE")),
+        responses(
+            (status = 200, description = "A page of locations along with 'total', 'limit', 'offset' and 'next_cursor', and a 'Link' header to the neighbouring pages. Pages requested with a 'cursor' only carry 'limit' and 'next_cursor'. 'created_at' and 'updated_at' are left out for READERs, and items only include the given 'fields' when any are", body = Object),
+            (status = 400, description = "Invalid pagination, cursor, sort or fields", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below READER", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn list_locations_handler(
+        headers: HeaderMap,
+        uri: Uri,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        pagination: Pagination,
+        extract::Query(query): extract::Query<ListLocationsQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
 
-            // Extract body from response
-            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
 
-            // Construct JSON consisting of expected payload
-            let expected_response = json!({
-                "id": created_location.id,
-                "area": updated_request_body.area,
-                "star_system": updated_request_body.star_system
-            });
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::READER).await;
 
-            // Assert equality
-            assert_eq!(response_json, expected_response);
-        }
+        match authorization {
+            Ok(authorized_user) => {
+                let view = LocationView::new(&authorized_user, query.fields.as_deref())?;
+                let sort = match query.sort.as_deref() {
+                    None => LocationSort::default(),
+                    Some(sort) => LocationSort::from_query(sort)?,
+                };
 
-        #[tokio::test]
-        async fn put_locations_returns_401_for_unauthorized_user_without_edit_access() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
-            let service = locations_route(connection_pool.clone());
+                // 'q' searches both fields while 'star_system' must match exactly, and both apply when present
+                let filter = LocationFilter {
+                    q: query.q.filter(|q| !q.is_empty()),
+                    star_system: query.star_system,
+                };
 
-            // Create user with role WRITER and generate associated bearer token
-            let bearer_token = create_user_and_generate_token(connection_pool, "necromancer@gpf.no", UserRole::WRITER);
+                if let Some(cursor) = query.cursor {
+                    let connection = shared_state.pool.get()
+                        .expect("Failed to acquire connection from pool");
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+                    return list_locations_after_cursor(locationsDB::new(connection), &filter, &cursor, pagination.limit(), query.offset, sort, &view);
+                }
 
-            // Create a new location with the above data
-            let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+                let (limit, offset) = (pagination.limit(), pagination.offset());
 
-            // Assert equality
-            assert_eq!(request_body.star_system, created_location.star_system);
-            assert_eq!(request_body.area, created_location.area);
+                match shared_state.with_retry(|connection| locationsDB::new(connection).list(&filter, limit, offset, sort)).await {
+                    Ok((items, total)) => {
 
-            let updated_request_body = UpsertLocation {
-                star_system: "Kador".to_string(),
-                area: "The Crimson Expanse".to_string(),
-            };
+                        // Lets clients start out without a cursor and switch to cursors from the second page on
+                        let next_cursor = match items.last() {
+                            Some(last) if sort == LocationSort::IdAsc && offset + limit < total => Some(encode_cursor(last.id)),
+                            _ => None,
+                        };
 
-            // Create a request with the above data as payload
-            let request = Request::builder()
-                .uri(format!("/locations/{}", created_location.id))
-                .method("PUT")
-                .header("content-type", "application/json")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::from(serde_json::to_string(&updated_request_body).unwrap()))
-                .unwrap();
+                        Ok((StatusCode::OK, pagination_links(&uri, pagination, total), Json(json!({
+                            "items": view.many(&items),
+                            "total": total,
+                            "limit": limit,
+                            "offset": offset,
+                            "next_cursor": next_cursor
+                        }))))
+                    }
+                    Err(err) => {
+                        eprintln!("Error listing locations: {:?}", err);
+                        Err(map_diesel_error("location", "Failed to list locations", &err).into())
+                    }
+                }
+            }
+            Err(err) => Err(err.into())
+        }
+    }
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+    // Cursor pages are in id order and carry no total, as neither counting nor skipping rows scales with the table.
+    // 'next_cursor' is null on the last page
+    fn list_locations_after_cursor(
+        mut locations: locationsDB,
+        filter: &LocationFilter,
+        cursor: &str,
+        limit: i64,
+        offset: Option<i64>,
+        sort: LocationSort,
+        view: &LocationView,
+    ) -> Result<(StatusCode, HeaderMap, Json<Value>), ApiError> {
+        if offset.is_some() {
+            return Err(ApiError::bad_request("Query params 'cursor' and 'offset' can't be combined"));
+        }
 
-            // Assert that the response status is 401
-            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        if sort != LocationSort::IdAsc {
+            return Err(ApiError::bad_request("Query param 'cursor' can't be combined with 'sort'"));
         }
 
-        #[tokio::test]
-        async fn get_locations_returns_200_for_authorized_user_with_read_access() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
-            let service = locations_route(connection_pool.clone());
+        let after_id = decode_cursor(cursor)?;
 
-            let bearer_token = create_user_and_generate_token(connection_pool, "duvetdet@gjerrigknark.no", UserRole::READER);
+        // One row more than the page tells whether there is a next page without counting
+        match locations.list_after(filter, after_id, limit + 1) {
+            Ok(mut items) => {
+                let has_next_page = items.len() as i64 > limit;
+                items.truncate(limit as usize);
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+                let next_cursor = match items.last() {
+                    Some(last) if has_next_page => Some(encode_cursor(last.id)),
+                    _ => None,
+                };
 
-            // Create a new location with the above data
-            let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+                Ok((StatusCode::OK, HeaderMap::new(), Json(json!({
+                    "items": view.many(&items),
+                    "limit": limit,
+                    "next_cursor": next_cursor
+                }))))
+            }
+            Err(err) => {
+                eprintln!("Error listing locations: {:?}", err);
+                Err(map_diesel_error("location", "Failed to list locations", &err).into())
+            }
+        }
+    }
 
-            // Create a request with the ID associated with our newly inserted row
-            let request = Request::builder()
-                .uri(format!("/locations/{}", created_location.id))
-                .method("GET")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::empty())
-                .unwrap();
+    #[utoipa::path(
+        get,
+        path = "/locations/export",
+        tag = "locations",
+        params(ExportLocationsQuery),
+        responses(
+            (status = 200, description = "One location per line, as JSON or, with 'format=csv', as CSV after a header row", body = Location,
+                content_type = ["application/x-ndjson", "text/csv"]),
+            (status = 400, description = "Invalid 'since' or unsupported format", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below READER", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn export_locations_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        extract::Query(query): extract::Query<ExportLocationsQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::READER).await;
 
-            // Assert that the response status is 200
-            assert_eq!(response.status(), StatusCode::OK);
+        match authorization {
+            Ok(authorized_user) => {
 
-            // Extract body from response
-            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                // Lines are shaped for the user's role like every other read, so READERs don't see the audit fields
+                let view = Arc::new(LocationView::new(&authorized_user, None)?);
 
-            // Construct JSON consisting of expected payload
-            let expected_response = json!({
-                "id": created_location.id,
-                "area": request_body.area,
-                "star_system": request_body.star_system
-            });
+                // NDJSON stays the default, CSV is downloaded as a file for spreadsheets
+                let (content_type, header_row, line): (&str, Option<&str>, ExportLine) = match query.format.as_deref() {
+                    None | Some("ndjson") => ("application/x-ndjson", None, ndjson_line),
+                    Some("csv") => ("text/csv", Some("id,star_system,area\n"), csv_line),
+                    Some(format) => return Err(ApiError::bad_request(&format!("Unsupported format '{}', expected ndjson or csv", format))),
+                };
 
-            // Assert equality
-            assert_eq!(response_json, expected_response);
-        }
+                let since = match query.since.as_deref() {
+                    None => None,
+                    Some(since) => Some(DateTime::parse_from_rfc3339(since)
+                        .map(|since| since.with_timezone(&Utc))
+                        .map_err(|_| ApiError::bad_request("Query param 'since' must be an RFC 3339 timestamp"))?),
+                };
 
-        #[tokio::test]
-        async fn get_locations_returns_200_for_authorized_user_with_write_access() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
-            let service = locations_route(connection_pool.clone());
+                let filter = LocationFilter {
+                    q: query.q.filter(|q| !q.is_empty()),
+                    star_system: None,
+                };
 
-            let bearer_token = create_user_and_generate_token(connection_pool, "kokefaktura@woodworm.org", UserRole::WRITER);
+                let connection = shared_state.pool.get()
+                    .expect("Failed to acquire connection from pool");
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+                // One location per line, read a page at a time - the stream ends after the first empty page
+                let lines = stream::unfold(Some((locationsDB::new(connection), 0)), move |state| {
+                    let filter = filter.clone();
+                    let view = view.clone();
 
-            // Create a new location with the above data
-            let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+                    async move {
+                        let (mut locations, after_id) = state?;
 
-            // Create a request with the ID associated with our newly inserted row
-            let request = Request::builder()
-                .uri(format!("/locations/{}", created_location.id))
-                .method("GET")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::empty())
-                .unwrap();
+                        match locations.changed_since(&filter, since, after_id, EXPORT_PAGE_SIZE) {
+                            Ok(page) if page.is_empty() => None,
+                            Ok(page) => {
+                                let last_id = page[page.len() - 1].id;
+                                let chunk: String = page.iter().map(|location| line(&view, location)).collect();
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+                                Some((Ok(chunk), Some((locations, last_id))))
+                            }
 
-            // Assert that the response status is 200
-            assert_eq!(response.status(), StatusCode::OK);
+                            // Headers are already sent by now, so aborting the body is the only way to signal the failure
+                            Err(err) => {
+                                eprintln!("Error exporting locations: {:?}", err);
+                                Some((Err(err), None))
+                            }
+                        }
+                    }
+                });
 
-            // Extract body from response
-            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let body = stream::iter(header_row.map(|header_row| Ok(header_row.to_string()))).chain(lines);
 
-            // Construct JSON consisting of expected payload
-            let expected_response = json!({
-                "id": created_location.id,
-                "area": request_body.area,
-                "star_system": request_body.star_system
-            });
+                let mut response_headers = HeaderMap::new();
+                response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+                if header_row.is_some() {
+                    response_headers.insert(header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment; filename=\"locations.csv\""));
+                }
 
-            // Assert equality
-            assert_eq!(response_json, expected_response);
+                Ok((StatusCode::OK, response_headers, StreamBody::new(body)))
+            }
+            Err(err) => Err(err.into())
         }
+    }
 
-        #[tokio::test]
-        async fn get_locations_returns_401_for_unauthorized_user_without_read_access() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
-            let service = locations_route(connection_pool.clone());
+    // Renders one location as a line of the export
+    type ExportLine = fn(&LocationView, &Location) -> String;
+
+    fn ndjson_line(view: &LocationView, location: &Location) -> String {
+        format!("{}\n", view.one(location))
+    }
+
+    // Only the columns every role may see are exported as CSV, so the view has nothing to leave out
+    fn csv_line(_view: &LocationView, location: &Location) -> String {
+        format!("{},{},{}\n", location.id, csv_field(&location.star_system), csv_field(&location.area))
+    }
+
+    // Fields holding a separator, quote or line break are quoted, with their quotes doubled (RFC 4180)
+    fn csv_field(value: &str) -> String {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/locations/area-stats",
+        tag = "locations",
+        params(AreaStatsQuery),
+        responses(
+            (status = 200, description = "The number of distinct areas per star system, only the given one if 'star_system' is set", body = [AreaStats]),
+            (status = 401, description = "Missing or invalid token, or a role below READER", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn area_stats_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        extract::Query(query): extract::Query<AreaStatsQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::READER).await;
+
+        match authorization {
+            Ok(_authorized_user) => {
+                let connection = shared_state.pool.get()
+                    .expect("Failed to acquire connection from pool");
+
+                match locationsDB::new(connection).area_stats(query.star_system.as_deref()) {
+                    Ok(stats) => Ok((StatusCode::OK, Json(stats))),
+                    Err(err) => {
+                        eprintln!("Error counting areas: {:?}", err);
+                        Err(map_diesel_error("location", "Failed to count areas", &err).into())
+                    }
+                }
+            }
+            Err(err) => Err(err.into())
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/locations/stats/by-star-system",
+        tag = "locations",
+        params(StarSystemCountsQuery),
+        responses(
+            (status = 200, description = "The number of locations per star system, most first, and only the top 'limit' if given", body = [StarSystemCount]),
+            (status = 400, description = "'limit' is not a positive number", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below READER", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn star_system_counts_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        extract::Query(query): extract::Query<StarSystemCountsQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        enforce_role_policy(&shared_state, &claims, UserRole::READER).await?;
+
+        if query.limit.is_some_and(|limit| limit < 1) {
+            return Err(ApiError::bad_request("Query param 'limit' must be a positive number"));
+        }
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        match locationsDB::new(connection).count_by_star_system(query.limit) {
+            Ok(counts) => Ok((StatusCode::OK, Json(counts))),
+            Err(err) => {
+                eprintln!("Error counting locations per star system: {:?}", err);
+                Err(map_diesel_error("location", "Failed to count locations per star system", &err).into())
+            }
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/locations/nearby",
+        tag = "locations",
+        params(NearbyLocationsQuery),
+        responses(
+            (status = 200, description = "Locations within 'radius' of the point, nearest first and at most a page of them. 'created_at' and 'updated_at' are left out for READERs", body = [Location]),
+            (status = 400, description = "Missing coordinate, radius not above 0 or invalid limit", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below READER", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn nearby_locations_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        extract::Query(query): extract::Query<NearbyLocationsQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        let view = LocationView::new(&enforce_role_policy(&shared_state, &claims, UserRole::READER).await?, None)?;
+
+        let (Some(x), Some(y), Some(z), Some(radius)) = (query.x, query.y, query.z, query.radius) else {
+            return Err(ApiError::bad_request("Query params 'x', 'y', 'z' and 'radius' are required"));
+        };
+
+        // "NaN" and "inf" parse as floats, but no location is at or within them
+        if ![x, y, z, radius].iter().all(|value| value.is_finite()) {
+            return Err(ApiError::bad_request("Query params 'x', 'y', 'z' and 'radius' must be finite numbers"));
+        }
+
+        if radius <= 0.0 {
+            return Err(ApiError::bad_request("Query param 'radius' must be greater than 0"));
+        }
+
+        // Nearby results aren't paged, the page size only caps how many of them are returned
+        let limit = Pagination::new(query.limit, None)?.limit();
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        match locationsDB::new(connection).find_within_radius(x, y, z, radius, limit) {
+            Ok(locations) => Ok((StatusCode::OK, Json(view.many(&locations)))),
+            Err(err) => {
+                eprintln!("Error finding nearby locations: {:?}", err);
+                Err(map_diesel_error("location", "Failed to find nearby locations", &err).into())
+            }
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/locations/stream",
+        tag = "locations",
+        params(StreamLocationsQuery),
+        responses(
+            (status = 101, description = "Upgraded to a WebSocket pushing '{\"action\": \"created\", \"id\": 5}' for every location created, updated or deleted from then on"),
+            (status = 401, description = "Missing or invalid 'token', or a role below READER", body = ErrorResponse),
+            (status = 503, description = "Change notifications are disabled, see ENABLE_NOTIFY", body = ErrorResponse)
+        )
+    )]
+    pub async fn stream_location_changes_handler(
+        ws: WebSocketUpgrade,
+        State(AppState { connection_pool: shared_state, config, location_changes }): State<AppState>,
+        extract::Query(query): extract::Query<StreamLocationsQuery>,
+    ) -> Result<Response, ApiError> {
+
+        // Browsers can't set headers on a WebSocket handshake, so the token comes as a query param instead
+        let claims = decode_query_token(query.token.as_deref())?;
+
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        enforce_role_policy(&shared_state, &claims, UserRole::READER).await?;
+
+        // Without the listener nothing would ever be pushed, which a client couldn't tell from a quiet catalog
+        if !config.notify_changes {
+            return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Location change notifications are disabled"));
+        }
+
+        // Subscribed before the upgrade, so the client misses no change made once it is connected
+        let subscriber = location_changes.subscribe();
+
+        Ok(ws.on_upgrade(move |socket| push_location_changes(socket, subscriber)))
+    }
+
+    // Runs until either side hangs up. Returning drops the subscription, so a client that went away holds nothing
+    async fn push_location_changes(mut socket: WebSocket, mut subscriber: broadcast::Receiver<LocationChange>) {
+        loop {
+            tokio::select! {
+                change = subscriber.recv() => match change {
+                    Ok(change) => {
+                        if socket.send(Message::Text(change.to_message().to_string())).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => tracing::warn!("WebSocket client missed {} location changes", missed),
+                    Err(RecvError::Closed) => return,
+                },
+
+                // Pings are answered by axum, and there is nothing else for the client to say
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/locations/events",
+        tag = "locations",
+        params(StreamLocationsQuery),
+        responses(
+            (status = 200, description = "A 'text/event-stream' with a 'location' event, its data like '{\"action\": \"created\", \"id\": 5}', for every location created, updated or deleted from then on, and a keep-alive comment every 15 seconds", content_type = "text/event-stream"),
+            (status = 401, description = "Missing or invalid token, or a role below READER", body = ErrorResponse),
+            (status = 503, description = "Change notifications are disabled, see ENABLE_NOTIFY", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn location_events_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, config, location_changes }): State<AppState>,
+        extract::Query(query): extract::Query<StreamLocationsQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
+
+        // Browsers' EventSource can't set headers either, so the token may come as a query param like for the WebSocket
+        let claims = match query.token {
+            Some(token) if !headers.contains_key(header::AUTHORIZATION) => decode_query_token(Some(&token))?,
+            _ => decode_claims(&headers)?,
+        };
+
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        enforce_role_policy(&shared_state, &claims, UserRole::READER).await?;
+
+        if !config.notify_changes {
+            return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Location change notifications are disabled"));
+        }
+
+        // The subscription lives in the stream, which is dropped along with it when the client disconnects
+        let events = stream::unfold(location_changes.subscribe(), |mut subscriber| async move {
+            loop {
+                match subscriber.recv().await {
+                    Ok(change) => {
+                        let event = Event::default().event("location").data(change.to_message().to_string());
+                        return Some((Ok::<_, Infallible>(event), subscriber));
+                    }
+                    Err(RecvError::Lagged(missed)) => tracing::warn!("Event stream client missed {} location changes", missed),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Sse::new(events).keep_alive(KeepAlive::new().interval(EVENTS_KEEP_ALIVE_INTERVAL)))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/locations/{location_id}",
+        tag = "locations",
+        params(("location_id" = i32, Path, description = "Id of the location"), ReadLocationQuery),
+        responses(
+            (status = 200, description = "The location, with its current version in the 'ETag' header. 'created_at' and 'updated_at' are left out for READERs, and only the given 'fields' are included when any are", body = Location),
+            (status = 400, description = "Unknown field in 'fields'", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below READER", body = ErrorResponse),
+            (status = 404, description = "Location not found", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn read_location_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        Extension(read_audit): Extension<ReadAudit>,
+        path: extract::Path<(i32, )>,
+        extract::Query(query): extract::Query<ReadLocationQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let (location_id, ) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'READER' or higher
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::READER).await;
+
+        match authorization {
+            Ok(authorized_user) => {
+                let view = LocationView::new(&authorized_user, query.fields.as_deref())?;
+                // The connection is handed back before the read is audited, which needs one of its own
+                let location = shared_state.with_retry(|connection| locationsDB::new(connection).get(location_id)).await;
+
+                match location {
+                    Ok(location) => {
+                        if let Some(location) = location {
+                            if read_audit.0 {
+                                record_read(&shared_state, authorized_user, location.id);
+                            }
+
+                            Ok((StatusCode::OK, [(header::ETAG, location.etag())], Json(view.one(&location))))
+                        } else {
+                            Err(ApiError::not_found("location"))
+                        }
+                    },
+                    Err(err) => {
+                        eprintln!("Error reading location: {:?}", err);
+                        Err(map_diesel_error("location", "Failed to read location", &err).into())
+                    }
+                }
+            }
+            Err(err) => Err(err.into())
+        }
+    }
+
+    #[utoipa::path(
+        put,
+        path = "/locations/{location_id}",
+        tag = "locations",
+        params(("location_id" = i32, Path, description = "Id of the location, chosen by the client when creating")),
+        request_body = UpsertLocation,
+        responses(
+            (status = 200, description = "The replaced location", body = Location),
+            (status = 201, description = "No location had the id, so it was created with it", body = Location,
+                headers(("Location" = String, description = "URL of the created location"))),
+            (status = 400, description = "Id is not positive", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, a role below WRITER, or below EDITOR for a location that exists", body = ErrorResponse),
+            (status = 409, description = "Another location has the same star system and area, or the id of a missing location is past the last one handed out", body = ErrorResponse),
+            (status = 413, description = "Body too large"),
+            (status = 422, description = "Invalid location", body = ValidationErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn update_location_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32, )>,
+        JsonBody(upsert_location): JsonBody<UpsertLocation>,
+    ) -> Result<Response, ApiError> {
+        let (location_id, ) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Sync clients create locations with ids of their own through PUT, which like POST requires 'WRITER' or higher
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::WRITER).await;
+
+        match authorization {
+            Ok(authorized_user) => {
+                upsert_location.validate_or_422("Invalid location")?;
+
+                // Ids come from the sequence starting at 1, so nothing else can be told apart from them
+                if location_id < 1 {
+                    return Err(ApiError::bad_request("Location id must be a positive number"));
+                }
+
+                // Replacing a location that exists is an edit, which still takes 'EDITOR' or higher
+                let role = authorized_user.as_ref().map(|user| string_to_user_role(user.role.clone()));
+                let may_replace = matches!(role, Some(UserRole::EDITOR | UserRole::ADMIN));
+
+                let connection = shared_state.pool.get()
+                    .expect("Failed to acquire connection from pool");
+
+                match locationsDB::new(connection).acting_as(&actor_email(authorized_user)).upsert_if(location_id, upsert_location, |_| may_replace) {
+                    Ok(Some(Upserted::Created(created_location))) => Ok(created(created_location).into_response()),
+                    Ok(Some(Upserted::Updated(updated_location))) => Ok((StatusCode::OK, Json(updated_location)).into_response()),
+                    Ok(Some(Upserted::Unassigned)) => Err(ApiError::conflict("Locations can only be created under ids that were already handed out")),
+                    Ok(None) => Err(ApiError::forbidden(UserRole::EDITOR, role.unwrap_or(UserRole::INVALID))),
+                    Err(err) => {
+                        eprintln!("Error updating location: {:?}", err);
+                        Err(location_write_error("Failed to update location", &err))
+                    }
+                }
+            }
+            Err(err) => Err(err.into())
+        }
+    }
+
+    #[utoipa::path(
+        patch,
+        path = "/locations/{location_id}",
+        tag = "locations",
+        params(("location_id" = i32, Path, description = "Id of the location")),
+        request_body = PatchLocation,
+        responses(
+            (status = 200, description = "The patched location", body = Location),
+            (status = 401, description = "Missing or invalid token, or a role below EDITOR", body = ErrorResponse),
+            (status = 404, description = "Location not found", body = ErrorResponse),
+            (status = 409, description = "Another location has the same star system and area", body = ErrorResponse),
+            (status = 413, description = "Body too large"),
+            (status = 422, description = "Invalid location", body = ValidationErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn patch_location_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32, )>,
+        JsonBody(patch_location): JsonBody<PatchLocation>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let (location_id, ) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'EDITOR' or higher
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::EDITOR).await;
+
+        match authorization {
+            Ok(authorized_user) => {
+                patch_location.validate_or_422("Invalid location")?;
+
+                let connection = shared_state.pool.get()
+                    .expect("Failed to acquire connection from pool");
+
+                match locationsDB::new(connection).acting_as(&actor_email(authorized_user)).patch(location_id, patch_location) {
+                    Ok(patched_location) => Ok((StatusCode::OK, Json(patched_location))),
+                    Err(err) => {
+                        eprintln!("Error patching location: {:?}", err);
+                        Err(location_write_error("Failed to patch location", &err))
+                    }
+                }
+            }
+            Err(err) => Err(err.into())
+        }
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/locations/{location_id}",
+        tag = "locations",
+        params(
+            ("location_id" = i32, Path, description = "Id of the location"),
+            ("If-Match" = String, Header, description = "The location's current ETag, as returned by GET, or '*' for any version"),
+            DeleteLocationQuery
+        ),
+        responses(
+            (status = 200, description = "Location deleted, answered with the location as it was when 'return=representation' is given", body = Location),
+            (status = 204, description = "Location deleted"),
+            (status = 400, description = "Unsupported 'return' value", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 404, description = "Location not found", body = ErrorResponse),
+            (status = 412, description = "The location has changed since the ETag in 'If-Match' was issued", body = ErrorResponse),
+            (status = 428, description = "Missing 'If-Match' header", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn delete_location_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32, )>,
+        extract::Query(query): extract::Query<DeleteLocationQuery>,
+    ) -> Result<Response, ApiError> {
+        let (location_id, ) = path.0;
+
+        let return_representation = match query.return_preference.as_deref() {
+            None | Some("minimal") => false,
+            Some("representation") => true,
+            Some(other) => return Err(ApiError::bad_request(&format!("Unsupported return '{}', expected representation or minimal", other))),
+        };
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'ADMIN'
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await;
+
+        match authorization {
+            Ok(authorized_user) => {
+
+                // Deleting is irreversible, so the client has to show it knows which version it is deleting
+                let if_match = headers.get(header::IF_MATCH)
+                    .ok_or_else(|| ApiError::new(StatusCode::PRECONDITION_REQUIRED, "Header 'If-Match' with the location's ETag is required"))?
+                    .to_str()
+                    .map_err(|_| ApiError::bad_request("Header 'If-Match' must be visible ASCII"))?
+                    .to_string();
+
+                let connection = shared_state.pool.get()
+                    .expect("Failed to acquire connection from pool");
+
+                let deleted = locationsDB::new(connection).acting_as(&actor_email(authorized_user))
+                    .delete_if(location_id, |location| if_match_satisfied(&if_match, &location.etag()));
+
+                match deleted {
+                    Ok(Some(deleted_location)) if return_representation => Ok((StatusCode::OK, Json(deleted_location)).into_response()),
+                    Ok(Some(_)) => Ok(StatusCode::NO_CONTENT.into_response()),
+                    Ok(None) => Err(ApiError::new(StatusCode::PRECONDITION_FAILED, "Location has changed since it was read")),
+                    Err(err) => {
+                        eprintln!("Error deleting location: {:?}", err);
+                        Err(map_diesel_error("location", "Failed to delete location", &err).into())
+                    }
+                }
+            }
+            Err(err) => Err(err.into())
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/locations/{location_id}/history",
+        tag = "locations",
+        params(("location_id" = i32, Path, description = "Id of the location"), LocationHistoryQuery, PaginationQuery),
+        responses(
+            (status = 200, description = "A page of the changes to the location, oldest first and including its deletion, along with 'total', 'limit' and 'offset', and a 'Link' header to the neighbouring pages", body = Object),
+            (status = 400, description = "Invalid pagination or unknown action", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below EDITOR", body = ErrorResponse),
+            (status = 404, description = "Location not found and never existed", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn location_history_handler(
+        headers: HeaderMap,
+        uri: Uri,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32, )>,
+        pagination: Pagination,
+        extract::Query(query): extract::Query<LocationHistoryQuery>,
+    ) -> Result<impl IntoResponse, ApiError> {
+        let (location_id, ) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'EDITOR' or higher
+        let authorization = enforce_role_policy(&shared_state, &claims, UserRole::EDITOR).await;
+
+        match authorization {
+            Ok(_authorized_user) => {
+                let (limit, offset) = (pagination.limit(), pagination.offset());
+
+                if let Some(action) = &query.action {
+                    if !HISTORY_ACTIONS.contains(&action.as_str()) {
+                        return Err(ApiError::bad_request(&format!("Unknown action '{}', expected one of {}", action, HISTORY_ACTIONS.join(", "))));
+                    }
+                }
+
+                let connection = shared_state.pool.get()
+                    .expect("Failed to acquire connection from pool");
+                let mut locations = locationsDB::new(connection);
+
+                let (history, total) = locations.history(location_id, query.action.as_deref(), limit, offset).map_err(|err| {
+                    eprintln!("Error reading location history: {:?}", err);
+                    ApiError::from(map_diesel_error("location", "Failed to read location history", &err))
+                })?;
+
+                // Deleted locations keep their history, so only a location without any is unknown - unless it predates the history.
+                // The action filter may leave out all of a known location's changes, which is still an empty page rather than 404
+                if total == 0 {
+                    let known = locations.has_history(location_id)
+                        .and_then(|has_history| Ok(has_history || locations.get(location_id)?.is_some()))
+                        .map_err(|err| {
+                            eprintln!("Error reading location: {:?}", err);
+                            ApiError::from(map_diesel_error("location", "Failed to read location", &err))
+                        })?;
+
+                    if !known {
+                        return Err(ApiError::not_found("location"));
+                    }
+                }
+
+                Ok((StatusCode::OK, pagination_links(&uri, pagination, total), Json(json!({
+                    "items": history,
+                    "total": total,
+                    "limit": limit,
+                    "offset": offset
+                }))))
+            }
+            Err(err) => Err(err.into())
+        }
+    }
+
+    // If-Match holds '*' or a list of ETags, one of which has to be the current one. Weak tags never match
+    // since a delete needs the strong comparison
+    fn if_match_satisfied(if_match: &str, etag: &str) -> bool {
+        if_match.split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate == etag)
+    }
+
+    // The email the changes of an authorized user are attributed to in the location history
+    fn actor_email(authorized_user: Option<User>) -> String {
+        authorized_user.map(|user| user.email).unwrap_or_default()
+    }
+
+    // A failure to record the read is logged, but never keeps the location from the reader
+    fn record_read(shared_state: &ConnectionPool, reader: Option<User>, location_id: i32) {
+        // A read that can't be recorded still gets its response, so a busy pool only costs the audit entry
+        let connection = match shared_state.pool.get() {
+            Ok(connection) => connection,
+            Err(err) => {
+                eprintln!("Skipping the record of the read of location {}: {:?}", location_id, err);
+                return;
+            }
+        };
+
+        if let Err(err) = AuditLogTable::new(connection).record(NewAuditEntry {
+            actor: reader.map(|reader| reader.email).unwrap_or_default(),
+            action: "read".to_string(),
+            target: format!("location:{}", location_id),
+        }) {
+            eprintln!("Error recording read of location {}: {:?}", location_id, err);
+        }
+    }
+
+    // Reads the optional Idempotency-Key header, rejecting values that can't be stored as a key
+    fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+        match headers.get(IDEMPOTENCY_KEY_HEADER) {
+            None => Ok(None),
+            Some(value) => match value.to_str() {
+                Ok(key) if !key.trim().is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => Ok(Some(key.to_string())),
+                _ => Err(ApiError::bad_request(
+                    &format!("Header '{}' must be between 1 and {} visible ASCII characters", IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LENGTH)
+                )),
+            }
+        }
+    }
+
+    // Returns the location created by the user's earlier request with the same key, or 409 if that request had another body
+    fn replay_idempotent_create(locations: &mut locationsDB, user_id: i32, idempotency_key: &str, request_body: &str) -> Result<Option<Location>, ApiError> {
+        match locations.get_by_idempotency_key(user_id, idempotency_key) {
+            Ok(Some((stored_key, _))) if stored_key.request_body != request_body => Err(ApiError::conflict(
+                "Idempotency-Key has already been used with a different request body"
+            )),
+            Ok(Some((_, location))) => Ok(Some(location)),
+            Ok(None) => Ok(None),
+            Err(err) => {
+                eprintln!("Error reading idempotency key: {:?}", err);
+                Err(map_diesel_error("idempotency key", "Failed to read idempotency key", &err).into())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use axum::{
+            body::Body,
+            http::{Request, StatusCode}
+        };
+        use serde_json::json;
+        use tower::ServiceExt;
+        use crate::{
+            common::{
+                pagination::max_page_size,
+                security::hash_password,
+                state::AppState,
+                test_db::with_test_db,
+                util::load_environment_variable
+            },
+            locations::{
+                model::{Location, LocationFilter, LocationSort, PatchLocation, UpsertLocation},
+                service::service::LocationsTable
+            },
+            users::{
+                model::UpsertUser,
+                service::service::UsersTable
+            },
+            locations_route
+        };
+        use crate::audit::model::ReadAudit;
+        use crate::common::db::{create_shared_connection_pool_with_config, ConnectionPool, PoolConfig, RetryPolicy};
+        use crate::locations::router::router::locations_route_with_read_audit;
+        use crate::common::security::generate_token;
+        use crate::users::model::UserRole;
+        use crate::schema::{audit_log, users};
+        use diesel::prelude::*;
+        use std::{sync::Arc, time::Duration};
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite;
+        use crate::{common::config::Config, locations::changes::LocationChange};
+
+        // Helper method utilized to create user with a specific role and return the associated bearer token in one line of code
+        pub fn create_user_and_generate_token(connection_pool: ConnectionPool, email: &str, user_role: UserRole) -> Result<String, jsonwebtoken::errors::Error> {
+
+            // Only email and role are mutable as password and fullname has no constraints
+            let mut new_user = UpsertUser {
+                email: email.to_string(),
+                role: user_role.to_string(),
+                password: "StålGardinerFunkerFjell53".to_string(),
+                fullname: "Josef Stålhard".to_string()
+            };
+
+            // Hash the password
+            hash_password(&mut new_user).expect("Hash failed");
+
+            // INVALID can't be persisted through UsersTable, so a stored legacy role is simulated by overwriting it afterwards
+            let invalid_role = user_role == UserRole::INVALID;
+            if invalid_role {
+                new_user.role = UserRole::READER.to_string();
+            }
+
+            // Perform the user creation
+            let create_user_result = {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                UsersTable::new(connection).create(new_user.clone())
+            };
+            let created_user = create_user_result.unwrap();
+
+            if invalid_role {
+                let mut connection = connection_pool.pool.get().expect("Failed to get connection");
+                diesel::update(users::table.find(created_user.id))
+                    .set(users::role.eq(UserRole::INVALID.to_string()))
+                    .execute(&mut connection)
+                    .expect("Failed to overwrite role");
+            }
+
+            // Generate the bearer token
+            generate_token(&created_user)
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_201_for_authorized_user_with_write_access() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                // Create user with role WRITER and generate associated bearer token
+                let bearer_token = create_user_and_generate_token(connection_pool, "stål.hard.russer@ugreit.ru", UserRole::WRITER);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a request with the above data as payload
+                let request = Request::builder()
+                    .uri("/locations")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 201
+                assert_eq!(response.status(), StatusCode::CREATED);
+
+                // Assert that the Location header points at the created location
+                let location_header = response.headers().get(http::header::LOCATION).unwrap().to_str().unwrap().to_string();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let created_location: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(location_header, format!("/locations/{}", created_location["id"]));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_409_on_duplicate_star_system_and_area() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "dobbel@lokasjon.no", UserRole::WRITER).unwrap();
+
+                let request_body = UpsertLocation {
+                    star_system: "Gemini".to_string(),
+                    area: "Twin Peaks".to_string(),
+                };
+
+                let mut responses = Vec::new();
+                for _ in 0..2 {
+                    let request = Request::builder()
+                        .uri("/locations")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                        .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                        .unwrap();
+
+                    responses.push(locations_route(AppState::test(connection_pool.clone())).oneshot(request).await.unwrap());
+                }
+
+                // Assert that the first request created the location and the duplicate conflicts
+                assert_eq!(responses[0].status(), StatusCode::CREATED);
+                assert_eq!(responses[1].status(), StatusCode::CONFLICT);
+
+                let body = hyper::body::to_bytes(responses.pop().unwrap().into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json, json!({"error": "location already exists"}));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_and_patch_locations_return_409_when_colliding_with_another_location() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "kollisjon@lokasjon.no", UserRole::EDITOR).unwrap();
+
+                let (taken, moving) = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    let mut location_db = LocationsTable::new(connection);
+                    let taken = location_db.create(UpsertLocation {
+                        star_system: "Gemini".to_string(),
+                        area: "Castor".to_string(),
+                    }).expect("Create location failed");
+                    let moving = location_db.create(UpsertLocation {
+                        star_system: "Gemini".to_string(),
+                        area: "Pollux".to_string(),
+                    }).expect("Create location failed");
+                    (taken, moving)
+                };
+
+                for method in ["PUT", "PATCH"] {
+                    let request = Request::builder()
+                        .uri(format!("/locations/{}", moving.id))
+                        .method(method)
+                        .header("content-type", "application/json")
+                        .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                        .body(Body::from(json!({"star_system": taken.star_system, "area": taken.area}).to_string()))
+                        .unwrap();
+
+                    let response = locations_route(AppState::test(connection_pool.clone())).oneshot(request).await.unwrap();
+
+                    // Assert that the change is refused as it would duplicate the other location
+                    assert_eq!(response.status(), StatusCode::CONFLICT, "Expected 409 for {}", method);
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    assert_eq!(response_json, json!({"error": "location already exists"}));
+                }
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_401_for_unauthorized_user_without_write_access() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                // Create user with role READER and generate associated bearer token
+                let bearer_token = create_user_and_generate_token(connection_pool, "myk.og.ekkel.russer@put.in", UserRole::READER);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a request with the above data as payload
+                let request = Request::builder()
+                    .uri("/locations")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 401
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_400_json_error_on_malformed_body() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "krøllparentes@ugyldig.no", UserRole::WRITER);
+
+                let request = Request::builder()
+                    .uri("/locations")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from("{"))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 400
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                assert_eq!(response_json, json!({"error": "invalid JSON", "detail": "EOF while parsing an object at line 1 column 1"}));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_415_json_error_on_plain_text_body() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "ren@tekst.no", UserRole::WRITER);
+
+                // Valid JSON, but not sent as such
+                let request = Request::builder()
+                    .uri("/locations")
+                    .method("POST")
+                    .header("content-type", "text/plain")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(r#"{"star_system": "Klartekst", "area": "Sone"}"#))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 415
+                assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                assert_eq!(response_json, json!({"error": "Expected request with `Content-Type: application/json`"}));
+
+                // Assert that nothing was created
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let filter = LocationFilter { star_system: Some("Klartekst".to_string()), ..Default::default() };
+                let (_, total) = LocationsTable::new(connection).list(&filter, 10, 0, LocationSort::IdAsc).expect("List locations failed");
+                assert_eq!(total, 0);
+            }).await;
+        }
+
+        async fn post_location_body(body: serde_json::Value, email: &str) -> (StatusCode, serde_json::Value) {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, email, UserRole::WRITER);
+
+                let request = Request::builder()
+                    .uri("/locations")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+
+                let response = service.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+                (status, serde_json::from_slice(&body).unwrap())
+            }).await
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_422_naming_a_missing_field() {
+            let (status, body) = post_location_body(json!({"star_system": "Fountain"}), "manglende@felt.no").await;
+
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(body["errors"], json!({"area": "missing field"}));
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_422_naming_a_field_of_the_wrong_type() {
+            let (status, body) = post_location_body(json!({"star_system": "Fountain", "area": 5}), "feil@type.no").await;
+
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(body["errors"], json!({"area": "invalid type: integer `5`, expected a string"}));
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_422_naming_an_unknown_field() {
+            let (status, body) = post_location_body(json!({"star_system": "Fountain", "area": "Gold", "foo": 1}), "ukjent@felt.no").await;
+
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(body["errors"], json!({"foo": "unknown field, expected `star_system` or `area`"}));
+        }
+
+        #[tokio::test]
+        async fn post_locations_returns_413_on_oversized_body() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "altfor.stor@kropp.no", UserRole::WRITER);
+
+                // Well beyond the default limit of 1MB for single-item writes
+                let request_body = json!({
+                    "star_system": "Fountain",
+                    "area": "x".repeat(2 * 1024 * 1024)
+                });
+
+                // Create a request with the above data as payload
+                let request = Request::builder()
+                    .uri("/locations")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(request_body.to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 413
+                assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_locations_returns_200_for_authorized_user_with_edit_access() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                // Create user with role WRITER and generate associated bearer token
+                let bearer_token = create_user_and_generate_token(connection_pool, "dagfinnkuk@blåfjelletsvenner.no", UserRole::EDITOR);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Assert equality
+                assert_eq!(request_body.star_system, created_location.star_system);
+                assert_eq!(request_body.area, created_location.area);
+
+                let updated_request_body = UpsertLocation {
+                    star_system: "Kador".to_string(),
+                    area: "The Crimson Expanse".to_string(),
+                };
+
+                // Create a request with the above data as payload
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(serde_json::to_string(&updated_request_body).unwrap()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                // Construct JSON consisting of expected payload
+                let expected_response = json!({
+                    "id": created_location.id,
+                    "area": updated_request_body.area,
+                    "star_system": updated_request_body.star_system,
+                    "created_at": created_location.created_at,
+                    "updated_at": response_json["updated_at"]
+                });
+
+                // Assert that the update was timestamped without resetting created_at
+                assert_ne!(response_json["updated_at"], json!(created_location.updated_at));
+
+                // Assert equality
+                assert_eq!(response_json, expected_response);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_locations_returns_401_for_unauthorized_user_without_edit_access() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                // Create user with role WRITER and generate associated bearer token
+                let bearer_token = create_user_and_generate_token(connection_pool, "necromancer@gpf.no", UserRole::WRITER);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Assert equality
+                assert_eq!(request_body.star_system, created_location.star_system);
+                assert_eq!(request_body.area, created_location.area);
+
+                let updated_request_body = UpsertLocation {
+                    star_system: "Kador".to_string(),
+                    area: "The Crimson Expanse".to_string(),
+                };
+
+                // Create a request with the above data as payload
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(serde_json::to_string(&updated_request_body).unwrap()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 401 and the location was left untouched
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                assert_eq!(location_db.get(created_location.id).unwrap().unwrap().star_system, "Fountain");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_locations_creates_a_missing_location_with_the_given_id() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                // Creating through PUT only takes the same role as creating through POST
+                let bearer_token = create_user_and_generate_token(connection_pool, "synkron@klient.no", UserRole::WRITER).unwrap();
+
+                let removed_location = location_db.create(UpsertLocation {
+                    star_system: "Syncora".to_string(),
+                    area: "Removed".to_string(),
+                }).expect("Create location failed");
+                location_db.delete(removed_location.id).expect("Delete location failed");
+
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", removed_location.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::from(json!({"star_system": "Syncora", "area": "Client Chosen"}).to_string()))
+                    .unwrap();
+
+                let response = service.oneshot(request).await.unwrap();
+
+                // Assert that the location was created under the id it was put to
+                assert_eq!(response.status(), StatusCode::CREATED);
+                assert_eq!(response.headers()[http::header::LOCATION], format!("/locations/{}", removed_location.id));
+
+                let created_location = location_db.get(removed_location.id).unwrap().expect("Expected the location to exist");
+                assert_eq!(created_location.area, "Client Chosen");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_locations_refuses_ids_past_the_sequence() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "sekvens@klient.no", UserRole::WRITER).unwrap();
+
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", i32::MAX))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::from(json!({"star_system": "Syncora", "area": "Far Ahead"}).to_string()))
+                    .unwrap();
+
+                let response = service.oneshot(request).await.unwrap();
+
+                // Assert that nothing was created under the id
+                assert_eq!(response.status(), StatusCode::CONFLICT);
+                assert!(location_db.get(i32::MAX).unwrap().is_none());
+
+                // Assert that the sequence is left alone, so locations are still created without an id
+                location_db.create(UpsertLocation {
+                    star_system: "Syncora".to_string(),
+                    area: "Server Chosen".to_string(),
+                }).expect("Create location failed");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_locations_does_not_move_the_id_sequence_back() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "bakover@klient.no", UserRole::EDITOR).unwrap();
+
+                let first_location = location_db.create(UpsertLocation {
+                    star_system: "Syncora".to_string(),
+                    area: "First".to_string(),
+                }).expect("Create location failed");
+                let second_location = location_db.create(UpsertLocation {
+                    star_system: "Syncora".to_string(),
+                    area: "Second".to_string(),
+                }).expect("Create location failed");
+                location_db.delete(first_location.id).expect("Delete location failed");
+                location_db.delete(second_location.id).expect("Delete location failed");
+
+                // Recreate the first location, whose id is below the last one handed out
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", first_location.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::from(json!({"star_system": "Syncora", "area": "Restored"}).to_string()))
+                    .unwrap();
+                let response = service.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::CREATED);
+
+                // Assert that the deleted location's id isn't handed out again
+                let next_location = location_db.create(UpsertLocation {
+                    star_system: "Syncora".to_string(),
+                    area: "Third".to_string(),
+                }).expect("Create location failed");
+                assert!(next_location.id > second_location.id);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_200_for_authorized_user_with_read_access() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "duvetdet@gjerrigknark.no", UserRole::READER);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Create a request with the ID associated with our newly inserted row
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                // Construct JSON consisting of expected payload, which leaves out the audit fields for readers
+                let expected_response = json!({
+                    "id": created_location.id,
+                    "area": request_body.area,
+                    "star_system": request_body.star_system
+                });
+
+                // Assert equality
+                assert_eq!(response_json, expected_response);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_location_shows_audit_fields_to_editors_but_not_readers() {
+            with_test_db(|connection_pool| async move {
+                let created_location = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Tidsstempel".to_string(),
+                        area: "Loggen".to_string(),
+                    }).expect("Create location failed")
+                };
+
+                let reader_token = create_user_and_generate_token(connection_pool.clone(), "leser@revisjon.no", UserRole::READER).unwrap();
+                let editor_token = create_user_and_generate_token(connection_pool.clone(), "redaktor@revisjon.no", UserRole::EDITOR).unwrap();
+
+                let mut bodies = Vec::new();
+                for bearer_token in [reader_token, editor_token] {
+                    let request = Request::builder()
+                        .uri(format!("/locations/{}", created_location.id))
+                        .method("GET")
+                        .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                        .body(Body::empty())
+                        .unwrap();
+
+                    let response = locations_route(AppState::test(connection_pool.clone())).oneshot(request).await.unwrap();
+                    assert_eq!(response.status(), StatusCode::OK);
+
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    bodies.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+                }
+
+                assert_eq!(bodies[0], json!({
+                    "id": created_location.id,
+                    "star_system": "Tidsstempel",
+                    "area": "Loggen"
+                }));
+                assert_eq!(bodies[1], json!({
+                    "id": created_location.id,
+                    "star_system": "Tidsstempel",
+                    "area": "Loggen",
+                    "created_at": created_location.created_at,
+                    "updated_at": created_location.updated_at
+                }));
+            }).await;
+        }
+
+        // Serves the locations on a port of its own, as a WebSocket upgrade needs a real connection to take over
+        fn serve_locations_with_notifications(connection_pool: ConnectionPool) -> (std::net::SocketAddr, AppState) {
+            let state = AppState {
+                config: Arc::new(Config { notify_changes: true, ..Config::from_env() }),
+                ..AppState::test(connection_pool)
+            };
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+            let address = listener.local_addr().unwrap();
+            let server = axum::Server::from_tcp(listener).unwrap().serve(locations_route(state.clone()).into_make_service());
+            tokio::spawn(server);
+
+            (address, state)
+        }
+
+        #[tokio::test]
+        async fn stream_pushes_location_changes_and_lets_go_of_closed_clients() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "strom@endringer.no", UserRole::READER).unwrap();
+                let (address, state) = serve_locations_with_notifications(connection_pool);
+
+                let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/locations/stream?token={}", address, bearer_token))
+                    .await
+                    .expect("Failed to connect");
+                assert_eq!(state.location_changes.subscriber_count(), 1);
+
+                state.location_changes.publish(LocationChange { id: 42, action: "update".to_string() });
+
+                let message = tokio::time::timeout(Duration::from_secs(5), socket.next()).await
+                    .expect("Expected a message within 5 seconds")
+                    .expect("Expected the socket to stay open")
+                    .expect("Failed to read message");
+                let message_json: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+                assert_eq!(message_json, json!({"action": "updated", "id": 42}));
+
+                // Once the client has gone, so is its subscription
+                socket.close(None).await.expect("Failed to close");
+                for _ in 0..50 {
+                    if state.location_changes.subscriber_count() == 0 {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                panic!("Expected the closed client's subscription to be dropped");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn events_stream_location_changes_until_the_client_goes() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "hendelser@endringer.no", UserRole::READER).unwrap();
+                let state = AppState {
+                    config: Arc::new(Config { notify_changes: true, ..Config::from_env() }),
+                    ..AppState::test(connection_pool)
+                };
+
+                let request = Request::builder()
+                    .uri("/locations/events")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                let response = locations_route(state.clone()).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+                state.location_changes.publish(LocationChange { id: 7, action: "create".to_string() });
+
+                let mut body = response.into_body();
+                let frame = tokio::time::timeout(Duration::from_secs(5), hyper::body::HttpBody::data(&mut body)).await
+                    .expect("Expected an event within 5 seconds")
+                    .expect("Expected the stream to stay open")
+                    .expect("Failed to read event");
+                assert_eq!(std::str::from_utf8(&frame).unwrap(), "event:location\ndata:{\"action\":\"created\",\"id\":7}\n\n");
+
+                // A client going away drops the body, and the subscription with it
+                drop(body);
+                assert_eq!(state.location_changes.subscriber_count(), 0);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn stream_without_a_valid_token_is_refused_before_upgrading() {
+            with_test_db(|connection_pool| async move {
+                let (address, state) = serve_locations_with_notifications(connection_pool);
+
+                for query in ["", "?token=", "?token=not.a.jwt"] {
+                    let err = tokio_tungstenite::connect_async(format!("ws://{}/locations/stream{}", address, query)).await
+                        .expect_err("Expected the upgrade to be refused");
+
+                    match err {
+                        tungstenite::Error::Http(response) => assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", query),
+                        other => panic!("Expected an HTTP error, got {:?}", other),
+                    }
+                }
+
+                assert_eq!(state.location_changes.subscriber_count(), 0);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_location_with_fields_returns_only_those_fields() {
+            with_test_db(|connection_pool| async move {
+                let created_location = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Utvalg".to_string(),
+                        area: "Felt".to_string(),
+                    }).expect("Create location failed")
+                };
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "felt@utvalg.no", UserRole::READER).unwrap();
+
+                let request = Request::builder()
+                    .uri(format!("/locations/{}?fields=id,area", created_location.id))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                let response = locations_route(AppState::test(connection_pool.clone())).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json, json!({"id": created_location.id, "area": "Felt"}));
+
+                // The list narrows down each of its items the same way, and leaves the page fields alone
+                let (status, response_json) = get_locations_page(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "q=Utvalg&fields=area").await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json["items"], json!([{"area": "Felt"}]));
+                assert_eq!(response_json["total"], 1);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_with_unknown_field_returns_400() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "ukjent@utvalg.no", UserRole::READER).unwrap();
+
+                let (status, response_json) = get_locations_page(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "fields=id,password").await;
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(response_json["error"], "Unknown field 'password', expected one of id, star_system, area, x, y, z, created_at, updated_at");
+
+                let request = Request::builder()
+                    .uri("/locations/1?fields=password")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                let response = locations_route(AppState::test(connection_pool.clone())).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_200_for_authorized_user_with_write_access() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "kokefaktura@woodworm.org", UserRole::WRITER);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Create a request with the ID associated with our newly inserted row
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                // Construct JSON consisting of expected payload
+                let expected_response = json!({
+                    "id": created_location.id,
+                    "area": request_body.area,
+                    "star_system": request_body.star_system,
+                    "created_at": created_location.created_at,
+                    "updated_at": created_location.updated_at
+                });
+
+                // Assert equality
+                assert_eq!(response_json, expected_response);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_401_for_unauthorized_user_without_read_access() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "igor.invalidus@bogdanov.fr", UserRole::INVALID);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Create a request with the ID associated with our newly inserted row
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 401
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED)
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_404_on_non_existing_id() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "birdman@ifi.uio.no", UserRole::READER);
+
+                // Create a request with the aforementioned id
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", -666)) // Use a non-existent ID
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 404 as there are no locations associated with the id
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }).await;
+        }
+
+        // Reads a location through a router with read-audit switched on or off, returning how many reads of it were audited
+        async fn audited_reads_after_get(read_audit: ReadAudit, email: &str) -> i64 {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route_with_read_audit(AppState::test(connection_pool.clone()), read_audit);
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), email, UserRole::READER);
+
+                let created_location = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Compliance".to_string(),
+                        area: "Records Office".to_string(),
+                    }).expect("Create location failed")
+                };
+
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response is unaffected by auditing
+                assert_eq!(response.status(), StatusCode::OK);
+
+                let mut connection = connection_pool.pool.get().expect("Failed to get connection");
+                audit_log::table
+                    .filter(audit_log::actor.eq(email))
+                    .filter(audit_log::action.eq("read"))
+                    .filter(audit_log::target.eq(format!("location:{}", created_location.id)))
+                    .count()
+                    .get_result(&mut connection)
+                    .expect("Failed to count audit entries")
+            }).await
+        }
+
+        #[tokio::test]
+        async fn get_location_writes_audit_entry_when_read_audit_is_on() {
+            assert_eq!(audited_reads_after_get(ReadAudit(true), "revisor@etterlevelse.no").await, 1);
+        }
+
+        #[tokio::test]
+        async fn get_location_writes_no_audit_entry_when_read_audit_is_off() {
+            assert_eq!(audited_reads_after_get(ReadAudit(false), "ikke.revisor@etterlevelse.no").await, 0);
+        }
+
+        #[tokio::test]
+        async fn record_read_skips_the_entry_when_the_pool_is_exhausted() {
+            let exhausted_pool = create_shared_connection_pool_with_config(load_environment_variable("TEST_DB"), PoolConfig {
+                max_size: 1,
+                min_idle: Some(1),
+                connection_timeout: Duration::from_millis(100),
+                idle_timeout: None,
+                statement_timeout: None,
+                retry: RetryPolicy { retries: 0, backoff: Duration::ZERO },
+            });
+            let _held_connection = exhausted_pool.pool.get().expect("Failed to get connection");
+
+            // Assert that the missing connection is logged rather than panicking the request
+            super::record_read(&exhausted_pool, None, 1);
+        }
+
+        #[tokio::test]
+        async fn delete_locations_returns_204_for_authorized_user_with_admin_role() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool,"you.know.your.judo.well@succulentmail.gb", UserRole::ADMIN);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Create a request with the ID associated with our newly inserted row
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("DELETE")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .header("If-Match", created_location.etag())
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 204
+                assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+                // Attempt to retrieve the deleted location
+                let deleted_location_result = location_db.get(created_location.id);
+
+                // Assert that the Result is Ok (no error)
+                assert!(deleted_location_result.is_ok());
+
+                // Extract the Option<Location> from the Ok variant
+                let deleted_location = deleted_location_result.unwrap();
+
+                // Assert that the deleted location is None (i.e., it doesn't exist)
+                assert!(deleted_location.is_none());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_locations_returns_401_for_unauthorized_user_without_admin_role() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool,"donttouchmys@p.succulentor.gb", UserRole::EDITOR);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Create a request with the ID associated with our newly inserted row
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("DELETE")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 401
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            }).await;
+        }
+
+        async fn delete_location_with_if_match(service: axum::Router, bearer_token: &str, location_id: i32, if_match: Option<&str>) -> StatusCode {
+            let mut request = Request::builder()
+                .uri(format!("/locations/{}", location_id))
+                .method("DELETE")
+                .header("Authorization", format!("Bearer {}", bearer_token)); // Add the bearer token
+            if let Some(if_match) = if_match {
+                request = request.header("If-Match", if_match);
+            }
+
+            service.oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+        }
+
+        #[tokio::test]
+        async fn delete_location_returns_428_without_if_match() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "uten.forbehold@sletting.no", UserRole::ADMIN).unwrap();
+
+                let created_location = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Precondia".to_string(),
+                        area: "Unguarded".to_string(),
+                    }).expect("Create location failed")
+                };
+
+                let status = delete_location_with_if_match(service, &bearer_token, created_location.id, None).await;
+                assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+
+                // Assert that the location is still there
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                assert!(LocationsTable::new(connection).get(created_location.id).unwrap().is_some());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_location_returns_412_when_location_changed_since_it_was_read() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "utdatert@sletting.no", UserRole::ADMIN).unwrap();
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let created_location = location_db.create(UpsertLocation {
+                    star_system: "Precondia".to_string(),
+                    area: "Stale".to_string(),
+                }).expect("Create location failed");
+
+                // Someone else updates the location after our ETag was issued
+                location_db.patch(created_location.id, PatchLocation {
+                    star_system: None,
+                    area: Some("Fresh".to_string()),
+                    ..Default::default()
+                }).expect("Patch location failed");
+
+                let status = delete_location_with_if_match(service, &bearer_token, created_location.id, Some(&created_location.etag())).await;
+                assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+                assert!(location_db.get(created_location.id).unwrap().is_some());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_location_returns_204_with_etag_from_get() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "gyldig.forbehold@sletting.no", UserRole::ADMIN).unwrap();
+
+                let created_location = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Precondia".to_string(),
+                        area: "Current".to_string(),
+                    }).expect("Create location failed")
+                };
+
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+                let response = service.clone().oneshot(request).await.unwrap();
+                let etag = response.headers().get(http::header::ETAG).unwrap().to_str().unwrap().to_string();
+
+                let status = delete_location_with_if_match(service, &bearer_token, created_location.id, Some(&etag)).await;
+                assert_eq!(status, StatusCode::NO_CONTENT);
+            }).await;
+        }
+
+        async fn delete_location_with_query(service: axum::Router, bearer_token: &str, location_id: i32, query: &str) -> (StatusCode, Vec<u8>) {
+            let request = Request::builder()
+                .uri(format!("/locations/{}{}", location_id, query))
+                .method("DELETE")
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                .header("If-Match", "*")
+                .body(Body::empty())
+                .unwrap();
+
+            let response = service.oneshot(request).await.unwrap();
+            let status = response.status();
+            (status, hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec())
+        }
+
+        #[tokio::test]
+        async fn delete_location_with_return_representation_returns_the_deleted_location() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "angre@sletting.no", UserRole::ADMIN).unwrap();
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let created_location = location_db.create(UpsertLocation {
+                    star_system: "Undoria".to_string(),
+                    area: "Regretted".to_string(),
+                }).expect("Create location failed");
+
+                let (status, body) = delete_location_with_query(service, &bearer_token, created_location.id, "?return=representation").await;
+                assert_eq!(status, StatusCode::OK);
+
+                // Assert that the body is the location as it was and that it is gone
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json, serde_json::to_value(&created_location).unwrap());
+                assert!(location_db.get(created_location.id).unwrap().is_none());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_location_without_return_param_returns_204_with_empty_body() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "tom.kropp@sletting.no", UserRole::ADMIN).unwrap();
+
+                let created_location = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Undoria".to_string(),
+                        area: "Forgotten".to_string(),
+                    }).expect("Create location failed")
+                };
+
+                let (status, body) = delete_location_with_query(service, &bearer_token, created_location.id, "").await;
+                assert_eq!(status, StatusCode::NO_CONTENT);
+                assert!(body.is_empty());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_location_returns_404_for_missing_id_in_either_mode() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "ingen@sletting.no", UserRole::ADMIN).unwrap();
+
+                for query in ["", "?return=representation"] {
+                    let (status, _) = delete_location_with_query(locations_route(AppState::test(connection_pool.clone())), &bearer_token, 424242, query).await;
+                    assert_eq!(status, StatusCode::NOT_FOUND);
+                }
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_location_returns_400_for_unsupported_return_value() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "ukjent@sletting.no", UserRole::ADMIN).unwrap();
+
+                let (status, _) = delete_location_with_query(locations_route(AppState::test(connection_pool.clone())), &bearer_token, 1, "?return=everything").await;
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_bulk_delete_returns_number_of_existing_locations_deleted() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "masse.sletting@rydde.no", UserRole::EDITOR);
+
+                let request_body = UpsertLocation {
+                    star_system: "Cleanup".to_string(),
+                    area: "Doomed".to_string(),
+                };
+                let first_location = location_db.create(request_body.clone()).expect("Create location failed");
+                let second_location = location_db.create(UpsertLocation {
+                    area: "Doomed Too".to_string(),
+                    ..request_body.clone()
+                }).expect("Create location failed");
+
+                let request = Request::builder()
+                    .uri("/locations/bulk-delete")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(json!({"ids": [first_location.id, second_location.id, -666]}).to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Assert that the non-existent id is skipped rather than counted
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json, json!({"deleted": 2}));
+
+                assert!(location_db.get(first_location.id).expect("Read location failed").is_none());
+                assert!(location_db.get(second_location.id).expect("Read location failed").is_none());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_bulk_delete_returns_413_on_too_many_ids() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "for.mange@rydde.no", UserRole::EDITOR);
+
+                let ids: Vec<i32> = (1..=501).collect();
+
+                let request = Request::builder()
+                    .uri("/locations/bulk-delete")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(json!({"ids": ids}).to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 413
+                assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_bulk_delete_returns_401_for_user_without_edit_access() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "ikke.lov@rydde.no", UserRole::WRITER);
+
+                let request = Request::builder()
+                    .uri("/locations/bulk-delete")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(json!({"ids": [1]}).to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 401
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            }).await;
+        }
+
+        async fn delete_locations_with_query(service: axum::Router, bearer_token: &str, query: &str) -> (StatusCode, serde_json::Value) {
+            let request = Request::builder()
+                .uri(format!("/locations{}", query))
+                .method("DELETE")
+                .header("Authorization", format!("Bearer {}", bearer_token))
+                .body(Body::empty())
+                .unwrap();
+
+            let response = service.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        #[tokio::test]
+        async fn delete_locations_deletes_only_the_star_system_filtered_by() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "system.sletting@rydde.no", UserRole::ADMIN).unwrap();
+
+                let mut fountain_locations = Vec::new();
+                for area in ["Basin", "Spout"] {
+                    fountain_locations.push(location_db.create(UpsertLocation {
+                        star_system: "Fountain".to_string(),
+                        area: area.to_string(),
+                    }).expect("Create location failed"));
+                }
+                let spared_location = location_db.create(UpsertLocation {
+                    star_system: "Fountainhead".to_string(),
+                    area: "Basin".to_string(),
+                }).expect("Create location failed");
+
+                let (status, response_json) = delete_locations_with_query(
+                    locations_route(AppState::test(connection_pool.clone())), &bearer_token, "?star_system=Fountain"
+                ).await;
+
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json, json!({"deleted": 2}));
+
+                for location in fountain_locations {
+                    assert!(location_db.get(location.id).expect("Read location failed").is_none());
+                }
+                assert!(location_db.get(spared_location.id).expect("Read location failed").is_some());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_locations_without_a_filter_returns_400_and_deletes_nothing() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "alt.sletting@rydde.no", UserRole::ADMIN).unwrap();
+
+                let location = location_db.create(UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "Survivor".to_string(),
+                }).expect("Create location failed");
+
+                for query in ["", "?star_system=", "?star_system=%20", "?area=Survivor"] {
+                    let (status, _) = delete_locations_with_query(
+                        locations_route(AppState::test(connection_pool.clone())), &bearer_token, query
+                    ).await;
+                    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+                }
+
+                assert!(location_db.get(location.id).expect("Read location failed").is_some());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_locations_returns_401_below_admin() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "redaktor.sletting@rydde.no", UserRole::EDITOR).unwrap();
+
+                let (status, _) = delete_locations_with_query(
+                    locations_route(AppState::test(connection_pool.clone())), &bearer_token, "?star_system=Fountain"
+                ).await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn patch_locations_returns_200_and_only_changes_provided_fields() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "lappe.teppe@patchwork.no", UserRole::EDITOR);
+
+                let request_body = UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                };
+
+                // Create a new location with the above data
+                let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+
+                // Create a request which only changes the area
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("PATCH")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(json!({"area": "The Crimson Expanse"}).to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                // Construct JSON consisting of expected payload
+                let expected_response = json!({
+                    "id": created_location.id,
+                    "area": "The Crimson Expanse",
+                    "star_system": request_body.star_system,
+                    "created_at": created_location.created_at,
+                    "updated_at": response_json["updated_at"]
+                });
+
+                // Assert equality
+                assert_eq!(response_json, expected_response);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn patch_locations_returns_404_on_non_existing_id() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "lappe.luke@patchwork.no", UserRole::EDITOR);
+
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", -666)) // Use a non-existent ID
+                    .method("PATCH")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(json!({"area": "Nowhere"}).to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 404
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn patch_locations_returns_422_on_empty_field() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "lappe.tom@patchwork.no", UserRole::EDITOR);
+
+                let created_location = location_db.create(UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "The Serpent's Lair".to_string(),
+                }).expect("Create location failed");
+
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("PATCH")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::from(json!({"star_system": ""}).to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 422 and the error is listed under its field
+                assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json, json!({"error": "Invalid location", "errors": {"star_system": ["Field 'star_system' must not be empty"]}}));
+
+                // Assert that the location was left untouched
+                let unchanged_location = location_db.get(created_location.id).expect("Read location failed").unwrap();
+                assert_eq!(unchanged_location.star_system, "Fountain");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_sorted_by_created_at_desc_returns_newest_first() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "nyeste.foerst@sortering.no", UserRole::READER);
+
+                let older_location = location_db.create(UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "Older".to_string(),
+                }).expect("Create location failed");
+                let newer_location = location_db.create(UpsertLocation {
+                    star_system: "Fountain".to_string(),
+                    area: "Newer".to_string(),
+                }).expect("Create location failed");
+
+                let request = Request::builder()
+                    .uri("/locations?sort=created_at:desc&limit=2")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                // Assert that the two most recently created locations are returned newest first
+                assert_eq!(response_json["limit"], json!(2));
+                assert_eq!(response_json["items"][0]["id"], json!(newer_location.id));
+                assert_eq!(response_json["items"][1]["id"], json!(older_location.id));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_400_on_unsupported_sort() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "usortert@sortering.no", UserRole::READER);
+
+                let request = Request::builder()
+                    .uri("/locations?sort=created_at:sideways")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 400
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json["error"], "Unknown sort direction 'sideways', expected 'asc' or 'desc'");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_400_on_sort_field_outside_the_allowlist() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "injeksjon@sortering.no", UserRole::READER).unwrap();
+
+                for sort in ["x:asc", "area%3Bdrop%20table%20locations"] {
+                    let (status, response_json) = get_locations_page(locations_route(AppState::test(connection_pool.clone())), &bearer_token, &format!("sort={}", sort)).await;
+
+                    assert_eq!(status, StatusCode::BAD_REQUEST);
+                    assert!(response_json["error"].as_str().unwrap().starts_with("Unknown sort field"));
+                }
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_sorted_by_area_or_star_system() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "alfabetisk@sortering.no", UserRole::READER).unwrap();
+
+                {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    let mut location_db = LocationsTable::new(connection);
+                    for (star_system, area) in [("Sortia B", "Middle"), ("Sortia A", "Zenith"), ("Sortia C", "Apex")] {
+                        location_db.create(UpsertLocation {
+                            star_system: star_system.to_string(),
+                            area: area.to_string(),
+                        }).expect("Create location failed");
+                    }
+                }
+
+                let areas = |response_json: &serde_json::Value| -> Vec<String> {
+                    response_json["items"].as_array().unwrap().iter()
+                        .map(|location| location["area"].as_str().unwrap().to_string())
+                        .collect()
+                };
+
+                let (status, response_json) = get_locations_page(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "q=Sortia&sort=area:desc").await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(areas(&response_json), vec!["Zenith", "Middle", "Apex"]);
+
+                let (status, response_json) = get_locations_page(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "q=Sortia&sort=star_system").await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(areas(&response_json), vec!["Zenith", "Middle", "Apex"]);
+
+                let (status, response_json) = get_locations_page(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "q=Sortia&sort=star_system:desc").await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(areas(&response_json), vec!["Apex", "Middle", "Zenith"]);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_star_system_counts_returns_most_populated_systems_first() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "telling@dashbord.no", UserRole::READER).unwrap();
+
+                // Every seeded star system has a single location, 'Domain' gets a second one
+                {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    let mut location_db = LocationsTable::new(connection);
+                    for (star_system, area) in [("Tellus", "A"), ("Tellus", "B"), ("Tellus", "C"), ("Talos", "A"), ("Talos", "B"), ("Domain", "Tellerom")] {
+                        location_db.create(UpsertLocation {
+                            star_system: star_system.to_string(),
+                            area: area.to_string(),
+                        }).expect("Create location failed");
+                    }
+                }
+
+                let get_counts = |query: &str| {
+                    let request = Request::builder()
+                        .uri(format!("/locations/stats/by-star-system{}", query))
+                        .method("GET")
+                        .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                        .body(Body::empty())
+                        .unwrap();
+                    locations_route(AppState::test(connection_pool.clone())).oneshot(request)
+                };
+
+                let response = get_counts("?limit=3").await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                // Ties are broken by name, which puts 'Domain' before 'Talos'
+                assert_eq!(response_json, json!([
+                    {"star_system": "Tellus", "count": 3},
+                    {"star_system": "Domain", "count": 2},
+                    {"star_system": "Talos", "count": 2}
+                ]));
+
+                // Without a limit every star system is counted, and the counts add up to every location
+                let response = get_counts("").await.unwrap();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let (_, total) = LocationsTable::new(connection).list(&LocationFilter::default(), 1, 0, LocationSort::IdAsc).expect("List locations failed");
+                let counted: i64 = response_json.as_array().unwrap().iter().map(|entry| entry["count"].as_i64().unwrap()).sum();
+                assert_eq!(counted, total);
+                assert!(response_json.as_array().unwrap()[3..].iter().all(|entry| entry["count"] == json!(1)));
+
+                let response = get_counts("?limit=0").await.unwrap();
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_area_stats_returns_distinct_area_count_for_star_system() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "areal@statistikk.no", UserRole::READER);
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                for area in ["Ringen", "Kjernen", "Utkanten"] {
+                    location_db.create(UpsertLocation {
+                        star_system: "Arealia".to_string(),
+                        area: area.to_string(),
+                    }).expect("Create location failed");
+                }
+
+                let request = Request::builder()
+                    .uri("/locations/area-stats?star_system=Arealia")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                assert_eq!(response_json, json!([{"star_system": "Arealia", "distinct_areas": 3}]));
+            }).await;
+        }
+
+        async fn get_nearby(service: axum::Router, bearer_token: &str, query: &str) -> (StatusCode, serde_json::Value) {
+            let request = Request::builder()
+                .uri(format!("/locations/nearby?{}", query))
+                .method("GET")
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        #[tokio::test]
+        async fn get_nearby_locations_returns_locations_within_radius_nearest_first() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "nabo@koordinater.no", UserRole::READER).unwrap();
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                // The last one is out of range and the uncharted one has no coordinates at all
+                for (area, coordinates) in [("Far", Some((3.0, 4.0, 0.0))), ("Near", Some((1.0, 0.0, 0.0))), ("Beyond", Some((10.0, 0.0, 0.0))), ("Uncharted", None)] {
+                    let location = location_db.create(UpsertLocation {
+                        star_system: "Nabolaget".to_string(),
+                        area: area.to_string(),
+                    }).expect("Create location failed");
+
+                    if let Some((x, y, z)) = coordinates {
+                        location_db.patch(location.id, PatchLocation {
+                            x: Some(x),
+                            y: Some(y),
+                            z: Some(z),
+                            ..Default::default()
+                        }).expect("Patch location failed");
+                    }
+                }
+
+                let (status, response_json) = get_nearby(service, &bearer_token, "x=0&y=0&z=0&radius=5").await;
+
+                // Assert that only the locations within the radius are returned, nearest first
+                assert_eq!(status, StatusCode::OK);
+                let areas: Vec<&str> = response_json.as_array().unwrap().iter().map(|location| location["area"].as_str().unwrap()).collect();
+                assert_eq!(areas, vec!["Near", "Far"]);
+                assert_eq!(response_json[1]["x"], json!(3.0));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_nearby_locations_returns_400_on_missing_coordinate_or_non_positive_radius() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "ufullstendig@koordinater.no", UserRole::READER).unwrap();
+
+                for query in ["x=0&y=0&radius=5", "x=0&y=0&z=0", "x=0&y=0&z=0&radius=0", "x=0&y=0&z=0&radius=-1", "x=NaN&y=0&z=0&radius=1"] {
+                    let (status, response_json) = get_nearby(service.clone(), &bearer_token, query).await;
+
+                    assert_eq!(status, StatusCode::BAD_REQUEST, "Expected 400 for '{}'", query);
+                    assert!(response_json["error"].is_string());
+                }
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_location_history_returns_changes_after_delete() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "historiker@arkivet.no", UserRole::ADMIN).unwrap();
+
+                let created_location = {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Arkivia".to_string(),
+                        area: "Hvelvet".to_string(),
+                    }).expect("Create location failed")
+                };
+
+                // Delete the location through the API, so the deletion is attributed to the token's user
+                let request = Request::builder()
+                    .uri(format!("/locations/{}", created_location.id))
+                    .method("DELETE")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .header("If-Match", "*")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = service.clone().oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::NO_CONTENT);
 
-            let bearer_token = create_user_and_generate_token(connection_pool, "igor.invalidus@bogdanov.fr", UserRole::INVALID);
+                let request = Request::builder()
+                    .uri(format!("/locations/{}/history", created_location.id))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
 
-            // Create a new location with the above data
-            let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                assert_eq!(response_json["total"], 2);
+                assert_eq!(response_json["items"][0]["action"], "create");
+                assert_eq!(response_json["items"][1]["action"], "delete");
+                assert_eq!(response_json["items"][1]["actor"], "historiker@arkivet.no");
+                assert_eq!(response_json["items"][1]["before"]["area"], "Hvelvet");
+                assert_eq!(response_json["items"][1]["after"], serde_json::Value::Null);
+            }).await;
+        }
 
-            // Create a request with the ID associated with our newly inserted row
+        async fn get_history(service: axum::Router, bearer_token: &str, location_id: i32, query: &str) -> (StatusCode, serde_json::Value) {
             let request = Request::builder()
-                .uri(format!("/locations/{}", created_location.id))
+                .uri(format!("/locations/{}/history{}", location_id, query))
                 .method("GET")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
                 .body(Body::empty())
                 .unwrap();
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+            let response = service.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        // A location with a create and three updates in its history
+        fn location_with_history(connection_pool: &ConnectionPool) -> Location {
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut location_db = LocationsTable::new(connection);
+
+            let created_location = location_db.create(UpsertLocation {
+                star_system: "Arkivia".to_string(),
+                area: "Version 0".to_string(),
+            }).expect("Create location failed");
+
+            for version in 1..=3 {
+                location_db.update(created_location.id, UpsertLocation {
+                    star_system: "Arkivia".to_string(),
+                    area: format!("Version {}", version),
+                }).expect("Update location failed");
+            }
 
-            // Assert that the response status is 401
-            assert_eq!(response.status(), StatusCode::UNAUTHORIZED)
+            created_location
         }
 
         #[tokio::test]
-        async fn get_locations_returns_404_on_non_existing_id() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let service = locations_route(connection_pool.clone());
+        async fn get_location_history_is_paginated() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "sidevis@arkivet.no", UserRole::EDITOR).unwrap();
+                let location = location_with_history(&connection_pool);
+
+                let (status, response_json) = get_history(locations_route(AppState::test(connection_pool.clone())), &bearer_token, location.id, "?limit=2&offset=1").await;
+
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json["total"], 4);
+                assert_eq!(response_json["limit"], 2);
+                assert_eq!(response_json["offset"], 1);
+
+                let areas: Vec<&str> = response_json["items"].as_array().unwrap().iter()
+                    .map(|entry| entry["after"]["area"].as_str().unwrap())
+                    .collect();
+                assert_eq!(areas, vec!["Version 1", "Version 2"]);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_location_history_filters_by_action() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "filtrert@arkivet.no", UserRole::EDITOR).unwrap();
+                let location = location_with_history(&connection_pool);
+
+                let (status, response_json) = get_history(locations_route(AppState::test(connection_pool.clone())), &bearer_token, location.id, "?action=create").await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json["total"], 1);
+                assert_eq!(response_json["items"][0]["action"], "create");
+
+                let (_, response_json) = get_history(locations_route(AppState::test(connection_pool.clone())), &bearer_token, location.id, "?action=update").await;
+                assert_eq!(response_json["total"], 3);
+
+                // A known location with none of the changes asked for is an empty page rather than 404
+                let (status, response_json) = get_history(locations_route(AppState::test(connection_pool.clone())), &bearer_token, location.id, "?action=delete").await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json["total"], 0);
+                assert_eq!(response_json["items"], json!([]));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_location_history_returns_400_on_unknown_action() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "ukjent@arkivet.no", UserRole::EDITOR).unwrap();
+                let location = location_with_history(&connection_pool);
+
+                let (status, response_json) = get_history(locations_route(AppState::test(connection_pool.clone())), &bearer_token, location.id, "?action=patch").await;
+
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(response_json["error"], "Unknown action 'patch', expected one of create, update, delete");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_location_history_returns_404_on_unknown_location() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "glemt@arkivet.no", UserRole::EDITOR);
+
+                let request = Request::builder()
+                    .uri("/locations/-666/history")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 404
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }).await;
+        }
 
-            let bearer_token = create_user_and_generate_token(connection_pool, "birdman@ifi.uio.no", UserRole::READER);
+        #[tokio::test]
+        async fn get_locations_returns_400_on_overflowing_limit_and_offset() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "overflyt@paginering.no", UserRole::READER);
+
+                let request = Request::builder()
+                    .uri(format!("/locations?limit={}&offset={}", i64::MAX - 1, i64::MAX - 1))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
 
-            // Create a request with the aforementioned id
+                // Assert that the response status is 400
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }).await;
+        }
+
+        async fn get_locations_page(service: axum::Router, bearer_token: &str, query: &str) -> (StatusCode, serde_json::Value) {
             let request = Request::builder()
-                .uri(format!("/locations/{}", -666)) // Use a non-existent ID
+                .uri(format!("/locations?{}", query))
                 .method("GET")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
                 .body(Body::empty())
                 .unwrap();
 
@@ -552,92 +3395,802 @@ pub mod router {
                 .await
                 .unwrap();
 
-            // Assert that the response status is 404 as there are no locations associated with the id
-            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            (status, serde_json::from_slice(&body).unwrap())
         }
 
         #[tokio::test]
-        async fn delete_locations_returns_204_for_authorized_user_with_admin_role() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
-            let service = locations_route(connection_pool.clone());
+        async fn get_locations_walks_every_page_through_cursors() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
 
-            let bearer_token = create_user_and_generate_token(connection_pool,"you.know.your.judo.well@succulentmail.gb", UserRole::ADMIN);
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "markør@paginering.no", UserRole::READER).unwrap();
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+                let mut created_ids = Vec::new();
+                {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    let mut location_db = LocationsTable::new(connection);
+                    for index in 0..5 {
+                        created_ids.push(location_db.create(UpsertLocation {
+                            star_system: "Cursoria".to_string(),
+                            area: format!("Waypoint {}", index),
+                        }).expect("Create location failed").id);
+                    }
+                }
+
+                // The first page is an ordinary one, which hands out the cursor for the second
+                let (status, mut page) = get_locations_page(service.clone(), &bearer_token, "star_system=Cursoria&limit=2").await;
+                assert_eq!(status, StatusCode::OK);
+
+                let mut seen_ids = Vec::new();
+                let mut pages = 1;
+                loop {
+                    seen_ids.extend(page["items"].as_array().unwrap().iter().map(|location| location["id"].as_i64().unwrap() as i32));
+
+                    let Some(cursor) = page["next_cursor"].as_str().map(str::to_string) else { break };
+                    let (status, next_page) = get_locations_page(service.clone(), &bearer_token, &format!("star_system=Cursoria&limit=2&cursor={}", cursor)).await;
+                    assert_eq!(status, StatusCode::OK);
+                    assert!(next_page.get("total").is_none());
+
+                    page = next_page;
+                    pages += 1;
+                }
+
+                // Assert that every location was seen once, in order, over three pages
+                assert_eq!(seen_ids, created_ids);
+                assert_eq!(pages, 3);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_400_on_invalid_cursor_or_cursor_with_offset() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "ugyldig.markør@paginering.no", UserRole::READER).unwrap();
+
+                for query in ["cursor=%%%", "cursor=MQ&offset=0", "cursor=MQ&sort=created_at"] {
+                    let (status, response_json) = get_locations_page(service.clone(), &bearer_token, query).await;
+
+                    assert_eq!(status, StatusCode::BAD_REQUEST, "Expected 400 for '{}'", query);
+                    assert!(response_json["error"].is_string());
+                }
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_clamps_limit_to_max_page_size() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "grådig@paginering.no", UserRole::READER);
+
+                let request = Request::builder()
+                    .uri("/locations?limit=10000")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the request succeeds with the limit lowered to the cap
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json["limit"], json!(max_page_size()));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_filtered_by_star_system_returns_exact_matches_only() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "eksakt@filter.no", UserRole::READER);
+
+                let exact_location = location_db.create(UpsertLocation {
+                    star_system: "Exactia".to_string(),
+                    area: "Alpha".to_string(),
+                }).expect("Create location failed");
+                let similar_location = location_db.create(UpsertLocation {
+                    star_system: "Exactia Prime".to_string(),
+                    area: "Alpha".to_string(),
+                }).expect("Create location failed");
+
+                let request = Request::builder()
+                    .uri("/locations?star_system=Exactia&limit=200")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let items = response_json["items"].as_array().unwrap();
+
+                // Assert that only exact matches are returned, not ones merely containing the value
+                assert!(items.iter().all(|item| item["star_system"] == json!("Exactia")));
+                assert!(items.iter().any(|item| item["id"] == json!(exact_location.id)));
+                assert!(items.iter().all(|item| item["id"] != json!(similar_location.id)));
+                assert_eq!(response_json["total"], json!(items.len()));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_returns_link_header_preserving_query_params() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "lenke@sider.no", UserRole::READER);
+
+                for area in ["Første", "Andre", "Tredje"] {
+                    location_db.create(UpsertLocation {
+                        star_system: "Paginatus".to_string(),
+                        area: area.to_string(),
+                    }).expect("Create location failed");
+                }
+
+                let request = Request::builder()
+                    .uri("/locations?star_system=Paginatus&limit=1&offset=1")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                let link = response.headers().get(http::header::LINK).unwrap().to_str().unwrap();
+                assert_eq!(link, concat!(
+                    "</locations?star_system=Paginatus&limit=1&offset=0>; rel=\"first\", ",
+                    "</locations?star_system=Paginatus&limit=1&offset=0>; rel=\"prev\", ",
+                    "</locations?star_system=Paginatus&limit=1&offset=2>; rel=\"next\", ",
+                    "</locations?star_system=Paginatus&limit=1&offset=2>; rel=\"last\""
+                ));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_locations_returns_405_listing_the_allowed_methods() {
+            with_test_db(|connection_pool| async move {
+                let app = crate::create_app(AppState::test(connection_pool.clone()));
+
+                let request = Request::builder()
+                    .uri("/locations")
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap();
+
+                let response = app.oneshot(request).await.unwrap();
+
+                assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+                assert_eq!(response.headers()["allow"], "POST,GET,HEAD,DELETE");
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json["error"], "method not allowed");
+                assert_eq!(response_json["allowed"], json!(["POST", "GET", "HEAD", "DELETE"]));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn unknown_path_returns_json_404_echoing_the_path() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "borte@vekk.no", UserRole::READER).unwrap();
+
+                let get = |uri: &str| Request::builder()
+                    .uri(uri)
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                let response = crate::create_app(AppState::test(connection_pool.clone())).oneshot(get("/does-not-exist")).await.unwrap();
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json["error"], "not found");
+                assert_eq!(response_json["path"], "/does-not-exist");
+
+                // A missing location is still the handler's own 404
+                let response = crate::create_app(AppState::test(connection_pool.clone())).oneshot(get("/locations/999999")).await.unwrap();
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json["error"], "Location not found");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_is_gzip_compressed_when_accepted() {
+            with_test_db(|connection_pool| async move {
+                let app = crate::create_app(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "pakket@komprimert.no", UserRole::READER).unwrap();
+
+                // Enough rows to push the listing well past the compression threshold
+                {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    let mut location_db = LocationsTable::new(connection);
+                    for area in 0..30 {
+                        location_db.create(UpsertLocation {
+                            star_system: "Compressia".to_string(),
+                            area: format!("Sector {}", area),
+                        }).expect("Create location failed");
+                    }
+                }
+
+                let list_request = |accept_encoding: Option<&str>| {
+                    let mut request = Request::builder()
+                        .uri("/locations?star_system=Compressia")
+                        .method("GET")
+                        .header("Authorization", format!("Bearer {}", bearer_token)); // Add the bearer token
+                    if let Some(accept_encoding) = accept_encoding {
+                        request = request.header("Accept-Encoding", accept_encoding);
+                    }
+                    request.body(Body::empty()).unwrap()
+                };
+
+                let response = app.clone().oneshot(list_request(Some("gzip"))).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers().get(http::header::CONTENT_ENCODING).unwrap(), "gzip");
+
+                // Clients that don't ask for compression get the plain body
+                let response = app.oneshot(list_request(None)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert!(response.headers().get(http::header::CONTENT_ENCODING).is_none());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn small_responses_are_not_compressed() {
+            with_test_db(|connection_pool| async move {
+                let app = crate::create_app(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "liten@komprimert.no", UserRole::READER);
+
+                let request = Request::builder()
+                    .uri("/locations/-666")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .header("Accept-Encoding", "gzip")
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = app
+                    .oneshot(request)
+                    .await
+                    .unwrap();
 
-            // Create a new location with the above data
-            let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+                // Assert that the tiny 404 body is sent as is
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+                assert!(response.headers().get(http::header::CONTENT_ENCODING).is_none());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_filtered_by_star_system_and_q_applies_both() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "kombinert@filter.no", UserRole::READER);
+
+                let matching_location = location_db.create(UpsertLocation {
+                    star_system: "Combinatus".to_string(),
+                    area: "Beta Outpost".to_string(),
+                }).expect("Create location failed");
+                let wrong_area = location_db.create(UpsertLocation {
+                    star_system: "Combinatus".to_string(),
+                    area: "Gamma Outpost".to_string(),
+                }).expect("Create location failed");
+                let wrong_star_system = location_db.create(UpsertLocation {
+                    star_system: "Combinatus Minor".to_string(),
+                    area: "Beta Outpost".to_string(),
+                }).expect("Create location failed");
+
+                let request = Request::builder()
+                    .uri("/locations?star_system=Combinatus&q=beta&limit=200")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let ids: Vec<serde_json::Value> = response_json["items"].as_array().unwrap().iter()
+                    .map(|item| item["id"].clone())
+                    .collect();
+
+                // Assert that only rows satisfying both constraints are returned
+                assert!(ids.contains(&json!(matching_location.id)));
+                assert!(!ids.contains(&json!(wrong_area.id)));
+                assert!(!ids.contains(&json!(wrong_star_system.id)));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_export_since_streams_only_newer_rows_as_ndjson() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "inkrementell@backup.no", UserRole::READER);
+
+                let older_location = location_db.create(UpsertLocation {
+                    star_system: "Backupia".to_string(),
+                    area: "Before".to_string(),
+                }).expect("Create location failed");
+
+                // Make sure the second location is modified strictly after the cutoff
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                let since = chrono::Utc::now();
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+                let newer_location = location_db.create(UpsertLocation {
+                    star_system: "Backupia".to_string(),
+                    area: "After".to_string(),
+                }).expect("Create location failed");
+
+                let request = Request::builder()
+                    .uri(format!("/locations/export?format=ndjson&since={}", since.format("%Y-%m-%dT%H:%M:%S%.6fZ")))
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200 and the body is NDJSON
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+
+                // Extract body from response, every line being a location of its own
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                assert!(body.ends_with('\n'));
+
+                let ids: Vec<serde_json::Value> = body.lines()
+                    .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("Every line must be a JSON object")["id"].clone())
+                    .collect();
+
+                // Assert that only the location modified after 'since' is exported
+                assert!(ids.contains(&json!(newer_location.id)));
+                assert!(!ids.contains(&json!(older_location.id)));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_export_leaves_audit_fields_out_for_readers_only() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                LocationsTable::new(connection).create(UpsertLocation {
+                    star_system: "Backupia".to_string(),
+                    area: "Redacted".to_string(),
+                }).expect("Create location failed");
+
+                let reader_token = create_user_and_generate_token(connection_pool.clone(), "leser@eksport.no", UserRole::READER).unwrap();
+                let editor_token = create_user_and_generate_token(connection_pool.clone(), "redaktor@eksport.no", UserRole::EDITOR).unwrap();
+
+                let mut exported = Vec::new();
+                for bearer_token in [reader_token, editor_token] {
+                    let request = Request::builder()
+                        .uri("/locations/export?q=Redacted")
+                        .method("GET")
+                        .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                        .body(Body::empty())
+                        .unwrap();
+
+                    // Send the request through the service
+                    let response = locations_route(AppState::test(connection_pool.clone()))
+                        .oneshot(request)
+                        .await
+                        .unwrap();
+                    assert_eq!(response.status(), StatusCode::OK);
 
-            // Create a request with the ID associated with our newly inserted row
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    let line = String::from_utf8(body.to_vec()).unwrap();
+                    exported.push(serde_json::from_str::<serde_json::Value>(line.trim()).expect("Expected a single JSON line"));
+                }
+
+                // Assert that the READER gets the same row without the audit fields the EDITOR sees
+                let (reader_line, editor_line) = (&exported[0], &exported[1]);
+                assert!(reader_line.get("created_at").is_none());
+                assert!(reader_line.get("updated_at").is_none());
+                assert!(editor_line["created_at"].is_string());
+                assert!(editor_line["updated_at"].is_string());
+                assert_eq!(reader_line["id"], editor_line["id"]);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_export_as_csv_streams_a_header_row_and_matching_rows() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "regneark@analyse.no", UserRole::READER);
+
+                let quoted_location = location_db.create(UpsertLocation {
+                    star_system: "Tabulia".to_string(),
+                    area: "Rows, \"Columns\"".to_string(),
+                }).expect("Create location failed");
+                let other_location = location_db.create(UpsertLocation {
+                    star_system: "Elsewhere".to_string(),
+                    area: "Unmatched".to_string(),
+                }).expect("Create location failed");
+
+                let request = Request::builder()
+                    .uri("/locations/export?format=csv&q=tabul")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response is a CSV attachment
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()["content-type"], "text/csv");
+                assert_eq!(response.headers()["content-disposition"], "attachment; filename=\"locations.csv\"");
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                let lines: Vec<&str> = body.lines().collect();
+
+                // Assert that the header row comes first and only the location matching 'q' follows, quoted where needed
+                assert_eq!(lines, vec![
+                    "id,star_system,area".to_string(),
+                    format!("{},Tabulia,\"Rows, \"\"Columns\"\"\"", quoted_location.id),
+                ]);
+                assert!(!body.contains(&other_location.area));
+            }).await;
+        }
+
+        async fn post_import(service: axum::Router, bearer_token: &str, query: &str, csv: &str) -> (StatusCode, serde_json::Value) {
             let request = Request::builder()
-                .uri(format!("/locations/{}", created_location.id))
-                .method("DELETE")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::empty())
+                .uri(format!("/locations/import{}", query))
+                .method("POST")
+                .header("content-type", "text/csv")
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                .body(Body::from(csv.to_string()))
                 .unwrap();
 
-            // Send the request through the service
-            let response = service
-                .oneshot(request)
-                .await
-                .unwrap();
+            let response = service.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        #[tokio::test]
+        async fn post_locations_import_imports_every_line_of_a_clean_file() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "import@analyse.no", UserRole::WRITER).unwrap();
+
+                let csv = "star_system,area\nImportia,North\nImportia,\"South, Lower\"\n";
+                let (status, response_json) = post_import(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "", csv).await;
+
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json, json!({"imported": 2, "skipped": 0, "errors": []}));
+
+                // Assert that the rows were inserted as written, quoted field included
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let filter = LocationFilter { star_system: Some("Importia".to_string()), ..Default::default() };
+                let (imported, _) = LocationsTable::new(connection).list(&filter, 10, 0, LocationSort::IdAsc).expect("List locations failed");
+                let areas: Vec<&str> = imported.iter().map(|location| location.area.as_str()).collect();
+                assert_eq!(areas, vec!["North", "South, Lower"]);
+            }).await;
+        }
 
-            // Assert that the response status is 204
-            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        #[tokio::test]
+        async fn post_locations_import_reports_bad_lines_and_imports_the_rest() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "delvis@analyse.no", UserRole::WRITER).unwrap();
+
+                {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Importia".to_string(),
+                        area: "Existing".to_string(),
+                    }).expect("Create location failed");
+                }
+
+                let csv = "Importia,Good\nImportia\nImportia,\nImportia,Existing\nImportia,Good\nImportia,Also Good\n";
+                let (status, response_json) = post_import(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "", csv).await;
 
-            // Attempt to retrieve the deleted location
-            let deleted_location_result = location_db.get(created_location.id);
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json, json!({
+                    "imported": 2,
+                    "skipped": 4,
+                    "errors": [
+                        {"line": 2, "reason": "Expected 2 columns, got 1"},
+                        {"line": 3, "reason": "Field 'area' must not be empty"},
+                        {"line": 4, "reason": "location already exists"},
+                        {"line": 5, "reason": "Duplicate of line 1"}
+                    ]
+                }));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_import_in_strict_mode_imports_nothing_when_a_line_is_bad() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "streng@analyse.no", UserRole::WRITER).unwrap();
 
-            // Assert that the Result is Ok (no error)
-            assert!(deleted_location_result.is_ok());
+                let csv = "Strictia,Good\nStrictia\n";
+                let (status, response_json) = post_import(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "?strict=true", csv).await;
 
-            // Extract the Option<Location> from the Ok variant
-            let deleted_location = deleted_location_result.unwrap();
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+                assert_eq!(response_json["errors"], json!([{"line": 2, "reason": "Expected 2 columns, got 1"}]));
 
-            // Assert that the deleted location is None (i.e., it doesn't exist)
-            assert!(deleted_location.is_none());
+                // Assert that the good line wasn't imported either
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let filter = LocationFilter { star_system: Some("Strictia".to_string()), ..Default::default() };
+                let (_, total) = LocationsTable::new(connection).list(&filter, 10, 0, LocationSort::IdAsc).expect("List locations failed");
+                assert_eq!(total, 0);
+            }).await;
         }
 
         #[tokio::test]
-        async fn delete_locations_returns_401_for_unauthorized_user_without_admin_role() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
-            let service = locations_route(connection_pool.clone());
+        async fn post_locations_import_requires_writer_and_csv() {
+            with_test_db(|connection_pool| async move {
+                let reader_token = create_user_and_generate_token(connection_pool.clone(), "leser@analyse.no", UserRole::READER).unwrap();
+                let (status, _) = post_import(locations_route(AppState::test(connection_pool.clone())), &reader_token, "", "Importia,North\n").await;
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+                let writer_token = create_user_and_generate_token(connection_pool.clone(), "skriver@analyse.no", UserRole::WRITER).unwrap();
+                let request = Request::builder()
+                    .uri("/locations/import")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", writer_token)) // Add the bearer token
+                    .body(Body::from("[]"))
+                    .unwrap();
+                let response = locations_route(AppState::test(connection_pool.clone())).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }).await;
+        }
 
-            let bearer_token = create_user_and_generate_token(connection_pool,"donttouchmys@p.succulentor.gb", UserRole::EDITOR);
+        #[tokio::test]
+        async fn post_locations_import_validate_reports_every_line_and_imports_nothing() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "kontroll@analyse.no", UserRole::WRITER).unwrap();
 
-            let request_body = UpsertLocation {
-                star_system: "Fountain".to_string(),
-                area: "The Serpent's Lair".to_string(),
-            };
+                {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    LocationsTable::new(connection).create(UpsertLocation {
+                        star_system: "Validia".to_string(),
+                        area: "Existing".to_string(),
+                    }).expect("Create location failed");
+                }
 
-            // Create a new location with the above data
-            let created_location = location_db.create(request_body.clone()).expect("Create location failed");
+                let csv = "star_system,area\nValidia,Good\nValidia\nValidia,\nValidia,Existing\nValidia,Good\nValidia,Also Good\n";
+                let (status, response_json) = post_import(locations_route(AppState::test(connection_pool.clone())), &bearer_token, "/validate", csv).await;
 
-            // Create a request with the ID associated with our newly inserted row
+                // Assert that every line but the header gets the outcome the import would give it
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json, json!({
+                    "valid": 2,
+                    "invalid": 4,
+                    "results": [
+                        {"line": 2, "valid": true},
+                        {"line": 3, "valid": false, "reason": "Expected 2 columns, got 1"},
+                        {"line": 4, "valid": false, "reason": "Field 'area' must not be empty"},
+                        {"line": 5, "valid": false, "reason": "location already exists"},
+                        {"line": 6, "valid": false, "reason": "Duplicate of line 2"},
+                        {"line": 7, "valid": true}
+                    ]
+                }));
+
+                // Assert that only the location created above exists
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let filter = LocationFilter { star_system: Some("Validia".to_string()), ..Default::default() };
+                let (_, total) = LocationsTable::new(connection).list(&filter, 10, 0, LocationSort::IdAsc).expect("List locations failed");
+                assert_eq!(total, 1);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn get_locations_export_returns_400_on_invalid_since() {
+            with_test_db(|connection_pool| async move {
+                let service = locations_route(AppState::test(connection_pool.clone()));
+
+                let bearer_token = create_user_and_generate_token(connection_pool, "ugyldig.tid@backup.no", UserRole::READER);
+
+                let request = Request::builder()
+                    .uri("/locations/export?since=yesterday")
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 400
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }).await;
+        }
+
+        // Helper sending POST /locations with the given Idempotency-Key and returning the status and parsed body
+        async fn post_location_with_idempotency_key(connection_pool: ConnectionPool, bearer_token: &str, idempotency_key: &str, body: &UpsertLocation) -> (StatusCode, serde_json::Value) {
             let request = Request::builder()
-                .uri(format!("/locations/{}", created_location.id))
-                .method("DELETE")
-                .header("Authorization", format!("Bearer {}", bearer_token.unwrap())) // Add the bearer token
-                .body(Body::empty())
+                .uri("/locations")
+                .method("POST")
+                .header("content-type", "application/json")
+                .header("Idempotency-Key", idempotency_key)
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                .body(Body::from(serde_json::to_string(body).unwrap()))
                 .unwrap();
 
             // Send the request through the service
-            let response = service
+            let response = locations_route(AppState::test(connection_pool))
                 .oneshot(request)
                 .await
                 .unwrap();
 
-            // Assert that the response status is 401
-            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        #[tokio::test]
+        async fn post_locations_with_repeated_idempotency_key_returns_original_location() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "gjentatt@idempotent.no", UserRole::WRITER).unwrap();
+
+                let request_body = UpsertLocation {
+                    star_system: "Retry".to_string(),
+                    area: "Flaky Network Nebula".to_string(),
+                };
+
+                let (first_status, first_location) = post_location_with_idempotency_key(connection_pool.clone(), &bearer_token, "retry-flaky-network-1", &request_body).await;
+                let (second_status, second_location) = post_location_with_idempotency_key(connection_pool.clone(), &bearer_token, "retry-flaky-network-1", &request_body).await;
+
+                // Assert that both responses are 201 and describe the same location
+                assert_eq!(first_status, StatusCode::CREATED);
+                assert_eq!(second_status, StatusCode::CREATED);
+                assert_eq!(first_location, second_location);
+
+                // Assert that only one location was inserted
+                let filter = LocationFilter { q: None, star_system: Some("Retry".to_string()) };
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let (_, total) = LocationsTable::new(connection).list(&filter, 10, 0, LocationSort::default()).expect("List locations failed");
+                assert_eq!(total, 1);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_with_reused_idempotency_key_and_different_body_returns_409() {
+            with_test_db(|connection_pool| async move {
+                let bearer_token = create_user_and_generate_token(connection_pool.clone(), "gjenbrukt@idempotent.no", UserRole::WRITER).unwrap();
+
+                let first_body = UpsertLocation {
+                    star_system: "Reuse".to_string(),
+                    area: "First Area".to_string(),
+                };
+                let second_body = UpsertLocation {
+                    star_system: "Reuse".to_string(),
+                    area: "Second Area".to_string(),
+                };
+
+                let (first_status, _) = post_location_with_idempotency_key(connection_pool.clone(), &bearer_token, "reused-key-1", &first_body).await;
+                let (second_status, _) = post_location_with_idempotency_key(connection_pool, &bearer_token, "reused-key-1", &second_body).await;
+
+                // Assert that the first request succeeded and the mismatching retry conflicts
+                assert_eq!(first_status, StatusCode::CREATED);
+                assert_eq!(second_status, StatusCode::CONFLICT);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_locations_with_another_users_idempotency_key_creates_a_location_of_its_own() {
+            with_test_db(|connection_pool| async move {
+                let first_token = create_user_and_generate_token(connection_pool.clone(), "forste@idempotent.no", UserRole::WRITER).unwrap();
+                let second_token = create_user_and_generate_token(connection_pool.clone(), "andre@idempotent.no", UserRole::WRITER).unwrap();
+
+                let first_body = UpsertLocation {
+                    star_system: "Shared".to_string(),
+                    area: "First User's Area".to_string(),
+                };
+                let second_body = UpsertLocation {
+                    star_system: "Shared".to_string(),
+                    area: "Second User's Area".to_string(),
+                };
+
+                let (first_status, first_location) = post_location_with_idempotency_key(connection_pool.clone(), &first_token, "shared-key-1", &first_body).await;
+                let (second_status, second_location) = post_location_with_idempotency_key(connection_pool, &second_token, "shared-key-1", &second_body).await;
+
+                // Assert that the second user neither sees the first user's location nor is refused for their body
+                assert_eq!(first_status, StatusCode::CREATED);
+                assert_eq!(second_status, StatusCode::CREATED);
+                assert_ne!(first_location["id"], second_location["id"]);
+                assert_eq!(second_location["area"], json!("Second User's Area"));
+            }).await;
+        }
+
+        // Counts the locations in star system "Isolated" after creating the given number of them
+        async fn isolated_location_count(connection_pool: ConnectionPool, created: usize) -> i64 {
+            for index in 0..created {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                LocationsTable::new(connection).create(UpsertLocation {
+                    star_system: "Isolated".to_string(),
+                    area: format!("Quarantine Zone {}", index),
+                }).expect("Create location failed");
+                tokio::task::yield_now().await;
+            }
+
+            let filter = LocationFilter { q: None, star_system: Some("Isolated".to_string()) };
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let (_, total) = LocationsTable::new(connection).list(&filter, 10, 0, LocationSort::default()).expect("List locations failed");
+            total
+        }
+
+        #[tokio::test]
+        async fn concurrent_test_databases_do_not_see_each_others_rows() {
+            let (first_total, second_total) = tokio::join!(
+                with_test_db(|connection_pool| isolated_location_count(connection_pool, 2)),
+                with_test_db(|connection_pool| isolated_location_count(connection_pool, 3)),
+            );
+
+            // Assert that each schema only holds the locations created in it
+            assert_eq!(first_total, 2);
+            assert_eq!(second_total, 3);
         }
     }
 }
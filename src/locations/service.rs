@@ -1,37 +1,276 @@
 pub mod service {
+    use chrono::{DateTime, Duration, Utc};
     use diesel::{
+        dsl::{count, count_star, now, sql},
+        pg::Pg,
         prelude::*,
         PgConnection,
         r2d2::{ConnectionManager, PooledConnection},
+        sql_types::{BigInt, Nullable},
     };
     use crate::{
-        locations::model::{Location, UpsertLocation},
+        locations::changes::{change_notifications_enabled, notify_change, LocationChange},
+        locations::model::{
+            AreaStats, IdempotencyKey, Location, LocationAuditEntry, LocationFilter, LocationSort, PatchLocation, StarSystemCount, UpsertLocation
+        },
         schema
     };
 
     type PooledPg = PooledConnection<ConnectionManager<PgConnection>>;
 
+    // How long an Idempotency-Key is remembered before the same key creates a new location
+    pub const IDEMPOTENCY_KEY_TTL_HOURS: i64 = 24;
+
+    // Recorded as the actor of changes made without an authenticated user, such as in tests and scripts
+    const SYSTEM_ACTOR: &str = "system";
+
+    // Escapes LIKE wildcards so user input is matched literally
+    fn escape_like(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+    }
+
+    // Builds the filtered base query shared by the page and its total count. The exact star_system match lives here rather
+    // than in a separate list_by_star_system, so offset pages, cursor pages and the changes feed all combine it with 'q'
+    fn filtered_locations(filter: &LocationFilter) -> schema::locations::BoxedQuery<'static, Pg> {
+        use schema::locations;
+
+        let mut query = locations::table.into_boxed();
+
+        // Exact match, as opposed to the free-text search below
+        if let Some(star_system) = &filter.star_system {
+            query = query.filter(locations::star_system.eq(star_system.clone()));
+        }
+
+        if let Some(q) = &filter.q {
+            let pattern = format!("%{}%", escape_like(q));
+            query = query.filter(locations::star_system.ilike(pattern.clone()).or(locations::area.ilike(pattern)));
+        }
+
+        query
+    }
+
+    // Writes a row of the location's history, which has to happen in the same transaction as the change itself
+    fn record_change(connection: &mut PgConnection, location_id: i32, action: &str, recorder: &ChangeRecorder, before: Option<&Location>, after: Option<&Location>) -> Result<(), diesel::result::Error> {
+        use schema::location_audit;
+
+        let as_json = |location: Option<&Location>| location
+            .map(|location| serde_json::to_value(location).expect("Failed to serialize location"));
+
+        diesel::insert_into(location_audit::table)
+            .values((
+                location_audit::location_id.eq(location_id),
+                location_audit::action.eq(action),
+                location_audit::actor.eq(&recorder.actor),
+                location_audit::before.eq(as_json(before)),
+                location_audit::after.eq(as_json(after)),
+            ))
+            .execute(connection)?;
+
+        if recorder.notify {
+            notify_change(connection, &LocationChange { id: location_id, action: action.to_string() })?;
+        }
+
+        Ok(())
+    }
+
+    // Replaces the locked location with the new values and records the change
+    fn replace(connection: &mut PgConnection, existing_location: &Location, upsert_location: &UpsertLocation, recorder: &ChangeRecorder) -> Result<Location, diesel::result::Error> {
+        use schema::locations;
+
+        let updated_location: Location = diesel::update(locations::table.find(existing_location.id))
+            .set((
+                locations::star_system.eq(&upsert_location.star_system),
+                locations::area.eq(&upsert_location.area),
+                locations::updated_at.eq(now),
+            ))
+            .get_result(connection)?;
+
+        record_change(connection, existing_location.id, "update", recorder, Some(existing_location), Some(&updated_location))?;
+
+        Ok(updated_location)
+    }
+
+    // What upsert_if did with the location, which it returns either way
+    #[derive(Debug)]
+    pub enum Upserted {
+        Created(Location),
+        Updated(Location),
+
+        // Nothing was created, as the id is past the last one the sequence handed out
+        Unassigned,
+    }
+
+    // Who changes go on record as in the location history, and whether they are announced on location_changes
+    struct ChangeRecorder {
+        actor: String,
+        notify: bool,
+    }
+
     pub struct LocationsTable {
         connection: PooledPg,
+        recorder: ChangeRecorder,
     }
 
     impl LocationsTable {
         pub fn new(connection: PooledPg) -> LocationsTable {
-            LocationsTable { connection }
+            LocationsTable {
+                connection,
+                recorder: ChangeRecorder { actor: SYSTEM_ACTOR.to_string(), notify: change_notifications_enabled() },
+            }
+        }
+
+        // Attributes the changes made through this table to the given user in the location history
+        pub fn acting_as(mut self, actor: &str) -> LocationsTable {
+            self.recorder.actor = actor.to_string();
+            self
+        }
+
+        // Announces changes whether or not ENABLE_NOTIFY turned notifications on for the whole server
+        #[cfg(test)]
+        pub fn notifying_changes(mut self, notify: bool) -> LocationsTable {
+            self.recorder.notify = notify;
+            self
         }
 
+        // The location and its history row are written in one transaction, so a create that can't be recorded leaves
+        // no location behind
         pub fn create(&mut self, upsert_location: UpsertLocation) -> Result<Location, diesel::result::Error> {
             use schema::locations;
 
-            let new_location = diesel::insert_into(locations::table)
-                .values((
-                    locations::star_system.eq(&upsert_location.star_system),
-                    locations::area.eq(&upsert_location.area),
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+                let new_location: Location = diesel::insert_into(locations::table)
+                    .values((
+                        locations::star_system.eq(&upsert_location.star_system),
+                        locations::area.eq(&upsert_location.area),
+                    ))
+                    .get_result(connection)?;
+
+                record_change(connection, new_location.id, "create", recorder, None, Some(&new_location))?;
+
+                Ok(new_location)
+            })
+        }
+
+        // Creates the locations in one transaction. With skip_existing, locations sharing star system and area with
+        // one that exists are left out rather than failing the lot, and only the locations created are returned
+        pub fn create_many(&mut self, upsert_locations: &[UpsertLocation], skip_existing: bool) -> Result<Vec<Location>, diesel::result::Error> {
+            use schema::locations;
+
+            // Diesel refuses to build an INSERT without rows
+            if upsert_locations.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+                let values: Vec<_> = upsert_locations.iter()
+                    .map(|upsert_location| (
+                        locations::star_system.eq(&upsert_location.star_system),
+                        locations::area.eq(&upsert_location.area),
+                    ))
+                    .collect();
+
+                let insert = diesel::insert_into(locations::table).values(&values);
+                let new_locations: Vec<Location> = if skip_existing {
+                    insert.on_conflict((locations::star_system, locations::area)).do_nothing().get_results(connection)?
+                } else {
+                    insert.get_results(connection)?
+                };
+
+                for new_location in &new_locations {
+                    record_change(connection, new_location.id, "create", recorder, None, Some(new_location))?;
+                }
+
+                Ok(new_locations)
+            })
+        }
+
+        // Returns the locations sharing star system and area with any of the given ones
+        pub fn existing(&mut self, upsert_locations: &[UpsertLocation]) -> Result<Vec<Location>, diesel::result::Error> {
+            use schema::locations;
+
+            let star_systems: Vec<&str> = upsert_locations.iter().map(|upsert_location| upsert_location.star_system.as_str()).collect();
+            let areas: Vec<&str> = upsert_locations.iter().map(|upsert_location| upsert_location.area.as_str()).collect();
+
+            // Both lists match more pairs than were asked for, those are dropped here rather than in SQL
+            let candidates = locations::table
+                .filter(locations::star_system.eq_any(star_systems))
+                .filter(locations::area.eq_any(areas))
+                .load::<Location>(&mut self.connection)?;
+
+            Ok(candidates.into_iter()
+                .filter(|location| upsert_locations.iter().any(|upsert_location|
+                    upsert_location.star_system == location.star_system && upsert_location.area == location.area
                 ))
-                .get_result(&mut self.connection)
-                .expect("Create location failed");
+                .collect())
+        }
+
+        // Creates the location and remembers the key in one transaction, so a key never points at a missing row. Keys are
+        // the user's own, another user sending the same key creates a location of their own
+        pub fn create_with_idempotency_key(&mut self, upsert_location: UpsertLocation, user_id: i32, idempotency_key: &str, request_body: &str) -> Result<Location, diesel::result::Error> {
+            use schema::{idempotency_keys, locations};
+
+            let expired_before = Utc::now() - Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS);
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+
+                // An expired key may be reused, so its previous entry has to make room
+                diesel::delete(idempotency_keys::table
+                    .filter(idempotency_keys::user_id.eq(user_id))
+                    .filter(idempotency_keys::idempotency_key.eq(idempotency_key))
+                    .filter(idempotency_keys::created_at.le(expired_before)))
+                    .execute(connection)?;
+
+                let new_location: Location = diesel::insert_into(locations::table)
+                    .values((
+                        locations::star_system.eq(&upsert_location.star_system),
+                        locations::area.eq(&upsert_location.area),
+                    ))
+                    .get_result(connection)?;
+
+                diesel::insert_into(idempotency_keys::table)
+                    .values((
+                        idempotency_keys::user_id.eq(user_id),
+                        idempotency_keys::idempotency_key.eq(idempotency_key),
+                        idempotency_keys::request_body.eq(request_body),
+                        idempotency_keys::location_id.eq(new_location.id),
+                    ))
+                    .execute(connection)?;
+
+                record_change(connection, new_location.id, "create", recorder, None, Some(&new_location))?;
+
+                Ok(new_location)
+            })
+        }
+
+        // Returns the user's key entry and the location it created, unless the user never sent the key or it has expired
+        pub fn get_by_idempotency_key(&mut self, user_id: i32, idempotency_key: &str) -> Result<Option<(IdempotencyKey, Location)>, diesel::result::Error> {
+            use schema::{idempotency_keys, locations};
+
+            let expired_before = Utc::now() - Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS);
 
-            Ok(new_location)
+            let stored_key = idempotency_keys::table
+                .filter(idempotency_keys::user_id.eq(user_id))
+                .filter(idempotency_keys::idempotency_key.eq(idempotency_key))
+                .filter(idempotency_keys::created_at.gt(expired_before))
+                .select((idempotency_keys::request_body, idempotency_keys::location_id))
+                .get_result::<IdempotencyKey>(&mut self.connection)
+                .optional()?;
+
+            match stored_key {
+                Some(stored_key) => {
+                    let location = locations::table.find(stored_key.location_id)
+                        .get_result::<Location>(&mut self.connection)
+                        .optional()?;
+
+                    Ok(location.map(|location| (stored_key, location)))
+                }
+                None => Ok(None)
+            }
         }
 
         pub fn get(&mut self, location_id: i32) -> Result<Option<Location>, diesel::result::Error> {
@@ -44,180 +283,659 @@ pub mod service {
             Ok(location)
         }
 
-        pub fn update(&mut self, location_id: i32, upsert_location: UpsertLocation) -> Result<Location, diesel::result::Error> {
+        // Returns a page of the locations matching the filter along with the total number of matches
+        pub fn list(&mut self, filter: &LocationFilter, limit: i64, offset: i64, sort: LocationSort) -> Result<(Vec<Location>, i64), diesel::result::Error> {
             use schema::locations;
 
-            // Check if the location exists before attempting to update
-            let existing_location = locations::table.find(location_id)
-                .get_result::<Location>(&mut self.connection);
+            let query = filtered_locations(filter);
+            let query = match sort {
+                LocationSort::IdAsc => query.order(locations::id.asc()),
+                LocationSort::IdDesc => query.order(locations::id.desc()),
+                LocationSort::StarSystemAsc => query.order(locations::star_system.asc()),
+                LocationSort::StarSystemDesc => query.order(locations::star_system.desc()),
+                LocationSort::AreaAsc => query.order(locations::area.asc()),
+                LocationSort::AreaDesc => query.order(locations::area.desc()),
+                LocationSort::CreatedAtAsc => query.order(locations::created_at.asc()),
+                LocationSort::CreatedAtDesc => query.order(locations::created_at.desc()),
+                LocationSort::UpdatedAtAsc => query.order(locations::updated_at.asc()),
+                LocationSort::UpdatedAtDesc => query.order(locations::updated_at.desc()),
+            };
+
+            // Break ties on id so pages are stable when the sorted values are equal
+            let items = query
+                .then_order_by(locations::id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load::<Location>(&mut self.connection)?;
+
+            let total = filtered_locations(filter)
+                .count()
+                .get_result::<i64>(&mut self.connection)?;
+
+            Ok((items, total))
+        }
+
+        // Returns up to 'limit' of the locations matching the filter with ids above 'after_id', in id order. Unlike offsets,
+        // seeking past the previous page costs the same however deep into the listing it is
+        pub fn list_after(&mut self, filter: &LocationFilter, after_id: i32, limit: i64) -> Result<Vec<Location>, diesel::result::Error> {
+            use schema::locations;
+
+            filtered_locations(filter)
+                .filter(locations::id.gt(after_id))
+                .order(locations::id.asc())
+                .limit(limit)
+                .load::<Location>(&mut self.connection)
+        }
 
-            match existing_location {
-                Ok(_) => {
-                    let updated_location = diesel::update(locations::table.find(location_id))
-                        .set((
-                            locations::star_system.eq(&upsert_location.star_system),
-                            locations::area.eq(&upsert_location.area),
-                        ))
-                        .get_result(&mut self.connection)
-                        .expect("Update location failed");
+        // Returns the next page of locations matching the filter and modified at or after 'since', continuing after the
+        // id of the previous page
+        pub fn changed_since(&mut self, filter: &LocationFilter, since: Option<DateTime<Utc>>, after_id: i32, limit: i64) -> Result<Vec<Location>, diesel::result::Error> {
+            use schema::locations;
+
+            let mut query = filtered_locations(filter)
+                .filter(locations::id.gt(after_id));
 
-                    Ok(updated_location)
-                },
-                Err(_) => Err(diesel::result::Error::NotFound)
+            // Rows touched in the same instant as 'since' are included, as exporting them twice is harmless but missing them is not
+            if let Some(since) = since {
+                query = query.filter(locations::updated_at.ge(since));
             }
+
+            query
+                .order(locations::id.asc())
+                .limit(limit)
+                .load::<Location>(&mut self.connection)
         }
 
+        // Returns up to 'limit' locations no further than 'radius' from the point, nearest first. Locations without
+        // coordinates are never within any radius. Squared distances are compared, which orders the same as the distance
+        pub fn find_within_radius(&mut self, x: f64, y: f64, z: f64, radius: f64, limit: i64) -> Result<Vec<Location>, diesel::result::Error> {
+            use schema::locations;
+
+            let squared_distance = || {
+                (locations::x - x) * (locations::x - x)
+                    + (locations::y - y) * (locations::y - y)
+                    + (locations::z - z) * (locations::z - z)
+            };
+
+            locations::table
+                .filter(squared_distance().le(radius * radius))
+                .order((squared_distance().asc(), locations::id.asc()))
+                .limit(limit)
+                .load::<Location>(&mut self.connection)
+        }
+
+        // Counts the distinct areas per star system, optionally narrowed down to a single system
+        pub fn area_stats(&mut self, star_system: Option<&str>) -> Result<Vec<AreaStats>, diesel::result::Error> {
+            use schema::locations;
+
+            let mut query = locations::table
+                .group_by(locations::star_system)
+                .select((locations::star_system, count(locations::area).aggregate_distinct()))
+                .into_boxed();
+
+            if let Some(star_system) = star_system {
+                query = query.filter(locations::star_system.eq(star_system.to_string()));
+            }
+
+            query
+                .order(locations::star_system.asc())
+                .load::<AreaStats>(&mut self.connection)
+        }
+
+        // Counts the locations per star system, most first and ties in name order, keeping only the top 'limit' when given
+        pub fn count_by_star_system(&mut self, limit: Option<i64>) -> Result<Vec<StarSystemCount>, diesel::result::Error> {
+            use schema::locations;
+
+            let mut query = locations::table
+                .group_by(locations::star_system)
+                .select((locations::star_system, count_star()))
+                .order((count_star().desc(), locations::star_system.asc()))
+                .into_boxed();
+
+            if let Some(limit) = limit {
+                query = query.limit(limit);
+            }
+
+            query.load::<StarSystemCount>(&mut self.connection)
+        }
+
+        // The API replaces locations through upsert_if, this is for callers that expect a missing location to be an error
+        #[allow(dead_code)]
+        pub fn update(&mut self, location_id: i32, upsert_location: UpsertLocation) -> Result<Location, diesel::result::Error> {
+            use schema::locations;
+
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+
+                // Locking the row keeps the recorded 'before' in step with concurrent updates
+                let existing_location = locations::table.find(location_id)
+                    .for_update()
+                    .get_result::<Location>(connection)?;
+
+                replace(connection, &existing_location, &upsert_location, recorder)
+            })
+        }
+
+        // Creates the location under the given id when there is none, or replaces it when the precondition holds for it
+        // as stored. Returns None when the precondition failed. Only ids the sequence already handed out may be created,
+        // so locations created later without an id never collide with them and clients can't move the sequence along
+        pub fn upsert_if(&mut self, location_id: i32, upsert_location: UpsertLocation, precondition: impl FnOnce(&Location) -> bool) -> Result<Option<Upserted>, diesel::result::Error> {
+            use schema::locations;
+
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+                let existing_location = locations::table.find(location_id)
+                    .for_update()
+                    .get_result::<Location>(connection)
+                    .optional()?;
+
+                if let Some(existing_location) = existing_location {
+                    if !precondition(&existing_location) {
+                        return Ok(None);
+                    }
+
+                    return replace(connection, &existing_location, &upsert_location, recorder).map(|location| Some(Upserted::Updated(location)));
+                }
+
+                // The sequence only moves forward, so an id it hasn't reached yet stays refused for the whole transaction
+                let last_assigned_id = diesel::select(sql::<Nullable<BigInt>>(
+                    "pg_sequence_last_value(pg_get_serial_sequence('locations', 'id')::regclass)"
+                ))
+                    .get_result::<Option<i64>>(connection)?;
+
+                if i64::from(location_id) > last_assigned_id.unwrap_or(0) {
+                    return Ok(Some(Upserted::Unassigned));
+                }
+
+                // A concurrent PUT to the same id may insert it first, in which case this one replaces it like an update
+                let upserted_location: Location = diesel::insert_into(locations::table)
+                    .values((
+                        locations::id.eq(location_id),
+                        locations::star_system.eq(&upsert_location.star_system),
+                        locations::area.eq(&upsert_location.area),
+                    ))
+                    .on_conflict(locations::id)
+                    .do_update()
+                    .set((
+                        locations::star_system.eq(&upsert_location.star_system),
+                        locations::area.eq(&upsert_location.area),
+                        locations::updated_at.eq(now),
+                    ))
+                    .get_result(connection)?;
+
+                record_change(connection, location_id, "create", recorder, None, Some(&upserted_location))?;
+
+                Ok(Some(Upserted::Created(upserted_location)))
+            })
+        }
+
+        pub fn patch(&mut self, location_id: i32, patch_location: PatchLocation) -> Result<Location, diesel::result::Error> {
+            use schema::locations;
+
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+                let existing_location = locations::table.find(location_id)
+                    .for_update()
+                    .get_result::<Location>(connection)?;
+
+                // Diesel refuses to build an empty UPDATE, and there is nothing to change or record anyway
+                if patch_location.is_empty() {
+                    return Ok(existing_location);
+                }
+
+                let patched_location: Location = diesel::update(locations::table.find(location_id))
+                    .set((&patch_location, locations::updated_at.eq(now)))
+                    .get_result(connection)?;
+
+                record_change(connection, location_id, "update", recorder, Some(&existing_location), Some(&patched_location))?;
+
+                Ok(patched_location)
+            })
+        }
+
+        // The API always deletes through delete_if, this is for callers that have no version to check against
+        #[allow(dead_code)]
         pub fn delete(&mut self, location_id: i32) -> Result<(), diesel::result::Error> {
+            self.delete_if(location_id, |_| true).map(|_| ())
+        }
+
+        // Deletes the location only when the precondition holds for it as stored, checked while holding the row lock so
+        // it can't change in between. Returns the location as it was when deleted, None when the precondition failed, or
+        // NotFound when there is no such location
+        pub fn delete_if(&mut self, location_id: i32, precondition: impl FnOnce(&Location) -> bool) -> Result<Option<Location>, diesel::result::Error> {
             use schema::locations;
 
-            // Check if the location exists before attempting to delete
-            let existing_location = locations::table.find(location_id)
-                .get_result::<Location>(&mut self.connection);
+            let recorder = &self.recorder;
 
-            match existing_location {
-                Ok(_) => {
-                    diesel::delete(locations::table.find(location_id))
-                        .execute(&mut self.connection)?;
-                    Ok(())
-                },
-                Err(_) => {
-                    Err(diesel::result::Error::NotFound)
+            self.connection.transaction(|connection| {
+                let existing_location = locations::table.find(location_id)
+                    .for_update()
+                    .get_result::<Location>(connection)?;
+
+                if !precondition(&existing_location) {
+                    return Ok(None);
                 }
-            }
+
+                diesel::delete(locations::table.find(location_id))
+                    .execute(connection)?;
+
+                record_change(connection, location_id, "delete", recorder, Some(&existing_location), None)?;
+
+                Ok(Some(existing_location))
+            })
+        }
+
+        // Deletes every location with one of the ids in a single statement and returns how many there were
+        pub fn delete_many(&mut self, location_ids: &[i32]) -> Result<usize, diesel::result::Error> {
+            use schema::locations;
+
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+                let deleted_locations: Vec<Location> = diesel::delete(locations::table.filter(locations::id.eq_any(location_ids)))
+                    .get_results(connection)?;
+
+                for deleted_location in &deleted_locations {
+                    record_change(connection, deleted_location.id, "delete", recorder, Some(deleted_location), None)?;
+                }
+
+                Ok(deleted_locations.len())
+            })
+        }
+
+        // Deletes every location in the star system in a single statement and returns how many there were
+        pub fn delete_by_star_system(&mut self, star_system: &str) -> Result<usize, diesel::result::Error> {
+            use schema::locations;
+
+            let recorder = &self.recorder;
+
+            self.connection.transaction(|connection| {
+                let deleted_locations: Vec<Location> = diesel::delete(locations::table.filter(locations::star_system.eq(star_system)))
+                    .get_results(connection)?;
+
+                for deleted_location in &deleted_locations {
+                    record_change(connection, deleted_location.id, "delete", recorder, Some(deleted_location), None)?;
+                }
+
+                Ok(deleted_locations.len())
+            })
+        }
+
+        // A page of the location's history, oldest first, optionally only the changes of one kind, along with how many
+        // changes there are in all pages
+        pub fn history(&mut self, location_id: i32, action: Option<&str>, limit: i64, offset: i64) -> Result<(Vec<LocationAuditEntry>, i64), diesel::result::Error> {
+            use schema::location_audit;
+
+            let filtered_history = || {
+                let mut query = location_audit::table
+                    .filter(location_audit::location_id.eq(location_id))
+                    .into_boxed();
+
+                if let Some(action) = action {
+                    query = query.filter(location_audit::action.eq(action.to_string()));
+                }
+
+                query
+            };
+
+            let entries = filtered_history()
+                .order(location_audit::id.asc())
+                .limit(limit)
+                .offset(offset)
+                .load::<LocationAuditEntry>(&mut self.connection)?;
+
+            let total = filtered_history()
+                .count()
+                .get_result::<i64>(&mut self.connection)?;
+
+            Ok((entries, total))
+        }
+
+        pub fn has_history(&mut self, location_id: i32) -> Result<bool, diesel::result::Error> {
+            use schema::location_audit;
+
+            diesel::select(diesel::dsl::exists(location_audit::table.filter(location_audit::location_id.eq(location_id))))
+                .get_result(&mut self.connection)
         }
     }
 
     #[cfg(test)]
     mod tests {
+        use diesel::{connection::SimpleConnection, result::{DatabaseErrorKind, Error::DatabaseError}};
         use crate::{
-            common::{
-                db::create_shared_connection_pool,
-                util::load_environment_variable
-            },
+            common::test_db::with_test_db,
             locations::{
-                model::UpsertLocation,
+                model::{AreaStats, LocationFilter, LocationSort, PatchLocation, UpsertLocation},
                 service::service::LocationsTable
             }
         };
 
-        #[test]
-        fn create_succeeds_on_valid_input() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
+        #[tokio::test]
+        async fn create_succeeds_on_valid_input() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
 
-            let new_location = UpsertLocation {
-                star_system: "Test Star System".to_string(),
-                area: "Test Area".to_string(),
-            };
+                let new_location = UpsertLocation {
+                    star_system: "Test Star System".to_string(),
+                    area: "Test Area".to_string(),
+                };
 
-            let created_location = location_db.create(new_location.clone()).expect("Create location failed");
+                let created_location = location_db.create(new_location.clone()).expect("Create location failed");
 
-            assert_eq!(created_location.star_system, new_location.star_system);
-            assert_eq!(created_location.area, new_location.area);
+                assert_eq!(created_location.star_system, new_location.star_system);
+                assert_eq!(created_location.area, new_location.area);
+            }).await;
         }
 
 
-        #[test]
-        fn read_succeeds_on_existing_id() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
+        #[tokio::test]
+        async fn create_is_rolled_back_when_its_history_row_fails() {
+            with_test_db(|connection_pool| async move {
 
-            let new_location = UpsertLocation {
-                star_system: "Test Star System".to_string(),
-                area: "Test Area".to_string(),
-            };
-            let created_location = location_db.create(new_location.clone()).expect("Create location failed");
+                // Only this test's schema refuses history rows for creates, so the insert of the location itself succeeds
+                connection_pool.pool.get().expect("Failed to get connection")
+                    .batch_execute("ALTER TABLE location_audit ADD CONSTRAINT refuse_creates CHECK (action <> 'create')")
+                    .expect("Failed to add constraint");
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
 
-            let retrieved_location = location_db.get(created_location.id).expect("Read location failed").unwrap();
+                let result = location_db.create(UpsertLocation {
+                    star_system: "Halvveis".to_string(),
+                    area: "Tilbakerullet".to_string(),
+                });
+                assert!(matches!(result, Err(DatabaseError(DatabaseErrorKind::CheckViolation, _))), "{:?}", result);
 
-            assert_eq!(retrieved_location.star_system, new_location.star_system);
-            assert_eq!(retrieved_location.area, new_location.area);
+                let filter = LocationFilter { q: None, star_system: Some("Halvveis".to_string()) };
+                let (locations, total) = location_db.list(&filter, 10, 0, LocationSort::default()).expect("List locations failed");
+                assert!(locations.is_empty());
+                assert_eq!(total, 0);
+            }).await;
         }
 
-        #[test]
-        fn read_returns_none_on_nonexistent_id() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
+        #[tokio::test]
+        async fn list_matches_star_system_exactly_and_together_with_q() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                for (star_system, area) in [("Nøyaktig", "Havn"), ("Nøyaktig", "Fjell"), ("Nøyaktig Nord", "Havn")] {
+                    location_db.create(UpsertLocation {
+                        star_system: star_system.to_string(),
+                        area: area.to_string(),
+                    }).expect("Create location failed");
+                }
 
-            let retrieved_location = location_db.get(-666);  // Use a non-existent ID
-            assert!(retrieved_location.is_ok());  // Expecting Ok(None)
-            assert!(retrieved_location.unwrap().is_none());
+                // Assert that a star system sharing the prefix isn't matched
+                let filter = LocationFilter { q: None, star_system: Some("Nøyaktig".to_string()) };
+                let (locations, total) = location_db.list(&filter, 10, 0, LocationSort::default()).expect("List locations failed");
+                assert_eq!(total, 2);
+                assert!(locations.iter().all(|location| location.star_system == "Nøyaktig"));
+
+                // Assert that 'q' narrows the exact match down further
+                let filter = LocationFilter { q: Some("havn".to_string()), star_system: Some("Nøyaktig".to_string()) };
+                let (locations, total) = location_db.list(&filter, 10, 0, LocationSort::default()).expect("List locations failed");
+                assert_eq!(total, 1);
+                assert_eq!((locations[0].star_system.as_str(), locations[0].area.as_str()), ("Nøyaktig", "Havn"));
+            }).await;
         }
 
+        #[tokio::test]
+        async fn read_succeeds_on_existing_id() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
 
-        #[test]
-        fn update_succeeds_on_valid_input() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
+                let new_location = UpsertLocation {
+                    star_system: "Test Star System".to_string(),
+                    area: "Test Area".to_string(),
+                };
+                let created_location = location_db.create(new_location.clone()).expect("Create location failed");
 
-            let new_location = UpsertLocation {
-                star_system: "Test Star System".to_string(),
-                area: "Test Area".to_string(),
-            };
-            let created_location = location_db.create(new_location.clone()).expect("Create location failed");
+                let retrieved_location = location_db.get(created_location.id).expect("Read location failed").unwrap();
 
-            let updated_request = UpsertLocation {
-                star_system: "Updated Star System".to_string(),
-                area: "Updated Area".to_string(),
-            };
-            let updated_location = location_db.update(created_location.id, updated_request.clone()).expect("Update location failed");
+                assert_eq!(retrieved_location.star_system, new_location.star_system);
+                assert_eq!(retrieved_location.area, new_location.area);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn read_returns_none_on_nonexistent_id() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
 
-            assert_eq!(updated_location.star_system, updated_request.star_system);
-            assert_eq!(updated_location.area, updated_request.area);
+                let retrieved_location = location_db.get(-666);  // Use a non-existent ID
+                assert!(retrieved_location.is_ok());  // Expecting Ok(None)
+                assert!(retrieved_location.unwrap().is_none());
+            }).await;
         }
 
-        #[test]
-        fn update_fails_on_nonexistent_id() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
 
-            let request = UpsertLocation {
-                star_system: "This test will fail".to_string(),
-                area: "so write random skit here".to_string(),
-            };
+        #[tokio::test]
+        async fn update_succeeds_on_valid_input() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                let new_location = UpsertLocation {
+                    star_system: "Test Star System".to_string(),
+                    area: "Test Area".to_string(),
+                };
+                let created_location = location_db.create(new_location.clone()).expect("Create location failed");
+
+                let updated_request = UpsertLocation {
+                    star_system: "Updated Star System".to_string(),
+                    area: "Updated Area".to_string(),
+                };
+                let updated_location = location_db.update(created_location.id, updated_request.clone()).expect("Update location failed");
+
+                assert_eq!(updated_location.star_system, updated_request.star_system);
+                assert_eq!(updated_location.area, updated_request.area);
+            }).await;
+        }
 
-            let result = location_db.update(-1, request.clone());  // Use a non-existent ID
-            assert!(result.is_err());  // Expecting an error as the ID is not present
+        #[tokio::test]
+        async fn update_bumps_updated_at_and_keeps_created_at() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                let created_location = location_db.create(UpsertLocation {
+                    star_system: "Test Star System".to_string(),
+                    area: "Test Area".to_string(),
+                }).expect("Create location failed");
+
+                let updated_location = location_db.update(created_location.id, UpsertLocation {
+                    star_system: "Updated Star System".to_string(),
+                    area: "Updated Area".to_string(),
+                }).expect("Update location failed");
+
+                let patched_location = location_db.patch(created_location.id, PatchLocation {
+                    star_system: None,
+                    area: Some("Patched Area".to_string()),
+                    ..Default::default()
+                }).expect("Patch location failed");
+
+                assert_eq!(updated_location.created_at, created_location.created_at);
+                assert_eq!(patched_location.created_at, created_location.created_at);
+                assert!(updated_location.updated_at > created_location.updated_at);
+                assert!(patched_location.updated_at > updated_location.updated_at);
+            }).await;
         }
 
+        #[tokio::test]
+        async fn update_fails_on_nonexistent_id() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
 
-        #[test]
-        fn delete_succeeds_on_existing_id() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
+                let request = UpsertLocation {
+                    star_system: "This test will fail".to_string(),
+                    area: "so write random skit here".to_string(),
+                };
 
-            let new_location = UpsertLocation {
-                star_system: "Test Star System".to_string(),
-                area: "Test Area".to_string(),
-            };
+                let result = location_db.update(-1, request.clone());  // Use a non-existent ID
+                assert!(result.is_err());  // Expecting an error as the ID is not present
+            }).await;
+        }
+
+
+        #[tokio::test]
+        async fn patch_updates_only_provided_fields() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                let new_location = UpsertLocation {
+                    star_system: "Test Star System".to_string(),
+                    area: "Test Area".to_string(),
+                };
+                let created_location = location_db.create(new_location.clone()).expect("Create location failed");
+
+                let patch = PatchLocation {
+                    star_system: None,
+                    area: Some("Patched Area".to_string()),
+                    ..Default::default()
+                };
+                let patched_location = location_db.patch(created_location.id, patch).expect("Patch location failed");
+
+                assert_eq!(patched_location.star_system, new_location.star_system);  // Untouched as it was not provided
+                assert_eq!(patched_location.area, "Patched Area");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn patch_fails_on_nonexistent_id() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                let patch = PatchLocation {
+                    star_system: None,
+                    area: Some("Nowhere".to_string()),
+                    ..Default::default()
+                };
+
+                let result = location_db.patch(-666, patch);  // Use a non-existent ID
+                assert!(matches!(result, Err(diesel::result::Error::NotFound)));
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn history_records_every_change_including_the_delete() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection).acting_as("historian@example.com");
+
+                let created_location = location_db.create(UpsertLocation {
+                    star_system: "History Star System".to_string(),
+                    area: "First Area".to_string(),
+                }).expect("Create location failed");
+
+                location_db.patch(created_location.id, PatchLocation {
+                    star_system: None,
+                    area: Some("Second Area".to_string()),
+                    ..Default::default()
+                }).expect("Patch location failed");
+
+                // Nothing changes, so nothing is recorded
+                location_db.patch(created_location.id, PatchLocation::default()).expect("Patch location failed");
+
+                location_db.delete(created_location.id).expect("Delete location failed");
+
+                let (history, _) = location_db.history(created_location.id, None, 50, 0).expect("Read history failed");
+                let actions: Vec<&str> = history.iter().map(|entry| entry.action.as_str()).collect();
+
+                assert_eq!(actions, vec!["create", "update", "delete"]);
+                assert!(history.iter().all(|entry| entry.actor == "historian@example.com"));
+                assert!(history[0].before.is_none());
+                assert_eq!(history[1].before.as_ref().unwrap()["area"], "First Area");
+                assert_eq!(history[1].after.as_ref().unwrap()["area"], "Second Area");
+                assert_eq!(history[2].before.as_ref().unwrap()["area"], "Second Area");
+                assert!(history[2].after.is_none());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_succeeds_on_existing_id() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                let new_location = UpsertLocation {
+                    star_system: "Test Star System".to_string(),
+                    area: "Test Area".to_string(),
+                };
+
+                let created_location = location_db.create(new_location.clone()).expect("Create location failed");
+                location_db.delete(created_location.id.clone()).expect("Delete location failed");
+                let deleted_location = location_db.get(created_location.id).expect("Read location failed");
+                assert!(deleted_location.is_none()); // Expecting lack of value as location has been deleted
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_many_skips_nonexistent_ids() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                let new_location = UpsertLocation {
+                    star_system: "Test Star System".to_string(),
+                    area: "Test Area".to_string(),
+                };
+
+                let first_location = location_db.create(new_location.clone()).expect("Create location failed");
+                let second_location = location_db.create(UpsertLocation {
+                    area: "Second Test Area".to_string(),
+                    ..new_location
+                }).expect("Create location failed");
+
+                let deleted = location_db.delete_many(&[first_location.id, second_location.id, -666]).expect("Delete locations failed");
+
+                assert_eq!(deleted, 2);  // The non-existent ID doesn't count
+                assert!(location_db.get(first_location.id).expect("Read location failed").is_none());
+                assert!(location_db.get(second_location.id).expect("Read location failed").is_none());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn area_stats_counts_each_area_once() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
+
+                // Tellus has two areas and Luna a single one
+                for (star_system, area) in [("Tellus", "Scandinavia"), ("Tellus", "Patagonia"), ("Luna", "Tranquility Base")] {
+                    location_db.create(UpsertLocation {
+                        star_system: star_system.to_string(),
+                        area: area.to_string(),
+                    }).expect("Create location failed");
+                }
+
+                let tellus = location_db.area_stats(Some("Tellus")).expect("Area stats failed");
+                assert_eq!(tellus, vec![AreaStats { star_system: "Tellus".to_string(), distinct_areas: 2 }]);
 
-            let created_location = location_db.create(new_location.clone()).expect("Create location failed");
-            location_db.delete(created_location.id.clone()).expect("Delete location failed");
-            let deleted_location = location_db.get(created_location.id).expect("Read location failed");
-            assert!(deleted_location.is_none()); // Expecting lack of value as location has been deleted
+                let all_systems = location_db.area_stats(None).expect("Area stats failed");
+                assert!(all_systems.contains(&AreaStats { star_system: "Tellus".to_string(), distinct_areas: 2 }));
+                assert!(all_systems.contains(&AreaStats { star_system: "Luna".to_string(), distinct_areas: 1 }));
+            }).await;
         }
 
-        #[test]
-        fn delete_fails_on_nonexistent_id() {
-            let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 1);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut location_db = LocationsTable::new(connection);
+        #[tokio::test]
+        async fn delete_fails_on_nonexistent_id() {
+            with_test_db(|connection_pool| async move {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut location_db = LocationsTable::new(connection);
 
-            let result = location_db.delete(-666);  // Use a non-existent ID
-            assert!(result.is_err());  // Expecting an error as the ID is not present
+                let result = location_db.delete(-666);  // Use a non-existent ID
+                assert!(result.is_err());  // Expecting an error as the ID is not present
+            }).await;
         }
     }
 }
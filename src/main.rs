@@ -1,29 +1,165 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::{middleware, Router};
+use tokio::sync::Notify;
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::Level;
 use crate:: {
-    common::db::create_shared_connection_pool,
+    common::config::Config,
+    common::db::create_shared_connection_pool_with_config,
+    common::state::AppState,
+    common::migrations::run_migrations_from_env,
     locations::router::router::locations_route,
+    locations::changes::{enable_change_notifications, listen_for_location_changes, log_location_changes},
     empires::router::router::empires_route,
     users::router::router::users_route,
-    common::util::load_environment_variable,
+    users::bootstrap::bootstrap_admin_from_env,
+    common::util::{bind_address, load_env_optional, load_flag_environment_variable},
+    common::metrics::{metrics_route, track_metrics},
+    common::logging::{body_log_sample_rate, init_logging, log_sampled_bodies, request_span},
+    common::limits::{max_header_bytes, reject_oversized_headers},
+    common::compression::{compression_layer, compression_min_bytes},
+    common::cors::cors_layer,
+    common::net::resolve_client_ip,
+    common::request_id::assign_request_id,
+    common::method_not_allowed::describe_methods_not_allowed,
+    common::not_found::route_not_found,
+    common::error::expose_error_details_within,
+    common::timeout::{enforce_request_timeout, request_timeout, warn_if_statement_timeout_outlasts},
+    common::openapi::docs_route,
+    common::version::version_route,
+    common::security::{argon2_params, jwt_config, warn_if_auth_disabled},
+    common::shutdown::{shutdown_grace_period, shutdown_signal, track_in_flight, InFlightRequests},
 };
 
 mod locations;mod users;mod schema;mod common;
 mod empires;
+mod audit;
+
+pub fn create_app(state: AppState) -> Router {
+    let config = state.config.clone();
+    let app = users_route(state.clone())
+        .merge(locations_route(state.clone()))
+        .merge(empires_route(state.clone()))
+        .merge(docs_route())
+        .merge(version_route())
+
+        // Only reached for paths no route matches, so the handlers' own 404s for missing resources are left alone
+        .fallback(route_not_found)
+
+        // Innermost, so it wraps the handlers themselves where their errors are built
+        .layer(middleware::from_fn_with_state(config.expose_error_details, expose_error_details_within))
+        .layer(middleware::from_fn_with_state(request_timeout(), enforce_request_timeout))
+        .route_layer(middleware::from_fn(track_metrics));
+
+    let app = describe_methods_not_allowed(app)
+        .layer(middleware::from_fn_with_state(body_log_sample_rate(), log_sampled_bodies))
+        .layer(middleware::from_fn_with_state(max_header_bytes(), reject_oversized_headers))
+
+        // Inside the trace layer, so the request's span is there to be tagged with the client's address
+        .layer(middleware::from_fn_with_state(config.trusted_proxies.clone(), resolve_client_ip))
+        .layer(TraceLayer::new_for_http()
+            .make_span_with(request_span)
+            .on_response(DefaultOnResponse::new().level(Level::INFO)))
+        .layer(middleware::from_fn(assign_request_id))
+        .layer(compression_layer(compression_min_bytes()));
+
+    // Outermost, so preflights are answered before any other layer gets to refuse them
+    match &config.cors_allowed_origins {
+        Some(allowed_origins) => app.layer(cors_layer(allowed_origins, config.cors_max_age)),
+        None => app,
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let database_url = load_environment_variable("DEV_DB");
-    let shared_connection_pool = create_shared_connection_pool(database_url, 1);
-
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(users_route(shared_connection_pool.clone())
-            .nest("/", locations_route(shared_connection_pool.clone()))
-            .nest("/", empires_route(shared_connection_pool.clone()))
-                .into_make_service())
-        .await
-        .unwrap();
-}
+    init_logging();
+
+    // Every missing secret or URL is reported in one go, before any of them is needed
+    let config = Config::from_env();
+
+    // Load the JWT configuration and bind address up front so a bad key, TTL or port stops the server before it takes any traffic
+    jwt_config();
+    let address = bind_address();
 
+    // Bad argon2 costs only fall back to the defaults, but the warning should show at startup rather than on the first password change
+    argon2_params();
+    warn_if_auth_disabled();
+    warn_if_statement_timeout_outlasts(request_timeout(), config.pool.statement_timeout);
 
+    let shared_connection_pool = create_shared_connection_pool_with_config(config.database_url.clone(), config.pool.clone());
 
+    // Before the admin bootstrap, which needs the users table to exist
+    run_migrations_from_env(&shared_connection_pool);
 
+    if load_flag_environment_variable("BOOTSTRAP_ADMIN", false) {
+        bootstrap_admin_from_env(&shared_connection_pool);
+    }
 
+    let state = AppState::new(shared_connection_pool.clone(), config);
+
+    if state.config.notify_changes {
+        enable_change_notifications();
+        listen_for_location_changes(state.config.database_url.clone(), state.location_changes.clone());
+        tokio::spawn(log_location_changes(state.location_changes.subscribe()));
+    }
+
+    // Metrics are served on a separate internal port when METRICS_PORT is set, otherwise alongside the API
+    let app = match load_env_optional::<u16>("METRICS_PORT") {
+        Some(metrics_port) => {
+            let metrics_address = SocketAddr::from(([0, 0, 0, 0], metrics_port));
+
+            tokio::spawn(async move {
+                axum::Server::bind(&metrics_address)
+                    .serve(metrics_route().into_make_service())
+                    .await
+                    .unwrap();
+            });
+
+            create_app(state)
+        }
+        None => create_app(state).merge(metrics_route()),
+    };
+
+    // Keep track of in-flight requests so we can report how many were drained on shutdown
+    let in_flight = InFlightRequests::default();
+    let app = app.layer(middleware::from_fn_with_state(in_flight.clone(), track_in_flight));
+
+    let shutdown_started = Arc::new(Notify::new());
+    tracing::info!("Listening on {}", address);
+    let server = axum::Server::bind(&address)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown({
+            let shutdown_started = shutdown_started.clone();
+            async move {
+                shutdown_signal().await;
+                shutdown_started.notify_one();
+            }
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result.unwrap(),
+        _ = shutdown_started.notified() => {
+            let grace_period = shutdown_grace_period();
+            let draining = in_flight.count();
+            tracing::info!("Draining {} in-flight requests with a grace period of {}s", draining, grace_period.as_secs());
+
+            // The server stops accepting connections and waits for in-flight requests, but only for so long
+            match tokio::time::timeout(grace_period, &mut server).await {
+                Ok(result) => {
+                    result.unwrap();
+                    tracing::info!("Drained {} in-flight requests", draining);
+                }
+                Err(_) => tracing::warn!(
+                    "Grace period elapsed, drained {} of {} in-flight requests",
+                    draining.saturating_sub(in_flight.count()),
+                    draining
+                ),
+            }
+        }
+    }
+
+    // Only release database connections once the server has stopped serving requests
+    drop(shared_connection_pool);
+}
@@ -0,0 +1,82 @@
+use crate::{
+    common::{
+        db::ConnectionPool,
+        error::{CustomError, ErrorType},
+        security::hash_password_argon2,
+        util::load_environment_variable,
+    },
+    users::{
+        model::{UpsertUser, UserRole},
+        service::service::UsersTable,
+    },
+};
+
+// A fresh database has no admin, so when started with BOOTSTRAP_ADMIN=true the first one is created from
+// ADMIN_EMAIL and ADMIN_PASSWORD. Once any admin exists this does nothing, so the flag may be left on
+pub fn bootstrap_admin_from_env(shared_connection_pool: &ConnectionPool) {
+    let email = load_environment_variable("ADMIN_EMAIL");
+    let password = load_environment_variable("ADMIN_PASSWORD");
+
+    let connection = shared_connection_pool.pool.get()
+        .expect("Failed to acquire connection from pool");
+
+    match bootstrap_admin(&mut UsersTable::new(connection), &email, &password) {
+        Ok(true) => eprintln!("Bootstrapped admin {}", email),
+        Ok(false) => eprintln!("An admin already exists, skipped bootstrapping {}", email),
+        Err(err) => panic!("Failed to bootstrap admin: {}", err),
+    }
+}
+
+// Returns whether the admin was created
+pub fn bootstrap_admin(users: &mut UsersTable, email: &str, password: &str) -> Result<bool, CustomError> {
+    let admins = users.count_active_admins()
+        .map_err(|err| CustomError::from_diesel_err(err, "while counting admins"))?;
+
+    if admins > 0 {
+        return Ok(false);
+    }
+
+    let password_hash = hash_password_argon2(password)
+        .map_err(|_| CustomError::new("while hashing the admin password", ErrorType::Internal))?;
+
+    users.create(UpsertUser {
+        email: email.to_string(),
+        password: password_hash,
+        fullname: "Administrator".to_string(),
+        role: UserRole::ADMIN.to_string(),
+    })?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use crate::{
+        common::{db::create_shared_connection_pool, util::load_environment_variable},
+        schema::{players, users},
+        users::{bootstrap::bootstrap_admin, service::service::UsersTable},
+    };
+
+    #[test]
+    fn bootstrap_creates_admin_on_empty_users_table_only_once() {
+        let database_url = load_environment_variable("TEST_DB");
+        let connection_pool = create_shared_connection_pool(database_url, 1);
+        let mut connection = connection_pool.pool.get().expect("Failed to get connection");
+
+        // Emptied inside a transaction that is never committed, so other tests keep their users
+        connection.begin_test_transaction().expect("Failed to begin transaction");
+        diesel::delete(players::table).execute(&mut connection).expect("Failed to delete players");
+        diesel::delete(users::table).execute(&mut connection).expect("Failed to delete users");
+
+        let mut user_db = UsersTable::new(connection);
+
+        assert!(bootstrap_admin(&mut user_db, "first.admin@bootstrap.no", "Bootstrap123").expect("Bootstrap failed"));
+        assert_eq!(user_db.count_active_admins().expect("Count admins failed"), 1);
+
+        // The second run finds the admin created by the first
+        assert!(!bootstrap_admin(&mut user_db, "second.admin@bootstrap.no", "Bootstrap456").expect("Bootstrap failed"));
+        assert_eq!(user_db.count_active_admins().expect("Count admins failed"), 1);
+        assert!(user_db.get_by_email("second.admin@bootstrap.no".to_string()).expect("Read user failed").is_none());
+    }
+}
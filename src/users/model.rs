@@ -1,20 +1,54 @@
 use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
 use diesel::prelude::*;
 use regex::Regex;
 use serde_derive::{Serialize, Deserialize};
-use crate::schema::users;
+use utoipa::{IntoParams, ToSchema};
+use crate::{
+    common::validation::{add_error, into_result, Validate, ValidationErrors},
+    schema::users,
+};
 
-#[derive(Debug, Clone, Serialize, Queryable)]
+// Selected explicitly rather than by position, so bookkeeping columns like 'deleted_at' stay out of it
+#[derive(Debug, Clone, Serialize, Queryable, Selectable, ToSchema)]
 #[diesel(table_name = users)]
 pub struct User {
     pub id: i32,
     pub email: String,
     pub password: String,
     pub fullname: String,
-    pub role: String
+    pub role: String,
+    pub email_verified: bool,
+    pub must_change_password: bool
+}
+
+// Projection of a user which is safe to return to clients, as it never includes the password hash
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PublicUser {
+    pub id: i32,
+    pub email: String,
+    pub fullname: String,
+    pub role: String,
+    pub email_verified: bool
 }
 
+impl From<User> for PublicUser {
+    fn from(user: User) -> PublicUser {
+        PublicUser {
+            id: user.id,
+            email: user.email,
+            fullname: user.fullname,
+            role: user.role,
+            email_verified: user.email_verified
+        }
+    }
+}
+
+// On the wire, in tokens and JSON alike, a role is its uppercase name, the same as Display and the users table use.
+// Tokens already issued carry roles in this form, so changing it would log everyone out
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum UserRole {
     READER,
     WRITER,
@@ -35,6 +69,32 @@ impl fmt::Display for UserRole {
     }
 }
 
+// Returned when parsing a string that names none of the roles a user can have
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownRole(pub String);
+
+impl fmt::Display for UnknownRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown role '{}'", self.0)
+    }
+}
+
+// For roles coming from clients, which may be written in any case. Unlike string_to_user_role, which reads
+// stored roles, unknown strings are an error rather than INVALID, and INVALID itself is never accepted
+impl FromStr for UserRole {
+    type Err = UnknownRole;
+
+    fn from_str(role: &str) -> Result<UserRole, UnknownRole> {
+        match role.trim().to_uppercase().as_str() {
+            "READER" => Ok(UserRole::READER),
+            "WRITER" => Ok(UserRole::WRITER),
+            "EDITOR" => Ok(UserRole::EDITOR),
+            "ADMIN" => Ok(UserRole::ADMIN),
+            _ => Err(UnknownRole(role.to_string())),
+        }
+    }
+}
+
 pub fn string_to_user_role(role: String) -> UserRole {
     match role.as_str() {
         "READER" => UserRole::READER,
@@ -46,8 +106,54 @@ pub fn string_to_user_role(role: String) -> UserRole {
 }
 
 
-#[derive(Debug, Clone, Serialize, Deserialize, Insertable)]
+static EMAIL_PATTERN: OnceLock<Regex> = OnceLock::new();
+
+// Compiled once on first use, as validation runs on every registration
+fn email_pattern() -> &'static Regex {
+    EMAIL_PATTERN.get_or_init(|| Regex::new(r"^[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}$").unwrap())
+}
+
+// Characters that render as nothing, so emails containing them look identical to ones that don't
+const INVISIBLE_EMAIL_CHARS: [char; 5] = ['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+// What to do with invisible characters in an email, control characters are always refused
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InvisibleCharPolicy {
+    #[default]
+    Reject,
+    Strip,
+}
+
+impl InvisibleCharPolicy {
+    pub fn stripping(strip: bool) -> InvisibleCharPolicy {
+        if strip {
+            InvisibleCharPolicy::Strip
+        } else {
+            InvisibleCharPolicy::Reject
+        }
+    }
+}
+
+// The form an email is stored and compared in - trimmed and lowercased, or None if it contains characters the policy refuses
+pub fn canonicalize_email(email: &str, policy: InvisibleCharPolicy) -> Option<String> {
+    let email = email.trim();
+
+    if email.chars().any(char::is_control) {
+        return None;
+    }
+
+    let email = match policy {
+        InvisibleCharPolicy::Reject if email.contains(INVISIBLE_EMAIL_CHARS) => return None,
+        InvisibleCharPolicy::Reject => email.to_string(),
+        InvisibleCharPolicy::Strip => email.chars().filter(|c| !INVISIBLE_EMAIL_CHARS.contains(c)).collect(),
+    };
+
+    Some(email.to_lowercase())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable, ToSchema)]
 #[diesel(table_name = users)]
+#[serde(deny_unknown_fields)]
 pub struct UpsertUser {
     pub email: String,
     pub password: String,
@@ -57,20 +163,202 @@ pub struct UpsertUser {
 
 impl UpsertUser {
     pub fn is_valid_email(&self) -> bool {
-        let email_pattern = Regex::new(r"^[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}$").unwrap();
-        email_pattern.is_match(&self.email)
+        email_pattern().is_match(&self.email)
+    }
+
+    // Emails are stored lowercased, so addresses differing only by casing map to the same account
+    pub fn normalize_email(&mut self) {
+        self.email = self.email.to_lowercase();
+    }
+
+    // INVALID is only a fallback for unrecognized strings and must never be stored as a role
+    pub fn has_valid_role(&self) -> bool {
+        string_to_user_role(self.role.clone()) != UserRole::INVALID
+    }
+}
+
+// Body of PUT /users/:id. Passwords are only changed where they are checked and hashed, through /users/me/password
+// or an admin reset, so 'password' may be left out and is otherwise refused unless it is the current one
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateUser {
+    pub email: String,
+    pub password: Option<String>,
+    pub fullname: String,
+    pub role: String,
+}
+
+// Held to the same rules as a new user, the password aside
+impl Validate for UpdateUser {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        UpsertUser {
+            email: self.email.clone(),
+            password: String::new(),
+            fullname: self.fullname.clone(),
+            role: self.role.clone(),
+        }.validate()
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Expects the email to have been canonicalized already, as the handlers do before validating
+impl Validate for UpsertUser {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+
+        if !self.is_valid_email() {
+            add_error(&mut errors, "email", "Field 'email' must be a valid email address".to_string());
+        }
+
+        if !self.has_valid_role() {
+            add_error(&mut errors, "role", "Field 'role' must be one of READER, WRITER, EDITOR or ADMIN".to_string());
+        }
+
+        into_result(errors)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListUsersQuery {
+    pub role: Option<String>,
+}
+
+// Body of PATCH /users/:id/role. The role is parsed by the handler, so an unknown one is answered with 400
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ChangeRole {
+    #[schema(example = "EDITOR")]
+    pub role: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ChangePassword {
+    pub current: String,
+    pub new: String,
+}
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+// Lists every way the password falls short, empty when it is strong enough
+pub fn password_strength_errors(password: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    let length = password.chars().count();
+
+    if length < MIN_PASSWORD_LENGTH {
+        errors.push(format!("Password must be at least {} characters", MIN_PASSWORD_LENGTH));
+    }
+
+    // Hashing is deliberately slow, so unbounded input would make every attempt expensive
+    if length > MAX_PASSWORD_LENGTH {
+        errors.push(format!("Password must be at most {} characters", MAX_PASSWORD_LENGTH));
+    }
+
+    if !password.chars().any(char::is_alphabetic) {
+        errors.push("Password must contain a letter".to_string());
+    }
+
+    if !password.chars().any(|c| c.is_ascii_digit()) {
+        errors.push("Password must contain a digit".to_string());
+    }
+
+    errors
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
 pub struct LoginUser {
     pub email: String,
     pub password: String
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: i64,
-    pub role: UserRole
-}
\ No newline at end of file
+    pub role: UserRole,
+    pub iss: String,
+    pub aud: String,
+
+    // Email of the admin acting on behalf of the subject, only present on impersonation tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonated_by: Option<String>,
+
+    // Tells clients to send the user to change their password. Writes are refused based on the stored flag instead,
+    // which lets the token through as soon as the password has been changed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub must_change_password: bool
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use serde_json::json;
+    use crate::{
+        common::validation::Validate,
+        users::model::{Claims, UnknownRole, UpsertUser, UserRole},
+    };
+
+    #[test]
+    fn every_role_parses_from_its_name() {
+        for role in [UserRole::READER, UserRole::WRITER, UserRole::EDITOR, UserRole::ADMIN] {
+            assert_eq!(role.to_string().parse::<UserRole>(), Ok(role));
+        }
+    }
+
+    #[test]
+    fn role_parsing_ignores_case() {
+        assert_eq!("editor".parse::<UserRole>(), Ok(UserRole::EDITOR));
+        assert_eq!("Admin".parse::<UserRole>(), Ok(UserRole::ADMIN));
+    }
+
+    #[test]
+    fn unknown_role_is_an_error() {
+        assert_eq!("OWNER".parse::<UserRole>(), Err(UnknownRole("OWNER".to_string())));
+        assert_eq!("INVALID".parse::<UserRole>(), Err(UnknownRole("INVALID".to_string())));
+        assert_eq!(UnknownRole("OWNER".to_string()).to_string(), "Unknown role 'OWNER'");
+    }
+
+    #[test]
+    fn role_serializes_as_its_uppercase_name() {
+        assert_eq!(serde_json::to_string(&UserRole::EDITOR).unwrap(), "\"EDITOR\"");
+        assert_eq!(serde_json::from_str::<UserRole>("\"ADMIN\"").unwrap(), UserRole::ADMIN);
+    }
+
+    #[test]
+    fn claims_round_trip_through_json_with_their_role() {
+        let claims = Claims {
+            sub: "roundtrip@claims.no".to_string(),
+            exp: 1_700_000_000,
+            role: UserRole::WRITER,
+            iss: "axum_api_with_auth".to_string(),
+            aud: "axum_api_with_auth".to_string(),
+            impersonated_by: None,
+            must_change_password: false,
+        };
+
+        let serialized = serde_json::to_value(&claims).unwrap();
+        assert_eq!(serialized["role"], json!("WRITER"));
+        assert_eq!(serde_json::from_value::<Claims>(serialized).unwrap(), claims);
+    }
+
+    #[test]
+    fn upsert_user_validates_email_and_role_by_field() {
+        let user = |email: &str, role: &str| UpsertUser {
+            email: email.to_string(),
+            password: "Big100".to_string(),
+            fullname: "Valid Ator".to_string(),
+            role: role.to_string(),
+        };
+
+        assert_eq!(user("valid@email.com", "READER").validate(), Ok(()));
+
+        assert_eq!(user("eg-klare-meg", "READER").validate(), Err(HashMap::from([
+            ("email".to_string(), vec!["Field 'email' must be a valid email address".to_string()]),
+        ])));
+
+        assert_eq!(user("eg-klare-meg", "OWNER").validate(), Err(HashMap::from([
+            ("email".to_string(), vec!["Field 'email' must be a valid email address".to_string()]),
+            ("role".to_string(), vec!["Field 'role' must be one of READER, WRITER, EDITOR or ADMIN".to_string()]),
+        ])));
+    }
+}
@@ -1,50 +1,110 @@
 pub mod router {
     use serde_json::{json, Value};
-    use bcrypt::verify;
-    use axum::{extract, extract::State, http::StatusCode, Json, response::IntoResponse, Router};
+    use axum::{extract, extract::State, http::StatusCode, Json, response::{IntoResponse, Response}, Router, Extension};
+    use http::{HeaderMap, Uri};
     use crate::{
         common::{
+            config::Config,
             db::ConnectionPool,
-            security::{hash_password, generate_token}},
+            extract::JsonBody,
+            limits::{body_limit, max_body_bytes},
+            logging::record_user,
+            login_attempts::LoginAttempts,
+            pagination::{pagination_links, Pagination, PaginationQuery},
+            error::{database_error, internal_error, map_diesel_error, ApiError, ErrorType},
+            security::{hash_password, hash_password_argon2, generate_temporary_password, needs_rehash, rehash_password, verify_password, generate_token_with_ttl, generate_impersonation_token, decode_claims, enforce_role_policy, enforce_not_impersonating, SensitiveOperation, IMPERSONATION_TOKEN_TTL},
+            state::AppState,
+            validation::{Validate, ValidationErrors}},
+        audit::{
+            model::NewAuditEntry,
+            service::service::AuditLogTable,
+        },
         users::{
             service::service::UsersTable,
             model::{
+                User,
+                PublicUser,
                 UpsertUser,
+                UpdateUser,
                 LoginUser,
+                ChangePassword,
+                ChangeRole,
+                ListUsersQuery,
+                UserRole,
+                InvisibleCharPolicy,
+                canonicalize_email,
+                password_strength_errors,
             },
         },
     };
 
     // - - - - - - - - - - - [ROUTES] - - - - - - - - - - -
 
-    pub fn users_route(shared_connection_pool: ConnectionPool) -> Router {
+    pub fn users_route(state: AppState) -> Router {
+        let max_body_bytes = max_body_bytes();
+
         Router::new()
-            .route("/users", axum::routing::post(create_user_handler))
+            .route("/users", axum::routing::post(create_user_handler).layer(body_limit(max_body_bytes)))
+            .route("/users", axum::routing::get(list_users_handler))
             .route("/users/:user_id", axum::routing::get(get_user_handler))
-            .route("/users/:user_id", axum::routing::put(update_user_handler))
+            .route("/users/:user_id", axum::routing::put(update_user_handler).layer(body_limit(max_body_bytes)))
             .route("/users/:user_id", axum::routing::delete(delete_user_handler))
+            .route("/users/:user_id/restore", axum::routing::post(restore_user_handler))
+            .route("/users/:user_id/reset-password", axum::routing::post(reset_password_handler))
+            .route("/users/:user_id/role", axum::routing::patch(change_role_handler).layer(body_limit(max_body_bytes)))
             .route("/users/login", axum::routing::post(login_user_handler))
-            .with_state(shared_connection_pool)
+            .route("/auth/check", axum::routing::post(check_credentials_handler))
+            .route("/me", axum::routing::get(me_handler))
+            .route("/users/me/password", axum::routing::post(change_password_handler))
+            .route("/admin/impersonate/:user_id", axum::routing::post(impersonate_user_handler))
+            .layer(Extension(LoginAttempts::new(state.config.login_max_failures, state.config.login_failure_window)))
+            .with_state(state)
     }
 
 
     // - - - - - - - - - - - [HANDLERS] - - - - - - - - - - -
 
+    #[utoipa::path(
+        post,
+        path = "/users",
+        tag = "users",
+        request_body = UpsertUser,
+        responses(
+            (status = 201, description = "User created", body = User),
+            (status = 409, description = "The email is already registered, regardless of casing", body = ErrorResponse),
+            (status = 413, description = "Body too large"),
+            (status = 422, description = "Invalid email or role", body = ValidationErrorResponse)
+        )
+    )]
     pub async fn create_user_handler(
-        State(shared_state): State<ConnectionPool>,
-        Json(mut body): Json<UpsertUser>,
+        State(AppState { connection_pool: shared_state, config, .. }): State<AppState>,
+        JsonBody(mut body): JsonBody<UpsertUser>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
-        if !validate_email(&body) {
-            return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(json!({"error": "Invalid input for field 'email'"}))));
-        }
+        body.email = canonical_email_or_422(&body.email, config.invisible_email_chars)?;
+        body.validate_or_422("Invalid user")?;
 
         hash_password(&mut body)?;
 
         let connection = shared_state.pool.get()
             .expect("Failed to acquire connection from pool");
 
-        match UsersTable::new(connection).create(body) {
+        let mut users = UsersTable::new(connection);
+
+        match users.email_exists(&body.email) {
+            Ok(true) => return Err((StatusCode::CONFLICT, Json(json!({"error": "email already registered"})))),
+            Ok(false) => {},
+            Err(err) => {
+                eprintln!("Error reading user: {:?}", err);
+                return Err(database_error("Failed to read user", &err));
+            }
+        }
+
+        // The unique index still catches a registration racing this one between the check and the insert
+        match users.create(body) {
             Ok(created_user) => Ok((StatusCode::CREATED, Json(created_user))),
+            Err(err) if err.err_type == ErrorType::UniqueViolation => {
+                Err((StatusCode::CONFLICT, Json(json!({"error": "email already registered"}))))
+            },
             Err(err) => {
                 eprintln!("Create user failed: {:?}", err);
                 Err((StatusCode::UNPROCESSABLE_ENTITY, Json(json!({"error": "Failed to create user"}))))
@@ -52,12 +112,78 @@ pub mod router {
         }
     }
 
-    fn validate_email(body: &UpsertUser) -> bool {
-        body.is_valid_email()
+    // Padding and casing are dropped before validation, so they can't be used to register the same address twice
+    fn canonical_email_or_422(email: &str, policy: InvisibleCharPolicy) -> Result<String, ApiError> {
+        canonicalize_email(email, policy).ok_or_else(|| {
+            let errors = ValidationErrors::from([("email".to_string(), vec!["Field 'email' must not contain control or invisible characters".to_string()])]);
+            ApiError::unprocessable("Invalid user", errors)
+        })
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/users",
+        tag = "users",
+        params(ListUsersQuery, PaginationQuery),
+        responses(
+            (status = 200, description = "A page of users, without passwords, along with 'total', 'limit' and 'offset', and a 'Link' header to the neighbouring pages", body = Object),
+            (status = 400, description = "Invalid pagination or unknown role", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn list_users_handler(
+        headers: HeaderMap,
+        uri: Uri,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        pagination: Pagination,
+        extract::Query(query): extract::Query<ListUsersQuery>,
+    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'ADMIN'
+        enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await?;
+
+        let (limit, offset) = (pagination.limit(), pagination.offset());
+
+        let role = query.role
+            .map(|role| role.parse::<UserRole>())
+            .transpose()
+            .map_err(|err| (StatusCode::BAD_REQUEST, Json(json!({"error": err.to_string()}))))?;
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        match UsersTable::new(connection).list(limit, offset, role) {
+            Ok((users, total)) => Ok((StatusCode::OK, pagination_links(&uri, pagination, total), Json(json!({
+                "items": users.into_iter().map(PublicUser::from).collect::<Vec<_>>(),
+                "total": total,
+                "limit": limit,
+                "offset": offset
+            })))),
+            Err(err) => {
+                eprintln!("Error listing users: {:?}", err);
+                Err(database_error("Failed to list users", &err))
+            }
+        }
     }
 
+    #[utoipa::path(
+        get,
+        path = "/users/{user_id}",
+        tag = "users",
+        params(("user_id" = i32, Path, description = "Id of the user")),
+        responses(
+            (status = 200, description = "The user", body = User),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        )
+    )]
     pub async fn get_user_handler(
-        State(shared_state): State<ConnectionPool>,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
         path: extract::Path<(i32,)>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
         let (user_id,) = path.0;
@@ -77,98 +203,688 @@ pub mod router {
             },
             Err(err) => {
                 eprintln!("Error reading user: {:?}", err);
-                Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to read user"}))))
+                Err(database_error("Failed to read user", &err))
             }
         }
     }
 
+    #[utoipa::path(
+        put,
+        path = "/users/{user_id}",
+        tag = "users",
+        params(("user_id" = i32, Path, description = "Id of the user")),
+        request_body = UpdateUser,
+        responses(
+            (status = 200, description = "The updated user", body = User),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN when editing someone else or changing a role", body = ErrorResponse),
+            (status = 403, description = "Role or password change attempted with an impersonation token, or the caller's password must be changed first", body = ErrorResponse),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 409, description = "The email is already registered to another user, regardless of casing, or the user is the last remaining admin", body = ErrorResponse),
+            (status = 413, description = "Body too large"),
+            (status = 422, description = "Invalid email or role, or a 'password' other than the current one", body = ValidationErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
     pub async fn update_user_handler(
-        State(shared_state): State<ConnectionPool>,
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, config, .. }): State<AppState>,
         path: extract::Path<(i32,)>,
-        Json(update_user): Json<UpsertUser>,
+        JsonBody(mut update_user): JsonBody<UpdateUser>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
         let (user_id,) = path.0;
 
+        update_user.email = canonical_email_or_422(&update_user.email, config.invisible_email_chars)?;
+        update_user.validate_or_422("Invalid user")?;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
         let connection = shared_state.pool.get()
             .expect("Failed to acquire connection from pool");
 
-        let mut users = UsersTable::new(connection);
+        let existing_user = match UsersTable::new(connection).get(user_id) {
+            Ok(Some(existing_user)) => existing_user,
+            Ok(None) => return Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"})))),
+            Err(err) => {
+                eprintln!("Error reading user: {:?}", err);
+                return Err(database_error("Failed to read user", &err));
+            }
+        };
 
-        match users.update(user_id, update_user) {
-            Ok(updated_user) => Ok((StatusCode::OK, Json(updated_user))),
-            Err(diesel::result::Error::NotFound) => {
-                Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"}))))
+        let changes_role = existing_user.role != update_user.role;
+
+        // Clients sending the whole record back may repeat the current password, anything else would be a change
+        let changes_password = update_user.password.as_deref()
+            .is_some_and(|password| !verify_password(password, Some(&existing_user)));
+
+        // Requests sent with an impersonation token may edit the user, but never change anyone's role or password
+        if changes_role {
+            enforce_not_impersonating(&claims, SensitiveOperation::ChangeRole)?;
+        }
+
+        if changes_password {
+            enforce_not_impersonating(&claims, SensitiveOperation::ChangePassword)?;
+        }
+
+        // Users may edit their own record, but only admins may edit someone else's or change a role
+        let caller = enforce_role_policy(&shared_state, &claims, UserRole::READER).await?;
+
+        // READER only lets a reset password through for reading, and this is a write like any other
+        if caller.as_ref().is_some_and(|caller| caller.must_change_password) {
+            eprintln!("Refused update of user {} until the password is changed", user_id);
+            return Err((StatusCode::FORBIDDEN, Json(json!({"error": "Password change required"}))));
+        }
+
+        let edits_self = caller.map(|caller| caller.id == user_id).unwrap_or(false);
+
+        if changes_role || !edits_self {
+            enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await?;
+        }
+
+        // Passwords are changed where the current one is checked and the new one hashed
+        if changes_password {
+            let errors = ValidationErrors::from([("password".to_string(), vec!["Field 'password' can't be changed here, use /users/me/password or an admin reset".to_string()])]);
+            return Err(ApiError::unprocessable("Invalid user", errors).into());
+        }
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        // Another user may already have the email, which the unique index on lower(email) refuses like on create
+        match UsersTable::new(connection).update(user_id, update_user) {
+            Ok(Some(updated_user)) => Ok((StatusCode::OK, Json(updated_user))),
+            Ok(None) => Err((StatusCode::CONFLICT, Json(json!({"error": "Cannot demote the last admin"})))),
+            Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _)) => {
+                Err((StatusCode::CONFLICT, Json(json!({"error": "email already registered"}))))
             },
             Err(err) => {
                 eprintln!("Error updating user: {:?}", err);
-                Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to update user"}))))
+                Err(map_diesel_error("user", "Failed to update user", &err))
             }
         }
     }
 
+    #[utoipa::path(
+        delete,
+        path = "/users/{user_id}",
+        tag = "users",
+        params(("user_id" = i32, Path, description = "Id of the user")),
+        responses(
+            (status = 204, description = "User soft-deleted, it can be brought back through /users/{user_id}/restore"),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 409, description = "The user is the last remaining admin", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
     pub async fn delete_user_handler(
-        State(shared_state): State<ConnectionPool>,
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32,)>,
+    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+        let (user_id,) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'ADMIN'
+        enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await?;
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        // Deleting the last admin would leave nobody able to restore users or manage roles
+        match UsersTable::new(connection).delete(user_id) {
+            Ok(true) => Ok((StatusCode::NO_CONTENT, ())),
+            Ok(false) => Err((StatusCode::CONFLICT, Json(json!({"error": "Cannot delete the last admin"})))),
+            Err(diesel::result::Error::NotFound) => {
+                Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"}))))
+            },
+            Err(err) => {
+                eprintln!("Error deleting user: {:?}", err);
+                Err(database_error("Failed to delete user", &err))
+            }
+        }
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/users/{user_id}/restore",
+        tag = "users",
+        params(("user_id" = i32, Path, description = "Id of the soft-deleted user")),
+        responses(
+            (status = 200, description = "The restored user", body = PublicUser),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 404, description = "No soft-deleted user with this id", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn restore_user_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32,)>,
+    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+        let (user_id,) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Ensure that the user derived from claims exists and has the role 'ADMIN'
+        enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await?;
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        match UsersTable::new(connection).restore(user_id) {
+            Ok(restored_user) => Ok((StatusCode::OK, Json(PublicUser::from(restored_user)))),
+            Err(diesel::result::Error::NotFound) => {
+                Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"}))))
+            },
+            Err(err) => {
+                eprintln!("Error restoring user: {:?}", err);
+                Err(database_error("Failed to restore user", &err))
+            }
+        }
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/users/{user_id}/reset-password",
+        tag = "users",
+        params(("user_id" = i32, Path, description = "Id of the user")),
+        responses(
+            (status = 200, description = "The temporary password, only ever shown in this response", body = Object),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 403, description = "Reset attempted with an impersonation token", body = ErrorResponse),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn reset_password_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
         path: extract::Path<(i32,)>,
     ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
         let (user_id,) = path.0;
 
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        enforce_not_impersonating(&claims, SensitiveOperation::ChangePassword)?;
+
+        // Ensure that the user derived from claims exists and has the role 'ADMIN'
+        let admin = match enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await {
+            Ok(Some(admin)) => admin,
+            Ok(None) => return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "User in claims not found in DB"})))),
+            Err(err) => return Err(err),
+        };
+
         let connection = shared_state.pool.get()
             .expect("Failed to acquire connection from pool");
 
         let mut users = UsersTable::new(connection);
 
-        match users.delete(user_id) {
-            Ok(_) => Ok((StatusCode::NO_CONTENT, ())),
+        let target_user = match users.get(user_id) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"})))),
             Err(err) => {
-                eprintln!("Error deleting user: {:?}", err);
-                Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to delete user"}))))
+                eprintln!("Error reading user: {:?}", err);
+                return Err(database_error("Failed to read user", &err));
+            }
+        };
+
+        // The audit entry is written before the password is replaced so every reset is attributable
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        if let Err(err) = AuditLogTable::new(connection).record(NewAuditEntry {
+            actor: admin.email.clone(),
+            action: "reset_password".to_string(),
+            target: target_user.email.clone(),
+        }) {
+            eprintln!("Error recording password reset: {:?}", err);
+            return Err(database_error("Failed to record password reset", &err));
+        }
+
+        let temporary_password = generate_temporary_password();
+        let password_hash = hash_password_argon2(&temporary_password)?;
+
+        // The user has to pick a password of their own on their next login
+        match users.update_password(target_user.id, &password_hash, true) {
+            Ok(()) => Ok((StatusCode::OK, Json(json!({"temporary_password": temporary_password})))),
+            Err(diesel::result::Error::NotFound) => {
+                Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"}))))
+            },
+            Err(err) => {
+                eprintln!("Error resetting password: {:?}", err);
+                Err(database_error("Failed to reset password", &err))
             }
         }
     }
 
+    #[utoipa::path(
+        post,
+        path = "/users/login",
+        tag = "users",
+        request_body = LoginUser,
+        responses(
+            (status = 200, description = "A bearer token, or 'token' along with 'must_change_password' when the password must be changed", body = String),
+            (status = 401, description = "Wrong password, or no active user with this email", body = ErrorResponse),
+            (status = 403, description = "Email not verified while verified login is required", body = Object),
+            (status = 429, description = "Too many failed attempts for this email", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        )
+    )]
     pub async fn login_user_handler(
-        State(shared_state): State<ConnectionPool>,
-        Json(body): Json<LoginUser>,
-    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+        State(AppState { connection_pool: shared_state, config, .. }): State<AppState>,
+        Extension(login_attempts): Extension<LoginAttempts>,
+        JsonBody(body): JsonBody<LoginUser>,
+    ) -> Response {
+        let email = login_email(&body, config.invisible_email_chars);
+        let response = login(&shared_state, &config, &login_attempts, &email, &body.password).into_response();
+
+        with_attempts_left(response, &login_attempts, &email)
+    }
+
+    fn login(shared_state: &ConnectionPool, config: &Config, login_attempts: &LoginAttempts, email: &str, password: &str) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+        let user = authenticate(shared_state, login_attempts, email, password)?;
+        record_user(&user);
+
+        enforce_verified_login(&user, config.require_verified_login)?;
+
+        match generate_token_with_ttl(&user, config.token_ttl) {
+
+            // Only a flagged login answers with an object, so clients that expect a bare token keep working otherwise
+            Ok(token) if user.must_change_password => Ok((StatusCode::OK, Json(json!({"token": token, "must_change_password": true})))),
+            Ok(token) => Ok((StatusCode::OK, Json(json!(token)))),
+            Err(err) => {
+                eprintln!("Error generating token: {:?}", err);
+                Err(internal_error("Failed to generate token", &err))
+            }
+        }
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/auth/check",
+        tag = "users",
+        request_body = LoginUser,
+        responses(
+            (status = 200, description = "The credentials are valid, no token is issued", body = Object),
+            (status = 401, description = "Wrong password, or no active user with this email", body = ErrorResponse),
+            (status = 429, description = "Too many failed attempts for this email", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        )
+    )]
+    pub async fn check_credentials_handler(
+        State(AppState { connection_pool: shared_state, config, .. }): State<AppState>,
+        Extension(login_attempts): Extension<LoginAttempts>,
+        JsonBody(body): JsonBody<LoginUser>,
+    ) -> Response {
+        let email = login_email(&body, config.invisible_email_chars);
+        let response = authenticate(&shared_state, &login_attempts, &email, &body.password)
+            .map(|_| (StatusCode::OK, Json(json!({"valid": true}))))
+            .into_response();
+
+        with_attempts_left(response, &login_attempts, &email)
+    }
+
+    // Every answer to a credentials check says how many failures the email has left, so clients can back off before
+    // they are locked out rather than only learning of it from a 429
+    fn with_attempts_left(mut response: Response, login_attempts: &LoginAttempts, email: &str) -> Response {
+        login_attempts.attempts_left(email).insert_headers(response.headers_mut());
+        response
+    }
+
+    // An email the policy refuses can't belong to anyone, and is answered like any other unknown email
+    fn login_email(body: &LoginUser, policy: InvisibleCharPolicy) -> String {
+        canonicalize_email(&body.email, policy).unwrap_or_default()
+    }
+
+    // Shared by login and the credentials check, so both count towards the same lockout. Missing, soft-deleted and
+    // wrong-password users are answered alike and take as long, so neither response nor timing reveals which accounts exist
+    fn authenticate(shared_state: &ConnectionPool, login_attempts: &LoginAttempts, email: &str, password: &str) -> Result<User, (StatusCode, Json<Value>)> {
+        if login_attempts.is_locked(email) {
+            eprintln!("Refused credentials for locked out email: {}", email);
+            return Err((StatusCode::TOO_MANY_REQUESTS, Json(json!({"error": "Too many failed attempts, try again later"}))));
+        }
+
         let connection = shared_state.pool.get()
             .expect("Failed to acquire connection from pool");
 
-        match UsersTable::new(connection).get_by_email(body.email.clone()) {
-            Ok(Some(user)) if body.email == user.email => {
-                return if verify(&body.password, &user.password).unwrap_or(false) {
-                    if let Some(token) = generate_token(&user).ok() {
-                        Ok((StatusCode::OK, Json(token)))
-                    } else {
-                        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to generate token"}))))
-                    }
-                } else {
-                    Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "Wrong password"}))))
-                }
+        let user = match UsersTable::new(connection).get_by_email(email.to_string()) {
+            Ok(user) => user.filter(|user| !email.is_empty() && email == user.email.to_lowercase()),
+            Err(err) => {
+                eprintln!("Error reading user: {:?}", err);
+                return Err(database_error("Failed to read user", &err));
+            }
+        };
+
+        let valid = verify_password(password, user.as_ref());
+
+        match user {
+            Some(user) if valid => {
+                login_attempts.reset(email);
+                upgrade_password_hash(shared_state, &user, password);
+                Ok(user)
+            }
+            _ => {
+                login_attempts.record_failure(email);
+                Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "Invalid email or password"}))))
             }
-            Ok(Some(_)) => Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"})))),
+        }
+    }
+
+    // Hashes made before the cost settings last changed are replaced while we have the password at hand. Failing to
+    // only leaves the old hash in place, it doesn't fail the login
+    fn upgrade_password_hash(shared_state: &ConnectionPool, user: &User, password: &str) {
+        if !needs_rehash(&user.password) {
+            return;
+        }
+
+        let password_hash = match rehash_password(password, &user.password) {
+            Ok(password_hash) => password_hash,
+            Err(_) => return,
+        };
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        if let Err(err) = UsersTable::new(connection).update_password(user.id, &password_hash, user.must_change_password) {
+            eprintln!("Error upgrading password hash: {:?}", err);
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/me",
+        tag = "users",
+        responses(
+            (status = 200, description = "The authenticated user", body = PublicUser),
+            (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn me_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+
+        // Decode claims from bearer token header
+        let claims = match decode_claims(&headers) {
+            Ok(Some(claims)) => claims,
+            Ok(None) => return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "invalid token"})))),
+            Err((status_code, json_value)) => return Err((status_code, json_value)),
+        };
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        // The token may outlive the account it was issued for
+        match UsersTable::new(connection).get_by_email(claims.claims.sub) {
+            Ok(Some(user)) => Ok((StatusCode::OK, Json(PublicUser::from(user)))),
             Ok(None) => Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"})))),
             Err(err) => {
                 eprintln!("Error reading user: {:?}", err);
-                Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Failed to read user"}))))
+                Err(database_error("Failed to read user", &err))
+            }
+        }
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/users/me/password",
+        tag = "users",
+        request_body = ChangePassword,
+        responses(
+            (status = 204, description = "Password changed"),
+            (status = 401, description = "Missing or invalid token, or the current password is wrong", body = ErrorResponse),
+            (status = 403, description = "Password change attempted with an impersonation token", body = ErrorResponse),
+            (status = 422, description = "The new password is too weak", body = ValidationErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn change_password_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        JsonBody(body): JsonBody<ChangePassword>,
+    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        // Whoever is impersonating the user must not be able to lock them out of their account
+        enforce_not_impersonating(&claims, SensitiveOperation::ChangePassword)?;
+
+        let claims = match claims {
+            Some(claims) => claims,
+            None => return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "invalid token"})))),
+        };
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        let mut users = UsersTable::new(connection);
+
+        let user = match users.get_by_email(claims.claims.sub) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "User in claims not found in DB"})))),
+            Err(err) => {
+                eprintln!("Error reading user: {:?}", err);
+                return Err(database_error("Failed to read user", &err));
+            }
+        };
+
+        // A stolen token alone isn't enough to take over the account
+        if !verify_password(&body.current, Some(&user)) {
+            return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "Current password is wrong"}))));
+        }
+
+        let errors = password_strength_errors(&body.new);
+        if !errors.is_empty() {
+            return Err(ApiError::unprocessable("Invalid password", ValidationErrors::from([("new".to_string(), errors)])).into());
+        }
+
+        let password_hash = hash_password_argon2(&body.new)?;
+
+        match users.update_password(user.id, &password_hash, false) {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => {
+                eprintln!("Error changing password: {:?}", err);
+                Err(database_error("Failed to change password", &err))
+            }
+        }
+    }
+
+    #[utoipa::path(
+        patch,
+        path = "/users/{user_id}/role",
+        tag = "users",
+        params(("user_id" = i32, Path, description = "Id of the user")),
+        request_body = ChangeRole,
+        responses(
+            (status = 200, description = "The user with their new role", body = PublicUser),
+            (status = 400, description = "Unknown role", body = ErrorResponse),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 403, description = "Role change attempted with an impersonation token", body = ErrorResponse),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 409, description = "The user is the last remaining admin", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn change_role_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32,)>,
+        JsonBody(change_role): JsonBody<ChangeRole>,
+    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+        let (user_id,) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = decode_claims(&headers)?;
+
+        enforce_not_impersonating(&claims, SensitiveOperation::ChangeRole)?;
+
+        // Ensure that the user derived from claims exists and has the role 'ADMIN'
+        let admin = match enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await {
+            Ok(Some(admin)) => admin,
+            Ok(None) => return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "User in claims not found in DB"})))),
+            Err(err) => return Err(err),
+        };
+
+        let role = change_role.role.parse::<UserRole>()
+            .map_err(|err| (StatusCode::BAD_REQUEST, Json(json!({"error": err.to_string()}))))?;
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        // Demoting the last admin would leave nobody able to manage roles, just like deleting them
+        let updated_user = match UsersTable::new(connection).set_role(user_id, role.clone()) {
+            Ok(Some(updated_user)) => updated_user,
+            Ok(None) => return Err((StatusCode::CONFLICT, Json(json!({"error": "Cannot demote the last admin"})))),
+            Err(diesel::result::Error::NotFound) => {
+                return Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"}))));
+            },
+            Err(err) => {
+                eprintln!("Error changing role: {:?}", err);
+                return Err(database_error("Failed to change role", &err));
+            }
+        };
+
+        // Recorded once the role has actually changed, so refused demotions leave no trace of a change
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        if let Err(err) = AuditLogTable::new(connection).record(NewAuditEntry {
+            actor: admin.email.clone(),
+            action: format!("change_role:{}", role),
+            target: updated_user.email.clone(),
+        }) {
+            eprintln!("Error recording role change: {:?}", err);
+            return Err(database_error("Failed to record role change", &err));
+        }
+
+        Ok((StatusCode::OK, Json(PublicUser::from(updated_user))))
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/admin/impersonate/{user_id}",
+        tag = "users",
+        params(("user_id" = i32, Path, description = "Id of the user to impersonate")),
+        responses(
+            (status = 200, description = "A short-lived token along with 'impersonated_by' and 'expires_in'", body = Object),
+            (status = 401, description = "Missing or invalid token, or a role below ADMIN", body = ErrorResponse),
+            (status = 403, description = "Impersonation attempted with an impersonation token", body = ErrorResponse),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 500, description = "Internal error", body = ErrorResponse)
+        ),
+        security(("bearer_auth" = []))
+    )]
+    pub async fn impersonate_user_handler(
+        headers: HeaderMap,
+        State(AppState { connection_pool: shared_state, .. }): State<AppState>,
+        path: extract::Path<(i32,)>,
+    ) -> Result<impl IntoResponse, (StatusCode, Json<Value>)> {
+        let (user_id,) = path.0;
+
+        // Decode claims from bearer token header
+        let claims = match decode_claims(&headers) {
+            Ok(claims) => claims,
+            Err((status_code, json_value)) => return Err((status_code, json_value)),
+        };
+
+        // An impersonation token must never be used to start another impersonation
+        enforce_not_impersonating(&claims, SensitiveOperation::Impersonate)?;
+
+        // Ensure that the user derived from claims exists and has the role 'ADMIN'
+        let admin = match enforce_role_policy(&shared_state, &claims, UserRole::ADMIN).await {
+            Ok(Some(admin)) => admin,
+            Ok(None) => return Err((StatusCode::UNAUTHORIZED, Json(json!({"error": "User in claims not found in DB"})))),
+            Err(err) => return Err(err),
+        };
+
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        let target_user = match UsersTable::new(connection).get(user_id) {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err((StatusCode::NOT_FOUND, Json(json!({"error": "User not found"})))),
+            Err(err) => {
+                eprintln!("Error reading user: {:?}", err);
+                return Err(database_error("Failed to read user", &err));
+            }
+        };
+
+        // The audit entry is written before the token is handed out so every impersonation is attributable
+        let connection = shared_state.pool.get()
+            .expect("Failed to acquire connection from pool");
+
+        if let Err(err) = AuditLogTable::new(connection).record(NewAuditEntry {
+            actor: admin.email.clone(),
+            action: "impersonate".to_string(),
+            target: target_user.email.clone(),
+        }) {
+            eprintln!("Error recording impersonation: {:?}", err);
+            return Err(database_error("Failed to record impersonation", &err));
+        }
+
+        eprintln!("{} is impersonating {}", admin.email, target_user.email);
+
+        match generate_impersonation_token(&target_user, &admin.email) {
+            Ok(token) => Ok((StatusCode::OK, Json(json!({
+                "token": token,
+                "impersonated_by": admin.email,
+                "expires_in": IMPERSONATION_TOKEN_TTL.as_secs()
+            })))),
+            Err(err) => {
+                eprintln!("Error generating token: {:?}", err);
+                Err(internal_error("Failed to generate token", &err))
             }
         }
     }
 
+    // Users who have not verified their email address are refused when verified login is required
+    fn enforce_verified_login(user: &User, require_verified: bool) -> Result<(), (StatusCode, Json<Value>)> {
+        if require_verified && !user.email_verified {
+            eprintln!("Login refused for unverified user: {}", user.email);
+            return Err((StatusCode::FORBIDDEN, Json(json!({
+                "error": "email_not_verified",
+                "hint": "Verify your email address before logging in"
+            }))));
+        }
+
+        Ok(())
+    }
+
     #[cfg(test)]
     mod tests {
         use axum::body::Body;
         use axum::http::{Request, StatusCode};
         use serde_json::json;
         use tower::ServiceExt;
-        use crate::{create_shared_connection_pool, load_environment_variable, users_route};
-        use crate::users::model::UpsertUser;
+        use crate::{common::{config::Config, db::create_shared_connection_pool, state::AppState, test_db::with_test_db, util::load_environment_variable}, users_route};
+        use crate::users::model::{UpdateUser, UpsertUser};
         use crate::users::service::service::UsersTable;
+        use crate::users::router::router::enforce_verified_login;
+        use crate::common::security::{decode_token, generate_token, hash_password, hash_password_argon2, hash_password_argon2_with_params, jwt_config, needs_rehash};
+        use crate::users::model::{canonicalize_email, InvisibleCharPolicy, LoginUser, User};
+        use crate::schema::{audit_log, users};
+        use diesel::prelude::*;
 
         #[tokio::test]
         async fn post_users_returns_201_on_valid_data() {
             let database_url = load_environment_variable("TEST_DB");
             let connection_pool = create_shared_connection_pool(database_url, 1);
-            let service = users_route(connection_pool);
+            let service = users_route(AppState::test(connection_pool));
 
             let request_body = UpsertUser {
                 email: "valid@email.com".to_string(),
@@ -196,15 +912,54 @@ pub mod router {
         }
 
         #[tokio::test]
-        async fn post_users_returns_422_on_invalid_email() {
+        async fn post_users_returns_409_on_email_differing_only_by_casing() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool));
+
+            for (email, expected_status) in [("Foo@x.com", StatusCode::CREATED), ("foo@x.com", StatusCode::CONFLICT)] {
+                let request_body = UpsertUser {
+                    email: email.to_string(),
+                    password: "Big100".to_string(),
+                    fullname: "Foo Casing".to_string(),
+                    role: "READER".to_string()
+                };
+
+                // Create a request with the above data as payload
+                let request = Request::builder()
+                    .uri("/users")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .clone()
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), expected_status);
+
+                if expected_status == StatusCode::CONFLICT {
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    assert_eq!(response_json, json!({"error": "email already registered"}));
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn post_users_stores_padded_email_in_canonical_form() {
             let database_url = load_environment_variable("TEST_DB");
             let connection_pool = create_shared_connection_pool(database_url, 1);
-            let service = users_route(connection_pool);
+            let service = users_route(AppState::test(connection_pool));
 
             let request_body = UpsertUser {
-                email: "eg-klare-meg".to_string(),
+                email: "  Padded@Whitespace.no \t".to_string(),
                 password: "Big100".to_string(),
-                fullname: "Kenneth Molasses".to_string(),
+                fullname: "Polstret Bruker".to_string(),
                 role: "READER".to_string()
             };
 
@@ -222,29 +977,197 @@ pub mod router {
                 .await
                 .unwrap();
 
-            // Assert that the response status is 422
-            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            // Assert that the response status is 201
+            assert_eq!(response.status(), StatusCode::CREATED);
+
+            // Extract body from response
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(response_json["email"], "padded@whitespace.no");
         }
 
         #[tokio::test]
-        async fn put_users_returns_200_on_valid_data() {
+        async fn post_users_returns_422_on_email_with_zero_width_char() {
             let database_url = load_environment_variable("TEST_DB");
-            let connection_pool = create_shared_connection_pool(database_url, 2);
-            let connection = connection_pool.pool.get().expect("Failed to get connection");
-            let mut user_db = UsersTable::new(connection);
-            let service = users_route(connection_pool);
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool));
 
-            // Data
             let request_body = UpsertUser {
-                email: "ernst@snowmail.com".to_string(),
-                password: "feltedsnowmen".to_string(),
-                fullname: "Ernst van Schnee".to_string(),
+                email: "zero\u{200B}width@invisible.no".to_string(),
+                password: "Big100".to_string(),
+                fullname: "Usynlig Bruker".to_string(),
                 role: "READER".to_string()
             };
 
-            // Create a new location with the above data
-            let created_user = user_db.create(request_body.clone()).expect("Create location failed");
-
+            // Create a request with the above data as payload
+            let request = Request::builder()
+                .uri("/users")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 422
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        #[tokio::test]
+        async fn post_users_strips_zero_width_chars_when_the_config_says_so() {
+            with_test_db(|connection_pool| async move {
+                let config = Config { invisible_email_chars: InvisibleCharPolicy::Strip, ..Config::from_env() };
+                let service = users_route(AppState::new(connection_pool, config));
+
+                let request_body = UpsertUser {
+                    email: "zero\u{200B}width@stripped.no".to_string(),
+                    password: "Big100".to_string(),
+                    fullname: "Synlig Bruker".to_string(),
+                    role: "READER".to_string()
+                };
+
+                // Create a request with the above data as payload
+                let request = Request::builder()
+                    .uri("/users")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the user is created without the invisible character
+                assert_eq!(response.status(), StatusCode::CREATED);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                assert_eq!(response_json["email"], "zerowidth@stripped.no");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_users_and_login_return_422_naming_an_unknown_field() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool));
+
+            let bodies = [
+                ("/users", json!({"email": "ukjent@felt.no", "password": "Big100", "fullname": "Ukjent Felt", "role": "READER", "foo": 1})),
+                ("/users/login", json!({"email": "ukjent@felt.no", "password": "Big100", "foo": 1})),
+            ];
+
+            for (uri, body) in bodies {
+                let request = Request::builder()
+                    .uri(uri)
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+
+                let response = service.clone().oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", uri);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert!(response_json["errors"]["foo"].as_str().unwrap().starts_with("unknown field, expected"), "{}", response_json);
+            }
+        }
+
+        #[test]
+        fn is_valid_email_gives_the_same_answer_on_every_call() {
+            let with_email = |email: &str| UpsertUser {
+                email: email.to_string(),
+                password: "Big100".to_string(),
+                fullname: "Regex Reuser".to_string(),
+                role: "READER".to_string()
+            };
+
+            for _ in 0..3 {
+                assert!(with_email("valid@email.com").is_valid_email());
+                assert!(with_email("first.last+tag@sub.domain.no").is_valid_email());
+                assert!(!with_email("eg-klare-meg").is_valid_email());
+                assert!(!with_email("missing@tld").is_valid_email());
+                assert!(!with_email("@nobody.com").is_valid_email());
+            }
+        }
+
+        #[test]
+        fn canonicalize_email_applies_the_invisible_char_policy() {
+            let padded = " \tUser@X.com\n";
+            let zero_width = "us\u{200B}er@x.com";
+
+            assert_eq!(canonicalize_email(padded, InvisibleCharPolicy::Reject), Some("user@x.com".to_string()));
+            assert_eq!(canonicalize_email(zero_width, InvisibleCharPolicy::Reject), None);
+            assert_eq!(canonicalize_email(zero_width, InvisibleCharPolicy::Strip), Some("user@x.com".to_string()));
+
+            // Control characters inside the address are refused whatever the policy
+            assert_eq!(canonicalize_email("us\u{0007}er@x.com", InvisibleCharPolicy::Strip), None);
+        }
+
+        #[tokio::test]
+        async fn post_users_returns_422_on_invalid_email() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool));
+
+            let request_body = UpsertUser {
+                email: "eg-klare-meg".to_string(),
+                password: "Big100".to_string(),
+                fullname: "Kenneth Molasses".to_string(),
+                role: "READER".to_string()
+            };
+
+            // Create a request with the above data as payload
+            let request = Request::builder()
+                .uri("/users")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 422 and the error is listed under its field
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(response_json, json!({"error": "Invalid user", "errors": {"email": ["Field 'email' must be a valid email address"]}}));
+        }
+
+        #[tokio::test]
+        async fn put_users_returns_200_on_valid_data() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+            let service = users_route(AppState::test(connection_pool));
+
+            // Data
+            let request_body = UpsertUser {
+                email: "ernst@snowmail.com".to_string(),
+                password: "feltedsnowmen".to_string(),
+                fullname: "Ernst van Schnee".to_string(),
+                role: "READER".to_string()
+            };
+
+            // Create a new location with the above data
+            let created_user = user_db.create(request_body.clone()).expect("Create location failed");
+
             // Assert equality
             assert_eq!(request_body.email, created_user.email);
             assert_eq!(request_body.password, created_user.password);
@@ -252,18 +1175,22 @@ pub mod router {
             assert_eq!(request_body.role, created_user.role);
 
             // Data
-            let updated_request_body = UpsertUser {
+            let updated_request_body = UpdateUser {
                 email: "ernst@snowmail.com".to_string(),
-                password: "feltseng?".to_string(),
-                fullname: "Ernst van Schnee".to_string(),
+                password: None,
+                fullname: "Ernst von Schnee".to_string(),
                 role: "READER".to_string()
             };
 
+            // Users may edit their own record
+            let bearer_token = generate_token(&created_user).expect("Generate token failed");
+
             // Create a request with the above data as payload
             let request = Request::builder()
                 .uri(format!("/users/{}", created_user.id))
                 .method("PUT")
                 .header("content-type", "application/json")
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
                 .body(Body::from(serde_json::to_string(&updated_request_body).unwrap()))
                 .unwrap();
 
@@ -284,22 +1211,101 @@ pub mod router {
             let expected_response = json!({
                 "id": created_user.id,
                 "email": updated_request_body.email,
-                "password": updated_request_body.password,
+                "password": created_user.password,
                 "fullname": updated_request_body.fullname,
-                "role": updated_request_body.role
+                "role": updated_request_body.role,
+                "email_verified": false,
+                "must_change_password": false
             });
 
             // Assert equality
             assert_eq!(response_json, expected_response);
         }
 
+        #[tokio::test]
+        async fn put_users_returns_422_on_password_change_but_accepts_the_current_one() {
+            with_test_db(|connection_pool| async move {
+                let user = create_user_with_password(&connection_pool, "uendret@passord.no", "Original123");
+                let bearer_token = generate_token(&user).expect("Generate token failed");
+
+                let put_password = |password: &'static str| {
+                    let connection_pool = connection_pool.clone();
+                    let (user, bearer_token) = (user.clone(), bearer_token.clone());
+                    async move {
+                        let request_body = json!({
+                            "email": user.email,
+                            "password": password,
+                            "fullname": user.fullname,
+                            "role": user.role
+                        });
+
+                        let request = Request::builder()
+                            .uri(format!("/users/{}", user.id))
+                            .method("PUT")
+                            .header("content-type", "application/json")
+                            .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                            .body(Body::from(request_body.to_string()))
+                            .unwrap();
+
+                        users_route(AppState::test(connection_pool)).oneshot(request).await.unwrap().status()
+                    }
+                };
+
+                // Assert that a new password is refused, while repeating the current one is not a change
+                assert_eq!(put_password("Replacement456").await, StatusCode::UNPROCESSABLE_ENTITY);
+                assert_eq!(put_password("Original123").await, StatusCode::OK);
+
+                // Assert that the stored hash is untouched
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let stored_user = UsersTable::new(connection).get(user.id).expect("Read user failed").unwrap();
+                assert_eq!(stored_user.password, user.password);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_users_returns_409_on_email_taken_by_another_user() {
+            with_test_db(|connection_pool| async move {
+                create_user_with_role(&connection_pool, "opptatt@epost.no", "READER");
+                let user = create_user_with_role(&connection_pool, "ledig@epost.no", "READER");
+                let bearer_token = generate_token(&user).expect("Generate token failed");
+
+                // Take over the other user's email, differing only by casing
+                let request_body = json!({
+                    "email": "Opptatt@Epost.no",
+                    "fullname": user.fullname,
+                    "role": "READER"
+                });
+
+                let request = Request::builder()
+                    .uri(format!("/users/{}", user.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::from(request_body.to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = users_route(AppState::test(connection_pool))
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the collision is answered with 409 rather than bringing down the worker
+                assert_eq!(response.status(), StatusCode::CONFLICT);
+
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(response_json, json!({"error": "email already registered"}));
+            }).await;
+        }
+
         #[tokio::test]
         async fn get_users_returns_200_on_existing_id() {
             let database_url = load_environment_variable("TEST_DB");
             let connection_pool = create_shared_connection_pool(database_url, 2);
             let connection = connection_pool.pool.get().expect("Failed to get connection");
             let mut user_db = UsersTable::new(connection);
-            let service = users_route(connection_pool);
+            let service = users_route(AppState::test(connection_pool));
 
             let request_body = UpsertUser {
                 email: "glossy@ringdue.no".to_string(),
@@ -337,7 +1343,9 @@ pub mod router {
                 "email": request_body.email,
                 "password": request_body.password,
                 "fullname": request_body.fullname,
-                "role": request_body.role
+                "role": request_body.role,
+                "email_verified": false,
+                "must_change_password": false
             });
 
             // Assert equality
@@ -348,7 +1356,7 @@ pub mod router {
         async fn get_users_returns_404_on_non_existing_id() {
             let database_url = load_environment_variable("TEST_DB");
             let connection_pool = create_shared_connection_pool(database_url, 2);
-            let service = users_route(connection_pool);
+            let service = users_route(AppState::test(connection_pool));
 
             // Create a request with the aforementioned id
             let request = Request::builder()
@@ -373,7 +1381,7 @@ pub mod router {
             let connection_pool = create_shared_connection_pool(database_url, 2);
             let connection = connection_pool.pool.get().expect("Failed to get connection");
             let mut user_db = UsersTable::new(connection);
-            let service = users_route(connection_pool);
+            let service = users_route(AppState::test(connection_pool));
 
             let request_body = UpsertUser {
                 email: "josek@ifi.uio.no".to_string(),
@@ -385,10 +1393,19 @@ pub mod router {
             // Create a new user with the above data
             let created_user = user_db.create(request_body.clone()).expect("Create location failed");
 
+            let admin = user_db.create(UpsertUser {
+                email: "josek.admin@ifi.uio.no".to_string(),
+                password: "TurboPascalLife".to_string(),
+                fullname: "Jose Kernelio".to_string(),
+                role: "ADMIN".to_string()
+            }).expect("Create user failed");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+
             // Create a request with the ID associated with our newly inserted row
             let request = Request::builder()
                 .uri(format!("/users/{}", created_user.id))
                 .method("DELETE")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
                 .body(Body::empty())
                 .unwrap();
 
@@ -413,5 +1430,1106 @@ pub mod router {
             // Assert that the deleted user is None (i.e., it doesn't exist)
             assert!(deleted_user.is_none());
         }
+
+        async fn delete_user(connection_pool: crate::common::db::ConnectionPool, token: Option<String>, user_id: i32) -> StatusCode {
+            let mut request = Request::builder()
+                .uri(format!("/users/{}", user_id))
+                .method("DELETE");
+
+            if let Some(token) = token {
+                request = request.header("Authorization", format!("Bearer {}", token)); // Add the bearer token
+            }
+
+            // Send the request through the service
+            users_route(AppState::test(connection_pool))
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status()
+        }
+
+        #[tokio::test]
+        async fn delete_users_returns_401_without_an_admin_token() {
+            with_test_db(|connection_pool| async move {
+                let writer = create_user_with_role(&connection_pool, "skribent@sletting.no", "WRITER");
+                let target = create_user_with_role(&connection_pool, "offer@sletting.no", "READER");
+                let writer_token = generate_token(&writer).expect("Generate token failed");
+
+                assert_eq!(delete_user(connection_pool.clone(), None, target.id).await, StatusCode::UNAUTHORIZED);
+                assert_eq!(delete_user(connection_pool.clone(), Some(writer_token), target.id).await, StatusCode::UNAUTHORIZED);
+
+                // Assert that the user is still around
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                assert!(UsersTable::new(connection).get(target.id).expect("Read user failed").is_some());
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn delete_users_refuses_to_delete_the_last_admin() {
+            with_test_db(|connection_pool| async move {
+                let admin = create_user_with_role(&connection_pool, "eneste.admin@sletting.no", "ADMIN");
+                let admin_token = generate_token(&admin).expect("Generate token failed");
+
+                // Assert that the only admin can't delete themselves
+                assert_eq!(delete_user(connection_pool.clone(), Some(admin_token.clone()), admin.id).await, StatusCode::CONFLICT);
+
+                // With a second admin around the delete goes through
+                create_user_with_role(&connection_pool, "andre.admin@sletting.no", "ADMIN");
+                assert_eq!(delete_user(connection_pool, Some(admin_token), admin.id).await, StatusCode::NO_CONTENT);
+            }).await;
+        }
+
+        #[test]
+        fn login_is_refused_for_unverified_user_when_verification_is_required() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+
+            let created_user = user_db.create(UpsertUser {
+                email: "unverified@gatekeeper.no".to_string(),
+                password: "NotYetVerified".to_string(),
+                fullname: "Ulrik Unverified".to_string(),
+                role: "READER".to_string()
+            }).expect("Create user failed");
+
+            // Newly created users have not verified their email
+            assert!(!created_user.email_verified);
+
+            let result = enforce_verified_login(&created_user, true);
+
+            // Assert that the user is refused with 403 and a hint to verify
+            let (status, body) = result.expect_err("Expected unverified login to be refused");
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(body.0["error"], json!("email_not_verified"));
+            assert!(body.0["hint"].is_string());
+        }
+
+        #[test]
+        fn login_is_allowed_for_unverified_user_when_verification_is_not_required() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+
+            let created_user = user_db.create(UpsertUser {
+                email: "relaxed@gatekeeper.no".to_string(),
+                password: "NotYetVerified".to_string(),
+                fullname: "Rita Relaxed".to_string(),
+                role: "READER".to_string()
+            }).expect("Create user failed");
+
+            assert!(enforce_verified_login(&created_user, false).is_ok());
+        }
+
+        #[tokio::test]
+        async fn get_me_returns_200_with_public_user_for_valid_token() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+            let service = users_route(AppState::test(connection_pool));
+
+            let created_user = user_db.create(UpsertUser {
+                email: "whoami@mirror.no".to_string(),
+                password: "LookingGlass".to_string(),
+                fullname: "Speil Speilsen".to_string(),
+                role: "WRITER".to_string()
+            }).expect("Create user failed");
+
+            let bearer_token = generate_token(&created_user).expect("Generate token failed");
+
+            let request = Request::builder()
+                .uri("/me")
+                .method("GET")
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 200
+            assert_eq!(response.status(), StatusCode::OK);
+
+            // Extract body from response
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            // Construct JSON consisting of expected payload - the password must never be included
+            let expected_response = json!({
+                "id": created_user.id,
+                "email": created_user.email,
+                "fullname": created_user.fullname,
+                "role": created_user.role,
+                "email_verified": false
+            });
+
+            // Assert equality
+            assert_eq!(response_json, expected_response);
+        }
+
+        #[tokio::test]
+        async fn get_me_returns_401_without_token() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool));
+
+            let request = Request::builder()
+                .uri("/me")
+                .method("GET")
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 401
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn get_me_returns_404_when_user_in_token_was_deleted() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+            let service = users_route(AppState::test(connection_pool));
+
+            let created_user = user_db.create(UpsertUser {
+                email: "ghost@mirror.no".to_string(),
+                password: "NoReflection".to_string(),
+                fullname: "Gjenganger".to_string(),
+                role: "READER".to_string()
+            }).expect("Create user failed");
+
+            // Issue a token and then remove the account it was issued for
+            let bearer_token = generate_token(&created_user).expect("Generate token failed");
+            user_db.delete(created_user.id).expect("Delete user failed");
+
+            let request = Request::builder()
+                .uri("/me")
+                .method("GET")
+                .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 404
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        // Helper creating a user with the given role directly in the database
+        fn create_user_with_role(connection_pool: &crate::common::db::ConnectionPool, email: &str, role: &str) -> User {
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            UsersTable::new(connection).create(UpsertUser {
+                email: email.to_string(),
+                password: "ImpersonateMeNot".to_string(),
+                fullname: "Ingrid Imposter".to_string(),
+                role: role.to_string()
+            }).expect("Create user failed")
+        }
+
+        async fn patch_role(connection_pool: crate::common::db::ConnectionPool, admin: &User, user_id: i32, role: &str) -> (StatusCode, serde_json::Value) {
+            let admin_token = generate_token(admin).expect("Generate token failed");
+
+            let request = Request::builder()
+                .uri(format!("/users/{}/role", user_id))
+                .method("PATCH")
+                .header("content-type", "application/json")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::from(json!({"role": role}).to_string()))
+                .unwrap();
+
+            // Send the request through the service
+            let response = users_route(AppState::test(connection_pool))
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        #[tokio::test]
+        async fn patch_role_promotes_user_and_returns_public_user() {
+            with_test_db(|connection_pool| async move {
+                let admin = create_user_with_role(&connection_pool, "rolle.admin@forfremmelse.no", "ADMIN");
+                let writer = create_user_with_role(&connection_pool, "skribent@forfremmelse.no", "WRITER");
+
+                let (status, response_json) = patch_role(connection_pool.clone(), &admin, writer.id, "editor").await;
+
+                // Assert that the user is returned with the new role and without the password
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(response_json["role"], json!("EDITOR"));
+                assert!(response_json.get("password").is_none());
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let stored = UsersTable::new(connection).get(writer.id).expect("Read user failed").unwrap();
+                assert_eq!(stored.role, "EDITOR");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn patch_role_returns_400_on_invalid_or_unknown_role() {
+            with_test_db(|connection_pool| async move {
+                let admin = create_user_with_role(&connection_pool, "rolle.admin@ugyldig.no", "ADMIN");
+                let reader = create_user_with_role(&connection_pool, "leser@ugyldig.no", "READER");
+
+                for role in ["INVALID", "OWNER"] {
+                    let (status, response_json) = patch_role(connection_pool.clone(), &admin, reader.id, role).await;
+
+                    assert_eq!(status, StatusCode::BAD_REQUEST, "Expected 400 for '{}'", role);
+                    assert_eq!(response_json, json!({"error": format!("Unknown role '{}'", role)}));
+                }
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn patch_role_refuses_to_demote_the_last_admin() {
+            with_test_db(|connection_pool| async move {
+                let admin = create_user_with_role(&connection_pool, "eneste.admin@degradering.no", "ADMIN");
+
+                let (status, response_json) = patch_role(connection_pool.clone(), &admin, admin.id, "READER").await;
+
+                // Assert that the only admin keeps their role
+                assert_eq!(status, StatusCode::CONFLICT);
+                assert_eq!(response_json, json!({"error": "Cannot demote the last admin"}));
+
+                // With a second admin around the demotion goes through
+                create_user_with_role(&connection_pool, "andre.admin@degradering.no", "ADMIN");
+                let (status, _) = patch_role(connection_pool, &admin, admin.id, "READER").await;
+                assert_eq!(status, StatusCode::OK);
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn put_users_refuses_to_demote_the_last_admin() {
+            with_test_db(|connection_pool| async move {
+                let admin = create_user_with_role(&connection_pool, "eneste.admin@redigering.no", "ADMIN");
+                let admin_token = generate_token(&admin).expect("Generate token failed");
+
+                let request_body = json!({
+                    "email": admin.email,
+                    "fullname": admin.fullname,
+                    "role": "READER"
+                });
+
+                let request = Request::builder()
+                    .uri(format!("/users/{}", admin.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                    .body(Body::from(request_body.to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = users_route(AppState::test(connection_pool.clone()))
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the full update is held to the same guard as PATCH /users/:id/role
+                assert_eq!(response.status(), StatusCode::CONFLICT);
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let stored_user = UsersTable::new(connection).get(admin.id).unwrap().unwrap();
+                assert_eq!(stored_user.role, "ADMIN");
+            }).await;
+        }
+
+        #[tokio::test]
+        async fn post_impersonate_returns_token_tagged_with_impersonated_by() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let admin = create_user_with_role(&connection_pool, "support.admin@impersonation.no", "ADMIN");
+            let target = create_user_with_role(&connection_pool, "locked.out@impersonation.no", "READER");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+
+            let request = Request::builder()
+                .uri(format!("/admin/impersonate/{}", target.id))
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 200
+            assert_eq!(response.status(), StatusCode::OK);
+
+            // Extract body from response
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            // Assert that the token is issued for the target and attributed to the admin
+            let token = response_json["token"].as_str().unwrap();
+            let decoded = decode_token(token, jwt_config()).expect("Decode token failed");
+            assert_eq!(decoded.claims.sub, target.email);
+            assert_eq!(decoded.claims.impersonated_by, Some(admin.email.clone()));
+
+            // Assert that the impersonation was written to the audit log
+            let mut connection = connection_pool.pool.get().expect("Failed to get connection");
+            let audit_entries: i64 = audit_log::table
+                .filter(audit_log::actor.eq(&admin.email))
+                .filter(audit_log::action.eq("impersonate"))
+                .filter(audit_log::target.eq(&target.email))
+                .count()
+                .get_result(&mut connection)
+                .expect("Read audit log failed");
+            assert_eq!(audit_entries, 1);
+        }
+
+        #[tokio::test]
+        async fn post_impersonate_returns_403_when_using_an_impersonation_token() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+
+            let admin = create_user_with_role(&connection_pool, "first.admin@impersonation.no", "ADMIN");
+            let other_admin = create_user_with_role(&connection_pool, "second.admin@impersonation.no", "ADMIN");
+            let target = create_user_with_role(&connection_pool, "third.party@impersonation.no", "READER");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+
+            // Impersonate another admin, so only the impersonation itself can be the reason for refusal
+            let request = Request::builder()
+                .uri(format!("/admin/impersonate/{}", other_admin.id))
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            let response = users_route(AppState::test(connection_pool.clone()))
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let impersonation_token = response_json["token"].as_str().unwrap().to_string();
+
+            // Attempt to impersonate again using the impersonation token
+            let request = Request::builder()
+                .uri(format!("/admin/impersonate/{}", target.id))
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", impersonation_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            let response = users_route(AppState::test(connection_pool))
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 403
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+
+        // Helper issuing an impersonation token for the target user through the admin endpoint
+        async fn impersonate(connection_pool: crate::common::db::ConnectionPool, admin: &User, target: &User) -> String {
+            let admin_token = generate_token(admin).expect("Generate token failed");
+
+            let request = Request::builder()
+                .uri(format!("/admin/impersonate/{}", target.id))
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            let response = users_route(AppState::test(connection_pool))
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            response_json["token"].as_str().unwrap().to_string()
+        }
+
+        #[tokio::test]
+        async fn put_users_with_impersonation_token_returns_403_on_role_change() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+
+            let admin = create_user_with_role(&connection_pool, "role.guard.admin@impersonation.no", "ADMIN");
+            let target = create_user_with_role(&connection_pool, "role.guard.target@impersonation.no", "READER");
+            let impersonation_token = impersonate(connection_pool.clone(), &admin, &target).await;
+
+            // Attempt to promote the impersonated user
+            let request_body = json!({
+                "email": target.email,
+                "fullname": target.fullname,
+                "role": "ADMIN"
+            });
+
+            let request = Request::builder()
+                .uri(format!("/users/{}", target.id))
+                .method("PUT")
+                .header("content-type", "application/json")
+                .header("Authorization", format!("Bearer {}", impersonation_token)) // Add the bearer token
+                .body(Body::from(request_body.to_string()))
+                .unwrap();
+
+            // Send the request through the service
+            let response = users_route(AppState::test(connection_pool.clone()))
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 403 and the role is untouched
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let stored_user = UsersTable::new(connection).get(target.id).unwrap().unwrap();
+            assert_eq!(stored_user.role, "READER");
+        }
+
+        #[tokio::test]
+        async fn put_users_without_token_returns_401_on_role_change() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+
+            let target = create_user_with_role(&connection_pool, "role.guard.anonymous@impersonation.no", "READER");
+
+            // Attempt to promote the user without any token at all
+            let request_body = json!({
+                "email": target.email,
+                "fullname": target.fullname,
+                "role": "ADMIN"
+            });
+
+            let request = Request::builder()
+                .uri(format!("/users/{}", target.id))
+                .method("PUT")
+                .header("content-type", "application/json")
+                .body(Body::from(request_body.to_string()))
+                .unwrap();
+
+            // Send the request through the service
+            let response = users_route(AppState::test(connection_pool.clone()))
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 401 and the role is untouched
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let stored_user = UsersTable::new(connection).get(target.id).unwrap().unwrap();
+            assert_eq!(stored_user.role, "READER");
+        }
+
+        #[tokio::test]
+        async fn put_users_with_impersonation_token_returns_200_without_role_change() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+
+            let admin = create_user_with_role(&connection_pool, "name.fix.admin@impersonation.no", "ADMIN");
+            let target = create_user_with_role(&connection_pool, "name.fix.target@impersonation.no", "READER");
+            let impersonation_token = impersonate(connection_pool.clone(), &admin, &target).await;
+
+            // Fix the impersonated user's name while keeping their role
+            let request_body = json!({
+                "email": target.email,
+                "fullname": "Ingrid Corrected",
+                "role": "READER"
+            });
+
+            let request = Request::builder()
+                .uri(format!("/users/{}", target.id))
+                .method("PUT")
+                .header("content-type", "application/json")
+                .header("Authorization", format!("Bearer {}", impersonation_token)) // Add the bearer token
+                .body(Body::from(request_body.to_string()))
+                .unwrap();
+
+            // Send the request through the service
+            let response = users_route(AppState::test(connection_pool))
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 200
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        #[tokio::test]
+        async fn put_users_with_impersonation_token_returns_403_on_password_change() {
+            with_test_db(|connection_pool| async move {
+                let admin = create_user_with_role(&connection_pool, "password.guard.admin@impersonation.no", "ADMIN");
+                let target = create_user_with_password(&connection_pool, "password.guard.target@impersonation.no", "ImpersonateMeNot1");
+                let impersonation_token = impersonate(connection_pool.clone(), &admin, &target).await;
+
+                // Keep everything but the password
+                let request_body = json!({
+                    "email": target.email,
+                    "password": "TakenOver123",
+                    "fullname": target.fullname,
+                    "role": target.role
+                });
+
+                let request = Request::builder()
+                    .uri(format!("/users/{}", target.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", impersonation_token)) // Add the bearer token
+                    .body(Body::from(request_body.to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = users_route(AppState::test(connection_pool.clone()))
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 403 and the password is untouched
+                assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let stored_user = UsersTable::new(connection).get(target.id).expect("Read user failed").unwrap();
+                assert_eq!(stored_user.password, target.password);
+            }).await;
+        }
+
+        async fn post_credentials(service: axum::Router, uri: &str, email: &str, password: &str) -> (StatusCode, serde_json::Value) {
+            let request_body = LoginUser {
+                email: email.to_string(),
+                password: password.to_string()
+            };
+
+            let request = Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&request_body).unwrap()))
+                .unwrap();
+
+            let response = service.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        #[tokio::test]
+        async fn credential_checks_report_the_attempts_left() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            create_user_with_password(&connection_pool, "nedtelling@ratelimit.no", "Original123");
+
+            let attempts_left = |uri: &'static str, password: &'static str| {
+                let service = service.clone();
+                async move {
+                    let request = Request::builder()
+                        .uri(uri)
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(json!({"email": "nedtelling@ratelimit.no", "password": password}).to_string()))
+                        .unwrap();
+
+                    let response = service.oneshot(request).await.unwrap();
+                    let header = |name: &str| response.headers()[name].to_str().unwrap().parse::<u64>().unwrap();
+
+                    (response.status(), header("x-ratelimit-limit"), header("x-ratelimit-remaining"), header("x-ratelimit-reset"))
+                }
+            };
+
+            let (status, limit, remaining, reset) = attempts_left("/auth/check", "WrongGuess1").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(remaining, limit - 1);
+            assert!(reset > 0);
+
+            // The lockout is shared, so a failed login counts down the same allowance
+            let (status, _, remaining, _) = attempts_left("/users/login", "WrongGuess2").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(remaining, limit - 2);
+
+            // Successful logins are answered with the headers too, and give the whole allowance back
+            let (status, _, remaining, reset) = attempts_left("/users/login", "Original123").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!((remaining, reset), (limit, 0));
+        }
+
+        #[tokio::test]
+        async fn soft_deleted_user_cannot_log_in_until_restored() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let admin = create_user_with_role(&connection_pool, "restorer@softdelete.no", "ADMIN");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+
+            let mut new_user = UpsertUser {
+                email: "sleeper@softdelete.no".to_string(),
+                password: "ZzzZzzZzz".to_string(),
+                fullname: "Rip van Winkle".to_string(),
+                role: "READER".to_string()
+            };
+            hash_password(&mut new_user).expect("Hash password failed");
+            let user = {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                UsersTable::new(connection).create(new_user).expect("Create user failed")
+            };
+
+            let request = Request::builder()
+                .uri(format!("/users/{}", user.id))
+                .method("DELETE")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+            let response = service.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+            // The soft-deleted user is answered exactly like an email nobody registered
+            let (status, body) = post_credentials(service.clone(), "/users/login", "sleeper@softdelete.no", "ZzzZzzZzz").await;
+            let (unknown_status, unknown_body) = post_credentials(service.clone(), "/users/login", "nobody@softdelete.no", "ZzzZzzZzz").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!((status, body), (unknown_status, unknown_body));
+
+            let request = Request::builder()
+                .uri(format!("/users/{}/restore", user.id))
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+            let response = service.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let (status, _) = post_credentials(service, "/users/login", "sleeper@softdelete.no", "ZzzZzzZzz").await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        #[tokio::test]
+        async fn post_restore_returns_401_for_non_admin() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let editor = create_user_with_role(&connection_pool, "editor@softdelete.no", "EDITOR");
+            let editor_token = generate_token(&editor).expect("Generate token failed");
+
+            let request = Request::builder()
+                .uri(format!("/users/{}/restore", editor.id))
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", editor_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 401
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn get_users_returns_only_users_with_the_requested_role() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let admin = create_user_with_role(&connection_pool, "lister@userlist.no", "ADMIN");
+            let editor = create_user_with_role(&connection_pool, "editor@userlist.no", "EDITOR");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+
+            for (uri, expect_editor_only) in [("/users?role=EDITOR&limit=1000", true), ("/users?limit=1000", false)] {
+                let request = Request::builder()
+                    .uri(uri)
+                    .method("GET")
+                    .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Send the request through the service
+                let response = service
+                    .clone()
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 200
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // Extract body from response
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let items = response_json["items"].as_array().unwrap();
+
+                assert!(items.iter().any(|user| user["id"] == editor.id));
+                assert_eq!(items.iter().any(|user| user["id"] == admin.id), !expect_editor_only);
+                assert!(items.iter().all(|user| user.get("password").is_none()));
+            }
+        }
+
+        #[tokio::test]
+        async fn get_users_returns_400_on_unknown_role() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let admin = create_user_with_role(&connection_pool, "unknown.role@userlist.no", "ADMIN");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+
+            let request = Request::builder()
+                .uri("/users?role=OVERLORD")
+                .method("GET")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 400
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        async fn post_auth_check_answers_without_issuing_a_token() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let mut new_user = UpsertUser {
+                email: "integration@authcheck.no".to_string(),
+                password: "RightAnswer42".to_string(),
+                fullname: "Ingrid Integrasjon".to_string(),
+                role: "READER".to_string()
+            };
+            hash_password(&mut new_user).expect("Hash password failed");
+            {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                UsersTable::new(connection).create(new_user).expect("Create user failed");
+            }
+
+            let (status, body) = post_credentials(service.clone(), "/auth/check", "integration@authcheck.no", "RightAnswer42").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, json!({"valid": true}));
+
+            let (status, body) = post_credentials(service.clone(), "/auth/check", "integration@authcheck.no", "WrongAnswer41").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert!(body.get("token").is_none());
+
+            let (status, _) = post_credentials(service, "/auth/check", "nobody@authcheck.no", "RightAnswer42").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        fn create_user_with_password(connection_pool: &crate::common::db::ConnectionPool, email: &str, password: &str) -> User {
+            let mut new_user = UpsertUser {
+                email: email.to_string(),
+                password: password.to_string(),
+                fullname: "Passord Bytter".to_string(),
+                role: "READER".to_string()
+            };
+            hash_password(&mut new_user).expect("Hash password failed");
+
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            UsersTable::new(connection).create(new_user).expect("Create user failed")
+        }
+
+        async fn change_password(service: axum::Router, token: &str, current: &str, new: &str) -> StatusCode {
+            let request = Request::builder()
+                .uri("/users/me/password")
+                .method("POST")
+                .header("content-type", "application/json")
+                .header("Authorization", format!("Bearer {}", token)) // Add the bearer token
+                .body(Body::from(json!({"current": current, "new": new}).to_string()))
+                .unwrap();
+
+            service.oneshot(request).await.unwrap().status()
+        }
+
+        #[tokio::test]
+        async fn post_change_password_returns_401_on_wrong_current_password() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let user = create_user_with_password(&connection_pool, "forgetful@password.no", "Original123");
+            let token = generate_token(&user).expect("Generate token failed");
+
+            let status = change_password(service.clone(), &token, "NotMyPassword1", "Replacement456").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            // The original password still works
+            let (status, _) = post_credentials(service, "/auth/check", "forgetful@password.no", "Original123").await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        #[tokio::test]
+        async fn post_change_password_returns_422_on_weak_new_password() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let user = create_user_with_password(&connection_pool, "lazy@password.no", "Original123");
+            let token = generate_token(&user).expect("Generate token failed");
+
+            let status = change_password(service, &token, "Original123", "short").await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        #[tokio::test]
+        async fn login_upgrades_a_hash_made_with_old_argon2_params() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let user = create_user_with_password(&connection_pool, "gammel.hash@password.no", "Original123");
+            let old_params = argon2::Params::new(8, 1, 1, None).expect("Invalid argon2 params");
+            let old_hash = hash_password_argon2_with_params("Original123", &old_params).expect("Hash password failed");
+            {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                UsersTable::new(connection).update_password(user.id, &old_hash, false).expect("Update password failed");
+            }
+            assert!(needs_rehash(&old_hash));
+
+            let (status, _) = post_credentials(service.clone(), "/users/login", "gammel.hash@password.no", "Original123").await;
+            assert_eq!(status, StatusCode::OK);
+
+            let stored_user = {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                UsersTable::new(connection).get(user.id).unwrap().unwrap()
+            };
+            assert_ne!(stored_user.password, old_hash);
+            assert!(stored_user.password.starts_with("$argon2"));
+            assert!(!needs_rehash(&stored_user.password));
+            assert!(!stored_user.must_change_password);
+
+            // The upgraded hash is of the same password
+            let (status, _) = post_credentials(service, "/users/login", "gammel.hash@password.no", "Original123").await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        #[tokio::test]
+        async fn post_change_password_replaces_the_password_with_an_argon2_hash() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let user = create_user_with_password(&connection_pool, "rotator@password.no", "Original123");
+            let token = generate_token(&user).expect("Generate token failed");
+
+            let status = change_password(service.clone(), &token, "Original123", "Replacement456").await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+
+            let stored_user = {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                UsersTable::new(connection).get(user.id).unwrap().unwrap()
+            };
+            assert!(stored_user.password.starts_with("$argon2"));
+
+            let (status, _) = post_credentials(service.clone(), "/users/login", "rotator@password.no", "Replacement456").await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (status, _) = post_credentials(service, "/users/login", "rotator@password.no", "Original123").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn login_token_expires_after_the_configured_ttl() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            create_user_with_password(&connection_pool, "ttl@token.no", "Lifetime123");
+
+            let (status, body) = post_credentials(service, "/users/login", "ttl@token.no", "Lifetime123").await;
+            assert_eq!(status, StatusCode::OK);
+
+            let claims = decode_token(body.as_str().unwrap(), jwt_config()).expect("Decode token failed").claims;
+            let expected_exp = chrono::Utc::now().timestamp() + jwt_config().ttl.as_secs() as i64;
+
+            // Allow for the time spent between issuing the token and checking it
+            assert!((claims.exp - expected_exp).abs() <= 5);
+        }
+
+        #[tokio::test]
+        async fn post_reset_password_sets_a_temporary_password_that_must_be_changed() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let admin = create_user_with_role(&connection_pool, "helpdesk@reset.no", "ADMIN");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+            let user = create_user_with_password(&connection_pool, "locked.out@reset.no", "Forgotten123");
+
+            let request = Request::builder()
+                .uri(format!("/users/{}/reset-password", user.id))
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .clone()
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 200
+            assert_eq!(response.status(), StatusCode::OK);
+
+            // Extract body from response
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response_json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let temporary_password = response_json["temporary_password"].as_str().unwrap();
+
+            // Assert that the account is flagged and the temporary password replaced the old one
+            let mut connection = connection_pool.pool.get().expect("Failed to get connection");
+            let must_change_password: bool = users::table
+                .find(user.id)
+                .select(users::must_change_password)
+                .get_result(&mut connection)
+                .expect("Read user failed");
+            assert!(must_change_password);
+
+            let (status, _) = post_credentials(service.clone(), "/auth/check", "locked.out@reset.no", temporary_password).await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (status, _) = post_credentials(service, "/auth/check", "locked.out@reset.no", "Forgotten123").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn post_reset_password_returns_404_on_unknown_id() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let service = users_route(AppState::test(connection_pool.clone()));
+
+            let admin = create_user_with_role(&connection_pool, "helpdesk.404@reset.no", "ADMIN");
+            let admin_token = generate_token(&admin).expect("Generate token failed");
+
+            let request = Request::builder()
+                .uri(format!("/users/{}/reset-password", -666)) // Use a non-existent ID
+                .method("POST")
+                .header("Authorization", format!("Bearer {}", admin_token)) // Add the bearer token
+                .body(Body::empty())
+                .unwrap();
+
+            // Send the request through the service
+            let response = service
+                .oneshot(request)
+                .await
+                .unwrap();
+
+            // Assert that the response status is 404
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn flagged_token_is_refused_on_writes_until_the_password_is_changed() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 2);
+            let app = crate::create_app(AppState::test(connection_pool.clone()));
+
+            let mut new_user = UpsertUser {
+                email: "freshly.reset@mustchange.no".to_string(),
+                password: "NotUsed123".to_string(),
+                fullname: "Nylig Nullstilt".to_string(),
+                role: "WRITER".to_string()
+            };
+            hash_password(&mut new_user).expect("Hash password failed");
+            {
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let mut user_db = UsersTable::new(connection);
+                let user = user_db.create(new_user).expect("Create user failed");
+                let temporary_hash = hash_password_argon2("Temporary123").expect("Hash password failed");
+                user_db.update_password(user.id, &temporary_hash, true).expect("Reset password failed");
+            }
+
+            // Login still succeeds, but tells the client the password must be changed
+            let (status, body) = post_credentials(app.clone(), "/users/login", "freshly.reset@mustchange.no", "Temporary123").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["must_change_password"], true);
+            let token = body["token"].as_str().unwrap().to_string();
+            assert!(decode_token(&token, jwt_config()).expect("Decode token failed").claims.must_change_password);
+
+            let create_location = |token: &str| Request::builder()
+                .uri("/locations")
+                .method("POST")
+                .header("content-type", "application/json")
+                .header("Authorization", format!("Bearer {}", token)) // Add the bearer token
+                .body(Body::from(json!({"star_system": "Gatekeeper", "area": "Threshold"}).to_string()))
+                .unwrap();
+
+            let response = app.clone().oneshot(create_location(&token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+            assert_eq!(change_password(app.clone(), &token, "Temporary123", "Permanent456").await, StatusCode::NO_CONTENT);
+
+            // The same token is let through once the password has been changed
+            let response = app.oneshot(create_location(&token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        #[tokio::test]
+        async fn flagged_token_is_refused_on_put_users_for_its_own_record() {
+            with_test_db(|connection_pool| async move {
+                let user = create_user_with_password(&connection_pool, "selv.redigering@mustchange.no", "NotUsed123");
+                {
+                    let connection = connection_pool.pool.get().expect("Failed to get connection");
+                    let temporary_hash = hash_password_argon2("Temporary123").expect("Hash password failed");
+                    UsersTable::new(connection).update_password(user.id, &temporary_hash, true).expect("Reset password failed");
+                }
+                let bearer_token = generate_token(&user).expect("Generate token failed");
+
+                let request_body = json!({
+                    "email": "overtatt@mustchange.no",
+                    "fullname": "Ny Eier",
+                    "role": user.role
+                });
+
+                let request = Request::builder()
+                    .uri(format!("/users/{}", user.id))
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .header("Authorization", format!("Bearer {}", bearer_token)) // Add the bearer token
+                    .body(Body::from(request_body.to_string()))
+                    .unwrap();
+
+                // Send the request through the service
+                let response = users_route(AppState::test(connection_pool.clone()))
+                    .oneshot(request)
+                    .await
+                    .unwrap();
+
+                // Assert that the response status is 403 and the record is untouched
+                assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+                let connection = connection_pool.pool.get().expect("Failed to get connection");
+                let stored_user = UsersTable::new(connection).get(user.id).expect("Read user failed").unwrap();
+                assert_eq!((stored_user.email, stored_user.fullname), (user.email, user.fullname));
+            }).await;
+        }
     }
 }
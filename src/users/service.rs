@@ -1,6 +1,8 @@
 pub mod service {
 
+    use chrono::{DateTime, Utc};
     use diesel::{
+        dsl::now,
         prelude::*,
         PgConnection,
         result::Error,
@@ -8,13 +10,15 @@ pub mod service {
     };
 
     use crate::{
-        users::model::{User, UpsertUser},
+        users::model::{User, UpdateUser, UpsertUser, UserRole, string_to_user_role},
         schema,
-        common::error::CustomError
+        common::error::{CustomError, ErrorType}
     };
 
     type PooledPg = PooledConnection<ConnectionManager<PgConnection>>;
 
+    define_sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
     pub struct UsersTable {
         connection: PooledPg,
     }
@@ -24,9 +28,19 @@ pub mod service {
             UsersTable { connection }
         }
 
-        pub fn create(&mut self, create_user: UpsertUser) -> Result<User, CustomError> {
+        pub fn create(&mut self, mut create_user: UpsertUser) -> Result<User, CustomError> {
             use schema::users;
 
+            create_user.normalize_email();
+
+            // Refuse unknown roles before touching the database so they can never be persisted
+            if !create_user.has_valid_role() {
+                return Err(CustomError::new(
+                    format!("while creating user: invalid role '{}'", create_user.role).as_str(),
+                    ErrorType::InvalidInput,
+                ));
+            }
+
             diesel::insert_into(users::table)
                 .values((
                     users::email.eq(&create_user.email),
@@ -34,6 +48,7 @@ pub mod service {
                     users::fullname.eq(&create_user.fullname),
                     users::role.eq(&create_user.role),
                 ))
+                .returning(User::as_returning())
                 .get_result::<User>(&mut self.connection)
                 .map_err(|err| {
                     CustomError::from_diesel_err(err, "while creating user")
@@ -44,67 +59,194 @@ pub mod service {
             use schema::users;
 
             let user = users::table.find(user_id)
+                .filter(users::deleted_at.is_null())
+                .select(User::as_select())
                 .get_result(&mut self.connection)
                 .optional()?;
 
             Ok(user)
         }
 
+        // Matches regardless of casing, which also covers rows stored before emails were normalized
         pub fn get_by_email(&mut self, email: String) -> Result<Option<User>, Error> {
             use schema::users;
 
             let user = users::table
-                .filter(users::email.eq(email))
+                .filter(lower(users::email).eq(email.to_lowercase()))
+                .filter(users::deleted_at.is_null())
+                .select(User::as_select())
                 .get_result(&mut self.connection)
                 .optional()?;
 
             Ok(user)
         }
 
-        pub fn update(&mut self, user_id: i32, update_user: UpsertUser) -> Result<User, Error> {
+        // Returns a page of the active users, optionally only those with the given role, along with the total number of matches
+        pub fn list(&mut self, limit: i64, offset: i64, role: Option<UserRole>) -> Result<(Vec<User>, i64), Error> {
             use schema::users;
 
-            // Check if the user exists before attempting to update
-            let existing_user = users::table.find(user_id)
-                .get_result::<User>(&mut self.connection);
-
-            match existing_user {
-                Ok(_) => {
-                    let updated_user = diesel::update(users::table.find(user_id))
-                        .set((
-                            users::email.eq(&update_user.email),
-                            users::password.eq(&update_user.password),
-                            users::fullname.eq(&update_user.fullname),
-                            users::role.eq(&update_user.role),
-                        ))
-                        .get_result(&mut self.connection)
-                        .expect("Update user failed");
-
-                    Ok(updated_user)
-                },
-                Err(_) => Err(Error::NotFound)
-            }
+            let filtered_users = || {
+                let mut query = users::table
+                    .filter(users::deleted_at.is_null())
+                    .into_boxed();
+
+                if let Some(role) = &role {
+                    query = query.filter(users::role.eq(role.to_string()));
+                }
+
+                query
+            };
+
+            let items = filtered_users()
+                .order(users::id.asc())
+                .limit(limit)
+                .offset(offset)
+                .select(User::as_select())
+                .load::<User>(&mut self.connection)?;
+
+            let total = filtered_users()
+                .count()
+                .get_result::<i64>(&mut self.connection)?;
+
+            Ok((items, total))
         }
 
+        // Like get_by_email, casing is ignored. Soft-deleted users still hold on to their email, as they may be restored
+        pub fn email_exists(&mut self, email: &str) -> QueryResult<bool> {
+            use schema::users;
+
+            diesel::select(diesel::dsl::exists(
+                users::table.filter(lower(users::email).eq(email.to_lowercase()))
+            ))
+                .get_result(&mut self.connection)
+        }
 
-        pub fn delete(&mut self, user_id: i32) -> Result<(), diesel::result::Error> {
+        // Returns None, changing nothing, when the update would demote the last active admin. The password is left as it
+        // is, it is only changed through update_password
+        pub fn update(&mut self, user_id: i32, mut update_user: UpdateUser) -> Result<Option<User>, Error> {
             use schema::users;
 
-            // Check if the location exists before attempting to delete
-            let existing_location = users::table.find(user_id)
-                .get_result::<User>(&mut self.connection);
-
-            match existing_location {
-                Ok(_) => {
-                    diesel::delete(users::table.find(user_id))
-                        .execute(&mut self.connection)?;
-                    Ok(())
-                },
-                Err(_) => {
-                    Err(Error::NotFound)
+            update_user.email = update_user.email.to_lowercase();
+
+            self.connection.transaction(|connection| {
+
+                // Check if the user exists before attempting to update
+                let existing_user = users::table.find(user_id)
+                    .filter(users::deleted_at.is_null())
+                    .select(User::as_select())
+                    .get_result::<User>(connection);
+
+                match existing_user {
+                    Ok(_) => {
+                        if string_to_user_role(update_user.role.clone()) != UserRole::ADMIN && is_last_admin(connection, user_id)? {
+                            return Ok(None);
+                        }
+
+                        let updated_user = diesel::update(users::table.find(user_id))
+                            .set((
+                                users::email.eq(&update_user.email),
+                                users::fullname.eq(&update_user.fullname),
+                                users::role.eq(&update_user.role),
+                            ))
+                            .returning(User::as_returning())
+                            .get_result(connection)?;
+
+                        Ok(Some(updated_user))
+                    },
+                    Err(_) => Err(Error::NotFound)
                 }
+            })
+        }
+
+        // A password set by the user clears 'must_change_password', while one set on their behalf should set it
+        pub fn update_password(&mut self, user_id: i32, password_hash: &str, must_change_password: bool) -> Result<(), Error> {
+            use schema::users;
+
+            let updated = diesel::update(users::table.find(user_id).filter(users::deleted_at.is_null()))
+                .set((
+                    users::password.eq(password_hash),
+                    users::must_change_password.eq(must_change_password),
+                ))
+                .execute(&mut self.connection)?;
+
+            match updated {
+                0 => Err(Error::NotFound),
+                _ => Ok(())
             }
         }
+
+        // Soft-deletes the user, who is from then on treated as missing until restored. Returns false, deleting nothing,
+        // when the user is the last active admin, as nobody would be left to restore users or manage roles
+        pub fn delete(&mut self, user_id: i32) -> Result<bool, diesel::result::Error> {
+            use schema::users;
+
+            self.connection.transaction(|connection| {
+                if is_last_admin(connection, user_id)? {
+                    return Ok(false);
+                }
+
+                let deleted = diesel::update(users::table.find(user_id).filter(users::deleted_at.is_null()))
+                    .set(users::deleted_at.eq(now))
+                    .execute(connection)?;
+
+                match deleted {
+                    0 => Err(Error::NotFound),
+                    _ => Ok(true)
+                }
+            })
+        }
+
+        pub fn restore(&mut self, user_id: i32) -> Result<User, diesel::result::Error> {
+            use schema::users;
+
+            // Only a soft-deleted user can be restored, anything else is reported as not found
+            diesel::update(users::table.find(user_id).filter(users::deleted_at.is_not_null()))
+                .set(users::deleted_at.eq(None::<DateTime<Utc>>))
+                .returning(User::as_returning())
+                .get_result(&mut self.connection)
+        }
+
+        // Like update, returns None when the change would demote the last active admin
+        pub fn set_role(&mut self, user_id: i32, role: UserRole) -> Result<Option<User>, Error> {
+            use schema::users;
+
+            self.connection.transaction(|connection| {
+                if role != UserRole::ADMIN && is_last_admin(connection, user_id)? {
+                    return Ok(None);
+                }
+
+                diesel::update(users::table.find(user_id).filter(users::deleted_at.is_null()))
+                    .set(users::role.eq(role.to_string()))
+                    .returning(User::as_returning())
+                    .get_result(connection)
+                    .map(Some)
+            })
+        }
+
+        pub fn count_active_admins(&mut self) -> QueryResult<i64> {
+            use schema::users;
+
+            users::table
+                .filter(users::role.eq(UserRole::ADMIN.to_string()))
+                .filter(users::deleted_at.is_null())
+                .count()
+                .get_result(&mut self.connection)
+        }
+    }
+
+    // Locks the active admins until the transaction ends, so concurrent deletes or demotions can't each see the other
+    // admin as still around and both go through
+    fn is_last_admin(connection: &mut PgConnection, user_id: i32) -> QueryResult<bool> {
+        use schema::users;
+
+        let admin_ids = users::table
+            .filter(users::role.eq(UserRole::ADMIN.to_string()))
+            .filter(users::deleted_at.is_null())
+            .select(users::id)
+            .for_update()
+            .load::<i32>(connection)?;
+
+        Ok(admin_ids == [user_id])
     }
 
     #[cfg(test)]
@@ -116,7 +258,7 @@ pub mod service {
                 error::ErrorType
             },
             users::{
-                model::UpsertUser,
+                model::{UpdateUser, UpsertUser, UserRole},
                 service::service::UsersTable
             }
         };
@@ -187,6 +329,66 @@ pub mod service {
             }
         }
 
+        #[test]
+        fn email_exists_is_true_for_registered_email_regardless_of_casing() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+
+            user_db.create(UpsertUser {
+                email: "finnes@allerede.no".to_string(),
+                password: "Eksisterer1".to_string(),
+                fullname: "Finn Esallerede".to_string(),
+                role: "READER".to_string()
+            }).expect("Create user failed");
+
+            assert!(user_db.email_exists("finnes@allerede.no").expect("Lookup failed"));
+            assert!(user_db.email_exists("Finnes@Allerede.no").expect("Lookup failed"));
+        }
+
+        #[test]
+        fn email_exists_is_false_for_unknown_email() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+
+            assert!(!user_db.email_exists("finnes@ikke.no").expect("Lookup failed"));
+        }
+
+        #[test]
+        fn list_filters_by_role_and_skips_deleted_users() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+
+            let mut create = |email: &str, role: &str| user_db.create(UpsertUser {
+                email: email.to_string(),
+                password: "Oppramset1".to_string(),
+                fullname: "Liste Listesen".to_string(),
+                role: role.to_string()
+            }).expect("Create user failed");
+
+            let writer = create("skribent@liste.no", "WRITER");
+            let deleted_writer = create("slettet.skribent@liste.no", "WRITER");
+            let reader = create("leser@liste.no", "READER");
+
+            user_db.delete(deleted_writer.id).expect("Delete user failed");
+
+            let (writers, total_writers) = user_db.list(1000, 0, Some(UserRole::WRITER)).expect("List users failed");
+            assert!(writers.iter().all(|user| user.role == "WRITER"));
+            assert!(writers.iter().any(|user| user.id == writer.id));
+            assert!(!writers.iter().any(|user| user.id == deleted_writer.id));
+            assert_eq!(total_writers, writers.len() as i64);
+
+            let (everyone, _) = user_db.list(1000, 0, None).expect("List users failed");
+            assert!(everyone.iter().any(|user| user.id == writer.id));
+            assert!(everyone.iter().any(|user| user.id == reader.id));
+            assert!(!everyone.iter().any(|user| user.id == deleted_writer.id));
+        }
+
         #[test]
         fn read_succeeds_on_existing_id() {
             let database_url = load_environment_variable("TEST_DB");
@@ -239,17 +441,18 @@ pub mod service {
 
             let original_user = user_db.create(original_request.clone()).expect("Create user failed");
 
-            let updated_request = UpsertUser {
+            let updated_request = UpdateUser {
                 email: "uhi@wwf.com".to_string(),
-                password: "SlafsSlafsSlaf".to_string(),
+                password: Some("SlafsSlafsSlaf".to_string()),
                 fullname: "Panda Pondi".to_string(),
                 role: "READER".to_string()
             };
 
-            let updated_user = user_db.update(original_user.id, updated_request.clone()).expect("Update user failed");
+            let updated_user = user_db.update(original_user.id, updated_request.clone()).expect("Update user failed").unwrap();
 
+            // Assert that everything but the password was updated
             assert_eq!(updated_user.email, updated_request.email);
-            assert_eq!(updated_user.password, updated_request.password);
+            assert_eq!(updated_user.password, original_user.password);
             assert_eq!(updated_user.fullname, updated_request.fullname);
             assert_eq!(updated_user.role, updated_request.role);
         }
@@ -261,9 +464,9 @@ pub mod service {
             let connection = connection_pool.pool.get().expect("Failed to get connection");
             let mut user_db = UsersTable::new(connection);
 
-            let request = UpsertUser {
+            let request = UpdateUser {
                 email: "lukewarm@manlet.com".to_string(),
-                password: "realfrogeyes".to_string(),
+                password: None,
                 fullname: "Lukas Parrot".to_string(),
                 role: "READER".to_string()
             };
@@ -294,6 +497,34 @@ pub mod service {
             assert!(deleted_user.is_none()); // Expecting lack of value as user has been deleted
         }
 
+        #[test]
+        fn restore_brings_back_soft_deleted_user() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+
+            let user = user_db.create(UpsertUser {
+                email: "lazarus@bethany.org".to_string(),
+                password: "FourDaysLater".to_string(),
+                fullname: "Lazarus of Bethany".to_string(),
+                role: "READER".to_string()
+            }).expect("Create user failed");
+
+            user_db.delete(user.id).expect("Delete user failed");
+            assert!(user_db.get_by_email(user.email.clone()).expect("Read user failed").is_none());
+
+            // A soft-deleted user keeps the email address, so it can't be registered again in the meantime
+            assert!(user_db.email_exists(&user.email).expect("Lookup failed"));
+
+            let restored_user = user_db.restore(user.id).expect("Restore user failed");
+            assert_eq!(restored_user.id, user.id);
+            assert!(user_db.get(user.id).expect("Read user failed").is_some());
+
+            // Restoring a user that isn't deleted fails
+            assert!(user_db.restore(user.id).is_err());
+        }
+
         #[test]
         fn delete_fails_on_nonexistent_id() {
             let database_url = load_environment_variable("TEST_DB");
@@ -304,6 +535,32 @@ pub mod service {
 
             assert!(result.is_err());  // Expecting an error as the ID is not present
         }
+
+        #[test]
+        fn create_fails_on_invalid_role_before_insert() {
+            let database_url = load_environment_variable("TEST_DB");
+            let connection_pool = create_shared_connection_pool(database_url, 1);
+            let connection = connection_pool.pool.get().expect("Failed to get connection");
+            let mut user_db = UsersTable::new(connection);
+
+            for role in ["INVALID", "SUPERUSER"] {
+                let email = format!("{}@roleless.no", role.to_lowercase());
+                let request = UpsertUser {
+                    email: email.clone(),
+                    password: "NoRoleForMe".to_string(),
+                    fullname: "Rolf Roleless".to_string(),
+                    role: role.to_string()
+                };
+
+                let result = user_db.create(request);
+
+                // Check the specific error type
+                let err = result.expect_err("Expected create to fail for an invalid role");
+                assert_eq!(err.err_type, ErrorType::InvalidInput);
+
+                // Assert that nothing was inserted
+                assert!(user_db.get_by_email(email).expect("Read user failed").is_none());
+            }
+        }
     }
 }
-